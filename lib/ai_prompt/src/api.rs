@@ -7,9 +7,24 @@ use serde::Serialize;
 pub trait AsEmbeddable: Clone + Borrow<str> {}
 impl<T> AsEmbeddable for T where T: Clone + Borrow<str> {}
 
+/// Environments which send their requests with an api key
+pub trait WithApiKey: Sized {
+    /// Whether requests need an openai api key
+    fn uses_openai_key(&self) -> bool {
+        false
+    }
+
+    /// Copy of the environment, which sends its requests with `api_key`
+    fn with_api_key(&self, api_key: &str) -> Self;
+}
+
 /// API for embedding stuff
 #[async_trait::async_trait]
 pub trait AsEmbeddingEnv {
+    /// Identifies the backend and model as "{model},{name}", since only embeddings of the same
+    /// model can be compared
+    fn embedding_model(&self) -> String;
+
     /// Embed the specified strings
     async fn embed(&self, stmts: &[&str]) -> anyhow::Result<EmbedResult>;
 }
@@ -39,6 +54,21 @@ pub struct AiEnvInfo {
     pub check_model: Option<String>,
 }
 
+/// List price of a model in USD per million tokens
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl ModelPrice {
+    /// Price in USD of the given usage
+    pub fn cost(&self, prompt_tokens: i64, completion_tokens: i64) -> f64 {
+        (prompt_tokens as f64 * self.prompt + completion_tokens as f64 * self.completion)
+            / 1_000_000.0
+    }
+}
+
 /// Contains the information to identify a used prompt
 #[derive(Serialize, Clone, PartialEq, Eq, Debug)]
 pub struct PromptInfo {
@@ -77,14 +107,14 @@ pub trait AiEnv {
     ) -> anyhow::Result<Prompt::PromptResult>;
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum AiRole {
     System,
     User,
     Assistant,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct AiMessage {
     pub role: AiRole,
     pub content: String,
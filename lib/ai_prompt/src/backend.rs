@@ -0,0 +1,108 @@
+//! Backends selectable at runtime
+//!
+//! [AiEnv] has generic methods and can't be a trait object, so the backends are wrapped in enums
+//! which forward to the selected one.
+
+use async_trait::async_trait;
+
+use crate::api::{
+    AiEnv, AiEnvInfo, AiPrompt, AsEmbeddingEnv, CheckResult, EmbedResult, WithApiKey,
+};
+use crate::compatible::OpenAiCompatibleEnv;
+use crate::hashing::HashingEmbeddingEnv;
+use crate::openai::OpenAiEnv;
+
+/// Backend to run prompts with
+#[derive(Clone, Debug)]
+pub enum AiBackend {
+    OpenAi(OpenAiEnv),
+    OpenAiCompatible(OpenAiCompatibleEnv),
+}
+
+/// Backend to embed statements with
+#[derive(Clone, Debug)]
+pub enum EmbeddingBackend {
+    OpenAi(OpenAiEnv),
+    OpenAiCompatible(OpenAiCompatibleEnv),
+    Hashing(HashingEmbeddingEnv),
+}
+
+impl WithApiKey for AiBackend {
+    fn uses_openai_key(&self) -> bool {
+        match self {
+            Self::OpenAi(env) => env.uses_openai_key(),
+            Self::OpenAiCompatible(env) => env.uses_openai_key(),
+        }
+    }
+
+    fn with_api_key(&self, api_key: &str) -> Self {
+        match self {
+            Self::OpenAi(env) => Self::OpenAi(env.with_api_key(api_key)),
+            Self::OpenAiCompatible(env) => Self::OpenAiCompatible(env.with_api_key(api_key)),
+        }
+    }
+}
+
+impl WithApiKey for EmbeddingBackend {
+    fn uses_openai_key(&self) -> bool {
+        match self {
+            Self::OpenAi(env) => env.uses_openai_key(),
+            Self::OpenAiCompatible(env) => env.uses_openai_key(),
+            Self::Hashing(env) => env.uses_openai_key(),
+        }
+    }
+
+    fn with_api_key(&self, api_key: &str) -> Self {
+        match self {
+            Self::OpenAi(env) => Self::OpenAi(env.with_api_key(api_key)),
+            Self::OpenAiCompatible(env) => Self::OpenAiCompatible(env.with_api_key(api_key)),
+            Self::Hashing(env) => Self::Hashing(env.with_api_key(api_key)),
+        }
+    }
+}
+
+#[async_trait]
+impl AiEnv for AiBackend {
+    fn info(&self) -> AiEnvInfo {
+        match self {
+            Self::OpenAi(env) => env.info(),
+            Self::OpenAiCompatible(env) => env.info(),
+        }
+    }
+
+    async fn check_prompt<Prompt: AiPrompt>(&self, prompt: &Prompt) -> anyhow::Result<CheckResult> {
+        match self {
+            Self::OpenAi(env) => env.check_prompt(prompt).await,
+            Self::OpenAiCompatible(env) => env.check_prompt(prompt).await,
+        }
+    }
+
+    async fn send_prompt<Prompt: AiPrompt>(
+        &self,
+        prompt: &Prompt,
+    ) -> anyhow::Result<Prompt::PromptResult> {
+        match self {
+            Self::OpenAi(env) => env.send_prompt(prompt).await,
+            Self::OpenAiCompatible(env) => env.send_prompt(prompt).await,
+        }
+    }
+}
+
+#[async_trait]
+impl AsEmbeddingEnv for EmbeddingBackend {
+    fn embedding_model(&self) -> String {
+        match self {
+            Self::OpenAi(env) => env.embedding_model(),
+            Self::OpenAiCompatible(env) => env.embedding_model(),
+            Self::Hashing(env) => env.embedding_model(),
+        }
+    }
+
+    async fn embed(&self, stmts: &[&str]) -> anyhow::Result<EmbedResult> {
+        match self {
+            Self::OpenAi(env) => env.embed(stmts).await,
+            Self::OpenAiCompatible(env) => env.embed(stmts).await,
+            Self::Hashing(env) => env.embed(stmts).await,
+        }
+    }
+}
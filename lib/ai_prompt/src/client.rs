@@ -0,0 +1,292 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use reqwest::{Client, RequestBuilder};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+
+use crate::api::{AiMessage, AiRole, CheckResult, EmbedResult, Embedding};
+
+/// How long connecting to the API may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a request may take until its response is read, so that a stuck provider can't block
+/// a worker forever. Shorter than the time workers get to finish on shutdown.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// Client for the OpenAI HTTP API, which openai-compatible servers speak as well
+///
+/// Carries its own credentials, so requests with different keys can run concurrently.
+#[derive(Clone)]
+pub struct ApiClient {
+    base_url: String,
+    api_key: Option<String>,
+    http: Client,
+}
+
+// leaves out the api key, since envs get logged
+impl std::fmt::Debug for ApiClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiClient")
+            .field("base_url", &self.base_url)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Answer of a chat completion
+pub struct ChatResult {
+    pub content: String,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+}
+
+#[derive(Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<ChatMessage>,
+    temperature: f32,
+}
+
+#[derive(Serialize)]
+struct ChatMessage {
+    role: &'static str,
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct ChatResponse {
+    choices: Vec<ChatChoice>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct ChatChoice {
+    message: ChatResponseMessage,
+}
+
+#[derive(Deserialize)]
+struct ChatResponseMessage {
+    content: String,
+}
+
+#[derive(Deserialize, Default)]
+struct Usage {
+    prompt_tokens: u32,
+    #[serde(default)]
+    completion_tokens: u32,
+    total_tokens: u32,
+}
+
+#[derive(Serialize)]
+struct EmbeddingsRequest<'a> {
+    model: &'a str,
+    input: &'a [&'a str],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingsResponse {
+    data: Vec<EmbeddingData>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    embedding: Vec<f64>,
+    index: usize,
+}
+
+#[derive(Serialize)]
+struct ModerationRequest<'a> {
+    model: &'a str,
+    input: String,
+}
+
+#[derive(Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+#[derive(Deserialize)]
+struct ModerationResult {
+    flagged: bool,
+    categories: Categories,
+}
+
+#[derive(Deserialize)]
+struct Categories {
+    hate: bool,
+    #[serde(rename = "hate/threatening")]
+    hate_threatening: bool,
+    #[serde(rename = "self-harm")]
+    self_harm: bool,
+    sexual: bool,
+    #[serde(rename = "sexual/minors")]
+    sexual_minors: bool,
+    violence: bool,
+    #[serde(rename = "violence/graphic")]
+    violence_graphic: bool,
+}
+
+impl From<AiRole> for &'static str {
+    fn from(value: AiRole) -> Self {
+        match value {
+            AiRole::System => "system",
+            AiRole::User => "user",
+            AiRole::Assistant => "assistant",
+        }
+    }
+}
+
+impl ApiClient {
+    /// Client for the API at `base_url`, e.g. http://localhost:8080/v1
+    pub fn new(base_url: &str, api_key: Option<String>) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            http: Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("Unable to create http client"),
+        }
+    }
+
+    /// Copy of the client sending its requests with `api_key`, sharing the connection pool
+    pub fn with_api_key(&self, api_key: &str) -> Self {
+        Self {
+            api_key: Some(api_key.to_string()),
+            ..self.clone()
+        }
+    }
+
+    async fn post<T: DeserializeOwned>(
+        &self,
+        route: &str,
+        body: &impl Serialize,
+    ) -> anyhow::Result<T> {
+        let mut request: RequestBuilder = self
+            .http
+            .post(format!("{}/{route}", self.base_url))
+            .json(body);
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!("{} responded with {status}: {body}", self.base_url));
+        }
+        Ok(response.json().await?)
+    }
+
+    /// Completes the chat with `model`
+    pub async fn chat(&self, model: &str, messages: Vec<AiMessage>) -> anyhow::Result<ChatResult> {
+        let messages = messages
+            .into_iter()
+            .map(|m| ChatMessage {
+                role: m.role.into(),
+                content: m.content,
+            })
+            .collect();
+        let response: ChatResponse = self
+            .post(
+                "chat/completions",
+                &ChatRequest {
+                    model,
+                    messages,
+                    temperature: 0.0,
+                },
+            )
+            .await?;
+        let usage = response.usage.unwrap_or_default();
+        let content = response
+            .choices
+            .into_iter()
+            .next()
+            .ok_or(anyhow!("{} yielded no completion", self.base_url))?
+            .message
+            .content;
+        Ok(ChatResult {
+            content,
+            prompt_tokens: usage.prompt_tokens.into(),
+            completion_tokens: usage.completion_tokens.into(),
+            total_tokens: usage.total_tokens.into(),
+        })
+    }
+
+    /// Embeds the strings with `model`, in the order they were passed
+    pub async fn embed(&self, model: &str, stmts: &[&str]) -> anyhow::Result<EmbedResult> {
+        if stmts.is_empty() {
+            return Err(anyhow!("Passed nothing to embed."));
+        }
+        let mut response: EmbeddingsResponse = self
+            .post(
+                "embeddings",
+                &EmbeddingsRequest {
+                    model,
+                    input: stmts,
+                },
+            )
+            .await?;
+        if response.data.len() != stmts.len() {
+            return Err(anyhow!(
+                "{} yielded {} embeddings for {} inputs",
+                self.base_url,
+                response.data.len(),
+                stmts.len()
+            ));
+        }
+        response.data.sort_by_key(|e| e.index);
+        let usage = response.usage.unwrap_or_default();
+
+        Ok(EmbedResult {
+            data: response
+                .data
+                .into_iter()
+                .map(|e| Embedding {
+                    values: e.embedding,
+                })
+                .collect(),
+            prompt_tokens: usage.prompt_tokens,
+            total_tokens: usage.total_tokens,
+        })
+    }
+
+    /// Checks `input` against the moderation endpoint
+    pub async fn moderate(&self, model: &str, input: String) -> anyhow::Result<CheckResult> {
+        let response: ModerationResponse = self
+            .post("moderations", &ModerationRequest { model, input })
+            .await?;
+        match response.results.as_slice() {
+            [ModerationResult {
+                flagged,
+                categories:
+                    Categories {
+                        hate,
+                        hate_threatening,
+                        self_harm,
+                        sexual,
+                        sexual_minors,
+                        violence,
+                        violence_graphic,
+                    },
+            }, ..] => Ok(match flagged {
+                false => CheckResult::Ok,
+                true => CheckResult::Flagged(serde_json::to_string(&json!([{
+                    "hate": hate,
+                    "hate_threatening": hate_threatening,
+                    "self_harm": self_harm,
+                    "sexual": sexual,
+                    "sexual_minors": sexual_minors,
+                    "violence": violence,
+                    "violence_graphic": violence_graphic,
+                }]))?),
+            }),
+            _ => Err(anyhow!(
+                "{} moderation yielded an empty result",
+                self.base_url
+            )),
+        }
+    }
+}
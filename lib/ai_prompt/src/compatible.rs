@@ -0,0 +1,89 @@
+use async_trait::async_trait;
+
+use crate::api::{AsEmbeddingEnv, CheckResult, EmbedResult, WithApiKey};
+use crate::client::ApiClient;
+
+use super::api::{AiEnv, AiEnvInfo, AiPrompt, PromptResponse};
+
+/// Environment for running stuff against a server speaking the OpenAI HTTP API
+///
+/// Works with e.g. the llama.cpp server or vLLM. These have no moderation endpoint, so prompts
+/// are not checked.
+#[derive(Clone, Debug)]
+pub struct OpenAiCompatibleEnv {
+    /// Model used for prompts
+    pub model: String,
+    /// Model used for embeddings
+    pub embedding_model: String,
+    client: ApiClient,
+}
+
+impl OpenAiCompatibleEnv {
+    /// Env for the server at `base_url`, e.g. http://localhost:8080/v1. The api key is sent as
+    /// bearer token, if the server requires one.
+    pub fn new(
+        base_url: &str,
+        model: &str,
+        embedding_model: &str,
+        api_key: Option<String>,
+    ) -> Self {
+        Self {
+            model: model.to_string(),
+            embedding_model: embedding_model.to_string(),
+            client: ApiClient::new(base_url, api_key),
+        }
+    }
+}
+
+impl WithApiKey for OpenAiCompatibleEnv {
+    fn with_api_key(&self, api_key: &str) -> Self {
+        Self {
+            client: self.client.with_api_key(api_key),
+            ..self.clone()
+        }
+    }
+}
+
+#[async_trait]
+impl AiEnv for OpenAiCompatibleEnv {
+    fn info(&self) -> AiEnvInfo {
+        AiEnvInfo {
+            name: "openai-compatible".into(),
+            model: self.model.to_owned(),
+            check_model: None,
+        }
+    }
+
+    async fn check_prompt<Prompt: AiPrompt>(
+        &self,
+        _prompt: &Prompt,
+    ) -> anyhow::Result<CheckResult> {
+        Ok(CheckResult::Ok)
+    }
+
+    async fn send_prompt<Prompt: AiPrompt>(
+        &self,
+        prompt: &Prompt,
+    ) -> anyhow::Result<Prompt::PromptResult> {
+        let result = self.client.chat(&self.model, prompt.primer()).await?;
+        prompt.handle_response(PromptResponse {
+            env_info: self.info(),
+            prompt_info: prompt.info(),
+            content: result.content,
+            completion_tokens: result.completion_tokens,
+            prompt_tokens: result.prompt_tokens,
+            total_tokens: result.total_tokens,
+        })
+    }
+}
+
+#[async_trait]
+impl AsEmbeddingEnv for OpenAiCompatibleEnv {
+    fn embedding_model(&self) -> String {
+        format!("{},openai-compatible", self.embedding_model)
+    }
+
+    async fn embed(&self, stmts: &[&str]) -> anyhow::Result<EmbedResult> {
+        self.client.embed(&self.embedding_model, stmts).await
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::anyhow;
+use async_trait::async_trait;
+
+use crate::api::{AsEmbeddingEnv, EmbedResult, Embedding, WithApiKey};
+
+/// Embeds text in process by hashing it, without sending it anywhere
+///
+/// Hashes the words and character trigrams of a text into a fixed number of dimensions (the
+/// "hashing trick"). This is lexical only: texts sharing many words get similar embeddings, but
+/// it knows nothing about meaning or synonyms. Semantic embeddings without openai need a model
+/// behind an openai-compatible server. The hash is fixed, so stored embeddings stay comparable
+/// across releases.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct HashingEmbeddingEnv {
+    pub dimensions: usize,
+}
+
+impl Default for HashingEmbeddingEnv {
+    fn default() -> Self {
+        Self { dimensions: 512 }
+    }
+}
+
+/// 64 bit FNV-1a, since the std hashers may change between rust versions
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl HashingEmbeddingEnv {
+    /// Adds the feature to a dimension picked by its hash, with a sign picked by its hash to
+    /// cancel out collisions on average
+    fn add_feature(&self, values: &mut [f64], feature: &str, weight: f64) {
+        let hash = fnv1a(feature.as_bytes());
+        let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+        values[(hash % self.dimensions as u64) as usize] += sign * weight;
+    }
+
+    /// Embedding of a single text, normalized to length 1
+    pub fn embed_one(&self, text: &str) -> Vec<f64> {
+        let mut values = vec![0.0; self.dimensions];
+        let lowercase = text.to_lowercase();
+        for word in lowercase
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+        {
+            self.add_feature(&mut values, word, 1.0);
+            let padded: Vec<char> = format!(" {word} ").chars().collect();
+            for trigram in padded.windows(3) {
+                self.add_feature(&mut values, &trigram.iter().collect::<String>(), 0.5);
+            }
+        }
+        let norm = values.iter().map(|v| v * v).sum::<f64>().sqrt();
+        if norm > 0.0 {
+            values.iter_mut().for_each(|v| *v /= norm);
+        }
+        values
+    }
+}
+
+// sends nothing, so the key is not needed
+impl WithApiKey for HashingEmbeddingEnv {
+    fn with_api_key(&self, _api_key: &str) -> Self {
+        self.clone()
+    }
+}
+
+#[async_trait]
+impl AsEmbeddingEnv for HashingEmbeddingEnv {
+    // embeddings of different dimensions hash the features differently
+    fn embedding_model(&self) -> String {
+        format!("hashing-{},local", self.dimensions)
+    }
+
+    async fn embed(&self, stmts: &[&str]) -> anyhow::Result<EmbedResult> {
+        if stmts.is_empty() {
+            return Err(anyhow!("Passed nothing to embed."));
+        }
+        Ok(EmbedResult {
+            data: stmts
+                .iter()
+                .map(|s| Embedding {
+                    values: self.embed_one(s),
+                })
+                .collect(),
+            // nothing is billed, so nothing needs to be rate limited
+            prompt_tokens: 0,
+            total_tokens: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn similarity(a: &[f64], b: &[f64]) -> f64 {
+        a.iter().zip(b).map(|(x, y)| x * y).sum()
+    }
+
+    #[test]
+    fn similar_texts_get_similar_embeddings() {
+        let env = HashingEmbeddingEnv::default();
+        let cats = env.embed_one("Cats should be allowed in every park");
+        let more_cats = env.embed_one("cats should be allowed in parks!");
+        let taxes = env.embed_one("Income taxes are too high");
+
+        assert_eq!(cats.len(), 512);
+        assert!((similarity(&cats, &cats) - 1.0).abs() < 1e-9);
+        assert!(similarity(&cats, &more_cats) > 0.7);
+        assert!(similarity(&cats, &taxes) < 0.3);
+        assert_eq!(cats, env.embed_one("Cats should be allowed in every park"));
+    }
+}
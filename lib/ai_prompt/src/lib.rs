@@ -1,2 +1,8 @@
 pub mod api;
+pub mod backend;
+pub mod client;
+pub mod compatible;
+pub mod hashing;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 pub mod openai;
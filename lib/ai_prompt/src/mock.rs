@@ -0,0 +1,361 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+
+use crate::api::{
+    AiEnv, AiEnvInfo, AiMessage, AiPrompt, AsEmbeddingEnv, CheckResult, EmbedResult, Embedding,
+    PromptResponse, WithApiKey,
+};
+use crate::hashing::HashingEmbeddingEnv;
+
+/// Scripted answer of a [MockAiEnv] to one prompt
+#[derive(Clone, Debug)]
+pub enum MockReply {
+    /// Passes the moderation check and answers with `content`
+    Content {
+        content: String,
+        prompt_tokens: i64,
+        completion_tokens: i64,
+    },
+    /// Fails the moderation check with the given categories
+    Flagged(String),
+    /// The request fails with the given message
+    Fail(String),
+}
+
+impl MockReply {
+    /// Answer with `content`, counting one token per word
+    pub fn content(content: &str) -> Self {
+        Self::Content {
+            content: content.to_string(),
+            prompt_tokens: 0,
+            completion_tokens: content.split_whitespace().count() as i64,
+        }
+    }
+}
+
+/// Picks the reply to the sent messages
+type Responder = Box<dyn Fn(&[AiMessage]) -> MockReply + Send + Sync>;
+
+/// Deterministic [AiEnv] and [AsEmbeddingEnv] for tests, which never sends anything anywhere
+///
+/// Prompts are answered with the queued replies first, then by the responder. Embeddings are
+/// computed by a [HashingEmbeddingEnv], unless a failure was queued. Every request is recorded
+/// with the api key it was sent with. Copies made by [WithApiKey::with_api_key] share the
+/// script and the records.
+#[derive(Clone)]
+pub struct MockAiEnv {
+    state: Arc<MockState>,
+    uses_openai_key: bool,
+    api_key: Option<String>,
+    embedder: HashingEmbeddingEnv,
+    embedding_model: String,
+}
+
+struct MockState {
+    responder: Responder,
+    replies: Mutex<VecDeque<MockReply>>,
+    /// Replies picked by the moderation check with the checked messages, used when a prompt
+    /// with the same messages is sent
+    checked: Mutex<Vec<(Vec<AiMessage>, MockReply)>>,
+    embedding_failures: Mutex<VecDeque<String>>,
+    prompts: Mutex<Vec<(Option<String>, Vec<AiMessage>)>>,
+    embedded: Mutex<Vec<(Option<String>, Vec<String>)>>,
+}
+
+impl std::fmt::Debug for MockAiEnv {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MockAiEnv").finish_non_exhaustive()
+    }
+}
+
+impl Default for MockAiEnv {
+    /// Fails every prompt, which has no queued reply
+    fn default() -> Self {
+        Self::new(|_| MockReply::Fail("No reply scripted".into()))
+    }
+}
+
+impl MockAiEnv {
+    /// Answers prompts without queued reply by calling `responder` with the sent messages
+    pub fn new(responder: impl Fn(&[AiMessage]) -> MockReply + Send + Sync + 'static) -> Self {
+        Self {
+            state: Arc::new(MockState {
+                responder: Box::new(responder),
+                replies: Mutex::new(VecDeque::new()),
+                checked: Mutex::new(vec![]),
+                embedding_failures: Mutex::new(VecDeque::new()),
+                prompts: Mutex::new(vec![]),
+                embedded: Mutex::new(vec![]),
+            }),
+            uses_openai_key: false,
+            api_key: None,
+            embedder: HashingEmbeddingEnv { dimensions: 16 },
+            embedding_model: "mock,mock".into(),
+        }
+    }
+
+    /// Lets the env ask for openai keys like [crate::openai::OpenAiEnv] does
+    pub fn with_openai_keys(self) -> Self {
+        Self {
+            uses_openai_key: true,
+            ..self
+        }
+    }
+
+    /// Copy of the environment, which reports `model` as its embedding model
+    pub fn with_embedding_model(&self, model: &str) -> Self {
+        Self {
+            embedding_model: model.into(),
+            ..self.clone()
+        }
+    }
+
+    /// Queues the reply to the next prompt
+    pub fn reply(&self, reply: MockReply) -> &Self {
+        self.state.replies.lock().unwrap().push_back(reply);
+        self
+    }
+
+    /// Lets the next embedding request fail
+    pub fn fail_embedding(&self, message: &str) -> &Self {
+        self.state
+            .embedding_failures
+            .lock()
+            .unwrap()
+            .push_back(message.to_string());
+        self
+    }
+
+    /// Messages of every prompt which was checked or sent, in order
+    pub fn prompts(&self) -> Vec<Vec<AiMessage>> {
+        let prompts = self.state.prompts.lock().unwrap();
+        prompts
+            .iter()
+            .map(|(_, messages)| messages.clone())
+            .collect()
+    }
+
+    /// Texts of every embedding request, in order
+    pub fn embedded(&self) -> Vec<Vec<String>> {
+        let embedded = self.state.embedded.lock().unwrap();
+        embedded.iter().map(|(_, texts)| texts.clone()).collect()
+    }
+
+    /// Api keys of every prompt and embedding request, in order
+    pub fn api_keys(&self) -> (Vec<Option<String>>, Vec<Option<String>>) {
+        let prompts = self.state.prompts.lock().unwrap();
+        let embedded = self.state.embedded.lock().unwrap();
+        (
+            prompts.iter().map(|(key, _)| key.clone()).collect(),
+            embedded.iter().map(|(key, _)| key.clone()).collect(),
+        )
+    }
+
+    fn next_reply(&self, messages: Vec<AiMessage>) -> MockReply {
+        let reply = self
+            .state
+            .replies
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| (self.state.responder)(&messages));
+        let mut prompts = self.state.prompts.lock().unwrap();
+        prompts.push((self.api_key.clone(), messages));
+        reply
+    }
+}
+
+impl WithApiKey for MockAiEnv {
+    fn uses_openai_key(&self) -> bool {
+        self.uses_openai_key
+    }
+
+    fn with_api_key(&self, api_key: &str) -> Self {
+        Self {
+            api_key: Some(api_key.to_string()),
+            ..self.clone()
+        }
+    }
+}
+
+#[async_trait]
+impl AiEnv for MockAiEnv {
+    fn info(&self) -> AiEnvInfo {
+        AiEnvInfo {
+            name: "mock".into(),
+            model: "mock".into(),
+            check_model: Some("mock".into()),
+        }
+    }
+
+    async fn check_prompt<Prompt: AiPrompt>(&self, prompt: &Prompt) -> anyhow::Result<CheckResult> {
+        let messages = prompt.primer();
+        match self.next_reply(messages.clone()) {
+            MockReply::Flagged(categories) => Ok(CheckResult::Flagged(categories)),
+            reply => {
+                self.state.checked.lock().unwrap().push((messages, reply));
+                Ok(CheckResult::Ok)
+            }
+        }
+    }
+
+    async fn send_prompt<Prompt: AiPrompt>(
+        &self,
+        prompt: &Prompt,
+    ) -> anyhow::Result<Prompt::PromptResult> {
+        let messages = prompt.primer();
+        let checked = {
+            let mut checked = self.state.checked.lock().unwrap();
+            checked
+                .iter()
+                .position(|(checked_messages, _)| *checked_messages == messages)
+                .map(|i| checked.remove(i).1)
+        };
+        let reply = checked.unwrap_or_else(|| self.next_reply(messages));
+        match reply {
+            MockReply::Content {
+                content,
+                prompt_tokens,
+                completion_tokens,
+            } => prompt.handle_response(PromptResponse {
+                env_info: self.info(),
+                prompt_info: prompt.info(),
+                content,
+                prompt_tokens,
+                completion_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+            }),
+            MockReply::Flagged(categories) => Err(anyhow!("Flagged: {categories}")),
+            MockReply::Fail(message) => Err(anyhow!(message)),
+        }
+    }
+}
+
+#[async_trait]
+impl AsEmbeddingEnv for MockAiEnv {
+    fn embedding_model(&self) -> String {
+        self.embedding_model.clone()
+    }
+
+    async fn embed(&self, stmts: &[&str]) -> anyhow::Result<EmbedResult> {
+        self.state.embedded.lock().unwrap().push((
+            self.api_key.clone(),
+            stmts.iter().map(|s| s.to_string()).collect(),
+        ));
+        if let Some(message) = self.state.embedding_failures.lock().unwrap().pop_front() {
+            return Err(anyhow!(message));
+        }
+        let tokens = stmts
+            .iter()
+            .map(|s| s.split_whitespace().count() as u32)
+            .sum();
+        Ok(EmbedResult {
+            data: stmts
+                .iter()
+                .map(|s| Embedding {
+                    values: self.embedder.embed_one(s),
+                })
+                .collect(),
+            prompt_tokens: tokens,
+            total_tokens: tokens,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::PromptInfo;
+
+    struct Echo;
+
+    impl AiPrompt for Echo {
+        type PromptResult = PromptResponse;
+
+        fn name(&self) -> &str {
+            "echo"
+        }
+        fn version(&self) -> u16 {
+            1
+        }
+        fn primer(&self) -> Vec<AiMessage> {
+            vec![AiMessage::user("hello")]
+        }
+        fn handle_response(&self, r: PromptResponse) -> anyhow::Result<Self::PromptResult> {
+            Ok(r)
+        }
+    }
+
+    /// Sends `self.0` as its only message
+    struct Say(&'static str);
+
+    impl AiPrompt for Say {
+        type PromptResult = PromptResponse;
+
+        fn name(&self) -> &str {
+            "say"
+        }
+        fn version(&self) -> u16 {
+            1
+        }
+        fn primer(&self) -> Vec<AiMessage> {
+            vec![AiMessage::user(self.0)]
+        }
+        fn handle_response(&self, r: PromptResponse) -> anyhow::Result<Self::PromptResult> {
+            Ok(r)
+        }
+    }
+
+    #[tokio::test]
+    async fn queued_replies_come_before_the_responder() {
+        let env = MockAiEnv::new(|messages| MockReply::content(&messages[0].content));
+        env.reply(MockReply::Flagged("hate".into()))
+            .reply(MockReply::Fail("timeout".into()));
+
+        assert!(matches!(
+            env.check_prompt(&Echo).await.unwrap(),
+            CheckResult::Flagged(c) if c == "hate"
+        ));
+        assert!(matches!(
+            env.check_prompt(&Echo).await.unwrap(),
+            CheckResult::Ok
+        ));
+        let Err(err) = env.send_prompt(&Echo).await else {
+            panic!("queued failure was not returned");
+        };
+        assert_eq!(err.to_string(), "timeout");
+        let response = env.send_prompt(&Echo).await.unwrap();
+        assert_eq!(response.content, "hello");
+        assert_eq!(response.total_tokens, 1);
+        assert_eq!(
+            response.prompt_info,
+            PromptInfo {
+                name: "echo".into(),
+                version: 1
+            }
+        );
+        assert_eq!(env.prompts().len(), 3);
+
+        // checked replies belong to the checked messages, whatever is sent in between
+        let (first, second) = (Say("first"), Say("second"));
+        env.reply(MockReply::content("one"))
+            .reply(MockReply::content("two"));
+        env.check_prompt(&first).await.unwrap();
+        env.check_prompt(&second).await.unwrap();
+        assert_eq!(env.send_prompt(&second).await.unwrap().content, "two");
+        assert_eq!(env.send_prompt(&first).await.unwrap().content, "one");
+
+        env.fail_embedding("down");
+        assert!(env.embed(&["a b"]).await.is_err());
+        let embedded = env.embed(&["a b"]).await.unwrap();
+        assert_eq!(embedded.total_tokens, 2);
+        assert_eq!(env.embedded(), vec![vec!["a b"], vec!["a b"]]);
+
+        // copies with a key share the script and the records
+        env.with_api_key("key").embed(&["c"]).await.unwrap();
+        assert_eq!(env.api_keys().1, vec![None, None, Some("key".into())]);
+    }
+}
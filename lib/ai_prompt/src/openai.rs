@@ -1,25 +1,12 @@
 use anyhow::anyhow;
 use async_trait::async_trait;
-use openai::{
-    chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole},
-    embeddings::Embeddings,
-    moderations::{Categories, ModerationBuilder, ModerationResult},
-};
-use serde_json::json;
 
-use crate::api::{AsEmbeddingEnv, CheckResult, EmbedResult, Embedding};
+use crate::api::{AsEmbeddingEnv, CheckResult, EmbedResult, ModelPrice, WithApiKey};
+use crate::client::ApiClient;
 
-use super::api::{AiEnv, AiEnvInfo, AiPrompt, AiRole, PromptResponse};
+use super::api::{AiEnv, AiEnvInfo, AiPrompt, PromptResponse};
 
-impl From<AiRole> for ChatCompletionMessageRole {
-    fn from(value: AiRole) -> Self {
-        match value {
-            AiRole::System => Self::System,
-            AiRole::User => Self::User,
-            AiRole::Assistant => Self::Assistant,
-        }
-    }
-}
+const BASE_URL: &str = "https://api.openai.com/v1";
 
 pub enum OpenAiModel {
     Gpt4,
@@ -41,11 +28,44 @@ impl From<OpenAiModel> for &str {
     }
 }
 
+impl std::str::FromStr for OpenAiModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gpt-4" => Ok(OpenAiModel::Gpt4),
+            "gpt-3.5-turbo" => Ok(OpenAiModel::Gpt35Turbo),
+            "text-davinci-003" => Ok(OpenAiModel::Gpt35TextDavinci003),
+            "text-davinci-002" => Ok(OpenAiModel::Gpt35TextDavinci002),
+            "code-davinci-002" => Ok(OpenAiModel::Gpt35CodeDavinci002),
+            _ => Err(anyhow!("Unknown openai model: {s}")),
+        }
+    }
+}
+
+/// Model used for embeddings
+pub const EMBEDDING_MODEL: &str = "text-embedding-ada-002";
+
+/// List price of an openai model by its name, None if it is unknown
+pub fn model_price(model: &str) -> Option<ModelPrice> {
+    let (prompt, completion) = match model {
+        "gpt-4" => (30.0, 60.0),
+        "gpt-3.5-turbo" => (0.5, 1.5),
+        "text-davinci-003" | "text-davinci-002" | "code-davinci-002" => (20.0, 20.0),
+        EMBEDDING_MODEL => (0.1, 0.0),
+        _ => return None,
+    };
+    Some(ModelPrice { prompt, completion })
+}
+
 /// Environment for running stuff against OpenAI models
-#[derive(PartialEq, Eq, Debug)]
+///
+/// Requests are sent with the key given via [WithApiKey::with_api_key].
+#[derive(Clone, Debug)]
 pub struct OpenAiEnv {
     pub model: &'static str,       // e.g. gpt-3.5-turbo, text-davinci-003, etc.
     pub check_model: &'static str, // e.g. text-moderation-stable, text-moderation-latest etc.
+    client: ApiClient,
 }
 
 impl OpenAiEnv {
@@ -53,6 +73,20 @@ impl OpenAiEnv {
         Self {
             model: model.into(),
             check_model: "text-moderation-stable",
+            client: ApiClient::new(BASE_URL, None),
+        }
+    }
+}
+
+impl WithApiKey for OpenAiEnv {
+    fn uses_openai_key(&self) -> bool {
+        true
+    }
+
+    fn with_api_key(&self, api_key: &str) -> Self {
+        Self {
+            client: self.client.with_api_key(api_key),
+            ..self.clone()
         }
     }
 }
@@ -72,98 +106,32 @@ impl AiEnv for OpenAiEnv {
             let content = &msg.content;
             format!("{result}\n{content}")
         });
-        let result = ModerationBuilder::default()
-            .input(data)
-            .model(self.check_model)
-            .create()
-            .await??;
-        match result.results.as_slice() {
-            [ModerationResult {
-                flagged,
-                categories:
-                    Categories {
-                        hate,
-                        hate_threatening,
-                        self_harm,
-                        sexual,
-                        sexual_minors,
-                        violence,
-                        violence_graphic,
-                        ..
-                    },
-                ..
-            }, ..] => Ok(match flagged {
-                false => CheckResult::Ok,
-                true => CheckResult::Flagged(serde_json::to_string(&json!([{
-                    "hate": hate,
-                    "hate_threatening": hate_threatening,
-                    "self_harm": self_harm,
-                    "sexual": sexual,
-                    "sexual_minors": sexual_minors,
-                    "violence": violence,
-                    "violence_graphic": violence_graphic,
-                }]))?),
-            }),
-            _ => Err(anyhow!("OpenAI moderation API yielded an empty result")),
-        }
+        self.client.moderate(self.check_model, data).await
     }
 
     async fn send_prompt<Prompt: AiPrompt>(
         &self,
         prompt: &Prompt,
     ) -> anyhow::Result<Prompt::PromptResult> {
-        let mut messages: Vec<ChatCompletionMessage> = vec![];
-        for m in prompt.primer() {
-            messages.push(ChatCompletionMessage {
-                role: m.role.into(),
-                content: m.content,
-                name: None,
-            })
-        }
-
-        let result = ChatCompletion::builder(self.model, messages.to_owned())
-            .temperature(0.0)
-            .create()
-            .await?
-            .unwrap();
-        let (c, p, t) = result.usage.map_or((0, 0, 0), |us| {
-            (us.completion_tokens, us.prompt_tokens, us.total_tokens)
-        });
-        let raw_result = result.choices.first().unwrap().message.content.to_owned();
-
+        let result = self.client.chat(self.model, prompt.primer()).await?;
         prompt.handle_response(PromptResponse {
             env_info: self.info(),
             prompt_info: prompt.info(),
-            content: raw_result,
-            completion_tokens: c.into(),
-            prompt_tokens: p.into(),
-            total_tokens: t.into(),
+            content: result.content,
+            completion_tokens: result.completion_tokens,
+            prompt_tokens: result.prompt_tokens,
+            total_tokens: result.total_tokens,
         })
     }
 }
 
 #[async_trait]
 impl AsEmbeddingEnv for OpenAiEnv {
-    async fn embed(&self, stmts: &[&str]) -> anyhow::Result<EmbedResult> {
-        if stmts.is_empty() {
-            return Err(anyhow!("Passed nothing to embed."));
-        }
-        let model = "text-embedding-ada-002";
-        let embeddings = Embeddings::create(model, stmts.to_vec(), "").await??;
-        let mut data: Vec<Embedding> = vec![];
-        for e in embeddings.data {
-            data.push(Embedding { values: e.vec });
-        }
-
-        Ok(EmbedResult {
-            data,
-            prompt_tokens: embeddings.usage.prompt_tokens,
-            total_tokens: embeddings.usage.total_tokens,
-        })
+    fn embedding_model(&self) -> String {
+        format!("{EMBEDDING_MODEL},openai")
     }
-}
 
-/// Set the key to be used when next sending a request to openai
-pub fn set_key(s: String) {
-    openai::set_key(s);
+    async fn embed(&self, stmts: &[&str]) -> anyhow::Result<EmbedResult> {
+        self.client.embed(EMBEDDING_MODEL, stmts).await
+    }
 }
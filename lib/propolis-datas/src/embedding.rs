@@ -7,17 +7,70 @@ pub struct Embedding {
     pub data: Vec<f64>,
     pub prompt_tokens: i64,
     pub api_key_id: i64,
+    /// Backend and model which made the embedding, see `AsEmbeddingEnv::embedding_model`
+    pub model: String,
 }
 
 /// Defines which methods have to be implemented on the store to work with Embedding
 #[async_trait]
 pub trait EmbeddingStore {
-    /// Store the item inside the particular DB
+    /// Store the item inside the particular DB, replacing an embedding of another model
     async fn store(&mut self, item: &Embedding) -> anyhow::Result<Embedding>;
     /// Retrieve by id
     async fn by_statement_id(&self, id: i64) -> anyhow::Result<Option<Embedding>>;
 }
 
+/// Header of blobs created by sqlite-vector's `vector_to_blob`: magic byte and type (float32)
+const VECTOR_BLOB_HEADER: [u8; 2] = [b'v', 1];
+
+/// Decodes a blob as stored in `statement_embeddings.data`
+///
+/// Works without the sqlite-vector extension being loaded. Blobs without the sqlite-vector
+/// header are read as raw little endian f32 values.
+pub fn vector_from_blob(blob: &[u8]) -> Option<Vec<f32>> {
+    let data = blob.strip_prefix(&VECTOR_BLOB_HEADER).unwrap_or(blob);
+    let (chunks, rest) = data.as_chunks::<4>();
+    if !rest.is_empty() {
+        return None;
+    }
+    Some(
+        chunks
+            .iter()
+            .map(|bytes| f32::from_le_bytes(*bytes))
+            .collect(),
+    )
+}
+
+/// Encodes a vector like sqlite-vector's `vector_to_blob`, so it can be stored without the
+/// extension being loaded
+pub fn vector_to_blob(values: &[f64]) -> Vec<u8> {
+    let mut blob = VECTOR_BLOB_HEADER.to_vec();
+    for value in values {
+        blob.extend((*value as f32).to_le_bytes());
+    }
+    blob
+}
+
+/// Cosine similarity of two vectors in [-1, 1]. Yields 0 for vectors of different dimensions.
+///
+/// Only meaningful for embeddings of the same model, which callers have to make sure of.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f64 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let (mut dot, mut norm_a, mut norm_b) = (0.0_f64, 0.0_f64, 0.0_f64);
+    for (x, y) in a.iter().zip(b) {
+        let (x, y) = (*x as f64, *y as f64);
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt())
+}
+
 impl Embedding {
     pub async fn create<Store: EmbeddingStore>(
         store: &mut Store,
@@ -25,6 +78,7 @@ impl Embedding {
         data: Vec<f64>,
         prompt_tokens: i64,
         api_key_id: i64,
+        model: &str,
     ) -> anyhow::Result<Embedding> {
         store
             .store(&Embedding {
@@ -32,7 +86,33 @@ impl Embedding {
                 data,
                 prompt_tokens,
                 api_key_id,
+                model: model.to_string(),
             })
             .await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vector_from_blob() {
+        let mut blob = VECTOR_BLOB_HEADER.to_vec();
+        blob.extend(1.0_f32.to_le_bytes());
+        blob.extend((-0.5_f32).to_le_bytes());
+        assert_eq!(vector_from_blob(&blob), Some(vec![1.0, -0.5]));
+        assert_eq!(vector_from_blob(&blob[2..]), Some(vec![1.0, -0.5]));
+        assert_eq!(vector_from_blob(&blob[1..]), None);
+        assert_eq!(vector_to_blob(&[1.0, -0.5]), blob);
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]), 1.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[-1.0, 0.0]), -1.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+}
@@ -4,7 +4,7 @@ use tracing::debug;
 
 use crate::{
     apikey::{ApiKey, ApiKeyStore},
-    embedding::{Embedding, EmbeddingStore},
+    embedding::{vector_from_blob, vector_to_blob, Embedding, EmbeddingStore},
     statement::{StatementFlag, StatementFlagStore},
 };
 
@@ -105,19 +105,21 @@ impl StatementFlagStore for sqlx::SqlitePool {
 #[async_trait]
 impl EmbeddingStore for sqlx::SqlitePool {
     async fn store(&mut self, item: &Embedding) -> anyhow::Result<Embedding> {
-        let data_json = serde_json::to_string(&item.data)?;
-        debug!("Embedding JSON data:");
-        debug!("==========");
-        debug!("{}", data_json);
-        debug!("==========");
+        debug!(
+            "Storing embedding of {} dimensions for statement {}",
+            item.data.len(),
+            item.statement_id
+        );
         sqlx::query(
-            "INSERT INTO statement_embeddings (statement_id, data, prompt_tokens, api_key_id)
-             VALUES (?, vector_to_blob(vector_from_json(?)), ?, ?)",
+            "INSERT OR REPLACE INTO statement_embeddings
+             (statement_id, data, prompt_tokens, api_key_id, model)
+             VALUES (?, ?, ?, ?, ?)",
         )
         .bind(item.statement_id)
-        .bind(data_json)
+        .bind(vector_to_blob(&item.data))
         .bind(item.prompt_tokens)
         .bind(item.api_key_id)
+        .bind(&item.model)
         .execute(self as &sqlx::SqlitePool)
         .await?;
         let r = EmbeddingStore::by_statement_id(self, item.statement_id).await?;
@@ -125,7 +127,7 @@ impl EmbeddingStore for sqlx::SqlitePool {
     }
     async fn by_statement_id(&self, id: i64) -> anyhow::Result<Option<Embedding>> {
         let row = sqlx::query(
-            "SELECT statement_id, vector_to_json(vector_from_blob(data)), prompt_tokens, api_key_id
+            "SELECT statement_id, data, prompt_tokens, api_key_id, model
             FROM statement_embeddings
             WHERE statement_id = ?",
         )
@@ -134,10 +136,14 @@ impl EmbeddingStore for sqlx::SqlitePool {
         .await?;
         Ok(row.map(|row| Embedding {
             statement_id: row.try_get(0).expect("No id"),
-            data: serde_json::from_str(row.try_get(1).expect("No data"))
-                .expect("Unable to parse data as json"),
+            data: vector_from_blob(row.try_get(1).expect("No data"))
+                .expect("Unable to decode data as vector")
+                .into_iter()
+                .map(f64::from)
+                .collect(),
             prompt_tokens: row.try_get(2).expect("No prompt_tokens"),
             api_key_id: row.try_get(3).expect("No api key"),
+            model: row.try_get(4).expect("No model"),
         }))
     }
 }
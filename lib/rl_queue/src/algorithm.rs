@@ -0,0 +1,246 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How a limiter spends and regains its quota
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    /// Quota refills continuously by `capacity` per `period`, up to `capacity`. Allows bursts
+    /// after idle times and spreads requests out otherwise.
+    TokenBucket { capacity: f64, period: Duration },
+    /// At most `capacity` is used within any `period`. Quota is regained `period` after it was
+    /// used.
+    SlidingWindow { capacity: f64, period: Duration },
+}
+
+impl Limit {
+    pub fn capacity(&self) -> f64 {
+        match self {
+            Limit::TokenBucket { capacity, .. } | Limit::SlidingWindow { capacity, .. } => {
+                *capacity
+            }
+        }
+    }
+
+    pub fn period(&self) -> Duration {
+        match self {
+            Limit::TokenBucket { period, .. } | Limit::SlidingWindow { period, .. } => *period,
+        }
+    }
+}
+
+/// Quota used so far, one per limiter or key
+#[derive(Debug)]
+pub(crate) enum State {
+    TokenBucket {
+        /// Negative if more was used than available
+        tokens: f64,
+        updated: Instant,
+    },
+    SlidingWindow {
+        /// Amounts used within the last period, oldest first
+        log: VecDeque<(Instant, f64)>,
+        used: f64,
+    },
+}
+
+impl State {
+    pub(crate) fn new(limit: &Limit, now: Instant) -> Self {
+        match limit {
+            Limit::TokenBucket { capacity, .. } => State::TokenBucket {
+                tokens: *capacity,
+                updated: now,
+            },
+            Limit::SlidingWindow { .. } => State::SlidingWindow {
+                log: VecDeque::new(),
+                used: 0.0,
+            },
+        }
+    }
+
+    /// Regains the quota which became available until `now`
+    pub(crate) fn update(&mut self, limit: &Limit, now: Instant) {
+        match self {
+            State::TokenBucket { tokens, updated } => {
+                let elapsed = now.saturating_duration_since(*updated).as_secs_f64();
+                let refill = elapsed * limit.capacity() / limit.period().as_secs_f64();
+                *tokens = (*tokens + refill).min(limit.capacity());
+                *updated = now.max(*updated);
+            }
+            State::SlidingWindow { log, used } => {
+                while let Some((time, amount)) = log.front() {
+                    if *time + limit.period() > now {
+                        break;
+                    }
+                    *used -= amount;
+                    log.pop_front();
+                }
+                if log.is_empty() {
+                    // avoid drifting away from 0 by rounding errors
+                    *used = 0.0;
+                }
+            }
+        }
+    }
+
+    /// Quota left, negative if it is exceeded
+    pub(crate) fn remaining(&self, limit: &Limit) -> f64 {
+        match self {
+            State::TokenBucket { tokens, .. } => *tokens,
+            State::SlidingWindow { used, .. } => limit.capacity() - used,
+        }
+    }
+
+    pub(crate) fn consume(&mut self, amount: f64, now: Instant) {
+        match self {
+            State::TokenBucket { tokens, .. } => *tokens -= amount,
+            State::SlidingWindow { log, used } => {
+                log.push_back((now, amount));
+                *used += amount;
+            }
+        }
+    }
+
+    /// When at least `amount` will be remaining. More than the capacity is never remaining, so
+    /// that is treated like the capacity.
+    pub(crate) fn available_at(&self, limit: &Limit, amount: f64, now: Instant) -> Instant {
+        let missing = amount.min(limit.capacity()) - self.remaining(limit);
+        if missing <= 0.0 {
+            return now;
+        }
+        match self {
+            State::TokenBucket { .. } => {
+                let seconds = missing * limit.period().as_secs_f64() / limit.capacity();
+                now + Duration::from_secs_f64(seconds)
+            }
+            State::SlidingWindow { log, .. } => {
+                let mut freed = 0.0;
+                for (time, used) in log {
+                    freed += used;
+                    if freed >= missing {
+                        return *time + limit.period();
+                    }
+                }
+                // only reachable through rounding errors, everything is freed by then
+                log.back().map_or(now, |(time, _)| *time + limit.period())
+            }
+        }
+    }
+
+    /// Used quota as (time, amount), oldest first, which [State::replay] turns back into this
+    /// state. Expects the state to be updated to `now`.
+    pub(crate) fn usage(&self, limit: &Limit, now: Instant) -> Vec<(Instant, f64)> {
+        match self {
+            State::TokenBucket { tokens, .. } => {
+                let used = limit.capacity() - tokens;
+                if used > 0.0 {
+                    vec![(now, used)]
+                } else {
+                    vec![]
+                }
+            }
+            State::SlidingWindow { log, .. } => log.iter().copied().collect(),
+        }
+    }
+
+    /// State after using `usage`, oldest first, updated to `now`
+    pub(crate) fn replay(limit: &Limit, usage: &[(Instant, f64)], now: Instant) -> Self {
+        let start = usage.first().map_or(now, |(time, _)| *time);
+        let mut state = State::new(limit, start);
+        for (time, amount) in usage {
+            state.update(limit, *time);
+            state.consume(*amount, *time);
+        }
+        state.update(limit, now);
+        state
+    }
+
+    /// Whether the state is the same as a new one, so that it can be dropped
+    pub(crate) fn is_idle(&self, limit: &Limit) -> bool {
+        match self {
+            State::TokenBucket { tokens, .. } => *tokens >= limit.capacity(),
+            State::SlidingWindow { log, .. } => log.is_empty(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn token_bucket_refills_continuously() {
+        let limit = Limit::TokenBucket {
+            capacity: 10.0,
+            period: 10 * SECOND,
+        };
+        let start = Instant::now();
+        let mut state = State::new(&limit, start);
+        state.consume(10.0, start);
+        assert_eq!(state.remaining(&limit), 0.0);
+        assert_eq!(state.available_at(&limit, 3.0, start), start + 3 * SECOND);
+
+        state.update(&limit, start + 2 * SECOND);
+        assert_eq!(state.remaining(&limit), 2.0);
+        state.update(&limit, start + 60 * SECOND);
+        assert_eq!(state.remaining(&limit), 10.0);
+        assert!(state.is_idle(&limit));
+    }
+
+    #[test]
+    fn sliding_window_frees_quota_one_entry_at_a_time() {
+        let limit = Limit::SlidingWindow {
+            capacity: 10.0,
+            period: 10 * SECOND,
+        };
+        let start = Instant::now();
+        let mut state = State::new(&limit, start);
+        state.consume(6.0, start);
+        state.consume(6.0, start + 4 * SECOND);
+        assert_eq!(state.remaining(&limit), -2.0);
+        // getting back to 0 needs the first entry to leave the window, having 5 needs both
+        assert_eq!(
+            state.available_at(&limit, 0.0, start + 4 * SECOND),
+            start + 10 * SECOND
+        );
+        assert_eq!(
+            state.available_at(&limit, 5.0, start + 4 * SECOND),
+            start + 14 * SECOND
+        );
+
+        state.update(&limit, start + 10 * SECOND);
+        assert_eq!(state.remaining(&limit), 4.0);
+        state.update(&limit, start + 14 * SECOND);
+        assert!(state.is_idle(&limit));
+    }
+
+    #[test]
+    fn replayed_usage_restores_the_state() {
+        let start = Instant::now();
+        for limit in [
+            Limit::TokenBucket {
+                capacity: 10.0,
+                period: 10 * SECOND,
+            },
+            Limit::SlidingWindow {
+                capacity: 10.0,
+                period: 10 * SECOND,
+            },
+        ] {
+            let mut state = State::new(&limit, start);
+            state.consume(6.0, start);
+            state.update(&limit, start + 4 * SECOND);
+            state.consume(3.0, start + 4 * SECOND);
+            let now = start + 5 * SECOND;
+            state.update(&limit, now);
+
+            let replayed = State::replay(&limit, &state.usage(&limit, now), now);
+            assert_eq!(replayed.remaining(&limit), state.remaining(&limit));
+            assert_eq!(
+                replayed.available_at(&limit, 8.0, now),
+                state.available_at(&limit, 8.0, now)
+            );
+        }
+    }
+}
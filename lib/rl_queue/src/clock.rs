@@ -0,0 +1,79 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+
+/// Source of time for limiters, so that tests don't have to wait
+#[async_trait]
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Wall clock time at [Clock::now], to relate persisted usage to instants
+    fn system_now(&self) -> SystemTime;
+
+    /// Returns once `deadline` has passed
+    async fn sleep_until(&self, deadline: Instant);
+}
+
+/// The real time
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        async_std::task::sleep(deadline.saturating_duration_since(Instant::now())).await;
+    }
+}
+
+/// Clock which only moves when told to. Sleeping moves it to the deadline right away.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+    start: (Instant, SystemTime),
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        let start = Instant::now();
+        MockClock {
+            now: Arc::new(Mutex::new(start)),
+            start: (start, SystemTime::now()),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().expect("Mock clock mutex is poisoned") += duration;
+    }
+}
+
+#[async_trait]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().expect("Mock clock mutex is poisoned")
+    }
+
+    fn system_now(&self) -> SystemTime {
+        let (start, system_start) = self.start;
+        system_start + (self.now() - start)
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        let mut now = self.now.lock().expect("Mock clock mutex is poisoned");
+        *now = (*now).max(deadline);
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::algorithm::{Limit, State};
+use crate::clock::{Clock, SystemClock};
+use crate::{quota_state, to_instants, to_usage, try_acquire, QuotaState, Usage};
+
+/// One limit, tracked separately for every key, e.g. per client
+///
+/// Keys which are back to their full quota can be dropped with [KeyedRateLimiter::prune].
+pub struct KeyedRateLimiter<K, C: Clock = SystemClock> {
+    limit: Limit,
+    states: Mutex<HashMap<K, State>>,
+    clock: C,
+}
+
+impl<K: Hash + Eq + Clone> KeyedRateLimiter<K> {
+    /// See [Limit::SlidingWindow]
+    pub fn sliding_window(capacity: f64, period: Duration) -> Self {
+        Self::with_clock(Limit::SlidingWindow { capacity, period }, SystemClock)
+    }
+
+    /// See [Limit::TokenBucket]
+    pub fn token_bucket(capacity: f64, period: Duration) -> Self {
+        Self::with_clock(Limit::TokenBucket { capacity, period }, SystemClock)
+    }
+}
+
+impl<K: Hash + Eq + Clone, C: Clock> KeyedRateLimiter<K, C> {
+    pub fn with_clock(limit: Limit, clock: C) -> Self {
+        KeyedRateLimiter {
+            limit,
+            states: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    pub fn limit(&self) -> Limit {
+        self.limit
+    }
+
+    /// Runs `f` on the state of the key, after regaining quota up to now
+    fn with_state<T>(&self, key: &K, f: impl FnOnce(&mut State, Instant) -> T) -> T {
+        let now = self.clock.now();
+        let mut states = self.states.lock().expect("Rate limiter mutex is poisoned");
+        let state = states
+            .entry(key.clone())
+            .or_insert_with(|| State::new(&self.limit, now));
+        state.update(&self.limit, now);
+        f(state, now)
+    }
+
+    /// Returns true if the quota of the key is not exceeded
+    pub fn check(&self, key: &K) -> bool {
+        self.with_state(key, |state, _| state.remaining(&self.limit) >= 0.0)
+    }
+
+    /// Adds to the used quota of the key, see [crate::RateLimiter::add]
+    pub fn add<T: Into<f64>>(&self, key: &K, quota: T) -> QuotaState {
+        let quota = quota.into();
+        self.with_state(key, |state, now| {
+            state.consume(quota, now);
+            quota_state(state, &self.limit, now)
+        })
+    }
+
+    /// Uses `quota` of the key if that much is remaining. Otherwise returns when it will be.
+    pub fn try_acquire<T: Into<f64>>(&self, key: &K, quota: T) -> Result<f64, Instant> {
+        let quota = quota.into();
+        self.with_state(key, |state, now| {
+            try_acquire(state, &self.limit, quota, now)
+        })
+    }
+
+    /// async block until `quota` of the key is remaining and use it
+    pub async fn acquire<T: Into<f64>>(&self, key: &K, quota: T) {
+        let quota = quota.into();
+        while let Err(ready) = self.try_acquire(key, quota) {
+            self.clock.sleep_until(ready).await;
+        }
+    }
+
+    /// Drops keys which have their full quota again, they are recreated when used
+    pub fn prune(&self) {
+        let now = self.clock.now();
+        let mut states = self.states.lock().expect("Rate limiter mutex is poisoned");
+        states.retain(|_, state| {
+            state.update(&self.limit, now);
+            !state.is_idle(&self.limit)
+        });
+    }
+
+    /// Quota still counted against the limit of every tracked key
+    pub fn usage(&self) -> HashMap<K, Vec<Usage>> {
+        let now = self.clock.now();
+        let mut states = self.states.lock().expect("Rate limiter mutex is poisoned");
+        states
+            .iter_mut()
+            .map(|(key, state)| {
+                state.update(&self.limit, now);
+                let usage = to_usage(&self.clock, state.usage(&self.limit, now));
+                (key.clone(), usage)
+            })
+            .filter(|(_, usage)| !usage.is_empty())
+            .collect()
+    }
+
+    /// Replaces the state of the key by the one after using `usage`, see
+    /// [crate::RateLimiter::restore]
+    pub fn restore(&self, key: &K, usage: impl IntoIterator<Item = Usage>) {
+        let usage = to_instants(&self.clock, usage);
+        let now = self.clock.now();
+        self.states
+            .lock()
+            .expect("Rate limiter mutex is poisoned")
+            .insert(key.clone(), State::replay(&self.limit, &usage, now));
+    }
+
+    /// Number of tracked keys
+    pub fn len(&self) -> usize {
+        self.states
+            .lock()
+            .expect("Rate limiter mutex is poisoned")
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::{Clock, KeyedRateLimiter, Limit, MockClock};
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn keys_are_limited_separately() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let rl = KeyedRateLimiter::with_clock(
+            Limit::TokenBucket {
+                capacity: 2.0,
+                period: 10 * SECOND,
+            },
+            clock.clone(),
+        );
+        assert_eq!(rl.try_acquire(&"a", 2), Ok(0.0));
+        assert_eq!(rl.try_acquire(&"a", 1), Err(start + 5 * SECOND));
+        assert_eq!(rl.try_acquire(&"b", 1), Ok(1.0));
+
+        clock.advance(10 * SECOND);
+        assert!(rl.check(&"a"));
+        rl.prune();
+        assert!(rl.is_empty());
+    }
+
+    #[test]
+    fn limiter_can_be_shared_between_threads() {
+        let rl = Arc::new(KeyedRateLimiter::sliding_window(5.0, 60 * SECOND));
+        let threads: Vec<_> = (0..10)
+            .map(|_| {
+                let rl = rl.clone();
+                std::thread::spawn(move || rl.try_acquire(&"key", 1).is_ok())
+            })
+            .collect();
+        let acquired = threads
+            .into_iter()
+            .map(|thread| thread.join().expect("thread panicked"))
+            .filter(|acquired| *acquired)
+            .count();
+        assert_eq!(acquired, 5);
+    }
+
+    #[test]
+    fn waiting_wakes_up_when_the_key_has_quota() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let rl = KeyedRateLimiter::with_clock(
+            Limit::SlidingWindow {
+                capacity: 1.0,
+                period: 60 * SECOND,
+            },
+            clock.clone(),
+        );
+        async_std::task::block_on(async {
+            rl.acquire(&1, 1).await;
+            rl.acquire(&2, 1).await;
+            assert_eq!(clock.now(), start);
+            rl.acquire(&1, 1).await;
+            assert_eq!(clock.now(), start + 60 * SECOND);
+        });
+    }
+}
@@ -1,76 +1,202 @@
-use std::time::{Duration, Instant};
-
-/// Simple limiter for a floating point value
-pub struct RateLimiter {
-    pub period: Duration,
-    pub allowed_quota: f64,
-    quota: f64,
-    last_reset: Instant,
-}
+//! Rate limiting
+//!
+//! A [RateLimiter] limits one quota, a [KeyedRateLimiter] one quota per key, e.g. per client.
+//! Both use one of the algorithms of [Limit] and can be shared behind an `Arc`, since all
+//! methods take `&self`. Waiting sleeps until the exact time quota becomes available. Time is
+//! taken from a [Clock], which is mocked in tests.
+//!
+//! The state of a limiter can be saved as a list of [Usage] and restored from one, e.g. after a
+//! restart. With the `sqlite` feature, [RateLimiter::store] and [RateLimiter::load] do so in the
+//! `rate_limiter_usage` table.
+
+mod algorithm;
+mod clock;
+mod keyed;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use algorithm::State;
+
+pub use algorithm::Limit;
+pub use clock::{Clock, MockClock, SystemClock};
+pub use keyed::KeyedRateLimiter;
 
 #[derive(PartialEq, Debug)]
 pub enum QuotaState {
     Remaining(f64),
+    /// Quota was exceeded by this much and is available again at that time
     ExceededUntil(f64, Instant),
 }
 
+/// Quota used at some time, to keep the state of a limiter across restarts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Usage {
+    pub at: SystemTime,
+    pub amount: f64,
+}
+
+/// Limiter for a floating point value, e.g. api calls or used tokens
+pub struct RateLimiter<C: Clock = SystemClock> {
+    limit: Limit,
+    state: Mutex<State>,
+    clock: C,
+}
+
 impl RateLimiter {
-    /// Create a ratelimiter from an allowed quota and a reset period
+    /// Create a ratelimiter from an allowed quota and a period, in which it may be used up.
+    /// Same as [RateLimiter::sliding_window].
     pub fn new(allowed_quota: f64, period: Duration) -> Self {
+        Self::sliding_window(allowed_quota, period)
+    }
+
+    /// See [Limit::SlidingWindow]
+    pub fn sliding_window(capacity: f64, period: Duration) -> Self {
+        Self::with_clock(Limit::SlidingWindow { capacity, period }, SystemClock)
+    }
+
+    /// See [Limit::TokenBucket]
+    pub fn token_bucket(capacity: f64, period: Duration) -> Self {
+        Self::with_clock(Limit::TokenBucket { capacity, period }, SystemClock)
+    }
+}
+
+impl<C: Clock> RateLimiter<C> {
+    pub fn with_clock(limit: Limit, clock: C) -> Self {
         RateLimiter {
-            period,
-            allowed_quota,
-            quota: 0.0,
-            last_reset: Instant::now(),
+            limit,
+            state: Mutex::new(State::new(&limit, clock.now())),
+            clock,
         }
     }
 
-    /// Returns true if quota is below allowed threshold. Resets quota if over period.
-    pub fn check(&mut self) -> bool {
-        let now = Instant::now();
-        if self.last_reset + self.period < now {
-            self.quota = 0.0;
-            self.last_reset = now;
-        }
-        self.quota <= self.allowed_quota
+    pub fn limit(&self) -> Limit {
+        self.limit
+    }
+
+    /// Runs `f` on the state, after regaining quota up to now
+    fn with_state<T>(&self, f: impl FnOnce(&mut State, Instant) -> T) -> T {
+        let now = self.clock.now();
+        let mut state = self.state.lock().expect("Rate limiter mutex is poisoned");
+        state.update(&self.limit, now);
+        f(&mut state, now)
+    }
+
+    /// Returns true if the quota is not exceeded
+    pub fn check(&self) -> bool {
+        self.with_state(|state, _| state.remaining(&self.limit) >= 0.0)
     }
 
-    /// async block until add allowed again
-    pub async fn block_until_ok(&mut self) {
+    /// Adds to the used quota, even if that exceeds it. Returns the remaining quota or when it
+    /// is no longer exceeded.
+    pub fn add<T: Into<f64>>(&self, quota: T) -> QuotaState {
+        let quota = quota.into();
+        self.with_state(|state, now| {
+            state.consume(quota, now);
+            quota_state(state, &self.limit, now)
+        })
+    }
+
+    /// Uses `quota` if that much is remaining. Otherwise returns when it will be.
+    pub fn try_acquire<T: Into<f64>>(&self, quota: T) -> Result<f64, Instant> {
+        let quota = quota.into();
+        self.with_state(|state, now| try_acquire(state, &self.limit, quota, now))
+    }
+
+    /// async block until the quota is not exceeded anymore
+    pub async fn block_until_ok(&self) {
         loop {
-            if !self.check() {
-                async_std::task::sleep(Duration::from_secs(1)).await;
-            } else {
-                break;
+            let ready = self.with_state(|state, now| state.available_at(&self.limit, 0.0, now));
+            if ready <= self.clock.now() {
+                return;
             }
+            self.clock.sleep_until(ready).await;
         }
     }
 
-    /// Adds to quota & returns remaining quota or when it is going to be reset
-    pub fn add<T: Into<f64>>(&mut self, quota: T) -> QuotaState {
-        self.check();
+    /// async block until `quota` is remaining and use it
+    pub async fn acquire<T: Into<f64>>(&self, quota: T) {
         let quota = quota.into();
-        self.quota += quota;
-        if self.quota <= self.allowed_quota {
-            QuotaState::Remaining(self.allowed_quota - self.quota)
-        } else {
-            QuotaState::ExceededUntil(
-                self.quota - self.allowed_quota,
-                Instant::now() + self.period,
-            )
+        while let Err(ready) = self.try_acquire(quota) {
+            self.clock.sleep_until(ready).await;
         }
     }
+
+    /// Quota still counted against the limit, see [RateLimiter::restore]
+    pub fn usage(&self) -> Vec<Usage> {
+        self.with_state(|state, _| {
+            to_usage(&self.clock, state.usage(&self.limit, self.clock.now()))
+        })
+    }
+
+    /// Replaces the state by the one after using `usage`, e.g. as saved before a restart or
+    /// derived from other records
+    pub fn restore(&self, usage: impl IntoIterator<Item = Usage>) {
+        let usage = to_instants(&self.clock, usage);
+        let now = self.clock.now();
+        *self.state.lock().expect("Rate limiter mutex is poisoned") =
+            State::replay(&self.limit, &usage, now);
+    }
+}
+
+/// Times of `usage` as instants of `clock`, oldest first
+fn to_instants<C: Clock>(clock: &C, usage: impl IntoIterator<Item = Usage>) -> Vec<(Instant, f64)> {
+    let (now, system_now) = (clock.now(), clock.system_now());
+    let mut usage: Vec<(Instant, f64)> = usage
+        .into_iter()
+        .map(|Usage { at, amount }| {
+            let age = system_now.duration_since(at).unwrap_or_default();
+            // instants can't go back before the system started, count such usage as recent
+            (now.checked_sub(age).unwrap_or(now), amount)
+        })
+        .collect();
+    usage.sort_by_key(|(time, _)| *time);
+    usage
+}
+
+fn to_usage<C: Clock>(clock: &C, usage: Vec<(Instant, f64)>) -> Vec<Usage> {
+    let (now, system_now) = (clock.now(), clock.system_now());
+    usage
+        .into_iter()
+        .map(|(time, amount)| Usage {
+            at: system_now - now.saturating_duration_since(time),
+            amount,
+        })
+        .collect()
+}
+
+fn quota_state(state: &State, limit: &Limit, now: Instant) -> QuotaState {
+    let remaining = state.remaining(limit);
+    if remaining >= 0.0 {
+        QuotaState::Remaining(remaining)
+    } else {
+        QuotaState::ExceededUntil(-remaining, state.available_at(limit, 0.0, now))
+    }
+}
+
+fn try_acquire(state: &mut State, limit: &Limit, quota: f64, now: Instant) -> Result<f64, Instant> {
+    let ready = state.available_at(limit, quota, now);
+    if ready > now {
+        return Err(ready);
+    }
+    state.consume(quota, now);
+    Ok(state.remaining(limit))
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
     use std::time::Duration;
 
-    use crate::{QuotaState, RateLimiter};
+    use crate::{Clock, Limit, MockClock, QuotaState, RateLimiter};
+
+    const SECOND: Duration = Duration::from_secs(1);
 
     #[test]
     fn test_rate_limiter() {
-        let mut rl = RateLimiter::new(100.0, Duration::from_secs(1));
+        let rl = RateLimiter::new(100.0, Duration::from_secs(1));
 
         assert!(rl.check());
         assert_eq!(rl.add(50), QuotaState::Remaining(50.0));
@@ -81,4 +207,75 @@ mod tests {
             _ => panic!(),
         }
     }
+
+    #[test]
+    fn exceeded_quota_reports_when_it_is_available() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let rl = RateLimiter::with_clock(
+            Limit::SlidingWindow {
+                capacity: 10.0,
+                period: 60 * SECOND,
+            },
+            clock.clone(),
+        );
+        rl.add(4);
+        clock.advance(10 * SECOND);
+        assert_eq!(
+            rl.add(8),
+            QuotaState::ExceededUntil(2.0, start + 60 * SECOND)
+        );
+        assert!(!rl.check());
+
+        // only the first 4 are freed after a minute, not the whole quota at once
+        clock.advance(50 * SECOND);
+        assert!(rl.check());
+        assert_eq!(rl.try_acquire(3), Err(start + 70 * SECOND));
+        assert_eq!(rl.try_acquire(2), Ok(0.0));
+    }
+
+    #[test]
+    fn waiting_wakes_up_exactly_when_quota_is_available() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let rl = Arc::new(RateLimiter::with_clock(
+            Limit::TokenBucket {
+                capacity: 2.0,
+                period: 10 * SECOND,
+            },
+            clock.clone(),
+        ));
+
+        async_std::task::block_on(async {
+            rl.acquire(2).await;
+            assert_eq!(clock.now(), start);
+            // refills by one every 5 seconds
+            rl.acquire(1).await;
+            assert_eq!(clock.now(), start + 5 * SECOND);
+
+            rl.add(3);
+            rl.block_until_ok().await;
+            assert_eq!(clock.now(), start + 20 * SECOND);
+        });
+    }
+
+    #[test]
+    fn usage_survives_a_restart() {
+        let clock = MockClock::new();
+        let limit = Limit::SlidingWindow {
+            capacity: 10.0,
+            period: 60 * SECOND,
+        };
+        let rl = RateLimiter::with_clock(limit, clock.clone());
+        rl.add(4);
+        clock.advance(30 * SECOND);
+        rl.add(4);
+        let usage = rl.usage();
+
+        let restarted = RateLimiter::with_clock(limit, clock.clone());
+        restarted.restore(usage);
+        assert_eq!(restarted.try_acquire(3), Err(clock.now() + 30 * SECOND));
+        clock.advance(30 * SECOND);
+        assert_eq!(restarted.try_acquire(3), Ok(3.0));
+    }
 }
@@ -0,0 +1,42 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use sqlx::SqlitePool;
+
+use crate::{Clock, RateLimiter, Usage};
+
+impl<C: Clock> RateLimiter<C> {
+    /// Replaces the usage saved as `name` by the current one
+    pub async fn store(&self, name: &str, pool: &SqlitePool) -> anyhow::Result<()> {
+        let mut tx = pool.begin().await?;
+        sqlx::query("DELETE FROM rate_limiter_usage WHERE name = ?")
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+        for Usage { at, amount } in self.usage() {
+            let at = at.duration_since(UNIX_EPOCH)?.as_secs_f64();
+            sqlx::query("INSERT INTO rate_limiter_usage (name, at, amount) VALUES (?, ?, ?)")
+                .bind(name)
+                .bind(at)
+                .bind(amount)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Restores the usage saved as `name`, see [RateLimiter::restore]. Keeps a new state if
+    /// nothing was saved.
+    pub async fn load(&self, name: &str, pool: &SqlitePool) -> anyhow::Result<()> {
+        let rows: Vec<(f64, f64)> =
+            sqlx::query_as("SELECT at, amount FROM rate_limiter_usage WHERE name = ?")
+                .bind(name)
+                .fetch_all(pool)
+                .await?;
+        self.restore(rows.into_iter().map(|(at, amount)| Usage {
+            at: UNIX_EPOCH + Duration::from_secs_f64(at.max(0.0)),
+            amount,
+        }));
+        Ok(())
+    }
+}
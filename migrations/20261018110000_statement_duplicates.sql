@@ -0,0 +1,20 @@
+-- near-duplicate statements found by keyword search or embedding similarity
+create table duplicate_candidates (
+  -- the older statement, which would be kept when merging
+  statement_id integer not null references statements(id) on delete cascade on update cascade,
+  duplicate_id integer not null references statements(id) on delete cascade on update cascade,
+  -- 'keywords' or 'embedding'
+  method text not null,
+  -- in [0, 1], how similar the statements are according to the method
+  similarity real not null,
+  created integer not null default (strftime('%s', 'now')),
+  primary key (statement_id, duplicate_id)
+) strict;
+
+-- statements which were merged into another one, so that old urls keep working
+create table statement_redirects (
+  -- id of the deleted statement, so no foreign key
+  statement_id integer not null primary key,
+  canonical_id integer not null references statements(id) on delete cascade on update cascade,
+  created integer not null default (strftime('%s', 'now'))
+) strict;
@@ -0,0 +1,36 @@
+-- a relation (statement_id, related_statement_id, type) reads: statement is a <type> of related statement
+insert into relation_types (id, name) values
+  (1, 'generalization'),
+  (2, 'specialization'),
+  (3, 'alternative'),
+  (4, 'contradiction');
+
+-- only the creator of a relation may remove it again, relations from before are kept for good
+alter table statement_relations add column created_by integer references users(id) on delete set null;
+
+-- specializations in both directions: statement_id is a specialization of generalization_id
+create view statement_specializations as
+  select statement_id, related_statement_id as generalization_id
+  from statement_relations where relation_type = 2
+  union
+  select related_statement_id as statement_id, statement_id as generalization_id
+  from statement_relations where relation_type = 1;
+
+create trigger vote_history_ai_relations after insert on vote_history
+  when new.vote != 0 -- not skipped
+begin
+  -- voting on a specialization adds its generalizations to queue
+  insert into queue (user_id, statement_id)
+    select new.user_id, generalization_id
+    from statement_specializations
+    where statement_id = new.statement_id
+    and generalization_id not in (select statement_id from votes where user_id = new.user_id);
+  -- and the other specializations of these generalizations
+  insert into queue (user_id, statement_id)
+    select new.user_id, siblings.statement_id
+    from statement_specializations s
+    join statement_specializations siblings on siblings.generalization_id = s.generalization_id
+    where s.statement_id = new.statement_id
+    and siblings.statement_id != new.statement_id
+    and siblings.statement_id not in (select statement_id from votes where user_id = new.user_id);
+end;
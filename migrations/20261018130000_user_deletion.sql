@@ -0,0 +1,2 @@
+-- set when a user deleted their account, but kept their votes anonymously
+alter table users add column deleted integer;
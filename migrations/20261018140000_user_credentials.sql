@@ -0,0 +1,32 @@
+-- optional password to log into an account from another device
+create table user_passwords (
+  user_id integer not null primary key references users(id) on delete cascade on update cascade,
+  -- chosen by the user, to find the account when logging in
+  username text not null unique collate nocase,
+  -- argon2 PHC string, including the salt
+  hash text not null,
+  created integer not null default (strftime('%s', 'now'))
+) strict;
+
+-- WebAuthn passkeys to log into an account
+create table user_passkeys (
+  id integer not null primary key, -- rowid
+  user_id integer not null references users(id) on delete cascade on update cascade,
+  -- base64url, as reported by the authenticator
+  credential_id text not null unique,
+  -- DER encoded SubjectPublicKeyInfo of a P-256 key
+  public_key blob not null,
+  -- signature counter of the authenticator, to detect cloned keys
+  sign_count integer not null default 0,
+  name text not null,
+  created integer not null default (strftime('%s', 'now')),
+  last_used integer
+) strict;
+
+-- one-time challenges of WebAuthn ceremonies, valid for a few minutes
+create table webauthn_challenges (
+  challenge text not null primary key,
+  -- the user registering a passkey, null when logging in
+  user_id integer references users(id) on delete cascade on update cascade,
+  created integer not null default (strftime('%s', 'now'))
+) strict;
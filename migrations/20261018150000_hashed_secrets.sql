@@ -0,0 +1,6 @@
+-- `users.secret` holds a keyed hash of the secret instead of the secret itself.
+-- The first characters of the secret are kept to find the row, the hash is then compared in
+-- constant time. Rows without a prefix still hold the plain secret and are hashed on startup,
+-- since the key is not known to sql.
+alter table users add column secret_prefix text;
+create index users_secret_prefix on users(secret_prefix);
@@ -0,0 +1,33 @@
+-- one-time tokens to log another device into an account, e.g. via qr code
+create table device_link_tokens (
+  -- keyed hash of the token, like users.secret
+  token_hash text not null primary key,
+  user_id integer not null references users(id) on delete cascade on update cascade,
+  created integer not null default (strftime('%s', 'now')),
+  expires integer not null
+) strict;
+
+-- devices logged into an account with a secret of their own
+create table linked_devices (
+  id integer not null primary key, -- rowid
+  user_id integer not null references users(id) on delete cascade on update cascade,
+  -- like users.secret_prefix and users.secret
+  secret_prefix text not null,
+  secret_hash text not null unique,
+  -- user agent of the device
+  name text not null,
+  created integer not null default (strftime('%s', 'now')),
+  last_used integer
+) strict;
+create index linked_devices_secret_prefix on linked_devices(secret_prefix);
+
+-- security relevant events of an account, shown to its owner
+create table audit_log (
+  id integer not null primary key, -- rowid
+  user_id integer not null references users(id) on delete cascade on update cascade,
+  -- e.g. 'device_linked', see AuditEvent
+  event text not null,
+  detail text,
+  created integer not null default (strftime('%s', 'now'))
+) strict;
+create index audit_log_user_id on audit_log(user_id, created);
@@ -0,0 +1,14 @@
+-- server-side sessions of browsers, the session cookie only holds the token
+create table sessions (
+  id integer not null primary key, -- rowid
+  user_id integer not null references users(id) on delete cascade on update cascade,
+  -- keyed hash of the token, like users.secret
+  token_hash text not null unique,
+  -- user agent of the browser
+  name text not null,
+  created integer not null default (strftime('%s', 'now')),
+  last_seen integer not null default (strftime('%s', 'now')),
+  -- moved forward on use, see SESSION_LIFETIME_SECONDS
+  expires integer not null
+) strict;
+create index sessions_user_id on sessions(user_id);
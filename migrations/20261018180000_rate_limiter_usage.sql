@@ -0,0 +1,8 @@
+-- quota used by rate limiters, so that it is not forgotten on restarts
+create table rate_limiter_usage (
+  name text not null,
+  at real not null, -- unix time
+  amount real not null
+) strict;
+
+create index rate_limiter_usage_name on rate_limiter_usage (name);
@@ -0,0 +1,12 @@
+-- statements waiting for predictions or embeddings, removed when they need nothing more
+create table prediction_queue (
+  statement_id integer not null primary key references statements(id) on delete cascade on update cascade,
+  -- number of views since the statement was queued, the most viewed are worked on first
+  priority integer not null default 0,
+  created integer not null default (strftime('%s', 'now'))
+) strict;
+
+create index prediction_queue_priority on prediction_queue (priority desc, created);
+
+-- statements which are done already leave the queue on the first pass of the workers
+insert into prediction_queue (statement_id) select id from statements;
@@ -0,0 +1,4 @@
+-- backend and model which made the embedding, as "{model},{name}". Embeddings of different
+-- models can't be compared, so statements embedded with another model are embedded again.
+-- All embeddings so far were made by openai.
+alter table statement_embeddings add column model text not null default 'text-embedding-ada-002,openai';
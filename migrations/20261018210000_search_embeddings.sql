@@ -0,0 +1,17 @@
+-- tokens used to embed search queries, which are not stored, so they count towards the
+-- budgets and quotas of the api keys like the embeddings of statements
+create table search_embeddings (
+  api_key_id integer not null references api_keys (id),
+  model text not null,
+  prompt_tokens integer not null,
+  total_tokens integer GENERATED ALWAYS AS (prompt_tokens) VIRTUAL,
+  created integer not null default (strftime('%s', 'now'))
+) strict;
+
+CREATE TRIGGER search_embeddings_stats AFTER INSERT ON search_embeddings
+  BEGIN
+    -- update stats
+    UPDATE api_keys
+       SET total_tokens = total_tokens + new.total_tokens
+     WHERE id = new.api_key_id;
+  END;
@@ -0,0 +1,36 @@
+-- merging accounts is a single insert, so it runs atomically and its rules live with the schema
+create view user_merges (source_id, target_id, move_content) as
+  select null, null, null where false;
+
+create trigger user_merges_ii instead of insert on user_merges
+begin
+  select raise(abort, 'cannot merge a user with itself')
+    where new.source_id = new.target_id;
+  -- must run before the vote history is moved, to compare the latest votes of both
+  insert into votes (statement_id, user_id, vote)
+    select statement_id, new.target_id, vote from votes
+    where new.move_content and user_id = new.source_id
+    on conflict (statement_id, user_id) do update set vote = excluded.vote
+    where (select max(created) from vote_history where user_id = new.source_id and statement_id = excluded.statement_id)
+        > (select max(created) from vote_history where user_id = new.target_id and statement_id = excluded.statement_id);
+  -- updating does not fire the insert triggers, which would overwrite the merged votes
+  update vote_history set user_id = new.target_id
+    where new.move_content and user_id = new.source_id;
+  insert or ignore into authors (user_id, statement_id, created)
+    select new.target_id, statement_id, created from authors
+    where new.move_content and user_id = new.source_id;
+  update statement_relations set created_by = new.target_id
+    where new.move_content and created_by = new.source_id;
+  -- subscriptions and queue ignore conflicts on their primary key
+  insert into subscriptions (user_id, statement_id, created)
+    select new.target_id, statement_id, created from subscriptions
+    where new.move_content and user_id = new.source_id;
+  insert into queue (user_id, statement_id, created)
+    select new.target_id, statement_id, created from queue
+    where new.move_content and user_id = new.source_id;
+  -- statements may have been queued by one user, but voted on by the other
+  delete from queue where new.move_content and user_id = new.target_id
+    and statement_id in (select statement_id from votes where user_id = new.target_id);
+  -- cascades to everything that was not moved
+  delete from users where id = new.source_id;
+end;
@@ -1,5 +1,11 @@
+CREATE INDEX audit_log_user_id on audit_log(user_id, created);
+CREATE INDEX linked_devices_secret_prefix on linked_devices(secret_prefix);
+CREATE INDEX prediction_queue_priority on prediction_queue (priority desc, created);
 CREATE INDEX queue_statement_id on queue (user_id, created, statement_id);
+CREATE INDEX rate_limiter_usage_name on rate_limiter_usage (name);
+CREATE INDEX sessions_user_id on sessions(user_id);
 CREATE INDEX subscriptions_idx_statement_id ON subscriptions(statement_id);
+CREATE INDEX users_secret_prefix on users(secret_prefix);
 CREATE INDEX vote_history_statement_id on vote_history (user_id, created, statement_id);
 CREATE TABLE api_keys (
   id integer not null primary key,
@@ -8,6 +14,14 @@ CREATE TABLE api_keys (
   total_tokens integer not null default 0,
   created integer not null default (strftime('%s', 'now'))
 ) strict;
+CREATE TABLE audit_log (
+  id integer not null primary key, -- rowid
+  user_id integer not null references users(id) on delete cascade on update cascade,
+  -- e.g. 'device_linked', see AuditEvent
+  event text not null,
+  detail text,
+  created integer not null default (strftime('%s', 'now'))
+) strict;
 CREATE TABLE authors (
   -- if two statements are the same and were merged, there are multiple authors for one statement
   user_id integer not null references users(id) on delete cascade on update cascade,
@@ -15,6 +29,24 @@ CREATE TABLE authors (
   created integer not null default (strftime('%s', 'now')), -- https://stackoverflow.com/questions/11556546/sqlite-storing-default-timestamp-as-unixepoch
   primary key (user_id, statement_id)
 ) strict, without rowid;
+CREATE TABLE device_link_tokens (
+  -- keyed hash of the token, like users.secret
+  token_hash text not null primary key,
+  user_id integer not null references users(id) on delete cascade on update cascade,
+  created integer not null default (strftime('%s', 'now')),
+  expires integer not null
+) strict;
+CREATE TABLE duplicate_candidates (
+  -- the older statement, which would be kept when merging
+  statement_id integer not null references statements(id) on delete cascade on update cascade,
+  duplicate_id integer not null references statements(id) on delete cascade on update cascade,
+  -- 'keywords' or 'embedding'
+  method text not null,
+  -- in [0, 1], how similar the statements are according to the method
+  similarity real not null,
+  created integer not null default (strftime('%s', 'now')),
+  primary key (statement_id, duplicate_id)
+) strict;
 CREATE TABLE followups (
   statement_id integer not null references statements(id) on delete cascade on update cascade,
   followup_id integer not null references statements(id) on delete cascade on update cascade,
@@ -27,16 +59,57 @@ CREATE TABLE IF NOT EXISTS 'statements_fts_content'(id INTEGER PRIMARY KEY, c0,
 CREATE TABLE IF NOT EXISTS 'statements_fts_data'(id INTEGER PRIMARY KEY, block BLOB);
 CREATE TABLE IF NOT EXISTS 'statements_fts_docsize'(id INTEGER PRIMARY KEY, sz BLOB);
 CREATE TABLE IF NOT EXISTS 'statements_fts_idx'(segid, term, pgno, PRIMARY KEY(segid, term)) WITHOUT ROWID;
+CREATE TABLE linked_devices (
+  id integer not null primary key, -- rowid
+  user_id integer not null references users(id) on delete cascade on update cascade,
+  -- like users.secret_prefix and users.secret
+  secret_prefix text not null,
+  secret_hash text not null unique,
+  -- user agent of the device
+  name text not null,
+  created integer not null default (strftime('%s', 'now')),
+  last_used integer
+) strict;
+CREATE TABLE prediction_queue (
+  statement_id integer not null primary key references statements(id) on delete cascade on update cascade,
+  -- number of views since the statement was queued, the most viewed are worked on first
+  priority integer not null default 0,
+  created integer not null default (strftime('%s', 'now'))
+) strict;
 CREATE TABLE queue (
   user_id integer not null references users(id) on delete cascade on update cascade,
   statement_id integer not null references statements(id) on delete cascade on update cascade,
   created integer not null default (strftime('%s', 'now')), -- https://stackoverflow.com/questions/11556546/sqlite-storing-default-timestamp-as-unixepoch
   primary key (user_id, statement_id) on conflict ignore
 ) strict, without rowid;
+CREATE TABLE rate_limiter_usage (
+  name text not null,
+  at real not null, -- unix time
+  amount real not null
+) strict;
 CREATE TABLE relation_types (
     id integer primary key,
     name text not null
 );
+CREATE TABLE search_embeddings (
+  api_key_id integer not null references api_keys (id),
+  model text not null,
+  prompt_tokens integer not null,
+  total_tokens integer GENERATED ALWAYS AS (prompt_tokens) VIRTUAL,
+  created integer not null default (strftime('%s', 'now'))
+) strict;
+CREATE TABLE sessions (
+  id integer not null primary key, -- rowid
+  user_id integer not null references users(id) on delete cascade on update cascade,
+  -- keyed hash of the token, like users.secret
+  token_hash text not null unique,
+  -- user agent of the browser
+  name text not null,
+  created integer not null default (strftime('%s', 'now')),
+  last_seen integer not null default (strftime('%s', 'now')),
+  -- moved forward on use, see SESSION_LIFETIME_SECONDS
+  expires integer not null
+) strict;
 CREATE TABLE _sqlx_migrations (
     version BIGINT PRIMARY KEY,
     description TEXT NOT NULL,
@@ -51,7 +124,7 @@ CREATE TABLE statement_embeddings (
   prompt_tokens integer not null,
   total_tokens integer GENERATED ALWAYS AS (prompt_tokens) VIRTUAL,
   api_key_id integer not null references api_keys (id),
-  created integer not null default (strftime('%s', 'now')),
+  created integer not null default (strftime('%s', 'now')), model text not null default 'text-embedding-ada-002,openai',
   primary key (statement_id)
 ) strict;
 CREATE TABLE statement_flags (
@@ -77,11 +150,17 @@ CREATE TABLE statement_predictions (
   created integer not null default (strftime('%s', 'now')), api_key_id integer not null references api_keys (id),
   primary key (statement_id, prompt_name, prompt_version)
 ) strict;
+CREATE TABLE statement_redirects (
+  -- id of the deleted statement, so no foreign key
+  statement_id integer not null primary key,
+  canonical_id integer not null references statements(id) on delete cascade on update cascade,
+  created integer not null default (strftime('%s', 'now'))
+) strict;
 CREATE TABLE statement_relations (
     statement_id integer not null references statements(id),
     related_statement_id integer not null references statements(id),
     relation_type integer not null references relation_types(id),
-    created_at timestamp not null default current_timestamp,
+    created_at timestamp not null default current_timestamp, created_by integer references users(id) on delete set null,
     primary key (statement_id, related_statement_id, relation_type)
 );
 CREATE TABLE statements (
@@ -95,11 +174,32 @@ CREATE TABLE subscriptions (
   created integer not null default (strftime('%s', 'now')), -- https://stackoverflow.com/questions/11556546/sqlite-storing-default-timestamp-as-unixepoch
   primary key (user_id, statement_id) on conflict ignore
 ) strict, without rowid;
+CREATE TABLE user_passkeys (
+  id integer not null primary key, -- rowid
+  user_id integer not null references users(id) on delete cascade on update cascade,
+  -- base64url, as reported by the authenticator
+  credential_id text not null unique,
+  -- DER encoded SubjectPublicKeyInfo of a P-256 key
+  public_key blob not null,
+  -- signature counter of the authenticator, to detect cloned keys
+  sign_count integer not null default 0,
+  name text not null,
+  created integer not null default (strftime('%s', 'now')),
+  last_used integer
+) strict;
+CREATE TABLE user_passwords (
+  user_id integer not null primary key references users(id) on delete cascade on update cascade,
+  -- chosen by the user, to find the account when logging in
+  username text not null unique collate nocase,
+  -- argon2 PHC string, including the salt
+  hash text not null,
+  created integer not null default (strftime('%s', 'now'))
+) strict;
 CREATE TABLE users (
   id integer not null primary key, -- rowid
   secret text not null unique,
   created integer not null default (strftime('%s', 'now')) -- https://stackoverflow.com/questions/11556546/sqlite-storing-default-timestamp-as-unixepoch
-) strict;
+, deleted integer, secret_prefix text) strict;
 CREATE TABLE vote_history (
   user_id integer not null references users(id) on delete cascade on update cascade,
   statement_id integer not null references statements(id) on delete cascade on update cascade,
@@ -113,6 +213,14 @@ CREATE TABLE votes (
   vote integer not null, -- or separate table with skipped statements?
   primary key (statement_id, user_id)
 ) strict, without rowid;
+CREATE TABLE webauthn_challenges (
+  challenge text not null primary key,
+  -- the user registering a passkey, null when logging in
+  user_id integer references users(id) on delete cascade on update cascade,
+  created integer not null default (strftime('%s', 'now'))
+) strict;
+CREATE VIEW user_merges (source_id, target_id, move_content) as
+  select null, null, null where false;
 CREATE TRIGGER api_key_stats AFTER INSERT ON statement_predictions
   BEGIN
     -- update stats
@@ -155,6 +263,13 @@ BEGIN
        OR ( vote = -1 AND new.target_no  = 1)
     );
 END;
+CREATE TRIGGER search_embeddings_stats AFTER INSERT ON search_embeddings
+  BEGIN
+    -- update stats
+    UPDATE api_keys
+       SET total_tokens = total_tokens + new.total_tokens
+     WHERE id = new.api_key_id;
+  END;
 CREATE TRIGGER statement_embeddings_stats AFTER INSERT ON statement_embeddings
   BEGIN
     -- update stats
@@ -184,6 +299,38 @@ BEGIN
     FROM followups
     WHERE statement_id = new.statement_id;
 END;
+CREATE TRIGGER user_merges_ii instead of insert on user_merges
+begin
+  select raise(abort, 'cannot merge a user with itself')
+    where new.source_id = new.target_id;
+  -- must run before the vote history is moved, to compare the latest votes of both
+  insert into votes (statement_id, user_id, vote)
+    select statement_id, new.target_id, vote from votes
+    where new.move_content and user_id = new.source_id
+    on conflict (statement_id, user_id) do update set vote = excluded.vote
+    where (select max(created) from vote_history where user_id = new.source_id and statement_id = excluded.statement_id)
+        > (select max(created) from vote_history where user_id = new.target_id and statement_id = excluded.statement_id);
+  -- updating does not fire the insert triggers, which would overwrite the merged votes
+  update vote_history set user_id = new.target_id
+    where new.move_content and user_id = new.source_id;
+  insert or ignore into authors (user_id, statement_id, created)
+    select new.target_id, statement_id, created from authors
+    where new.move_content and user_id = new.source_id;
+  update statement_relations set created_by = new.target_id
+    where new.move_content and created_by = new.source_id;
+  -- subscriptions and queue ignore conflicts on their primary key
+  insert into subscriptions (user_id, statement_id, created)
+    select new.target_id, statement_id, created from subscriptions
+    where new.move_content and user_id = new.source_id;
+  insert into queue (user_id, statement_id, created)
+    select new.target_id, statement_id, created from queue
+    where new.move_content and user_id = new.source_id;
+  -- statements may have been queued by one user, but voted on by the other
+  delete from queue where new.move_content and user_id = new.target_id
+    and statement_id in (select statement_id from votes where user_id = new.target_id);
+  -- cascades to everything that was not moved
+  delete from users where id = new.source_id;
+end;
 CREATE TRIGGER vote_history_ai AFTER INSERT ON vote_history
 BEGIN
     -- update stats
@@ -202,6 +349,24 @@ BEGIN
     FROM followups
     WHERE statement_id = new.statement_id;
 END;
+CREATE TRIGGER vote_history_ai_relations after insert on vote_history
+  when new.vote != 0 -- not skipped
+begin
+  -- voting on a specialization adds its generalizations to queue
+  insert into queue (user_id, statement_id)
+    select new.user_id, generalization_id
+    from statement_specializations
+    where statement_id = new.statement_id
+    and generalization_id not in (select statement_id from votes where user_id = new.user_id);
+  -- and the other specializations of these generalizations
+  insert into queue (user_id, statement_id)
+    select new.user_id, siblings.statement_id
+    from statement_specializations s
+    join statement_specializations siblings on siblings.generalization_id = s.generalization_id
+    where s.statement_id = new.statement_id
+    and siblings.statement_id != new.statement_id
+    and siblings.statement_id not in (select statement_id from votes where user_id = new.user_id);
+end;
 CREATE TRIGGER vote_history_ai_yes AFTER INSERT ON vote_history
   WHEN new.vote = 1 OR new.vote = -1 -- yes or no
 BEGIN
@@ -215,6 +380,13 @@ BEGIN
         OR (new.vote = -1 AND target_no  = 1)
       );
 END;
+CREATE VIEW statement_specializations as
+  select statement_id, related_statement_id as generalization_id
+  from statement_relations where relation_type = 2
+  union
+  select related_statement_id as statement_id, statement_id as generalization_id
+  from statement_relations where relation_type = 1
+/* statement_specializations(statement_id,generalization_id) */;
 CREATE VIEW statement_stats AS
 WITH counted_votes as (
     SELECT
@@ -222,6 +394,7 @@ WITH counted_votes as (
         , coalesce(sum(vote = 1), 0) as yes_votes
         , coalesce(sum(vote = -1), 0) as no_votes
         , coalesce(sum(vote = 0), 0) as skip_votes
+        , coalesce(sum(vote = 2), 0) as itdepends_votes
     from statements
     left outer join votes
     on statements.id = votes.statement_id
@@ -244,12 +417,13 @@ WITH counted_votes as (
 )
 select
     *
-    , coalesce((cast(total_votes as real) / (total_votes + skip_votes)), 0) as participation
+    -- "it depends" is a considered answer, so it counts as participation
+    , coalesce((cast(total_votes + itdepends_votes as real) / (total_votes + itdepends_votes + skip_votes)), 0) as participation
     -- polarization: 1 = 50% yes and 50% no, 0 = 100% yes or 100% no
     , coalesce((1.0 - cast((abs(yes_votes - no_votes)) as real) / (total_votes)), 0) as polarization
     , coalesce((cast(total_votes as real) / (subscriptions)), 0) as votes_per_subscription
 from cte
-/* statement_stats(statement_id,yes_votes,no_votes,skip_votes,subscriptions,total_votes,participation,polarization,votes_per_subscription) */;
+/* statement_stats(statement_id,yes_votes,no_votes,skip_votes,itdepends_votes,subscriptions,total_votes,participation,polarization,votes_per_subscription) */;
 CREATE VIEW vote_stats as select statement_id, vote, count(*) as vote_count from votes group by statement_id, vote
 /* vote_stats(statement_id,vote,vote_count) */;
 CREATE VIRTUAL TABLE statements_fts USING fts5(id UNINDEXED, text)
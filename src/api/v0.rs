@@ -0,0 +1,54 @@
+use anyhow::Result;
+use axum::debug_handler;
+use axum::extract::Path;
+
+use axum::Extension;
+use axum::Json;
+
+use serde::Deserialize;
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::db::get_statement;
+use crate::selection::SharedSelectionStrategy;
+
+use crate::structs::Vote;
+use crate::{error::AppError, structs::User};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiStatement {
+    pub id: i64,
+    pub text: String,
+}
+
+pub async fn create_user(Extension(pool): Extension<SqlitePool>) -> Result<String, AppError> {
+    let user = User::create(&pool).await?;
+    Ok(user.known_secret()?.to_string())
+}
+
+pub async fn next_statement(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(selection): Extension<SharedSelectionStrategy>,
+    user: User,
+) -> Result<Json<ApiStatement>, AppError> {
+    let statement_id = user
+        .next_statement_for_user(&*selection, &pool)
+        .await?
+        .ok_or(AppError::NotFound("No more questions".to_string()))?;
+    let statement = get_statement(statement_id, &pool).await?;
+    Ok(Json(ApiStatement {
+        id: statement.id,
+        text: statement.text,
+    }))
+}
+
+#[debug_handler]
+pub async fn statement_vote(
+    Path(statement_id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+    Json(vote): Json<Vote>,
+) -> Result<(), AppError> {
+    user.vote(statement_id, vote, &pool).await?;
+    Ok(())
+}
@@ -0,0 +1,648 @@
+//! Version 1 of the JSON API
+//!
+//! Covers everything the html pages can do. Users authenticate by sending their secret as a
+//! bearer token, see the [User] extractor. Errors are answered with a proper status code and an
+//! [crate::error::ApiErrorBody].
+
+use anyhow::Result;
+use axum::extract::{Path, Query};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Extension, Json, Router};
+use http::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::auth::rotate_secret;
+use crate::credentials::user_id_from_password;
+use crate::db::{
+    add_followup, add_relation, delete_relation, find_statement, get_followups, get_relations,
+    get_subscriptions, relation_exists, search_statement, similar_statements, statement_stats,
+    SharedEmbeddingCache,
+};
+use crate::devices::{device_name, link_device, redeem_device_link};
+use crate::error::AppError;
+use crate::export::UserExport;
+use crate::rate_limit::SharedRateLimits;
+use crate::selection::SharedSelectionStrategy;
+use crate::structs::{
+    AccountDeletion, RelationType, SearchResultStatement, SimilarStatement, Statement,
+    StatementRelation, StatementStats, TargetSegment, User, Vote,
+};
+
+/// Maximum number of entries returned by the vote history endpoint
+const MAX_HISTORY_LIMIT: i32 = 1000;
+/// Maximum number of entries returned by the similar statements endpoint
+const MAX_SIMILAR_LIMIT: i32 = 100;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/user/create", post(create_user))
+        .route("/login/password", post(login_password))
+        .route("/user/password", put(set_password).delete(remove_password))
+        .route("/user/rotate_secret", post(rotate_user_secret))
+        .route("/user/device_link", post(create_device_link))
+        .route("/user/link", post(link_user))
+        .route("/user/devices", get(devices))
+        .route("/user/devices/:id", delete(revoke_device))
+        .route("/user/sessions", get(sessions))
+        .route("/user/sessions/:id", delete(revoke_session))
+        .route("/user/subscriptions", get(subscriptions))
+        .route("/user/vote_history", get(vote_history))
+        .route("/user/export", get(export))
+        .route("/user/delete", post(delete_user))
+        .route("/next_statement", get(next_statement))
+        .route("/search", get(search))
+        .route("/statement", post(create_statement))
+        .route("/statement/:id", get(statement))
+        .route("/statement/:id/stats", get(stats))
+        .route("/statement/:id/followups", get(followups))
+        .route("/statement/:id/similar", get(similar))
+        .route(
+            "/statement/:id/relations",
+            get(relations).post(create_relation).delete(remove_relation),
+        )
+        .route("/statement/:id/vote", get(current_vote).post(vote))
+        .route("/statement/:id/subscribe", post(subscribe))
+}
+
+type ApiResult<T> = Result<T, AppError>;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiUser {
+    pub secret: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiStatement {
+    pub id: i64,
+    pub text: String,
+}
+
+impl From<Statement> for ApiStatement {
+    fn from(statement: Statement) -> Self {
+        Self {
+            id: statement.id,
+            text: statement.text,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiSearchResult {
+    pub id: i64,
+    pub text: String,
+    /// Text with matches enclosed in [crate::highlight::HIGHLIGHT_BEGIN] and
+    /// [crate::highlight::HIGHLIGHT_END]
+    pub text_highlighted: String,
+}
+
+impl From<SearchResultStatement> for ApiSearchResult {
+    fn from(result: SearchResultStatement) -> Self {
+        Self {
+            id: result.id,
+            text: result.text_original,
+            text_highlighted: result.text_highlighted,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiVoteHistoryItem {
+    pub statement: ApiStatement,
+    pub vote: Vote,
+    pub timestamp: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiSimilarStatement {
+    pub statement: ApiStatement,
+    /// Cosine similarity of the embeddings in [-1, 1]
+    pub similarity: f64,
+}
+
+impl From<SimilarStatement> for ApiSimilarStatement {
+    fn from(similar: SimilarStatement) -> Self {
+        Self {
+            statement: similar.statement.into(),
+            similarity: similar.similarity,
+        }
+    }
+}
+
+/// Statement linked to another one. The type describes the linked statement, e.g. a
+/// `Generalization` is a more general question.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiRelation {
+    pub statement: ApiStatement,
+    pub relation_type: RelationType,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiRelationChange {
+    pub related_statement_id: i64,
+    /// Type of the related statement, as in [ApiRelation]
+    pub relation_type: RelationType,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiCurrentVote {
+    pub vote: Option<Vote>,
+}
+
+/// Shows the new statement as a follow-up to people who voted on the target statement
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiTargetSegment {
+    pub statement_id: i64,
+    #[serde(default)]
+    pub voted_yes: bool,
+    #[serde(default)]
+    pub voted_no: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiCreateStatement {
+    pub text: String,
+    pub target: Option<ApiTargetSegment>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: String,
+}
+
+#[derive(Deserialize)]
+pub struct LimitQuery {
+    limit: Option<i32>,
+}
+
+/// Returns the statement or a 404
+async fn existing_statement(statement_id: i64, pool: &SqlitePool) -> ApiResult<Statement> {
+    find_statement(statement_id, pool)
+        .await?
+        .ok_or(AppError::not_found("Statement"))
+}
+
+pub async fn create_user(
+    Extension(pool): Extension<SqlitePool>,
+) -> ApiResult<(StatusCode, Json<ApiUser>)> {
+    let user = User::create(&pool).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(ApiUser {
+            secret: user.known_secret()?.to_string(),
+        }),
+    ))
+}
+
+/// Replaces the secret. The old secret stops working, the new one is returned.
+pub async fn rotate_user_secret(
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+) -> ApiResult<Json<ApiUser>> {
+    let user = rotate_secret(user.id, &pool).await?;
+    Ok(Json(ApiUser {
+        secret: user.known_secret()?.to_string(),
+    }))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiDeviceLink {
+    /// One-time token for [link_user]
+    pub token: String,
+    /// Unix timestamp in seconds
+    pub expires: i64,
+}
+
+/// Creates a one-time token to link another device, see [crate::devices]
+pub async fn create_device_link(
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+) -> ApiResult<Json<ApiDeviceLink>> {
+    let link = user.create_device_link(&pool).await?;
+    Ok(Json(ApiDeviceLink {
+        token: link.token,
+        expires: link.expires,
+    }))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiLink {
+    /// Token of a device link of the account to switch to
+    pub token: String,
+    /// Move votes and statements to the other account. Otherwise they are deleted.
+    #[serde(default = "default_true")]
+    pub move_content: bool,
+}
+
+/// Uses up a device link: the account of the client is merged into the account of the link and
+/// the client gets a secret of its own for it
+pub async fn link_user(
+    Extension(pool): Extension<SqlitePool>,
+    headers: HeaderMap,
+    user: User,
+    Json(link): Json<ApiLink>,
+) -> ApiResult<Json<ApiUser>> {
+    let mut tx = pool.begin().await?;
+    let target_id = redeem_device_link(&link.token, &mut *tx)
+        .await?
+        .ok_or(AppError::NotFound(
+            "Device link is invalid or expired".to_string(),
+        ))?;
+    if target_id != user.id {
+        let target = User {
+            id: target_id,
+            secret: None,
+        };
+        user.merge_into(&target, link.move_content, &mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    let device = link_device(target_id, &device_name(&headers), "link", &pool).await?;
+    Ok(Json(ApiUser {
+        secret: device.known_secret()?.to_string(),
+    }))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiDevice {
+    pub id: i64,
+    /// User agent of the device
+    pub name: String,
+    pub created: i64,
+    pub last_used: Option<i64>,
+}
+
+pub async fn devices(
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+) -> ApiResult<Json<Vec<ApiDevice>>> {
+    let devices = user.linked_devices(&pool).await?;
+    Ok(Json(
+        devices
+            .into_iter()
+            .map(|device| ApiDevice {
+                id: device.id,
+                name: device.name,
+                created: device.created,
+                last_used: device.last_used,
+            })
+            .collect(),
+    ))
+}
+
+/// Logs a linked device out
+pub async fn revoke_device(
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+    Path(device_id): Path<i64>,
+) -> ApiResult<StatusCode> {
+    user.revoke_device(device_id, &pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiSession {
+    pub id: i64,
+    /// User agent of the browser
+    pub name: String,
+    pub created: i64,
+    pub last_seen: i64,
+    pub expires: i64,
+}
+
+/// Browsers logged into the account, see [crate::sessions]
+pub async fn sessions(
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+) -> ApiResult<Json<Vec<ApiSession>>> {
+    let sessions = user.sessions(&pool).await?;
+    Ok(Json(
+        sessions
+            .into_iter()
+            .map(|session| ApiSession {
+                id: session.id,
+                name: session.name,
+                created: session.created,
+                last_seen: session.last_seen,
+                expires: session.expires,
+            })
+            .collect(),
+    ))
+}
+
+/// Logs a browser out
+pub async fn revoke_session(
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+    Path(session_id): Path<i64>,
+) -> ApiResult<StatusCode> {
+    user.revoke_session(session_id, &pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Everything stored about the user, see [crate::export] for the format
+pub async fn export(
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+) -> ApiResult<Json<UserExport>> {
+    Ok(Json(UserExport::create(&user, &pool).await?))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiDeleteUser {
+    /// Keep votes anonymously or delete them
+    pub mode: AccountDeletion,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiPassword {
+    pub username: String,
+    pub password: String,
+}
+
+/// Links the client to the account with this username and password and returns its new secret
+pub async fn login_password(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(limits): Extension<SharedRateLimits>,
+    headers: HeaderMap,
+    Json(login): Json<ApiPassword>,
+) -> ApiResult<Json<ApiUser>> {
+    limits.limit_login(&login.username)?;
+    let user_id = user_id_from_password(&login.username, &login.password, &pool)
+        .await?
+        .ok_or(AppError::Unauthorized)?;
+    let user = link_device(user_id, &device_name(&headers), "password", &pool).await?;
+    Ok(Json(ApiUser {
+        secret: user.known_secret()?.to_string(),
+    }))
+}
+
+/// Sets username and password to log in from another device
+pub async fn set_password(
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+    Json(password): Json<ApiPassword>,
+) -> ApiResult<StatusCode> {
+    user.set_password(password.username.trim(), &password.password, &pool)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn remove_password(
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+) -> ApiResult<StatusCode> {
+    user.remove_password(&pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Deletes the account. The secret can't be used afterwards.
+pub async fn delete_user(
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+    Json(delete): Json<ApiDeleteUser>,
+) -> ApiResult<StatusCode> {
+    user.delete_account(delete.mode, &pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn subscriptions(
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+) -> ApiResult<Json<Vec<ApiStatement>>> {
+    let statements = get_subscriptions(&user, &pool).await?;
+    Ok(Json(statements.into_iter().map(Into::into).collect()))
+}
+
+pub async fn vote_history(
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+    Query(query): Query<LimitQuery>,
+) -> ApiResult<Json<Vec<ApiVoteHistoryItem>>> {
+    let limit = query.limit.unwrap_or(20);
+    if !(1..=MAX_HISTORY_LIMIT).contains(&limit) {
+        return Err(AppError::BadRequest(format!(
+            "limit must be between 1 and {MAX_HISTORY_LIMIT}"
+        )));
+    }
+
+    let mut items = vec![];
+    for item in user.vote_history(limit, &pool).await? {
+        items.push(ApiVoteHistoryItem {
+            statement: ApiStatement {
+                id: item.statement_id,
+                text: item.statement_text,
+            },
+            vote: Vote::from(item.vote)?,
+            timestamp: item.vote_timestamp,
+        });
+    }
+    Ok(Json(items))
+}
+
+/// Answers with 204 if there are no more statements to vote on
+pub async fn next_statement(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(selection): Extension<SharedSelectionStrategy>,
+    user: User,
+) -> ApiResult<Response> {
+    let next_statement_id = user.next_statement_for_user(&*selection, &pool).await?;
+    Ok(match next_statement_id {
+        Some(statement_id) => {
+            let statement = existing_statement(statement_id, &pool).await?;
+            Json(ApiStatement::from(statement)).into_response()
+        }
+        None => StatusCode::NO_CONTENT.into_response(),
+    })
+}
+
+pub async fn search(
+    Extension(pool): Extension<SqlitePool>,
+    Query(query): Query<SearchQuery>,
+) -> ApiResult<Json<Vec<ApiSearchResult>>> {
+    let results = search_statement(query.q.as_str(), &pool).await?;
+    Ok(Json(results.into_iter().map(Into::into).collect()))
+}
+
+pub async fn create_statement(
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+    Json(form): Json<ApiCreateStatement>,
+) -> ApiResult<(StatusCode, Json<ApiStatement>)> {
+    let text = form.text.trim();
+    if text.chars().count() < 3 {
+        return Err(AppError::BadRequest(
+            "text must be at least 3 characters long".to_string(),
+        ));
+    }
+    if let Some(target) = &form.target {
+        existing_statement(target.statement_id, &pool).await?;
+    }
+
+    let statement_id = user.add_statement(text, &pool).await?;
+    if let Some(target) = form.target {
+        let segment = TargetSegment {
+            statement_id: target.statement_id,
+            voted_yes: target.voted_yes,
+            voted_no: target.voted_no,
+        };
+        add_followup(segment, statement_id, &pool).await?;
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        Json(ApiStatement {
+            id: statement_id,
+            text: text.to_string(),
+        }),
+    ))
+}
+
+pub async fn statement(
+    Path(statement_id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+) -> ApiResult<Json<ApiStatement>> {
+    let statement = existing_statement(statement_id, &pool).await?;
+    Ok(Json(statement.into()))
+}
+
+pub async fn stats(
+    Path(statement_id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+) -> ApiResult<Json<StatementStats>> {
+    existing_statement(statement_id, &pool).await?;
+    Ok(Json(statement_stats(statement_id, &pool).await?))
+}
+
+pub async fn followups(
+    Path(statement_id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+) -> ApiResult<Json<Vec<ApiStatement>>> {
+    existing_statement(statement_id, &pool).await?;
+    let mut statements = vec![];
+    for followup_id in get_followups(statement_id, &pool).await? {
+        statements.push(existing_statement(followup_id, &pool).await?.into());
+    }
+    Ok(Json(statements))
+}
+
+/// Statements with the most similar embeddings. Empty until the statement has been embedded.
+pub async fn similar(
+    Path(statement_id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+    Extension(embeddings): Extension<SharedEmbeddingCache>,
+    Query(query): Query<LimitQuery>,
+) -> ApiResult<Json<Vec<ApiSimilarStatement>>> {
+    let limit = query.limit.unwrap_or(10);
+    if !(1..=MAX_SIMILAR_LIMIT).contains(&limit) {
+        return Err(AppError::BadRequest(format!(
+            "limit must be between 1 and {MAX_SIMILAR_LIMIT}"
+        )));
+    }
+    existing_statement(statement_id, &pool).await?;
+    let similar = similar_statements(statement_id, limit as usize, &embeddings, &pool).await?;
+    Ok(Json(similar.into_iter().map(Into::into).collect()))
+}
+
+pub async fn relations(
+    Path(statement_id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+) -> ApiResult<Json<Vec<ApiRelation>>> {
+    existing_statement(statement_id, &pool).await?;
+    let mut relations = vec![];
+    for relation in get_relations(statement_id, &pool).await? {
+        relations.push(ApiRelation {
+            statement: existing_statement(relation.related_statement_id, &pool)
+                .await?
+                .into(),
+            relation_type: relation.relation_type.inverse(),
+        });
+    }
+    Ok(Json(relations))
+}
+
+/// Relation as stored, seen from the statement of the path
+async fn relation_from_change(
+    statement_id: i64,
+    change: &ApiRelationChange,
+    pool: &SqlitePool,
+) -> ApiResult<StatementRelation> {
+    existing_statement(statement_id, pool).await?;
+    existing_statement(change.related_statement_id, pool).await?;
+    if statement_id == change.related_statement_id {
+        return Err(AppError::BadRequest(
+            "Cannot relate statement to itself".to_string(),
+        ));
+    }
+    Ok(StatementRelation {
+        statement_id,
+        related_statement_id: change.related_statement_id,
+        relation_type: change.relation_type.inverse(),
+    })
+}
+
+pub async fn create_relation(
+    Path(statement_id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+    Json(change): Json<ApiRelationChange>,
+) -> ApiResult<StatusCode> {
+    let relation = relation_from_change(statement_id, &change, &pool).await?;
+    add_relation(relation, user.id, &pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn remove_relation(
+    Path(statement_id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+    Json(change): Json<ApiRelationChange>,
+) -> ApiResult<StatusCode> {
+    let relation = relation_from_change(statement_id, &change, &pool).await?;
+    if !relation_exists(relation, &pool).await? {
+        return Err(AppError::not_found("Relation"));
+    }
+    if !delete_relation(relation, user.id, &pool).await? {
+        return Err(AppError::Forbidden(
+            "Only the creator of a relation can remove it".to_string(),
+        ));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn current_vote(
+    Path(statement_id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+) -> ApiResult<Json<ApiCurrentVote>> {
+    existing_statement(statement_id, &pool).await?;
+    Ok(Json(ApiCurrentVote {
+        vote: user.get_vote(statement_id, &pool).await?,
+    }))
+}
+
+pub async fn vote(
+    Path(statement_id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+    Json(vote): Json<Vote>,
+) -> ApiResult<StatusCode> {
+    existing_statement(statement_id, &pool).await?;
+    user.vote(statement_id, vote, &pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn subscribe(
+    Path(statement_id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+    user: User,
+) -> ApiResult<StatusCode> {
+    existing_statement(statement_id, &pool).await?;
+    user.subscribe(statement_id, &pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,113 +1,428 @@
 //! Authentication & user management
+//!
+//! Secrets are only stored as keyed hashes, see [hash_secret]. The plain secret is only known
+//! while handling a request of its owner. Browsers use sessions instead, see [crate::sessions].
 
-use anyhow::Result;
+use std::fs;
+
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use axum::extract::FromRequestParts;
-use axum::http::StatusCode;
-use axum::Extension;
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::{Extension, TypedHeader};
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
+use hmac::{Hmac, Mac};
 use http::request::Parts;
+use http::HeaderMap;
+use once_cell::sync::OnceCell;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
+use sha2::Sha256;
 use sqlx::SqlitePool;
-use tower_cookies::cookie::time::Duration;
 use tower_cookies::{Cookie, Cookies};
+use tracing::info;
 
+use crate::command_line_args::AuthArgs;
+use crate::devices::{add_audit_entry, touch_device, AuditEvent};
+use crate::error::AppError;
+use crate::sessions::{current_session, end_session, start_session};
 use crate::structs::User;
 
-const COOKIE_MAX_AGE: Duration = Duration::days(10 * 365);
+/// Cookie of browsers from before sessions existed, see [crate::sessions]
+pub const LEGACY_SECRET_COOKIE: &str = "secret";
+const SECRET_LEN: usize = 16;
+/// Characters of a secret which are stored in plain text to find its user
+const SECRET_PREFIX_LEN: usize = 4;
+
+pub type HmacSha256 = Hmac<Sha256>;
+
+static SECRET_HASH_KEY: OnceCell<Vec<u8>> = OnceCell::new();
 
 impl User {
-    /// If logged in with a secret, will return a [User]
-    pub async fn from_cookies(cookies: &Cookies, pool: &SqlitePool) -> Result<Option<Self>> {
-        Ok(match cookies.get("secret") {
-            Some(cookie) => User::from_secret(cookie.value(), pool).await?,
-            None => None,
-        })
+    /// If logged in with a session, will return a [User]
+    ///
+    /// A `secret` cookie from before sessions existed is replaced by a session.
+    pub async fn from_cookies(
+        cookies: &Cookies,
+        headers: &HeaderMap,
+        pool: &SqlitePool,
+    ) -> Result<Option<Self>> {
+        if let Some(session) = current_session(cookies, pool).await? {
+            return Ok(Some(User {
+                id: session.user_id,
+                secret: None,
+            }));
+        }
+        let Some(cookie) = cookies.get(LEGACY_SECRET_COOKIE) else {
+            return Ok(None);
+        };
+        let user = User::from_secret(cookie.value(), pool).await?;
+        if let Some(user) = &user {
+            start_session(user.id, headers, cookies, pool).await?;
+        }
+        remove_legacy_cookie(cookies);
+        Ok(user)
     }
 
-    /// returns [User] via secret
+    /// returns [User] via secret, either of the account itself or of a linked device
     pub async fn from_secret(secret: &str, pool: &SqlitePool) -> Result<Option<Self>> {
-        Ok(sqlx::query_as!(
-            User,
-            "SELECT id, secret from users WHERE secret = ?",
-            secret
-        )
-        .fetch_optional(pool)
-        .await?)
+        let Some(found) = find_secret(secret, pool)
+            .await?
+            .filter(|found| !found.deleted)
+        else {
+            return Ok(None);
+        };
+        if let Some(device_id) = found.device_id {
+            touch_device(device_id, pool).await?;
+        }
+        Ok(Some(User {
+            id: found.user_id,
+            secret: Some(secret.to_string()),
+        }))
     }
 
-    /// returns logged in [User] or creates a new one and returns that
-    pub async fn get_or_create(cookies: &Cookies, pool: &SqlitePool) -> Result<User> {
-        let existing_user: Option<User> = User::from_cookies(cookies, pool).await?;
+    /// returns logged in [User] or creates a new one with a session and returns that
+    pub async fn get_or_create(
+        cookies: &Cookies,
+        headers: &HeaderMap,
+        pool: &SqlitePool,
+    ) -> Result<User> {
+        let existing_user: Option<User> = User::from_cookies(cookies, headers, pool).await?;
 
         Ok(match existing_user {
             Some(user) => user,
             None => {
                 let user = User::create(pool).await?;
-                let cookie = Cookie::build("secret", user.secret.to_owned())
-                    .max_age(COOKIE_MAX_AGE)
-                    .finish();
-                cookies.add(cookie);
-
+                start_session(user.id, headers, cookies, pool).await?;
                 user
             }
         })
     }
 
+    /// The plain secret, which is only known if the user authenticated with it or it was just
+    /// created
+    pub fn known_secret(&self) -> Result<&str> {
+        self.secret
+            .as_deref()
+            .ok_or(anyhow!("Secret of user {} is not known", self.id))
+    }
+
     /// Creates a new [User] inside the database and return it
     pub async fn create(pool: &SqlitePool) -> Result<User> {
-        let secret = generate_secret();
-        let user =
-            sqlx::query_as::<_, User>("INSERT INTO users (secret) VALUES (?) RETURNING id, secret")
-                .bind(secret)
-                .fetch_one(pool)
-                .await?;
+        let secret = generate_unique_secret(pool).await?;
+        let (prefix, hash) = hash_secret(&secret);
+        let id = sqlx::query_scalar!(
+            "INSERT INTO users (secret, secret_prefix) VALUES (?, ?) RETURNING id",
+            hash,
+            prefix
+        )
+        .fetch_one(pool)
+        .await?;
 
-        Ok(user)
+        Ok(User {
+            id,
+            secret: Some(secret),
+        })
     }
 }
 
-/// Changes the cookie containing the secret to a different value
-pub fn change_auth_cookie(secret: &str, cookies: &Cookies) {
-    if let Some(mut cookie) = cookies.get("secret") {
-        // copy old cookie, but also set path, since it may come from e.g. /merge
-        cookie.set_value(secret);
-        cookie.set_path("/");
-        cookie.set_max_age(COOKIE_MAX_AGE);
-        cookies.add(cookie.into_owned());
+/// Replaces the secret of a user, so that the old one stops working everywhere. Linked devices,
+/// sessions and device links are revoked as well. Returns the [User] with the new secret.
+pub async fn rotate_secret(user_id: i64, pool: &SqlitePool) -> Result<User> {
+    let secret = generate_unique_secret(pool).await?;
+    let (prefix, hash) = hash_secret(&secret);
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "UPDATE users SET secret = ?, secret_prefix = ? WHERE id = ? and deleted is null",
+        hash,
+        prefix,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!("DELETE FROM linked_devices WHERE user_id = ?", user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM sessions WHERE user_id = ?", user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM device_link_tokens WHERE user_id = ?", user_id)
+        .execute(&mut *tx)
+        .await?;
+    add_audit_entry(user_id, AuditEvent::SecretRotated, None, &mut *tx).await?;
+    tx.commit().await?;
+    Ok(User {
+        id: user_id,
+        secret: Some(secret),
+    })
+}
+
+/// Sets the key used to hash secrets. Without `--secret-hash-key`, it is read from the key file,
+/// which is created with a random key if missing.
+pub fn init_secret_hash_key(args: &AuthArgs) -> Result<()> {
+    let key = match &args.secret_hash_key {
+        Some(key) => key.as_bytes().to_vec(),
+        None => {
+            let path = &args.secret_hash_key_file;
+            if !path.exists() {
+                info!("Creating secret hash key file {}", path.display());
+                fs::write(path, thread_rng().gen::<[u8; 32]>())
+                    .with_context(|| format!("Unable to create {}", path.display()))?;
+            }
+            fs::read(path).with_context(|| format!("Unable to read {}", path.display()))?
+        }
+    };
+    SECRET_HASH_KEY
+        .set(key)
+        .map_err(|_| anyhow!("Secret hash key is already set"))
+}
+
+fn secret_hash_key() -> &'static [u8] {
+    SECRET_HASH_KEY
+        .get()
+        .expect("Secret hash key is not initialized")
+}
+
+/// Keyed hash of a secret. Other values hashed with the same key need a prefix, which no secret
+/// has, so that their hashes never equal the stored hash of a secret.
+pub fn secret_mac(secret: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret_hash_key()).expect("HMAC accepts keys of any size");
+    mac.update(secret.as_bytes());
+    mac
+}
+
+fn secret_prefix(secret: &str) -> String {
+    secret.chars().take(SECRET_PREFIX_LEN).collect()
+}
+
+/// Returns lookup prefix and keyed hash of a secret, as stored in the users table
+pub fn hash_secret(secret: &str) -> (String, String) {
+    let hash = STANDARD_NO_PAD.encode(secret_mac(secret).finalize().into_bytes());
+    (secret_prefix(secret), hash)
+}
+
+/// Whether the stored hash belongs to the secret, compared in constant time, so that the hash
+/// can't be guessed by timing
+pub fn secret_matches(secret: &str, stored_hash: &str) -> bool {
+    STANDARD_NO_PAD
+        .decode(stored_hash)
+        .is_ok_and(|hash| secret_mac(secret).verify_slice(&hash).is_ok())
+}
+
+struct FoundSecret {
+    user_id: i64,
+    deleted: bool,
+    /// Set if the secret belongs to a linked device
+    device_id: Option<i64>,
+}
+
+/// Finds the user with this secret, including deleted ones
+async fn find_secret(secret: &str, pool: &SqlitePool) -> Result<Option<FoundSecret>> {
+    let prefix = secret_prefix(secret);
+    let user = sqlx::query!(
+        "SELECT id, secret, deleted is not null as \"deleted!: bool\" FROM users WHERE secret_prefix = ?",
+        prefix
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .find(|candidate| secret_matches(secret, &candidate.secret));
+    if let Some(user) = user {
+        return Ok(Some(FoundSecret {
+            user_id: user.id,
+            deleted: user.deleted,
+            device_id: None,
+        }));
+    }
+
+    let device = sqlx::query!(
+        "SELECT id, user_id, secret_hash FROM linked_devices WHERE secret_prefix = ?",
+        prefix
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .find(|candidate| secret_matches(secret, &candidate.secret_hash));
+    Ok(device.map(|device| FoundSecret {
+        user_id: device.user_id,
+        deleted: false,
+        device_id: Some(device.id),
+    }))
+}
+
+/// Hashes secrets of users created before secrets were hashed
+pub async fn hash_legacy_secrets(pool: &SqlitePool) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    // deleted users keep a marker instead of a secret
+    let legacy = sqlx::query!(
+        "SELECT id, secret FROM users WHERE secret_prefix is null and deleted is null"
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for user in &legacy {
+        let (prefix, hash) = hash_secret(&user.secret);
+        sqlx::query!(
+            "UPDATE users SET secret = ?, secret_prefix = ? WHERE id = ?",
+            hash,
+            prefix,
+            user.id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    if !legacy.is_empty() {
+        info!("Hashed {} legacy secrets", legacy.len());
+    }
+    Ok(())
+}
+
+/// Removes the `secret` cookie, which browsers got before sessions existed
+fn remove_legacy_cookie(cookies: &Cookies) {
+    let mut cookie = Cookie::named(LEGACY_SECRET_COOKIE);
+    cookie.set_path("/");
+    cookies.remove(cookie);
+}
+
+/// Logs the browser out of its account, e.g. after the account was deleted
+pub async fn remove_auth_cookie(cookies: &Cookies, pool: &SqlitePool) -> Result<()> {
+    end_session(cookies, pool).await?;
+    if cookies.get(LEGACY_SECRET_COOKIE).is_some() {
+        remove_legacy_cookie(cookies);
     }
+    Ok(())
 }
 
-fn generate_secret() -> String {
-    // TODO: check if used already
+pub fn generate_secret() -> String {
     thread_rng()
         .sample_iter(&Alphanumeric)
-        .take(16)
+        .take(SECRET_LEN)
         .map(char::from)
         .collect()
 }
 
+/// Generates a secret which no user or linked device has, including deleted users
+pub async fn generate_unique_secret(pool: &SqlitePool) -> Result<String> {
+    loop {
+        let secret = generate_secret();
+        if find_secret(&secret, pool).await?.is_none() {
+            return Ok(secret);
+        }
+    }
+}
+
+/// Extracts the logged in [User] from either the session cookie or a bearer token
+///
+/// A bearer token takes precedence over the cookie, since it is sent explicitly.
 #[async_trait]
 impl<S> FromRequestParts<S> for User
 where
     S: Send + Sync,
 {
-    type Rejection = (StatusCode, &'static str);
+    type Rejection = AppError;
 
-    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
         use axum::RequestPartsExt;
         let Extension(pool) = parts
             .extract::<Extension<SqlitePool>>()
             .await
             .expect("Unable to get sqlite connection");
-        let cookies = parts
-            .extract::<Cookies>()
+
+        let bearer = TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
             .await
-            .expect("Unable to get cookies");
+            .ok();
+        let user = match bearer {
+            Some(TypedHeader(Authorization(bearer))) => {
+                User::from_secret(bearer.token(), &pool).await?
+            }
+            None => match parts.extract::<Cookies>().await {
+                Ok(cookies) => User::from_cookies(&cookies, &parts.headers, &pool).await?,
+                Err(_) => None,
+            },
+        };
 
-        match User::from_cookies(&cookies, &pool).await {
-            Ok(result) => result.ok_or((StatusCode::UNAUTHORIZED, "Unauthorized")),
-            Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")),
-        }
+        user.ok_or(AppError::Unauthorized)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::init_auth;
+
+    #[sqlx::test]
+    async fn secrets_are_hashed(pool: SqlitePool) -> anyhow::Result<()> {
+        init_auth();
+        let user = User::create(&pool).await?;
+        let stored = sqlx::query_scalar!("select secret from users where id = ?", user.id)
+            .fetch_one(&pool)
+            .await?;
+        assert_ne!(stored, user.known_secret()?);
+        assert_eq!(
+            User::from_secret(user.known_secret()?, &pool)
+                .await?
+                .map(|u| u.id),
+            Some(user.id)
+        );
+        assert!(User::from_secret(&stored, &pool).await?.is_none());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn legacy_secrets_are_migrated(pool: SqlitePool) -> anyhow::Result<()> {
+        init_auth();
+        sqlx::query!("insert into users(id, secret) values (1, 'legacysecret1234')")
+            .execute(&pool)
+            .await?;
+        assert!(User::from_secret("legacysecret1234", &pool)
+            .await?
+            .is_none());
+
+        hash_legacy_secrets(&pool).await?;
+        assert_eq!(
+            User::from_secret("legacysecret1234", &pool)
+                .await?
+                .map(|u| u.id),
+            Some(1)
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn rotated_secret_replaces_old_one(pool: SqlitePool) -> anyhow::Result<()> {
+        init_auth();
+        let user = User::create(&pool).await?;
+        let rotated = rotate_secret(user.id, &pool).await?;
+        assert_ne!(rotated.secret, user.secret);
+        assert!(User::from_secret(user.known_secret()?, &pool)
+            .await?
+            .is_none());
+        assert_eq!(
+            User::from_secret(rotated.known_secret()?, &pool)
+                .await?
+                .map(|u| u.id),
+            Some(user.id)
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn secret_cookies_are_replaced_by_sessions(pool: SqlitePool) -> anyhow::Result<()> {
+        init_auth();
+        let user = User::create(&pool).await?;
+        let cookies = Cookies::default();
+        cookies.add(Cookie::new(
+            LEGACY_SECRET_COOKIE,
+            user.known_secret()?.to_string(),
+        ));
+
+        let found = User::from_cookies(&cookies, &HeaderMap::new(), &pool).await?;
+        assert_eq!(found.map(|u| u.id), Some(user.id));
+        assert!(cookies.get(LEGACY_SECRET_COOKIE).is_none());
+        assert!(cookies.get(crate::sessions::SESSION_COOKIE).is_some());
+        assert_eq!(user.sessions(&pool).await?.len(), 1);
+        Ok(())
     }
 }
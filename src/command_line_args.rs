@@ -1,4 +1,6 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[cfg(feature = "with_predictions")]
 #[derive(Parser, Clone, Debug)]
@@ -28,6 +30,80 @@ pub struct PredictionArgs {
     /// Duration length in seconds for rate limiting API calls
     #[arg(long, env, default_value_t = 1)]
     pub api_calls_seconds_per_duration: u64,
+
+    /// USD each API key may spend per day (UTC), not limited if not given
+    #[arg(long, env)]
+    pub api_key_daily_budget: Option<f64>,
+
+    /// USD each API key may spend per calendar month (UTC), not limited if not given
+    #[arg(long, env)]
+    pub api_key_monthly_budget: Option<f64>,
+
+    /// Workers predicting batches of statements at the same time
+    #[arg(long, env, default_value_t = 1)]
+    pub classification_workers: usize,
+
+    /// Workers embedding statements at the same time
+    #[arg(long, env, default_value_t = 1)]
+    pub embedding_workers: usize,
+
+    /// Workers going through statements of failed batches one by one at the same time
+    #[arg(long, env, default_value_t = 1)]
+    pub moderation_workers: usize,
+
+    /// Seconds an API key is not used after a request with it failed
+    #[arg(long, env, default_value_t = 5 * 60)]
+    pub api_key_error_seconds: u64,
+
+    /// Backend to run predictions with
+    #[arg(long, env, value_enum, default_value_t = AiBackendKind::Openai)]
+    pub ai_backend: AiBackendKind,
+
+    /// Backend to embed statements with
+    /// Embeddings of different backends and models can't be compared, so statements embedded
+    /// with another one are embedded again after a change
+    #[arg(long, env, value_enum, default_value_t = EmbeddingBackendKind::Openai)]
+    pub embedding_backend: EmbeddingBackendKind,
+
+    /// Base URL of the openai-compatible server, e.g. http://localhost:8080/v1
+    #[arg(long, env)]
+    pub ai_base_url: Option<String>,
+
+    /// API key sent to the openai-compatible server, if it requires one
+    #[arg(long, env)]
+    pub ai_base_url_api_key: Option<String>,
+
+    /// Model used for predictions, gpt-3.5-turbo if not given for openai
+    #[arg(long, env)]
+    pub ai_model: Option<String>,
+
+    /// Model used for embeddings by the openai-compatible server
+    #[arg(long, env)]
+    pub embedding_model: Option<String>,
+}
+
+/// Where prompts are sent to
+#[cfg(feature = "with_predictions")]
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AiBackendKind {
+    /// OpenAI, using the configured API keys
+    Openai,
+    /// Server speaking the OpenAI API at --ai-base-url, like llama.cpp or vLLM
+    OpenaiCompatible,
+}
+
+/// Where statements are embedded
+#[cfg(feature = "with_predictions")]
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmbeddingBackendKind {
+    /// OpenAI, using the configured API keys
+    Openai,
+    /// Server speaking the OpenAI API at --ai-base-url, like llama.cpp or vLLM
+    OpenaiCompatible,
+    /// Hashed words and character trigrams, computed in process. Lexical only: finds texts
+    /// sharing words, not texts with the same meaning. For semantic embeddings without openai,
+    /// run a model behind openai-compatible.
+    Hashing,
 }
 #[cfg(not(feature = "with_predictions"))]
 #[derive(Parser, Clone, Debug)]
@@ -40,6 +116,117 @@ pub struct DatabaseArgs {
     pub database_url: String,
 }
 
+#[derive(Parser, Clone, Debug)]
+pub struct AuthArgs {
+    /// Key to hash user secrets with. Changing it logs out every user.
+    #[arg(long, env)]
+    pub secret_hash_key: Option<String>,
+
+    /// File with the key to hash user secrets, if --secret-hash-key is not given.
+    /// A random key is written to it, if it doesn't exist.
+    #[arg(long, env, default_value = "data/secret_hash.key")]
+    pub secret_hash_key_file: PathBuf,
+
+    /// Send session and csrf cookies over plain http too, e.g. when developing without https
+    #[arg(long, env)]
+    pub insecure_cookies: bool,
+
+    /// Scheme, domain and port of the site, which passkeys are bound to.
+    /// Changing the domain makes existing passkeys unusable.
+    #[arg(long, env, default_value = "http://localhost:8000")]
+    pub passkey_origin: String,
+}
+
+/// Limits per client, see [crate::rate_limit]. A limit of 0 disables it.
+#[derive(Parser, Clone, Debug)]
+pub struct RateLimitArgs {
+    /// Accounts a client may create via the api or device links per duration
+    #[arg(long, env, default_value_t = 10)]
+    pub account_creations_per_duration: u64,
+
+    /// Duration length in seconds for rate limiting account creation
+    #[arg(long, env, default_value_t = 60 * 60)]
+    pub account_creations_seconds_per_duration: u64,
+
+    /// Login attempts a client may make, and a username may get, per duration
+    #[arg(long, env, default_value_t = 10)]
+    pub logins_per_duration: u64,
+
+    /// Duration length in seconds for rate limiting login attempts
+    #[arg(long, env, default_value_t = 15 * 60)]
+    pub logins_seconds_per_duration: u64,
+
+    /// Statements a client may create per duration
+    #[arg(long, env, default_value_t = 20)]
+    pub statements_per_duration: u64,
+
+    /// Duration length in seconds for rate limiting statement creation
+    #[arg(long, env, default_value_t = 60 * 60)]
+    pub statements_seconds_per_duration: u64,
+
+    /// Votes a client may cast per duration
+    #[arg(long, env, default_value_t = 60)]
+    pub votes_per_duration: u64,
+
+    /// Duration length in seconds for rate limiting votes
+    #[arg(long, env, default_value_t = 60)]
+    pub votes_seconds_per_duration: u64,
+
+    /// Searches a client may run per duration
+    #[arg(long, env, default_value_t = 60)]
+    pub searches_per_duration: u64,
+
+    /// Duration length in seconds for rate limiting searches
+    #[arg(long, env, default_value_t = 60)]
+    pub searches_seconds_per_duration: u64,
+
+    /// Header holding the ip of the client, set by a reverse proxy, e.g. Fly-Client-IP.
+    /// Without it, or if a request lacks the header, the address of the connection is used.
+    #[arg(long, env)]
+    pub client_ip_header: Option<String>,
+}
+
+/// Order in which statements are shown, once a user has voted on all follow-ups in their queue
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatementSelection {
+    /// Random statement the user has not voted on
+    Random,
+    /// Statement with the fewest votes overall
+    LeastVoted,
+    /// Statement with the highest polarization
+    MostPolarizing,
+    /// Statement least similar to what the user voted on recently (requires embeddings)
+    EmbeddingDiversity,
+    /// Like random, but statements skipped a while ago are shown again
+    NotRecentlySkipped,
+}
+
+#[derive(Parser, Clone, Debug)]
+pub struct SelectionArgs {
+    /// Strategy to select the next statement, once the user's queue is empty
+    #[arg(long, env, value_enum, default_value_t = StatementSelection::Random)]
+    pub statement_selection: StatementSelection,
+
+    /// Days after which skipped statements are shown again, used by not-recently-skipped
+    #[arg(long, env, default_value_t = 7)]
+    pub skip_cooldown_days: u64,
+}
+
+/// Maintenance tasks, which run instead of the server
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// List statements flagged as near-duplicates
+    ListDuplicates,
+    /// Merge a duplicate statement into the canonical one and redirect its url
+    MergeStatements {
+        duplicate_id: i64,
+        canonical_id: i64,
+    },
+    /// Show what every API key spent today and this month
+    #[cfg(feature = "with_predictions")]
+    ApiKeySpend,
+}
+
 /// Program options to be read via clap
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -48,4 +235,12 @@ pub struct CommandLineArgs {
     pub prediction: PredictionArgs,
     #[command(flatten)]
     pub database: DatabaseArgs,
+    #[command(flatten)]
+    pub auth: AuthArgs,
+    #[command(flatten)]
+    pub selection: SelectionArgs,
+    #[command(flatten)]
+    pub rate_limit: RateLimitArgs,
+    #[command(subcommand)]
+    pub command: Option<Command>,
 }
@@ -0,0 +1,342 @@
+//! Optional credentials to log into an account from another device
+//!
+//! Accounts stay anonymous and are identified by their secret. A password or passkey only
+//! provides another way into the account: browsers get a session, see
+//! [crate::sessions::log_in], api clients a secret of their own, see
+//! [crate::devices::link_device].
+
+use anyhow::{anyhow, Result};
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use sqlx::SqlitePool;
+use tracing::warn;
+
+use crate::error::AppError;
+use crate::structs::User;
+use crate::webauthn::{
+    new_challenge, verify_assertion, PasskeyAssertion, PasskeyRegistration, RelyingParty,
+    VerifiedPasskey, CHALLENGE_TIMEOUT_SECONDS,
+};
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// Verified against when the username doesn't exist, so that unknown usernames take as long to
+/// answer as wrong passwords
+static DUMMY_HASH: Lazy<String> = Lazy::new(|| {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>()).expect("16 bytes are a salt");
+    Argon2::default()
+        .hash_password(&rand::random::<[u8; 16]>(), &salt)
+        .expect("Unable to hash dummy password")
+        .to_string()
+});
+
+/// Passkey as shown to its owner
+#[derive(sqlx::FromRow, Debug)]
+pub struct Passkey {
+    pub id: i64,
+    pub name: String,
+    pub created: i64,
+    pub last_used: Option<i64>,
+}
+
+impl User {
+    /// Name to log in with a password, if the user set one
+    pub async fn username(&self, pool: &SqlitePool) -> Result<Option<String>> {
+        Ok(sqlx::query_scalar!(
+            "SELECT username FROM user_passwords WHERE user_id = ?",
+            self.id
+        )
+        .fetch_optional(pool)
+        .await?)
+    }
+
+    /// Whether nobody else uses this name to log in
+    pub async fn username_available(&self, username: &str, pool: &SqlitePool) -> Result<bool> {
+        let owner = sqlx::query_scalar!(
+            "SELECT user_id FROM user_passwords WHERE username = ?",
+            username
+        )
+        .fetch_optional(pool)
+        .await?;
+        Ok(owner.is_none_or(|owner| owner == self.id))
+    }
+
+    /// Sets or replaces username and password
+    pub async fn set_password(
+        &self,
+        username: &str,
+        password: &str,
+        pool: &SqlitePool,
+    ) -> Result<(), AppError> {
+        if username.trim().is_empty() {
+            return Err(AppError::BadRequest("Username is required".to_string()));
+        }
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(AppError::BadRequest(format!(
+                "Password must have at least {MIN_PASSWORD_LEN} characters"
+            )));
+        }
+        if !self.username_available(username, pool).await? {
+            return Err(AppError::Conflict("Username is taken".to_string()));
+        }
+        let salt =
+            SaltString::encode_b64(&rand::random::<[u8; 16]>()).map_err(|err| anyhow!(err))?;
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|err| anyhow!(err))?
+            .to_string();
+        sqlx::query!(
+            "INSERT INTO user_passwords (user_id, username, hash) VALUES (?, ?, ?)
+            ON CONFLICT (user_id) DO UPDATE SET username = excluded.username, hash = excluded.hash",
+            self.id,
+            username,
+            hash
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn remove_password(&self, pool: &SqlitePool) -> Result<()> {
+        sqlx::query!("DELETE FROM user_passwords WHERE user_id = ?", self.id)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn passkeys(&self, pool: &SqlitePool) -> Result<Vec<Passkey>> {
+        Ok(sqlx::query_as!(
+            Passkey,
+            "SELECT id, name, created, last_used FROM user_passkeys WHERE user_id = ? ORDER BY created",
+            self.id
+        )
+        .fetch_all(pool)
+        .await?)
+    }
+
+    /// Whether the account can be logged into with anything but the secret
+    pub async fn has_credentials(&self, pool: &SqlitePool) -> Result<bool> {
+        Ok(self.username(pool).await?.is_some() || !self.passkeys(pool).await?.is_empty())
+    }
+
+    /// Stores a passkey created for a challenge from [create_challenge]
+    ///
+    /// Returns false if the challenge is unknown or expired.
+    pub async fn add_passkey(
+        &self,
+        registration: &PasskeyRegistration,
+        passkey: VerifiedPasskey,
+        pool: &SqlitePool,
+    ) -> Result<bool> {
+        if !take_challenge(&passkey.challenge, Some(self.id), pool).await? {
+            return Ok(false);
+        }
+        sqlx::query!(
+            "INSERT INTO user_passkeys (user_id, credential_id, public_key, sign_count, name)
+            VALUES (?, ?, ?, ?, ?)",
+            self.id,
+            registration.credential_id,
+            passkey.public_key,
+            passkey.sign_count,
+            registration.name
+        )
+        .execute(pool)
+        .await?;
+        Ok(true)
+    }
+
+    pub async fn delete_passkey(&self, passkey_id: i64, pool: &SqlitePool) -> Result<()> {
+        sqlx::query!(
+            "DELETE FROM user_passkeys WHERE id = ? AND user_id = ?",
+            passkey_id,
+            self.id
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Returns the id of the user with this username and password
+///
+/// The caller logs the browser in with [crate::sessions::log_in]. Api clients need a secret of
+/// their own, since the secret of a user can't be recovered from its hash, see
+/// [crate::devices::link_device]. Callers limit the attempts per username with
+/// [crate::rate_limit::RateLimits::limit_login].
+pub async fn user_id_from_password(
+    username: &str,
+    password: &str,
+    pool: &SqlitePool,
+) -> Result<Option<i64>> {
+    let row = sqlx::query!(
+        "SELECT u.id, p.hash FROM user_passwords p
+        JOIN users u ON u.id = p.user_id
+        WHERE p.username = ? and u.deleted is null",
+        username
+    )
+    .fetch_optional(pool)
+    .await?;
+    let hash = row.as_ref().map_or(DUMMY_HASH.as_str(), |row| &row.hash);
+    let hash = PasswordHash::new(hash).map_err(|err| anyhow!(err))?;
+    let verified = Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok();
+    Ok(row.filter(|_| verified).map(|row| row.id))
+}
+
+/// Returns the id of the user who logged in with a passkey, answering a challenge from
+/// [create_challenge]. Like with [user_id_from_password], the caller logs the browser in.
+///
+/// Invalid signatures, challenges or counters return None.
+pub async fn user_id_from_passkey(
+    rp: &RelyingParty,
+    assertion: &PasskeyAssertion,
+    pool: &SqlitePool,
+) -> Result<Option<i64>> {
+    let Some(row) = sqlx::query!(
+        "SELECT k.id, k.public_key, k.sign_count, u.id as user_id FROM user_passkeys k
+        JOIN users u ON u.id = k.user_id
+        WHERE k.credential_id = ? and u.deleted is null",
+        assertion.credential_id
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+    let (challenge, sign_count) = match verify_assertion(rp, &row.public_key, assertion) {
+        Ok(verified) => verified,
+        Err(err) => {
+            warn!("Passkey login failed: {err}");
+            return Ok(None);
+        }
+    };
+    if !take_challenge(&challenge, None, pool).await? {
+        return Ok(None);
+    }
+    // authenticators without a counter always report 0
+    let sign_count = sign_count as i64;
+    if (sign_count != 0 || row.sign_count != 0) && sign_count <= row.sign_count {
+        warn!("Passkey {} was possibly cloned", row.id);
+        return Ok(None);
+    }
+    sqlx::query!(
+        "UPDATE user_passkeys SET sign_count = ?, last_used = strftime('%s', 'now') WHERE id = ?",
+        sign_count,
+        row.id
+    )
+    .execute(pool)
+    .await?;
+    Ok(Some(row.user_id))
+}
+
+/// New challenge to register a passkey for `user` or to log in, if `user` is None
+pub async fn create_challenge(user: Option<&User>, pool: &SqlitePool) -> Result<String> {
+    // challenges are created without logging in, so unanswered ones must not pile up
+    delete_expired_challenges(pool).await?;
+    let challenge = new_challenge();
+    let user_id = user.map(|user| user.id);
+    sqlx::query!(
+        "INSERT INTO webauthn_challenges (challenge, user_id) VALUES (?, ?)",
+        challenge,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(challenge)
+}
+
+async fn delete_expired_challenges(pool: &SqlitePool) -> Result<()> {
+    let expired_before = Utc::now().timestamp() - CHALLENGE_TIMEOUT_SECONDS;
+    sqlx::query!(
+        "DELETE FROM webauthn_challenges WHERE created <= ?",
+        expired_before
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Deletes the challenge, returns whether it was valid
+async fn take_challenge(challenge: &str, user_id: Option<i64>, pool: &SqlitePool) -> Result<bool> {
+    delete_expired_challenges(pool).await?;
+    let taken = sqlx::query!(
+        "DELETE FROM webauthn_challenges WHERE challenge = ? AND user_id IS ?",
+        challenge,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(taken.rows_affected() == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::init_auth;
+
+    #[sqlx::test]
+    async fn login_with_password(pool: SqlitePool) -> anyhow::Result<()> {
+        init_auth();
+        let user = User::create(&pool).await?;
+        let other = User::create(&pool).await?;
+        assert!(user.set_password("alice", "short", &pool).await.is_err());
+        assert!(user
+            .set_password("alice", "correct horse", &pool)
+            .await
+            .is_ok());
+        assert!(other
+            .set_password("Alice", "correct horse", &pool)
+            .await
+            .is_err());
+
+        assert_eq!(user.username(&pool).await?, Some("alice".to_string()));
+        assert!(!other.username_available("Alice", &pool).await?);
+        assert!(user.username_available("alice", &pool).await?);
+
+        assert_eq!(
+            user_id_from_password("alice", "correct horse", &pool).await?,
+            Some(user.id)
+        );
+        assert!(user_id_from_password("alice", "wrong horse", &pool)
+            .await?
+            .is_none());
+        assert!(user_id_from_password("bob", "correct horse", &pool)
+            .await?
+            .is_none());
+
+        user.remove_password(&pool).await?;
+        assert!(!user.has_credentials(&pool).await?);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn challenges_are_single_use(pool: SqlitePool) -> anyhow::Result<()> {
+        init_auth();
+        let user = User::create(&pool).await?;
+        let challenge = create_challenge(Some(&user), &pool).await?;
+        assert!(!take_challenge(&challenge, None, &pool).await?);
+        assert!(take_challenge(&challenge, Some(user.id), &pool).await?);
+        assert!(!take_challenge(&challenge, Some(user.id), &pool).await?);
+
+        let challenge = create_challenge(None, &pool).await?;
+        sqlx::query!("UPDATE webauthn_challenges SET created = created - 3600")
+            .execute(&pool)
+            .await?;
+        assert!(!take_challenge(&challenge, None, &pool).await?);
+
+        // unanswered challenges are deleted once they expire
+        create_challenge(None, &pool).await?;
+        sqlx::query!("UPDATE webauthn_challenges SET created = created - 3600")
+            .execute(&pool)
+            .await?;
+        create_challenge(None, &pool).await?;
+        let challenges = sqlx::query_scalar!("SELECT COUNT(*) FROM webauthn_challenges")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(challenges, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,342 @@
+//! Protection against cross-site request forgery
+//!
+//! Every browser gets a random nonce in the `csrf` cookie. Pages carry a keyed hash of it as
+//! token, see [csrf_token_for_nonce], in the headers of htmx requests and in a hidden field of
+//! every post form, see [crate::pages::base_template::BaseTemplate::csrf_field]. Requests which
+//! change state have to send the token back. A cross-site form can't, since it can neither read
+//! the cookie nor compute the hash.
+//!
+//! Api clients authenticating with a bearer token are exempt, browsers don't send those on
+//! their own.
+
+use axum::body::{Body, Bytes};
+use axum::extract::FromRequest;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Form;
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
+use hmac::Mac;
+use http::header::{AUTHORIZATION, CONTENT_TYPE};
+use http::{Method, Request};
+use maud::{html, Markup};
+use serde::Deserialize;
+use tower_cookies::Cookies;
+
+use crate::auth::{generate_secret, secret_mac, HmacSha256, LEGACY_SECRET_COOKIE};
+use crate::error::AppError;
+use crate::sessions::{secure_cookie, SESSION_COOKIE};
+
+pub const CSRF_COOKIE: &str = "csrf";
+/// Header htmx sends the token in
+pub const CSRF_HEADER: &str = "x-csrf-token";
+/// Name of the hidden form field with the token
+pub const CSRF_FIELD: &str = "csrf_token";
+
+#[derive(Deserialize)]
+struct CsrfForm {
+    csrf_token: Option<String>,
+}
+
+/// Token to put into pages, the nonce cookie is set if the browser has none yet
+pub fn csrf_token(cookies: &Cookies) -> String {
+    let nonce = match cookies.get(CSRF_COOKIE) {
+        Some(cookie) => cookie.value().to_string(),
+        None => {
+            let nonce = generate_secret();
+            cookies.add(secure_cookie(CSRF_COOKIE, nonce.clone()));
+            nonce
+        }
+    };
+    csrf_token_for_nonce(&nonce)
+}
+
+/// Secrets and session tokens are alphanumeric, so the prefix keeps tokens from ever being the
+/// stored hash of one of them
+fn token_mac(nonce: &str) -> HmacSha256 {
+    secret_mac(&format!("csrf:{nonce}"))
+}
+
+/// Token which belongs to the nonce of the `csrf` cookie
+pub fn csrf_token_for_nonce(nonce: &str) -> String {
+    STANDARD_NO_PAD.encode(token_mac(nonce).finalize().into_bytes())
+}
+
+/// Whether the token belongs to the nonce, compared in constant time
+fn token_matches(nonce: &str, token: &str) -> bool {
+    STANDARD_NO_PAD
+        .decode(token)
+        .is_ok_and(|token| token_mac(nonce).verify_slice(&token).is_ok())
+}
+
+/// Hidden field with the token, which every post form has to contain, since plain forms can't
+/// send the htmx header
+pub fn csrf_field(token: &str) -> Markup {
+    html! {
+        input type="hidden" name=(CSRF_FIELD) value=(token);
+    }
+}
+
+/// Whether the request can't be forged by another site, see module docs
+fn is_exempt<B>(request: &Request<B>, cookies: &Cookies) -> bool {
+    let safe_method = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    );
+    let bearer = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("Bearer "));
+    // without cookies, an api request acts for nobody
+    let cookie_free_api = request.uri().path().starts_with("/api/")
+        && cookies.get(SESSION_COOKIE).is_none()
+        && cookies.get(LEGACY_SECRET_COOKIE).is_none();
+    safe_method || bearer || cookie_free_api
+}
+
+/// Middleware rejecting state changing requests without a valid token, see module docs
+pub async fn verify_csrf(
+    cookies: Cookies,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, AppError> {
+    if is_exempt(&request, &cookies) {
+        return Ok(next.run(request).await);
+    }
+
+    let mut token = request
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let is_form = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+    let request = if token.is_none() && is_form {
+        // the body is read to find the field and then handed on unchanged
+        let (parts, body) = request.into_parts();
+        let bytes = match Bytes::from_request(Request::new(body), &()).await {
+            Ok(bytes) => bytes,
+            Err(rejection) => return Ok(rejection.into_response()),
+        };
+        let form_request = Request::post("/")
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(bytes.clone()))?;
+        if let Ok(Form(form)) = Form::<CsrfForm>::from_request(form_request, &()).await {
+            token = form.csrf_token;
+        }
+        Request::from_parts(parts, Body::from(bytes))
+    } else {
+        request
+    };
+
+    let valid = match (cookies.get(CSRF_COOKIE), token) {
+        (Some(nonce), Some(token)) => token_matches(nonce.value(), &token),
+        _ => false,
+    };
+    if !valid {
+        return Err(AppError::Forbidden(
+            "This form has expired, please reload the page and try again".to_string(),
+        ));
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::hash_secret;
+    use crate::devices::device_link_user_id;
+    use crate::structs::User;
+    use crate::testing::{body_text, full_app, init_auth};
+    use axum::routing::post;
+    use axum::{middleware, Router};
+    use http::header::COOKIE;
+    use http::StatusCode;
+    use sqlx::SqlitePool;
+    use tower::ServiceExt;
+    use tower_cookies::CookieManagerLayer;
+
+    fn app() -> Router {
+        Router::new()
+            .route(
+                "/vote",
+                post(|Form(form): Form<CsrfForm>| async move {
+                    // the form is still readable after the middleware looked at it
+                    form.csrf_token.unwrap_or_default()
+                }),
+            )
+            .route("/api/v1/user/create", post(|| async { "created" }))
+            .layer(middleware::from_fn(verify_csrf))
+            .layer(CookieManagerLayer::new())
+    }
+
+    fn post_form(uri: &str, cookie: Option<&str>, body: &str) -> Request<Body> {
+        let mut builder =
+            Request::post(uri).header(CONTENT_TYPE, "application/x-www-form-urlencoded");
+        if let Some(cookie) = cookie {
+            builder = builder.header(COOKIE, cookie);
+        }
+        builder.body(Body::from(body.to_string())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn cross_site_posts_are_rejected() {
+        init_auth();
+        let token = csrf_token_for_nonce("nonce");
+
+        // the other site knows neither cookie nor token
+        let response = app()
+            .oneshot(post_form("/vote", Some("session=abc"), "value=Yes"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // the browser sends the nonce cookie along, but the token is missing or wrong
+        let response = app()
+            .oneshot(post_form(
+                "/vote",
+                Some("session=abc; csrf=nonce"),
+                "value=Yes",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let other_token = csrf_token_for_nonce("other");
+        let body = format!("{CSRF_FIELD}={}", urlencode(&other_token));
+        let response = app()
+            .oneshot(post_form("/vote", Some("csrf=nonce"), &body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // nor is the stored hash of a secret or session token with the value of the nonce
+        let body = format!("{CSRF_FIELD}={}", urlencode(&hash_secret("nonce").1));
+        let response = app()
+            .oneshot(post_form("/vote", Some("csrf=nonce"), &body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // a token without the matching cookie is worthless
+        let response = app()
+            .oneshot(
+                Request::post("/vote")
+                    .header(CSRF_HEADER, &token)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // api requests with cookies are no exception
+        let response = app()
+            .oneshot(post_form("/api/v1/user/create", Some("session=abc"), ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn same_site_posts_are_accepted() {
+        init_auth();
+        let token = csrf_token_for_nonce("nonce");
+
+        let body = format!("value=Yes&{CSRF_FIELD}={}", urlencode(&token));
+        let response = app()
+            .oneshot(post_form("/vote", Some("session=abc; csrf=nonce"), &body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let echoed = Bytes::from_request(Request::new(response.into_body()), &())
+            .await
+            .unwrap();
+        assert_eq!(echoed, token.as_bytes());
+
+        let mut request = post_form("/vote", Some("session=abc; csrf=nonce"), "value=Yes");
+        request
+            .headers_mut()
+            .insert(CSRF_HEADER, token.parse().unwrap());
+        let response = app().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // api clients without cookies or with a bearer token
+        let response = app()
+            .oneshot(post_form("/api/v1/user/create", None, ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut request = post_form("/api/v1/user/create", Some("session=abc"), "");
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, "Bearer secret".parse().unwrap());
+        let response = app().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn forms_of_pages_carry_the_token(pool: SqlitePool) {
+        init_auth();
+        let token = csrf_token_for_nonce("nonce");
+        let request = Request::get("/login")
+            .header(COOKIE, "csrf=nonce")
+            .body(Body::empty())
+            .unwrap();
+        let response = full_app(&pool).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let page = body_text(response).await;
+        assert!(page.contains(&csrf_field(&token).into_string()));
+    }
+
+    #[sqlx::test]
+    async fn forged_votes_and_merges_are_rejected(pool: SqlitePool) -> anyhow::Result<()> {
+        init_auth();
+        let victim = User::create(&pool).await?;
+        let cookie = format!(
+            "{LEGACY_SECRET_COOKIE}={}; {CSRF_COOKIE}=nonce",
+            victim.known_secret()?
+        );
+        let statement_id = victim.add_statement("Is this forged?", &pool).await?;
+        let attacker = User::create(&pool).await?;
+        let link = attacker.create_device_link(&pool).await?;
+
+        let body = format!("statement_id={statement_id}&vote=Yes");
+        let response = full_app(&pool)
+            .oneshot(post_form("/statement/vote", Some(&cookie), &body))
+            .await?;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(victim.num_votes(&pool).await?, 0);
+
+        let response = full_app(&pool)
+            .oneshot(post_form(
+                &format!("/merge/{}", link.token),
+                Some(&cookie),
+                "value=Yes",
+            ))
+            .await?;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            device_link_user_id(&link.token, &pool).await?,
+            Some(attacker.id)
+        );
+
+        // the same vote from the site itself goes through
+        let body = format!(
+            "statement_id={statement_id}&vote=Yes&{CSRF_FIELD}={}",
+            urlencode(&csrf_token_for_nonce("nonce"))
+        );
+        let response = full_app(&pool)
+            .oneshot(post_form("/statement/vote", Some(&cookie), &body))
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(victim.num_votes(&pool).await?, 1);
+        Ok(())
+    }
+
+    fn urlencode(value: &str) -> String {
+        value.replace('+', "%2B").replace('/', "%2F")
+    }
+}
@@ -1,15 +1,23 @@
 //! Database access via sqlx
 
-use anyhow::Result;
-use sqlx::SqlitePool;
+use anyhow::{anyhow, Result};
+use propolis_datas::embedding::{cosine_similarity, vector_from_blob};
+use sqlx::{Sqlite, SqlitePool};
+use tracing::warn;
 
-use crate::structs::{StatementStats, TargetSegment, User, Vote};
-#[cfg(feature = "with_predictions")]
-use std::collections::HashMap;
+use crate::duplicates::detect_duplicates;
+use crate::prediction;
+
+use crate::selection::StatementSelectionStrategy;
+use crate::structs::{
+    AccountDeletion, RelationType, StatementRelation, StatementStats, TargetSegment, User, Vote,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 
 use crate::{
     highlight::{HIGHLIGHT_BEGIN, HIGHLIGHT_END},
-    structs::{SearchResultStatement, Statement, VoteHistoryItem},
+    structs::{SearchResultStatement, SimilarStatement, Statement, VoteHistoryItem},
 };
 
 #[cfg(feature = "with_predictions")]
@@ -43,41 +51,96 @@ impl User {
         .count)
     }
 
-    /// Moves content from one user to another
-    pub async fn move_content_to(&self, new_user: &User, pool: &SqlitePool) -> Result<()> {
-        for table in ["authors", "votes", "vote_history", "queue"] {
-            sqlx::query(format!("UPDATE {table} SET user_id=? WHERE user_id=?").as_str())
-                .bind(new_user.id)
-                .bind(self.id)
-                .execute(pool)
-                .await?;
-        }
+    /// Merges this user into `target` and deletes this user afterwards
+    ///
+    /// With `move_content`, votes, vote history, authorship, subscriptions and queue entries
+    /// move to `target`. If both voted on a statement, the newer vote wins. Without
+    /// `move_content`, everything of this user is deleted. The trigger on `user_merges` does
+    /// the work, see `tests/user_merges.rs`.
+    pub async fn merge_into(
+        &self,
+        target: &User,
+        move_content: bool,
+        executor: impl sqlx::Executor<'_, Database = Sqlite>,
+    ) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO user_merges (source_id, target_id, move_content) VALUES (?, ?, ?)",
+            self.id,
+            target.id,
+            move_content
+        )
+        .execute(executor)
+        .await?;
         Ok(())
     }
 
-    /// Deletes all content of a particular user
-    pub async fn delete_content(&self, pool: &SqlitePool) -> Result<()> {
-        for table in ["authors", "votes", "vote_history", "queue"] {
-            sqlx::query(format!("DELETE FROM {table} WHERE user_id=?").as_str())
-                .bind(self.id)
-                .execute(pool)
+    /// Deletes the account in one transaction, see [AccountDeletion]
+    pub async fn delete_account(&self, mode: AccountDeletion, pool: &SqlitePool) -> Result<()> {
+        let mut tx = pool.begin().await?;
+        match mode {
+            AccountDeletion::Anonymize => {
+                sqlx::query!("DELETE FROM authors WHERE user_id=?", self.id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query!("DELETE FROM subscriptions WHERE user_id=?", self.id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query!("DELETE FROM queue WHERE user_id=?", self.id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query!("DELETE FROM user_passwords WHERE user_id=?", self.id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query!("DELETE FROM user_passkeys WHERE user_id=?", self.id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query!("DELETE FROM webauthn_challenges WHERE user_id=?", self.id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query!("DELETE FROM linked_devices WHERE user_id=?", self.id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query!("DELETE FROM sessions WHERE user_id=?", self.id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query!("DELETE FROM device_link_tokens WHERE user_id=?", self.id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query!("DELETE FROM audit_log WHERE user_id=?", self.id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query!(
+                    "UPDATE statement_relations SET created_by = NULL WHERE created_by = ?",
+                    self.id
+                )
+                .execute(&mut *tx)
+                .await?;
+                // a unique marker, which is neither a secret nor the hash of one, so nothing matches it
+                sqlx::query!(
+                    "UPDATE users SET secret = 'deleted-' || id, secret_prefix = NULL,
+                    deleted = strftime('%s', 'now')
+                    WHERE id = ?",
+                    self.id
+                )
+                .execute(&mut *tx)
                 .await?;
+            }
+            AccountDeletion::Delete => {
+                // cascades to all content of the user
+                sqlx::query!("DELETE FROM users WHERE id=?", self.id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
         }
-        Ok(())
-    }
-
-    /// Deletes user without content
-    pub async fn delete(&self, pool: &SqlitePool) -> Result<()> {
-        sqlx::query!("DELETE FROM users WHERE id=?", self.id)
-            .execute(pool)
-            .await?;
+        tx.commit().await?;
         Ok(())
     }
 
     /// Votes on a statement
+    ///
+    /// Voting on a specialization adds its generalizations and their other specializations to
+    /// the queue, see trigger `vote_history_ai_relations`.
     pub async fn vote(&self, statement_id: i64, vote: Vote, pool: &SqlitePool) -> Result<()> {
-        // TODO: voting on specialization gets generalizations and other specializations into queue
-
         let vote_i32 = vote as i32;
         sqlx::query!(
             "INSERT INTO vote_history (user_id, statement_id, vote) VALUES (?, ?, ?)",
@@ -303,38 +366,23 @@ impl User {
         .execute(pool)
         .await?;
 
-        Ok(created_statement_id)
-    }
+        prediction::queue::enqueue(created_statement_id, 0, pool).await?;
 
-    // Retrieve next statement id for [User]
-    pub async fn next_statement_for_user(&self, pool: &SqlitePool) -> Result<Option<i64>> {
-        // try to pick a statement from the user's personal queue
-        let statement_id = self.next_statement_id_from_queue(pool).await?;
-
-        // if there is no statement in the queue, pick a random statement
-        Ok(match statement_id {
-            Some(statement_id) => Some(statement_id),
-            None => self.random_unvoted_statement_id(pool).await?,
-        })
-    }
+        // not embedded yet, so only keywords are compared
+        if let Err(err) = detect_duplicates(created_statement_id, None, pool).await {
+            warn!("duplicate detection failed: {err:?}");
+        }
 
-    /// Retrieve next statement id from [User] queue
-    pub async fn next_statement_id_from_queue(&self, pool: &SqlitePool) -> Result<Option<i64>> {
-        // TODO: sqlx bug: adding `order by timestamp` infers wrong type in macro
-        Ok(sqlx::query_scalar::<_, i64>(
-            "select statement_id from queue where user_id = ? order by created asc limit 1",
-        )
-        .bind(self.id)
-        .fetch_optional(pool)
-        .await?)
+        Ok(created_statement_id)
     }
 
-    pub async fn random_unvoted_statement_id(&self, pool: &SqlitePool) -> Result<Option<i64>> {
-        Ok(sqlx::query_scalar::<_, i64>(
-            "select id from statements where id not in (select statement_id from votes v where v.user_id = ?) order by random() limit 1")
-            .bind(self.id)
-            .fetch_optional(pool)
-            .await?)
+    /// Retrieve next statement id for [User], as chosen by the configured selection strategy
+    pub async fn next_statement_for_user(
+        &self,
+        selection: &dyn StatementSelectionStrategy,
+        pool: &SqlitePool,
+    ) -> Result<Option<i64>> {
+        selection.next_statement_id(self, pool).await
     }
 }
 
@@ -380,7 +428,9 @@ pub async fn statement_stats(statement_id: i64, pool: &SqlitePool) -> Result<Sta
         // TODO: sqlx bug: computed column types are wrong
         sqlx::query_as::<_, StatementStats>(
             "SELECT
-            yes_votes, no_votes, skip_votes, subscriptions, cast(total_votes as int) as total_votes, participation, polarization, votes_per_subscription
+            yes_votes, no_votes, skip_votes, itdepends_votes, subscriptions, cast(total_votes as int) as total_votes,
+            cast(participation as real) as participation, cast(polarization as real) as polarization,
+            cast(votes_per_subscription as real) as votes_per_subscription
             FROM statement_stats where statement_id = ?")
         .bind(statement_id)
         .fetch_one(pool)
@@ -412,6 +462,16 @@ pub async fn get_statement(statement_id: i64, pool: &SqlitePool) -> Result<State
     .await?)
 }
 
+pub async fn find_statement(statement_id: i64, pool: &SqlitePool) -> Result<Option<Statement>> {
+    Ok(sqlx::query_as!(
+        Statement,
+        "SELECT id, text from statements where id = ?",
+        statement_id,
+    )
+    .fetch_optional(pool)
+    .await?)
+}
+
 pub async fn search_statement(text: &str, pool: &SqlitePool) -> Result<Vec<SearchResultStatement>> {
     if text.is_empty() {
         return Ok(vec![]);
@@ -430,6 +490,252 @@ pub async fn search_statement(text: &str, pool: &SqlitePool) -> Result<Vec<Searc
     .await?)
 }
 
+/// Decoded embeddings of one database by statement id, so they are not read and decoded for
+/// every search
+#[derive(Default)]
+pub struct EmbeddingCache {
+    embeddings: Mutex<HashMap<i64, CachedEmbedding>>,
+}
+
+struct CachedEmbedding {
+    model: String,
+    /// Embeddings of a statement are only replaced by newer ones
+    created: i64,
+    vector: Vec<f32>,
+}
+
+/// Embedding cache shared by all searches, see [EmbeddingCache]
+pub type SharedEmbeddingCache = Arc<EmbeddingCache>;
+
+impl EmbeddingCache {
+    /// Returns the `k` statements whose embeddings are most similar to `query`, most similar first
+    ///
+    /// Compares against every stored embedding of `model`, the one `query` was made with, which
+    /// is fine for the amount of statements we have.
+    pub async fn nearest(
+        &self,
+        query: &[f32],
+        model: &str,
+        k: usize,
+        exclude_statement_id: Option<i64>,
+        pool: &SqlitePool,
+    ) -> Result<Vec<SimilarStatement>> {
+        // only the blobs of embeddings which are new since the last search are read
+        let stored = sqlx::query_as::<_, (i64, i64)>(
+            "select statement_id, created from statement_embeddings
+            where data is not null and model = ?",
+        )
+        .bind(model)
+        .fetch_all(pool)
+        .await?;
+        let missing: Vec<i64> = {
+            let embeddings = self.embeddings.lock().unwrap();
+            stored
+                .iter()
+                .filter(|(id, created)| {
+                    !embeddings
+                        .get(id)
+                        .is_some_and(|cached| cached.model == model && cached.created == *created)
+                })
+                .map(|(id, _)| *id)
+                .collect()
+        };
+        if !missing.is_empty() {
+            let rows = sqlx::query_as::<_, (i64, i64, Vec<u8>)>(
+                "select statement_id, created, data from statement_embeddings
+                where model = ? and statement_id in (select value from json_each(?))",
+            )
+            .bind(model)
+            .bind(serde_json::to_string(&missing)?)
+            .fetch_all(pool)
+            .await?;
+            let mut embeddings = self.embeddings.lock().unwrap();
+            for (statement_id, created, blob) in rows {
+                if let Some(vector) = vector_from_blob(&blob) {
+                    let model = model.to_string();
+                    embeddings.insert(
+                        statement_id,
+                        CachedEmbedding {
+                            model,
+                            created,
+                            vector,
+                        },
+                    );
+                }
+            }
+        }
+
+        let mut nearest: Vec<(i64, f64)> = {
+            let mut embeddings = self.embeddings.lock().unwrap();
+            // embeddings of deleted statements
+            let ids: HashSet<i64> = stored.iter().map(|(id, _)| *id).collect();
+            embeddings.retain(|id, cached| cached.model != model || ids.contains(id));
+            stored
+                .iter()
+                .filter(|(id, _)| Some(*id) != exclude_statement_id)
+                .filter_map(|(id, _)| {
+                    let cached = embeddings.get(id)?;
+                    Some((*id, cosine_similarity(query, &cached.vector)))
+                })
+                .collect()
+        };
+        nearest.sort_by(|a, b| b.1.total_cmp(&a.1));
+        nearest.truncate(k);
+
+        let ids: Vec<i64> = nearest.iter().map(|(id, _)| *id).collect();
+        let texts: HashMap<i64, String> = sqlx::query_as::<_, (i64, String)>(
+            "select id, text from statements where id in (select value from json_each(?))",
+        )
+        .bind(serde_json::to_string(&ids)?)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .collect();
+        Ok(nearest
+            .into_iter()
+            .filter_map(|(id, similarity)| {
+                Some(SimilarStatement {
+                    similarity,
+                    statement: Statement {
+                        id,
+                        text: texts.get(&id)?.to_owned(),
+                    },
+                })
+            })
+            .collect())
+    }
+}
+
+/// Returns the `k` statements most similar to the given one. Empty if it has no embedding yet.
+pub async fn similar_statements(
+    statement_id: i64,
+    k: usize,
+    embeddings: &EmbeddingCache,
+    pool: &SqlitePool,
+) -> Result<Vec<SimilarStatement>> {
+    let row = sqlx::query_as::<_, (Vec<u8>, String)>(
+        "select data, model from statement_embeddings where statement_id = ? and data is not null",
+    )
+    .bind(statement_id)
+    .fetch_optional(pool)
+    .await?;
+
+    match row.and_then(|(blob, model)| Some((vector_from_blob(&blob)?, model))) {
+        Some((query, model)) => {
+            embeddings
+                .nearest(&query, &model, k, Some(statement_id), pool)
+                .await
+        }
+        None => Ok(vec![]),
+    }
+}
+
+/// Adds a relation between two statements. Does nothing if it exists already, in either direction.
+pub async fn add_relation(
+    relation: StatementRelation,
+    created_by: i64,
+    pool: &SqlitePool,
+) -> Result<()> {
+    if relation.statement_id == relation.related_statement_id {
+        return Err(anyhow!("Cannot relate statement to itself"));
+    }
+    if relation_exists(relation, pool).await? {
+        return Ok(());
+    }
+    let relation_type = relation.relation_type as i64;
+    sqlx::query!(
+        "insert into statement_relations (statement_id, related_statement_id, relation_type, created_by)
+        values (?, ?, ?, ?)",
+        relation.statement_id,
+        relation.related_statement_id,
+        relation_type,
+        created_by
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Whether the relation is stored, in either direction
+pub async fn relation_exists(relation: StatementRelation, pool: &SqlitePool) -> Result<bool> {
+    let relation_type = relation.relation_type as i64;
+    let inverse_type = relation.relation_type.inverse() as i64;
+    Ok(sqlx::query_scalar!(
+        "select count(*) from statement_relations
+        where (statement_id = ? and related_statement_id = ? and relation_type = ?)
+           or (statement_id = ? and related_statement_id = ? and relation_type = ?)",
+        relation.statement_id,
+        relation.related_statement_id,
+        relation_type,
+        relation.related_statement_id,
+        relation.statement_id,
+        inverse_type
+    )
+    .fetch_one(pool)
+    .await?
+        > 0)
+}
+
+/// Relations of a statement, seen from that statement: `statement_id` is always the given one
+pub async fn get_relations(statement_id: i64, pool: &SqlitePool) -> Result<Vec<StatementRelation>> {
+    let rows = sqlx::query!(
+        "select statement_id, related_statement_id, relation_type from statement_relations
+        where statement_id = ? or related_statement_id = ?
+        order by created_at",
+        statement_id,
+        statement_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut relations = vec![];
+    for row in rows {
+        let relation_type = RelationType::from(row.relation_type)?;
+        relations.push(if row.statement_id == statement_id {
+            StatementRelation {
+                statement_id,
+                related_statement_id: row.related_statement_id,
+                relation_type,
+            }
+        } else {
+            StatementRelation {
+                statement_id,
+                related_statement_id: row.statement_id,
+                relation_type: relation_type.inverse(),
+            }
+        });
+    }
+    Ok(relations)
+}
+
+/// Removes a relation, in either direction, if it was created by `user_id`
+///
+/// Returns whether the relation was removed.
+pub async fn delete_relation(
+    relation: StatementRelation,
+    user_id: i64,
+    pool: &SqlitePool,
+) -> Result<bool> {
+    let relation_type = relation.relation_type as i64;
+    let inverse_type = relation.relation_type.inverse() as i64;
+    let result = sqlx::query!(
+        "delete from statement_relations
+        where ((statement_id = ? and related_statement_id = ? and relation_type = ?)
+            or (statement_id = ? and related_statement_id = ? and relation_type = ?))
+          and created_by = ?",
+        relation.statement_id,
+        relation.related_statement_id,
+        relation_type,
+        relation.related_statement_id,
+        relation.statement_id,
+        inverse_type,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
 pub async fn get_subscriptions(user: &User, pool: &SqlitePool) -> Result<Vec<Statement>> {
     // TODO: https://github.com/launchbadge/sqlx/issues/1524
     Ok(sqlx::query_as::<_, Statement>(
@@ -469,3 +775,211 @@ pub async fn get_followups(statement_id: i64, pool: &SqlitePool) -> Result<Vec<i
     .fetch_all(pool)
     .await?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::hash_legacy_secrets;
+    use crate::testing::init_auth;
+
+    #[sqlx::test]
+    async fn similar_statements_ordered_by_similarity(pool: SqlitePool) -> anyhow::Result<()> {
+        sqlx::query!("insert into api_keys(id, hash) values (1, 'hash')")
+            .execute(&pool)
+            .await?;
+        // vectors as sqlite-vector blobs: [1, 0], [0, 1], [1, 0.1], [-1, 0] and [1, 0] of another
+        // model, which can't be compared
+        for (id, data, model) in [
+            (1, "76010000803f00000000", "model"),
+            (2, "7601000000000000803f", "model"),
+            (3, "76010000803fcdcccc3d", "model"),
+            (4, "7601000080bf00000000", "model"),
+            (6, "76010000803f00000000", "other"),
+        ] {
+            sqlx::query!("insert into statements(id, text) values (?, 'text')", id)
+                .execute(&pool)
+                .await?;
+            sqlx::query(
+                "insert into statement_embeddings(statement_id, data, prompt_tokens, api_key_id, model)
+                values (?, unhex(?), 0, 1, ?)",
+            )
+            .bind(id)
+            .bind(data)
+            .bind(model)
+            .execute(&pool)
+            .await?;
+        }
+        sqlx::query!("insert into statements(id, text) values (5, 'not embedded')")
+            .execute(&pool)
+            .await?;
+
+        let embeddings = EmbeddingCache::default();
+        let similar: Vec<i64> = similar_statements(1, 10, &embeddings, &pool)
+            .await?
+            .iter()
+            .map(|s| s.statement.id)
+            .collect();
+        assert_eq!(similar, vec![3, 2, 4]);
+
+        assert_eq!(similar_statements(1, 1, &embeddings, &pool).await?.len(), 1);
+        assert!(similar_statements(5, 10, &embeddings, &pool)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn cached_embeddings_are_refreshed(pool: SqlitePool) -> anyhow::Result<()> {
+        sqlx::query!("insert into api_keys(id, hash) values (1, 'hash')")
+            .execute(&pool)
+            .await?;
+        let embed = |id: i64, data: &'static str, created: i64| {
+            sqlx::query(
+                "insert or replace into statement_embeddings
+                (statement_id, data, prompt_tokens, api_key_id, model, created)
+                values (?, unhex(?), 0, 1, 'model', ?)",
+            )
+            .bind(id)
+            .bind(data)
+            .bind(created)
+        };
+        for id in 1..=3 {
+            sqlx::query!("insert into statements(id, text) values (?, 'text')", id)
+                .execute(&pool)
+                .await?;
+        }
+        // [1, 0] and [0, 1]
+        embed(1, "76010000803f00000000", 1).execute(&pool).await?;
+        embed(2, "7601000000000000803f", 1).execute(&pool).await?;
+
+        let cache = EmbeddingCache::default();
+        async fn nearest(cache: &EmbeddingCache, pool: &SqlitePool) -> anyhow::Result<Vec<i64>> {
+            let nearest = cache.nearest(&[1.0, 0.0], "model", 10, None, pool).await?;
+            Ok(nearest.iter().map(|s| s.statement.id).collect())
+        }
+        assert_eq!(nearest(&cache, &pool).await?, vec![1, 2]);
+
+        // replaced and new embeddings are read again, those of deleted statements are dropped
+        embed(1, "7601000080bf00000000", 2).execute(&pool).await?;
+        embed(3, "76010000803fcdcccc3d", 2).execute(&pool).await?;
+        sqlx::query!("delete from statements where id = 2")
+            .execute(&pool)
+            .await?;
+        assert_eq!(nearest(&cache, &pool).await?, vec![3, 1]);
+        assert_eq!(cache.embeddings.lock().unwrap().len(), 2);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn delete_account_anonymize_or_delete(pool: SqlitePool) -> anyhow::Result<()> {
+        init_auth();
+        sqlx::query!("insert into users(id, secret) values (1, 'abc'), (2, 'def')")
+            .execute(&pool)
+            .await?;
+        hash_legacy_secrets(&pool).await?;
+        let users = [
+            User {
+                id: 1,
+                secret: Some("abc".to_string()),
+            },
+            User {
+                id: 2,
+                secret: Some("def".to_string()),
+            },
+        ];
+        let statement_id = users[0].add_statement("Is the world flat?", &pool).await?;
+        for user in &users {
+            user.vote(statement_id, Vote::Yes, &pool).await?;
+        }
+
+        sqlx::query!("insert into webauthn_challenges(challenge, user_id) values ('challenge', 1)")
+            .execute(&pool)
+            .await?;
+
+        assert!(User::from_secret("abc", &pool).await?.is_some());
+        users[0]
+            .delete_account(AccountDeletion::Anonymize, &pool)
+            .await?;
+        assert!(User::from_secret("abc", &pool).await?.is_none());
+        // restarts don't mistake the tombstone for a legacy secret
+        hash_legacy_secrets(&pool).await?;
+        let tombstone = sqlx::query!("select secret, secret_prefix from users where id = 1")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(tombstone.secret, "deleted-1");
+        assert_eq!(tombstone.secret_prefix, None);
+        assert_eq!(
+            sqlx::query_scalar!("select count(*) from webauthn_challenges")
+                .fetch_one(&pool)
+                .await?,
+            0
+        );
+        assert_eq!(statement_stats(statement_id, &pool).await?.yes_votes, 2);
+        assert_eq!(users[0].num_statements(&pool).await?, 0);
+        assert_eq!(
+            sqlx::query_scalar!("select count(*) from subscriptions where user_id = 1")
+                .fetch_one(&pool)
+                .await?,
+            0
+        );
+
+        users[1]
+            .delete_account(AccountDeletion::Delete, &pool)
+            .await?;
+        assert!(User::from_secret("def", &pool).await?.is_none());
+        assert_eq!(statement_stats(statement_id, &pool).await?.yes_votes, 1);
+        assert!(find_statement(statement_id, &pool).await?.is_some());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn relations_seen_from_both_statements(pool: SqlitePool) -> anyhow::Result<()> {
+        init_auth();
+        for id in [1, 2] {
+            sqlx::query!("insert into statements(id, text) values (?, 'text')", id)
+                .execute(&pool)
+                .await?;
+        }
+        let creator = User::create(&pool).await?;
+        let other = User::create(&pool).await?;
+        let relation = StatementRelation {
+            statement_id: 2,
+            related_statement_id: 1,
+            relation_type: RelationType::Specialization,
+        };
+        add_relation(relation, creator.id, &pool).await?;
+        // the same relation from the other side is not stored twice
+        add_relation(
+            StatementRelation {
+                statement_id: 1,
+                related_statement_id: 2,
+                relation_type: RelationType::Generalization,
+            },
+            other.id,
+            &pool,
+        )
+        .await?;
+
+        assert_eq!(get_relations(2, &pool).await?, vec![relation]);
+        assert_eq!(
+            get_relations(1, &pool).await?,
+            vec![StatementRelation {
+                statement_id: 1,
+                related_statement_id: 2,
+                relation_type: RelationType::Generalization,
+            }]
+        );
+
+        // only its creator may remove it
+        let inverse = StatementRelation {
+            statement_id: 1,
+            related_statement_id: 2,
+            relation_type: RelationType::Generalization,
+        };
+        assert!(!delete_relation(inverse, other.id, &pool).await?);
+        assert_eq!(get_relations(2, &pool).await?, vec![relation]);
+        assert!(delete_relation(inverse, creator.id, &pool).await?);
+        assert!(get_relations(2, &pool).await?.is_empty());
+        Ok(())
+    }
+}
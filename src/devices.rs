@@ -0,0 +1,312 @@
+//! Devices linked to an account and the audit log of an account
+//!
+//! A device is linked with a one-time link, which expires after [DEVICE_LINK_SECONDS], or by
+//! logging in with a password or passkey. Every linked device gets a secret of its own, so that
+//! it can be revoked without affecting the others.
+
+use anyhow::Result;
+use chrono::Utc;
+use http::header::USER_AGENT;
+use http::HeaderMap;
+use sqlx::{Sqlite, SqlitePool};
+
+use crate::auth::{generate_secret, generate_unique_secret, hash_secret};
+use crate::structs::User;
+
+/// Seconds a device link can be used
+pub const DEVICE_LINK_SECONDS: i64 = 15 * 60;
+/// Amount of audit log entries shown on the options page
+pub const RECENT_AUDIT_ENTRIES: i64 = 20;
+const DEVICE_NAME_MAX_LEN: usize = 100;
+/// last_used of a device is updated at most this often, to avoid a write on every request
+const LAST_USED_RESOLUTION_SECONDS: i64 = 60 * 60;
+
+/// Security relevant events of an account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    DeviceLinked,
+    DeviceRevoked,
+    SecretRotated,
+    LoggedIn,
+    SessionRevoked,
+}
+
+impl AuditEvent {
+    const ALL: [AuditEvent; 5] = [
+        AuditEvent::DeviceLinked,
+        AuditEvent::DeviceRevoked,
+        AuditEvent::SecretRotated,
+        AuditEvent::LoggedIn,
+        AuditEvent::SessionRevoked,
+    ];
+
+    /// Name stored in the database
+    pub fn name(self) -> &'static str {
+        match self {
+            AuditEvent::DeviceLinked => "device_linked",
+            AuditEvent::DeviceRevoked => "device_revoked",
+            AuditEvent::SecretRotated => "secret_rotated",
+            AuditEvent::LoggedIn => "logged_in",
+            AuditEvent::SessionRevoked => "session_revoked",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|event| event.name() == name)
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            AuditEvent::DeviceLinked => "Device linked",
+            AuditEvent::DeviceRevoked => "Device revoked",
+            AuditEvent::SecretRotated => "Secret rotated",
+            AuditEvent::LoggedIn => "Logged in",
+            AuditEvent::SessionRevoked => "Session revoked",
+        }
+    }
+}
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct AuditEntry {
+    pub event: String,
+    pub detail: Option<String>,
+    pub created: i64,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct LinkedDevice {
+    pub id: i64,
+    pub name: String,
+    pub created: i64,
+    pub last_used: Option<i64>,
+}
+
+/// One-time link to log another device into the account
+pub struct DeviceLink {
+    pub token: String,
+    pub expires: i64,
+}
+
+impl User {
+    /// Creates a one-time device link, expired links are cleaned up on the way
+    pub async fn create_device_link(&self, pool: &SqlitePool) -> Result<DeviceLink> {
+        let now = Utc::now().timestamp();
+        sqlx::query!("DELETE FROM device_link_tokens WHERE expires <= ?", now)
+            .execute(pool)
+            .await?;
+
+        let token = generate_secret();
+        let (_, token_hash) = hash_secret(&token);
+        let expires = now + DEVICE_LINK_SECONDS;
+        sqlx::query!(
+            "INSERT INTO device_link_tokens (token_hash, user_id, expires) VALUES (?, ?, ?)",
+            token_hash,
+            self.id,
+            expires
+        )
+        .execute(pool)
+        .await?;
+        Ok(DeviceLink { token, expires })
+    }
+
+    pub async fn linked_devices(&self, pool: &SqlitePool) -> Result<Vec<LinkedDevice>> {
+        Ok(sqlx::query_as!(
+            LinkedDevice,
+            "SELECT id, name, created, last_used FROM linked_devices
+            WHERE user_id = ? ORDER BY created",
+            self.id
+        )
+        .fetch_all(pool)
+        .await?)
+    }
+
+    /// Logs a linked device out, its secret stops working
+    pub async fn revoke_device(&self, device_id: i64, pool: &SqlitePool) -> Result<()> {
+        let mut tx = pool.begin().await?;
+        let name = sqlx::query_scalar!(
+            "DELETE FROM linked_devices WHERE id = ? AND user_id = ? RETURNING name",
+            device_id,
+            self.id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(name) = name {
+            add_audit_entry(self.id, AuditEvent::DeviceRevoked, Some(&name), &mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn audit_log(&self, limit: i64, pool: &SqlitePool) -> Result<Vec<AuditEntry>> {
+        Ok(sqlx::query_as!(
+            AuditEntry,
+            "SELECT event, detail, created FROM audit_log
+            WHERE user_id = ? ORDER BY created DESC, id DESC LIMIT ?",
+            self.id,
+            limit
+        )
+        .fetch_all(pool)
+        .await?)
+    }
+}
+
+/// Id of the account a valid device link belongs to, without using the link up
+pub async fn device_link_user_id(token: &str, pool: &SqlitePool) -> Result<Option<i64>> {
+    let (_, token_hash) = hash_secret(token);
+    let now = Utc::now().timestamp();
+    Ok(sqlx::query_scalar!(
+        "SELECT user_id FROM device_link_tokens WHERE token_hash = ? AND expires > ?",
+        token_hash,
+        now
+    )
+    .fetch_optional(pool)
+    .await?)
+}
+
+/// Uses up a device link and returns the id of its account, or None if the link is invalid or
+/// expired. The caller logs the device in, see [link_device] and [crate::sessions::log_in].
+pub async fn redeem_device_link(
+    token: &str,
+    executor: impl sqlx::Executor<'_, Database = Sqlite>,
+) -> Result<Option<i64>> {
+    let (_, token_hash) = hash_secret(token);
+    let now = Utc::now().timestamp();
+    Ok(sqlx::query_scalar!(
+        "DELETE FROM device_link_tokens WHERE token_hash = ? AND expires > ? RETURNING user_id",
+        token_hash,
+        now
+    )
+    .fetch_optional(executor)
+    .await?)
+}
+
+/// Gives a device its own secret for the account, used by api clients
+///
+/// `method` describes how the device was linked and ends up in the audit log.
+pub async fn link_device(
+    user_id: i64,
+    device_name: &str,
+    method: &str,
+    pool: &SqlitePool,
+) -> Result<User> {
+    let secret = generate_unique_secret(pool).await?;
+    let (prefix, hash) = hash_secret(&secret);
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "INSERT INTO linked_devices (user_id, secret_prefix, secret_hash, name) VALUES (?, ?, ?, ?)",
+        user_id,
+        prefix,
+        hash,
+        device_name
+    )
+    .execute(&mut *tx)
+    .await?;
+    let detail = format!("{device_name} via {method}");
+    add_audit_entry(user_id, AuditEvent::DeviceLinked, Some(&detail), &mut *tx).await?;
+    tx.commit().await?;
+    Ok(User {
+        id: user_id,
+        secret: Some(secret),
+    })
+}
+
+/// Updates when a linked device was last used
+pub async fn touch_device(device_id: i64, pool: &SqlitePool) -> Result<()> {
+    let now = Utc::now().timestamp();
+    let outdated = now - LAST_USED_RESOLUTION_SECONDS;
+    sqlx::query!(
+        "UPDATE linked_devices SET last_used = ? WHERE id = ? AND (last_used IS NULL OR last_used < ?)",
+        now,
+        device_id,
+        outdated
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn add_audit_entry(
+    user_id: i64,
+    event: AuditEvent,
+    detail: Option<&str>,
+    executor: impl sqlx::Executor<'_, Database = Sqlite>,
+) -> Result<()> {
+    let event = event.name();
+    sqlx::query!(
+        "INSERT INTO audit_log (user_id, event, detail) VALUES (?, ?, ?)",
+        user_id,
+        event,
+        detail
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Name of the requesting device, taken from its user agent
+pub fn device_name(headers: &HeaderMap) -> String {
+    let user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("Unknown device");
+    user_agent.chars().take(DEVICE_NAME_MAX_LEN).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::init_auth;
+
+    #[sqlx::test]
+    async fn device_links_are_single_use(pool: SqlitePool) -> anyhow::Result<()> {
+        init_auth();
+        let user = User::create(&pool).await?;
+        let link = user.create_device_link(&pool).await?;
+        assert_eq!(
+            device_link_user_id(&link.token, &pool).await?,
+            Some(user.id)
+        );
+
+        assert_eq!(redeem_device_link(&link.token, &pool).await?, Some(user.id));
+        assert!(redeem_device_link(&link.token, &pool).await?.is_none());
+
+        let device = link_device(user.id, "Phone", "link", &pool).await?;
+        let device_secret = device.known_secret()?;
+        assert_ne!(device.secret, user.secret);
+        assert_eq!(
+            User::from_secret(device_secret, &pool).await?.map(|u| u.id),
+            Some(user.id)
+        );
+        assert_eq!(user.audit_log(10, &pool).await?.len(), 1);
+
+        let devices = user.linked_devices(&pool).await?;
+        assert_eq!(devices.len(), 1);
+        user.revoke_device(devices[0].id, &pool).await?;
+        assert!(User::from_secret(device_secret, &pool).await?.is_none());
+        assert_eq!(
+            user.audit_log(10, &pool)
+                .await?
+                .iter()
+                .map(|entry| AuditEvent::from_name(&entry.event))
+                .collect::<Vec<_>>(),
+            vec![
+                Some(AuditEvent::DeviceRevoked),
+                Some(AuditEvent::DeviceLinked)
+            ]
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn expired_device_links_are_rejected(pool: SqlitePool) -> anyhow::Result<()> {
+        init_auth();
+        let user = User::create(&pool).await?;
+        let link = user.create_device_link(&pool).await?;
+        sqlx::query!("UPDATE device_link_tokens SET expires = expires - 3600")
+            .execute(&pool)
+            .await?;
+        assert!(device_link_user_id(&link.token, &pool).await?.is_none());
+        assert!(redeem_device_link(&link.token, &pool).await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,445 @@
+//! Detection and merging of near-duplicate statements
+
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::db::{find_statement, similar_statements, EmbeddingCache};
+
+/// Keyword overlap (jaccard index of the words) above which statements are flagged
+const MIN_KEYWORD_SIMILARITY: f64 = 0.8;
+/// Embedding cosine similarity above which statements are flagged
+const MIN_EMBEDDING_SIMILARITY: f64 = 0.95;
+/// Number of search results / nearest neighbors checked per statement
+const DUPLICATE_CANDIDATES: usize = 20;
+
+/// How a duplicate was detected
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DetectionMethod {
+    Keywords,
+    Embedding,
+}
+
+impl DetectionMethod {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DetectionMethod::Keywords => "keywords",
+            DetectionMethod::Embedding => "embedding",
+        }
+    }
+}
+
+/// Pair of statements which are probably the same
+#[derive(Serialize, sqlx::FromRow, Clone, Debug)]
+pub struct DuplicateCandidate {
+    /// The older statement, which is kept when merging
+    pub statement_id: i64,
+    pub duplicate_id: i64,
+    /// 'keywords' or 'embedding'
+    pub method: String,
+    pub similarity: f64,
+}
+
+/// Lowercased alphanumeric words of a statement
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// Jaccard index of the words of both texts
+fn keyword_similarity(a: &str, b: &str) -> f64 {
+    let (a, b) = (words(a), words(b));
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    a.intersection(&b).count() as f64 / union as f64
+}
+
+/// Finds statements which are near-duplicates of the given one and flags them in
+/// `duplicate_candidates`. Returns the number of flagged pairs.
+///
+/// Uses the full text search index and, with `embeddings` once the statement has been embedded,
+/// embedding similarity.
+pub async fn detect_duplicates(
+    statement_id: i64,
+    embeddings: Option<&EmbeddingCache>,
+    pool: &SqlitePool,
+) -> Result<usize> {
+    let Some(statement) = find_statement(statement_id, pool).await? else {
+        return Ok(0);
+    };
+
+    let mut found: Vec<(i64, DetectionMethod, f64)> = vec![];
+
+    let words = words(&statement.text);
+    if !words.is_empty() {
+        // quoted words joined by OR, so that punctuation can't break the fts query syntax
+        let fts_query = words
+            .iter()
+            .map(|word| format!("\"{word}\""))
+            .collect::<Vec<_>>()
+            .join(" OR ");
+        let matches = sqlx::query_as::<_, (i64, String)>(
+            "select id, text from statements_fts where text match ? and id != ? order by rank limit ?",
+        )
+        .bind(fts_query)
+        .bind(statement_id)
+        .bind(DUPLICATE_CANDIDATES as i64)
+        .fetch_all(pool)
+        .await?;
+        for (id, text) in matches {
+            let similarity = keyword_similarity(&statement.text, &text);
+            if similarity >= MIN_KEYWORD_SIMILARITY {
+                found.push((id, DetectionMethod::Keywords, similarity));
+            }
+        }
+    }
+
+    if let Some(embeddings) = embeddings {
+        for similar in
+            similar_statements(statement_id, DUPLICATE_CANDIDATES, embeddings, pool).await?
+        {
+            if similar.similarity >= MIN_EMBEDDING_SIMILARITY {
+                found.push((
+                    similar.statement.id,
+                    DetectionMethod::Embedding,
+                    similar.similarity,
+                ));
+            }
+        }
+    }
+
+    for (other_id, method, similarity) in &found {
+        let (older_id, newer_id) = if *other_id < statement_id {
+            (*other_id, statement_id)
+        } else {
+            (statement_id, *other_id)
+        };
+        let method = method.as_str();
+        sqlx::query!(
+            "insert into duplicate_candidates (statement_id, duplicate_id, method, similarity)
+            values (?, ?, ?, ?)
+            on conflict (statement_id, duplicate_id) do update
+            set method = excluded.method, similarity = excluded.similarity
+            where excluded.similarity > similarity",
+            older_id,
+            newer_id,
+            method,
+            similarity
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(found.len())
+}
+
+/// All flagged pairs, most similar first
+pub async fn duplicate_candidates(pool: &SqlitePool) -> Result<Vec<DuplicateCandidate>> {
+    Ok(sqlx::query_as::<_, DuplicateCandidate>(
+        "select statement_id, duplicate_id, method, similarity from duplicate_candidates
+        order by similarity desc",
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Statement which the given, merged statement was merged into
+pub async fn statement_redirect(statement_id: i64, pool: &SqlitePool) -> Result<Option<i64>> {
+    Ok(sqlx::query_scalar!(
+        "select canonical_id from statement_redirects where statement_id = ?",
+        statement_id
+    )
+    .fetch_optional(pool)
+    .await?)
+}
+
+/// Merges `duplicate_id` into `canonical_id` and deletes the duplicate
+///
+/// Votes, vote history, subscriptions, follow-ups, authors and queue entries are moved to the
+/// canonical statement. If a user voted on both, the vote on the canonical statement is kept.
+/// The old id is redirected to the canonical statement afterwards.
+pub async fn merge_statements(
+    duplicate_id: i64,
+    canonical_id: i64,
+    pool: &SqlitePool,
+) -> Result<()> {
+    if duplicate_id == canonical_id {
+        return Err(anyhow!("Cannot merge statement {duplicate_id} with itself"));
+    }
+    let mut tx = pool.begin().await?;
+
+    for id in [duplicate_id, canonical_id] {
+        sqlx::query!("select id from statements where id = ?", id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(anyhow!("Statement {id} does not exist"))?;
+    }
+
+    sqlx::query!(
+        "insert into votes (statement_id, user_id, vote)
+        select ?, user_id, vote from votes where statement_id = ?
+        on conflict (statement_id, user_id) do nothing",
+        canonical_id,
+        duplicate_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // updating does not fire the insert triggers, which would overwrite the current votes
+    sqlx::query!(
+        "update vote_history set statement_id = ? where statement_id = ?",
+        canonical_id,
+        duplicate_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "insert into subscriptions (user_id, statement_id, created)
+        select user_id, ?, created from subscriptions where statement_id = ?",
+        canonical_id,
+        duplicate_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "insert or ignore into authors (user_id, statement_id, created)
+        select user_id, ?, created from authors where statement_id = ?",
+        canonical_id,
+        duplicate_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "insert into followups (statement_id, followup_id, target_yes, target_no)
+        select ?, followup_id, target_yes, target_no from followups
+        where statement_id = ? and followup_id != ?
+        on conflict (statement_id, followup_id) do update
+        set target_yes = max(target_yes, excluded.target_yes),
+            target_no  = max(target_no,  excluded.target_no)",
+        canonical_id,
+        duplicate_id,
+        canonical_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "insert into followups (statement_id, followup_id, target_yes, target_no)
+        select statement_id, ?, target_yes, target_no from followups
+        where followup_id = ? and statement_id != ?
+        on conflict (statement_id, followup_id) do update
+        set target_yes = max(target_yes, excluded.target_yes),
+            target_no  = max(target_no,  excluded.target_no)",
+        canonical_id,
+        duplicate_id,
+        canonical_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "insert into queue (user_id, statement_id, created)
+        select user_id, ?, created from queue where statement_id = ?",
+        canonical_id,
+        duplicate_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // the follow-up triggers may have queued the canonical statement for users who voted on it
+    sqlx::query!(
+        "delete from queue where statement_id = ?
+        and user_id in (select user_id from votes where statement_id = ?)",
+        canonical_id,
+        canonical_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // statement_relations has no cascading foreign keys
+    sqlx::query!(
+        "update or ignore statement_relations set statement_id = ?
+        where statement_id = ? and related_statement_id != ?",
+        canonical_id,
+        duplicate_id,
+        canonical_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "update or ignore statement_relations set related_statement_id = ?
+        where related_statement_id = ? and statement_id != ?",
+        canonical_id,
+        duplicate_id,
+        canonical_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "delete from statement_relations where statement_id = ? or related_statement_id = ?",
+        duplicate_id,
+        duplicate_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "update statement_redirects set canonical_id = ? where canonical_id = ?",
+        canonical_id,
+        duplicate_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "insert into statement_redirects (statement_id, canonical_id) values (?, ?)",
+        duplicate_id,
+        canonical_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // cascades to everything that was not moved
+    sqlx::query!("delete from statements where id = ?", duplicate_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyword_similarity() {
+        assert_eq!(
+            keyword_similarity("Is the world flat?", "is the World flat"),
+            1.0
+        );
+        assert_eq!(keyword_similarity("a b", "c d"), 0.0);
+        assert_eq!(keyword_similarity("?", "!"), 0.0);
+    }
+
+    #[sqlx::test]
+    async fn detects_keyword_duplicates(pool: SqlitePool) -> anyhow::Result<()> {
+        for (id, text) in [
+            (1, "Is the world flat?"),
+            (2, "Is the world round?"),
+            (3, "Is the world flat"),
+        ] {
+            sqlx::query!("insert into statements(id, text) values (?, ?)", id, text)
+                .execute(&pool)
+                .await?;
+        }
+
+        assert_eq!(detect_duplicates(3, None, &pool).await?, 1);
+        let candidates = duplicate_candidates(&pool).await?;
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(
+            (candidates[0].statement_id, candidates[0].duplicate_id),
+            (1, 3)
+        );
+        assert_eq!(candidates[0].method, "keywords");
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn merge_moves_everything_to_canonical(pool: SqlitePool) -> anyhow::Result<()> {
+        for id in [1, 2, 3] {
+            sqlx::query!("insert into users(id, secret) values (?, ?)", id, id)
+                .execute(&pool)
+                .await?;
+        }
+        for (id, text) in [(1, "canonical"), (2, "duplicate"), (3, "follow-up")] {
+            sqlx::query!("insert into statements(id, text) values (?, ?)", id, text)
+                .execute(&pool)
+                .await?;
+        }
+        sqlx::query!("insert into authors(user_id, statement_id) values (1, 1), (2, 2)")
+            .execute(&pool)
+            .await?;
+        sqlx::query!(
+            "insert into followups(statement_id, followup_id, target_yes) values (2, 3, 1)"
+        )
+        .execute(&pool)
+        .await?;
+        // user 1 voted on both, user 2 only on the duplicate
+        sqlx::query!(
+            "insert into vote_history(user_id, statement_id, vote) values (1, 1, 1), (1, 2, -1), (2, 2, 1)"
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query!("insert into subscriptions(user_id, statement_id) values (3, 2)")
+            .execute(&pool)
+            .await?;
+        sqlx::query!("delete from queue").execute(&pool).await?;
+        sqlx::query!("insert into queue(user_id, statement_id) values (3, 2)")
+            .execute(&pool)
+            .await?;
+
+        merge_statements(2, 1, &pool).await?;
+
+        let votes =
+            sqlx::query!("select user_id, vote from votes where statement_id = 1 order by user_id")
+                .fetch_all(&pool)
+                .await?;
+        assert_eq!(
+            votes
+                .iter()
+                .map(|v| (v.user_id, v.vote))
+                .collect::<Vec<_>>(),
+            vec![(1, 1), (2, 1)]
+        );
+        let history =
+            sqlx::query_scalar!("select count(*) from vote_history where statement_id = 1")
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(history, 3);
+        let authors = sqlx::query_scalar!(
+            "select user_id from authors where statement_id = 1 order by user_id"
+        )
+        .fetch_all(&pool)
+        .await?;
+        assert_eq!(authors, vec![1, 2]);
+        let followups =
+            sqlx::query_scalar!("select followup_id from followups where statement_id = 1")
+                .fetch_all(&pool)
+                .await?;
+        assert_eq!(followups, vec![3]);
+        let subscribers =
+            sqlx::query_scalar!("select user_id from subscriptions where statement_id = 1")
+                .fetch_all(&pool)
+                .await?;
+        assert_eq!(subscribers, vec![3]);
+        let queued = sqlx::query_scalar!("select user_id from queue where statement_id = 1")
+            .fetch_all(&pool)
+            .await?;
+        assert_eq!(queued, vec![3]);
+
+        assert!(find_statement(2, &pool).await?.is_none());
+        assert_eq!(statement_redirect(2, &pool).await?, Some(1));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn merge_fails_for_missing_statement(pool: SqlitePool) -> anyhow::Result<()> {
+        sqlx::query!("insert into statements(id, text) values (1, 'text')")
+            .execute(&pool)
+            .await?;
+        assert!(merge_statements(2, 1, &pool).await.is_err());
+        assert!(merge_statements(1, 1, &pool).await.is_err());
+        assert!(find_statement(1, &pool).await?.is_some());
+        Ok(())
+    }
+}
@@ -1,21 +1,92 @@
 //! Error handling related code
 
-use axum::response::IntoResponse;
-use http::StatusCode;
+use std::time::Duration;
 
-// Make our own error that wraps `anyhow::Error`.
-pub struct AppError(pub anyhow::Error);
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use http::header::RETRY_AFTER;
+use http::{HeaderMap, Request, StatusCode};
+use maud::html;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tower_cookies::Cookies;
+
+use crate::csrf::csrf_token;
+use crate::pages::base_template::BaseTemplate;
+use crate::structs::User;
+
+/// Errors that can be returned by any handler
+///
+/// Every variant maps to a http status code. Internal errors are logged, but their message is
+/// never shown to the user, since it may contain e.g. database details.
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    Unauthorized,
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    TooManyRequests { retry_after: Duration },
+    Internal(anyhow::Error),
+}
+
+/// JSON body of every error response of the api
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiErrorBody {
+    pub error: String,
+}
+
+/// Attached to error responses, so that [render_errors] can render them for the client
+#[derive(Clone)]
+struct ErrorMessage(String);
+
+impl AppError {
+    pub fn not_found(what: &str) -> Self {
+        Self::NotFound(format!("{what} not found"))
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Message that is safe to show to the user
+    pub fn message(&self) -> String {
+        match self {
+            AppError::BadRequest(msg)
+            | AppError::Forbidden(msg)
+            | AppError::NotFound(msg)
+            | AppError::Conflict(msg) => msg.to_owned(),
+            AppError::Unauthorized => "Unauthorized".to_string(),
+            AppError::TooManyRequests { .. } => "Too many requests".to_string(),
+            AppError::Internal(_) => "Something went wrong".to_string(),
+        }
+    }
+}
 
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
-    fn into_response(self) -> axum::response::Response {
-        let msg = self.0.to_string();
-        tracing::error!("{msg}");
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {msg}"),
-        )
-            .into_response()
+    fn into_response(self) -> Response {
+        if let AppError::Internal(err) = &self {
+            tracing::error!("{err:?}");
+        }
+        let message = self.message();
+        let mut response = (self.status(), message.to_owned()).into_response();
+        if let AppError::TooManyRequests { retry_after } = self {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, retry_after.as_secs().max(1).into());
+        }
+        response.extensions_mut().insert(ErrorMessage(message));
+        response
     }
 }
 
@@ -26,6 +97,111 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        Self::Internal(err.into())
+    }
+}
+
+/// Middleware rendering [AppError] responses: JSON for the api, html pages for everything else
+pub async fn render_errors<B>(request: Request<B>, next: Next<B>) -> Response {
+    let is_api = request.uri().path().starts_with("/api/");
+    let headers = request.headers().to_owned();
+    let cookies = request.extensions().get::<Cookies>().cloned();
+    let pool = request.extensions().get::<SqlitePool>().cloned();
+
+    let response = next.run(request).await;
+    let Some(ErrorMessage(message)) = response.extensions().get::<ErrorMessage>().cloned() else {
+        return response;
+    };
+
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(http::header::CONTENT_TYPE);
+    parts.headers.remove(http::header::CONTENT_LENGTH);
+    let body = if is_api {
+        Json(ApiErrorBody { error: message }).into_response()
+    } else {
+        error_page(parts.status, message, headers, cookies, pool)
+            .await
+            .into_response()
+    };
+
+    let (body_parts, body) = body.into_parts();
+    parts.headers.extend(body_parts.headers);
+    Response::from_parts(parts, body)
+}
+
+async fn error_page(
+    status: StatusCode,
+    message: String,
+    headers: HeaderMap,
+    cookies: Option<Cookies>,
+    pool: Option<SqlitePool>,
+) -> maud::Markup {
+    let user = match (&cookies, pool) {
+        (Some(cookies), Some(pool)) => User::from_cookies(cookies, &headers, &pool)
+            .await
+            .ok()
+            .flatten(),
+        _ => None,
+    };
+    let title = status.canonical_reason().unwrap_or("Error");
+
+    let cookies = cookies.unwrap_or_default();
+    BaseTemplate {
+        user,
+        csrf_token: csrf_token(&cookies),
+        cookies,
+        headers,
+        title: Some(title.to_string()),
+        content: html! {
+            h1 class="text-xl mb-4" { (status.as_u16()) " " (title) }
+            p { (message) }
+        },
+        page_meta: None,
+    }
+    .render()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{body_text, full_app};
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    #[sqlx::test]
+    async fn unknown_paths_get_error_pages(pool: SqlitePool) {
+        for path in ["/no/such/page", "/no-such-file.js"] {
+            let request = Request::get(path).body(Body::empty()).unwrap();
+            let response = full_app(&pool).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert!(body_text(response).await.contains("404 Not Found"));
+        }
+        for request in [
+            Request::get("/api/v1/no/such/endpoint"),
+            Request::post("/api/v1/nothing"),
+        ] {
+            let response = full_app(&pool)
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            let body: ApiErrorBody = serde_json::from_str(&body_text(response).await).unwrap();
+            assert_eq!(body.error, "Page not found");
+        }
+    }
+
+    #[sqlx::test]
+    async fn unknown_ids_are_not_found_but_missing_rows_are_internal(pool: SqlitePool) {
+        let request = Request::get("/api/v1/statement/404")
+            .body(Body::empty())
+            .unwrap();
+        let response = full_app(&pool).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body: ApiErrorBody = serde_json::from_str(&body_text(response).await).unwrap();
+        assert_eq!(body.error, "Statement not found");
+
+        // handlers which expect a row to exist have a bug when it is missing
+        let error = AppError::from(sqlx::Error::RowNotFound);
+        assert_eq!(error.status(), StatusCode::INTERNAL_SERVER_ERROR);
     }
 }
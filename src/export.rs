@@ -0,0 +1,310 @@
+//! Export of all data stored about a user
+//!
+//! The export is a single JSON document. Its layout is identified by `format_version`, which is
+//! increased whenever fields are renamed or removed, so that importers can reject or convert
+//! documents they don't understand. Adding fields does not change the version.
+//!
+//! Version 1:
+//! - `format_version`: always `1`
+//! - `exported_at`, `user_created`: unix timestamps in seconds
+//! - `statements`: every statement referenced below, as `{ id, text }`
+//! - `authored_statements`: statements written by the user, as `{ statement_id, created }`
+//! - `votes`: the current vote per statement, as `{ statement_id, vote }`
+//! - `vote_history`: every vote ever cast, including skips, as `{ statement_id, vote, created }`
+//! - `subscriptions`, `queue`: as `{ statement_id, created }`
+//! - `ideology_stats`, `bfp_traits`: derived from the votes, as
+//!   `{ name: { votes_cast, votes_weight } }`. `null` if predictions are disabled.
+//! - `username`: name to log in with a password, `null` if no password is set
+//! - `passkeys`, `linked_devices`: as `{ name, created, last_used }`, `last_used` may be `null`
+//! - `sessions`: logged in browsers, as `{ name, created, last_used }`
+//! - `audit_log`: security relevant events, newest first, as `{ event, detail, created }`
+//!
+//! Votes are one of `"Yes"`, `"No"`, `"Skip"` and `"ItDepends"`. The user's secret is not part
+//! of the export, since the file could be shared. Neither are password hashes or passkeys' public
+//! keys.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Result;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::structs::{Statement, User, Vote};
+
+/// Increase when fields are renamed or removed
+pub const EXPORT_FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserExport {
+    pub format_version: u32,
+    pub exported_at: i64,
+    pub user_created: i64,
+    pub statements: Vec<Statement>,
+    pub authored_statements: Vec<ExportTimestamped>,
+    pub votes: Vec<ExportVote>,
+    pub vote_history: Vec<ExportVoteHistoryItem>,
+    pub subscriptions: Vec<ExportTimestamped>,
+    pub queue: Vec<ExportTimestamped>,
+    pub ideology_stats: Option<BTreeMap<String, ExportStat>>,
+    pub bfp_traits: Option<BTreeMap<String, ExportStat>>,
+    pub username: Option<String>,
+    pub passkeys: Vec<ExportDevice>,
+    pub linked_devices: Vec<ExportDevice>,
+    pub sessions: Vec<ExportDevice>,
+    pub audit_log: Vec<ExportAuditEntry>,
+}
+
+/// Reference to a statement with the time it was added to a table
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct ExportTimestamped {
+    pub statement_id: i64,
+    pub created: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportVote {
+    pub statement_id: i64,
+    pub vote: Vote,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportVoteHistoryItem {
+    pub statement_id: i64,
+    pub vote: Vote,
+    pub created: i64,
+}
+
+/// Passkey, linked device or session
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportDevice {
+    pub name: String,
+    pub created: i64,
+    pub last_used: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportAuditEntry {
+    pub event: String,
+    pub detail: Option<String>,
+    pub created: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportStat {
+    pub votes_cast: i64,
+    pub votes_weight: f64,
+}
+
+impl UserExport {
+    /// Collects everything stored about the user
+    pub async fn create(user: &User, pool: &SqlitePool) -> Result<Self> {
+        let user_created = sqlx::query_scalar!("select created from users where id = ?", user.id)
+            .fetch_one(pool)
+            .await?;
+
+        let authored_statements = timestamped("authors", user, pool).await?;
+        let subscriptions = timestamped("subscriptions", user, pool).await?;
+        let queue = timestamped("queue", user, pool).await?;
+
+        let mut votes = vec![];
+        for row in sqlx::query!(
+            "select statement_id, vote from votes where user_id = ? order by statement_id",
+            user.id
+        )
+        .fetch_all(pool)
+        .await?
+        {
+            votes.push(ExportVote {
+                statement_id: row.statement_id,
+                vote: Vote::from(row.vote)?,
+            });
+        }
+
+        let mut vote_history = vec![];
+        for row in sqlx::query!(
+            "select statement_id, vote, created from vote_history where user_id = ? order by created, rowid",
+            user.id
+        )
+        .fetch_all(pool)
+        .await?
+        {
+            vote_history.push(ExportVoteHistoryItem {
+                statement_id: row.statement_id,
+                vote: Vote::from(row.vote)?,
+                created: row.created,
+            });
+        }
+
+        let statement_ids: BTreeSet<i64> = authored_statements
+            .iter()
+            .chain(subscriptions.iter())
+            .chain(queue.iter())
+            .map(|item| item.statement_id)
+            .chain(votes.iter().map(|vote| vote.statement_id))
+            .chain(vote_history.iter().map(|item| item.statement_id))
+            .collect();
+        let mut statements = vec![];
+        for statement_id in statement_ids {
+            if let Some(statement) = crate::db::find_statement(statement_id, pool).await? {
+                statements.push(statement);
+            }
+        }
+
+        #[cfg(feature = "with_predictions")]
+        let (ideology_stats, bfp_traits) = (
+            Some(export_stats(user.ideology_stats_map(pool).await?)),
+            Some(export_stats(user.bfp_traits_map(pool).await?)),
+        );
+        #[cfg(not(feature = "with_predictions"))]
+        let (ideology_stats, bfp_traits) = (None, None);
+
+        let passkeys = user
+            .passkeys(pool)
+            .await?
+            .into_iter()
+            .map(|passkey| ExportDevice {
+                name: passkey.name,
+                created: passkey.created,
+                last_used: passkey.last_used,
+            })
+            .collect();
+        let linked_devices = user
+            .linked_devices(pool)
+            .await?
+            .into_iter()
+            .map(|device| ExportDevice {
+                name: device.name,
+                created: device.created,
+                last_used: device.last_used,
+            })
+            .collect();
+        let sessions = user
+            .sessions(pool)
+            .await?
+            .into_iter()
+            .map(|session| ExportDevice {
+                name: session.name,
+                created: session.created,
+                last_used: Some(session.last_seen),
+            })
+            .collect();
+        let audit_log = user
+            .audit_log(i64::MAX, pool)
+            .await?
+            .into_iter()
+            .map(|entry| ExportAuditEntry {
+                event: entry.event,
+                detail: entry.detail,
+                created: entry.created,
+            })
+            .collect();
+
+        Ok(Self {
+            format_version: EXPORT_FORMAT_VERSION,
+            exported_at: Utc::now().timestamp(),
+            user_created,
+            statements,
+            authored_statements,
+            votes,
+            vote_history,
+            subscriptions,
+            queue,
+            ideology_stats,
+            bfp_traits,
+            username: user.username(pool).await?,
+            passkeys,
+            linked_devices,
+            sessions,
+            audit_log,
+        })
+    }
+}
+
+/// Rows of a table with user_id, statement_id and created columns
+async fn timestamped(
+    table: &'static str,
+    user: &User,
+    pool: &SqlitePool,
+) -> Result<Vec<ExportTimestamped>> {
+    Ok(sqlx::query_as::<_, ExportTimestamped>(
+        format!(
+            "select statement_id, created from {table} where user_id = ? order by created, statement_id"
+        )
+        .as_str(),
+    )
+    .bind(user.id)
+    .fetch_all(pool)
+    .await?)
+}
+
+#[cfg(feature = "with_predictions")]
+fn export_stats(
+    stats: std::collections::HashMap<String, crate::db::UserStat>,
+) -> BTreeMap<String, ExportStat> {
+    stats
+        .into_iter()
+        .map(|(name, stat)| {
+            (
+                name,
+                ExportStat {
+                    votes_cast: stat.votes_cast,
+                    votes_weight: stat.votes_weight,
+                },
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn export_contains_user_data(pool: SqlitePool) -> anyhow::Result<()> {
+        sqlx::query!("insert into users(id, secret) values (1, 'abc'), (2, 'def')")
+            .execute(&pool)
+            .await?;
+        let user = User {
+            id: 1,
+            secret: Some("abc".to_string()),
+        };
+        let statement_id = user.add_statement("Is the world flat?", &pool).await?;
+        sqlx::query!("insert into statements(id, text) values (100, 'Not mine')")
+            .execute(&pool)
+            .await?;
+        user.vote(statement_id, Vote::Skip, &pool).await?;
+        user.vote(statement_id, Vote::Yes, &pool).await?;
+        User {
+            id: 2,
+            secret: Some("def".to_string()),
+        }
+        .vote(100, Vote::No, &pool)
+        .await?;
+
+        let export = UserExport::create(&user, &pool).await?;
+        assert_eq!(export.format_version, EXPORT_FORMAT_VERSION);
+        assert_eq!(
+            export.statements.iter().map(|s| s.id).collect::<Vec<_>>(),
+            vec![statement_id]
+        );
+        assert_eq!(export.authored_statements.len(), 1);
+        assert_eq!(export.subscriptions.len(), 1);
+        assert!(export.queue.is_empty());
+        assert_eq!(export.votes.len(), 1);
+        assert_eq!(export.votes[0].vote, Vote::Yes);
+        assert_eq!(
+            export
+                .vote_history
+                .iter()
+                .map(|item| item.vote)
+                .collect::<Vec<_>>(),
+            vec![Vote::Skip, Vote::Yes]
+        );
+
+        let json = serde_json::to_string(&export)?;
+        let parsed: UserExport = serde_json::from_str(&json)?;
+        assert_eq!(parsed.vote_history.len(), 2);
+        Ok(())
+    }
+}
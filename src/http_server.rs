@@ -1,6 +1,10 @@
+use std::future::Future;
 use std::net::SocketAddr;
 
 use crate::api;
+use crate::csrf::verify_csrf;
+use crate::db::SharedEmbeddingCache;
+use crate::error::{render_errors, AppError};
 use crate::http_static::static_handler;
 use crate::pages;
 use crate::pages::new_statement::create_statement;
@@ -9,24 +13,66 @@ use crate::pages::statement::statement_frontpage;
 use crate::pages::subscribe::subscribe;
 use crate::pages::user::profile::profile_page;
 use crate::pages::vote::vote_post;
+use crate::prediction::runner::SharedPredictor;
+use crate::rate_limit::{rate_limit, SharedRateLimits};
+use crate::selection::SharedSelectionStrategy;
 use anyhow::Result;
+use axum::middleware;
 use axum::routing::post;
 use axum::Extension;
 use axum::{routing::get, Router};
 use http::StatusCode;
 use pages::frontpage::{frontpage, search_results};
 use pages::new_statement::new_statement;
+use pages::relations::{add_relation_post, new_relation, relation_search_results};
 use pages::statement::statement_page;
 use pages::subscriptions::subscriptions;
+use pages::user::credentials::{
+    add_passkey, credentials, delete_passkey, passkey_options, remove_password, set_password,
+};
+use pages::user::delete::{delete_account, delete_account_post};
+use pages::user::export::export;
+use pages::user::login::{login, login_post, passkey_login, passkey_login_options};
 use pages::user::merge::{merge, merge_post};
-use pages::user::options::options;
+use pages::user::options::{
+    device_link_post, logout_post, options, revoke_device_post, revoke_session_post,
+    rotate_secret_post,
+};
 use sqlx::SqlitePool;
 use tower_cookies::CookieManagerLayer;
 use tower_http::compression::CompressionLayer;
 use tower_http::trace::TraceLayer;
 use tracing::info;
 
-pub async fn start_http_server(sqlite_pool: SqlitePool) -> Result<()> {
+pub async fn start_http_server(
+    sqlite_pool: SqlitePool,
+    selection: SharedSelectionStrategy,
+    rate_limits: SharedRateLimits,
+    embeddings: SharedEmbeddingCache,
+    predictor: SharedPredictor,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let app = router(sqlite_pool, selection, rate_limits, embeddings, predictor);
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], 8000));
+    info!("Http server listening on {}", addr);
+    axum::Server::bind(&addr)
+        // the address of the client is needed for rate limiting
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown)
+        .await?;
+
+    Ok(())
+}
+
+/// All routes with their middlewares
+pub fn router(
+    sqlite_pool: SqlitePool,
+    selection: SharedSelectionStrategy,
+    rate_limits: SharedRateLimits,
+    embeddings: SharedEmbeddingCache,
+    predictor: SharedPredictor,
+) -> Router {
     let mut app = Router::new();
 
     app = app
@@ -37,12 +83,36 @@ pub async fn start_http_server(sqlite_pool: SqlitePool) -> Result<()> {
         .route("/statement", get(statement_frontpage))
         .route("/statement/vote", post(vote_post))
         .route("/statement/:id", get(statement_page))
-        .route("/merge/:secret", get(merge))
-        .route("/merge/:secret", post(merge_post))
+        .route("/statement/:id/relations", post(add_relation_post))
+        .route("/statement/:id/relations/new", get(new_relation))
+        .route(
+            "/statement/:id/relations/search",
+            post(relation_search_results),
+        )
+        .route("/merge/:token", get(merge).post(merge_post))
         .route("/new", get(new_statement))
         .route("/new/completions", post(new_statement_completions))
         .route("/create", post(create_statement))
         .route("/options", get(options))
+        .route("/user/device_link", post(device_link_post))
+        .route("/user/secret/rotate", post(rotate_secret_post))
+        .route("/user/sessions/:id/revoke", post(revoke_session_post))
+        .route("/logout", post(logout_post))
+        .route("/user/devices/:id/revoke", post(revoke_device_post))
+        .route("/login", get(login).post(login_post))
+        .route("/login/passkey", post(passkey_login))
+        .route("/login/passkey/options", post(passkey_login_options))
+        .route("/user/credentials", get(credentials))
+        .route("/user/credentials/password", post(set_password))
+        .route("/user/credentials/password/remove", post(remove_password))
+        .route("/user/credentials/passkey", post(add_passkey))
+        .route("/user/credentials/passkey/options", post(passkey_options))
+        .route("/user/credentials/passkey/:id/delete", post(delete_passkey))
+        .route("/user/export", get(export))
+        .route(
+            "/user/delete",
+            get(delete_account).post(delete_account_post),
+        )
         .route("/subscriptions", get(subscriptions));
 
     #[cfg(feature = "with_predictions")]
@@ -54,33 +124,33 @@ pub async fn start_http_server(sqlite_pool: SqlitePool) -> Result<()> {
     }
 
     let apiv0 = Router::new()
-        .route("/user/create", post(api::create_user))
-        .route("/next_statement", get(api::next_statement))
-        .route("/statement/:id/vote", post(api::statement_vote))
-        .layer(Extension(sqlite_pool.clone()));
+        .route("/user/create", post(api::v0::create_user))
+        .route("/next_statement", get(api::v0::next_statement))
+        .route("/statement/:id/vote", post(api::v0::statement_vote));
 
-    app = app
+    app.nest("/api/v0", apiv0)
+        .nest("/api/v1", api::v1::router())
         .route("/healthy", get(handler_healthy))
-        .route("/*file", get(static_handler))
+        // other methods would get a bare 405, since every path could be a file
+        .route("/*file", get(static_handler).fallback(not_found))
+        .fallback(not_found)
+        .layer(middleware::from_fn(verify_csrf))
+        .layer(middleware::from_fn(rate_limit))
+        .layer(middleware::from_fn(render_errors))
         .layer(TraceLayer::new_for_http())
-        .layer(Extension(sqlite_pool.to_owned()))
+        .layer(Extension(sqlite_pool))
+        .layer(Extension(selection))
+        .layer(Extension(rate_limits))
+        .layer(Extension(embeddings))
+        .layer(Extension(predictor))
         .layer(CookieManagerLayer::new())
         .layer(CompressionLayer::new())
-        .fallback_service(get(not_found));
-
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8000));
-    info!("Http server listening on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.nest("/api/v0", apiv0).into_make_service())
-        .await?;
-
-    Ok(())
 }
 
 async fn handler_healthy() -> StatusCode {
     StatusCode::OK
 }
 
-async fn not_found() -> StatusCode {
-    StatusCode::NOT_FOUND
+async fn not_found() -> AppError {
+    AppError::not_found("Page")
 }
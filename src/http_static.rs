@@ -1,11 +1,13 @@
 use axum::{
     body::{boxed, Full},
-    http::{header, StatusCode, Uri},
+    http::{header, Uri},
     response::{IntoResponse, Response},
 };
 use http::HeaderValue;
 use rust_embed::RustEmbed;
 
+use crate::error::AppError;
+
 // static file serving inspired by:
 // https://github.com/pyrossh/rust-embed/blob/fe35dbdc8373817ea84e4962db18ad37e48b1522/examples/axum.rs
 
@@ -49,10 +51,7 @@ where
                     .body(body)
                     .unwrap()
             }
-            None => Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(boxed(Full::from("404")))
-                .unwrap(),
+            None => AppError::not_found("Page").into_response(),
         }
     }
 }
@@ -1,55 +1,145 @@
 mod api;
 mod auth;
 mod command_line_args;
+mod credentials;
+mod csrf;
 mod db;
 mod db_setup;
+mod devices;
+mod duplicates;
 mod error;
+mod export;
 mod highlight;
 mod pages;
 mod prediction;
+mod rate_limit;
+mod selection;
+mod sessions;
+mod shutdown;
 
 mod http_server;
 mod http_static;
 
 mod structs;
+#[cfg(test)]
+mod testing;
 mod util;
+mod webauthn;
 
 use clap::Parser;
 use http_server::start_http_server;
 
+use std::time::Duration;
+
 use anyhow::{Context, Result};
+use sqlx::SqlitePool;
+use tracing::{error, warn};
 
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-use crate::command_line_args::CommandLineArgs;
+use crate::command_line_args::{Command, CommandLineArgs};
+use crate::db::SharedEmbeddingCache;
 use crate::db_setup::setup_database;
+use crate::prediction::runner::SharedPredictor;
+use crate::shutdown::Shutdown;
+
+/// How long running predictions may take to finish after shutdown was requested. Keep it below
+/// `kill_timeout` in fly.toml.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
 
 #[tokio::main]
 async fn main() -> Result<()> {
     init_tracing();
 
     let command_line_args = CommandLineArgs::parse();
+    auth::init_secret_hash_key(&command_line_args.auth)?;
+    sessions::init_session_cookies(&command_line_args.auth);
+    webauthn::init_relying_party(&command_line_args.auth)?;
     let sqlite_pool = setup_database(&command_line_args.database).await;
-    let mut sqlite_pool_prediction_runner = sqlite_pool.clone();
+    auth::hash_legacy_secrets(&sqlite_pool).await?;
 
-    // depending on the feature flags, the pool needs a mutable reference or not
-    tokio::select! {
-        res = start_http_server(sqlite_pool.clone()) => {
-            res.context("http server crashed").unwrap();
-        }
+    if let Some(command) = command_line_args.command {
+        return run_command(command, &sqlite_pool).await;
+    }
 
-        res = {
-            #[allow(clippy::unnecessary_mut_passed)]
-            prediction::runner::run(&command_line_args.prediction, &mut sqlite_pool_prediction_runner)
-        } => {
-            res.context("prediction runner crashed").unwrap();
-        }
+    // predictions run in their own task, so failures there don't take down the http server
+    let shutdown = Shutdown::on_signal();
+    let embeddings = SharedEmbeddingCache::default();
+    let predictor = SharedPredictor::default();
+    let predictions = tokio::spawn(prediction::runner::run(
+        command_line_args.prediction,
+        embeddings.clone(),
+        predictor.clone(),
+        sqlite_pool.clone(),
+        shutdown.clone(),
+    ));
+    start_http_server(
+        sqlite_pool,
+        selection::from_args(&command_line_args.selection),
+        rate_limit::from_args(&command_line_args.rate_limit),
+        embeddings,
+        predictor,
+        shutdown.clone().wait(),
+    )
+    .await
+    .context("http server crashed")?;
 
+    // let the workers finish their requests
+    match tokio::time::timeout(SHUTDOWN_GRACE, predictions).await {
+        Ok(Ok(Ok(()))) => {}
+        Ok(Ok(Err(err))) => error!("Predictions failed: {:?}", err),
+        Ok(Err(err)) => error!("Predictions failed: {}", err),
+        Err(_) => warn!(
+            "Predictions did not stop within {}s",
+            SHUTDOWN_GRACE.as_secs()
+        ),
     }
 
     Ok(())
 }
 
+async fn run_command(command: Command, pool: &SqlitePool) -> Result<()> {
+    match command {
+        Command::ListDuplicates => {
+            for candidate in duplicates::duplicate_candidates(pool).await? {
+                println!(
+                    "{} {} {} {:.3}",
+                    candidate.statement_id,
+                    candidate.duplicate_id,
+                    candidate.method,
+                    candidate.similarity
+                );
+            }
+        }
+        Command::MergeStatements {
+            duplicate_id,
+            canonical_id,
+        } => {
+            duplicates::merge_statements(duplicate_id, canonical_id, pool).await?;
+            println!("Merged statement {duplicate_id} into {canonical_id}");
+        }
+        #[cfg(feature = "with_predictions")]
+        Command::ApiKeySpend => {
+            let spend = prediction::budget::key_spend(pool).await?;
+            let keys = sqlx::query!("SELECT id, note, total_tokens FROM api_keys ORDER BY id")
+                .fetch_all(pool)
+                .await?;
+            for key in keys {
+                let spend = spend.get(&key.id).copied().unwrap_or_default();
+                println!(
+                    "{} {:.2} {:.2} {} {}",
+                    key.id,
+                    spend.today,
+                    spend.month,
+                    key.total_tokens,
+                    key.note.unwrap_or_default()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
 fn init_tracing() {
     tracing_subscriber::registry()
         .with(fmt::layer())
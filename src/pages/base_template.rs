@@ -2,9 +2,11 @@ use async_trait::async_trait;
 use axum::extract::FromRequestParts;
 use http::HeaderMap;
 use maud::{html, Markup, DOCTYPE};
+use serde_json::json;
 use tower_cookies::Cookies;
 
 use crate::{
+    csrf::{csrf_field, csrf_token, CSRF_HEADER},
     http_static::StaticAsset,
     structs::{PageMeta, User},
     util::base_url,
@@ -16,6 +18,7 @@ fn render_base_template(
     content: Markup,
     headers: &HeaderMap,
     page_meta: Option<PageMeta>,
+    csrf_token: &str,
 ) -> Markup {
     html! {
         (DOCTYPE)
@@ -36,6 +39,7 @@ fn render_base_template(
                 meta name="theme-color" content="#ffffff";
 
                 meta property="og:type" content="website";
+                meta name="csrf-token" content=(csrf_token);
 
                 @if let Some(page_meta) = page_meta {
                     @if let Some(title) = page_meta.title {
@@ -64,20 +68,19 @@ fn render_base_template(
 
                 title { (title.unwrap_or("Propolis".to_string())) }
             }
-            body class="bg-slate-100 dark:bg-slate-800 dark:text-white" {
+            // htmx requests of all elements inherit the header, see src/csrf.rs
+            body class="bg-slate-100 dark:bg-slate-800 dark:text-white" hx-headers=(json!({ CSRF_HEADER: csrf_token })) {
                 nav class="px-5 py-3" {
                     ul class="flex gap-6" {
                         li { a href="/" data-testid="nav-home" { "Home" } }
                         li { a href="/statement" data-testid="nav-home" { "Vote" } }
                         li { a href="/new" data-testid="nav-add-statement" { "Ask Question" } }
                         li  class="mr-auto" { a href="/subscriptions" data-testid="nav-my-subscriptions" { "My Subscriptions" } }
-                        // first 4 characters of user id
-                        @if let Some(user) = user {
+                        @if user.is_some() {
                             li {
-                                a href="/user" class="font-mono" {
+                                a href="/user" {
                                     @let user_icon = "👤";
-                                    span class="mr-1" { (user_icon) }
-                                    (user.secret.chars().take(4).collect::<String>())
+                                    (user_icon)
                                 }
                             }
                         }
@@ -105,6 +108,8 @@ pub struct BaseTemplate {
     pub title: Option<String>,
     pub content: Markup,
     pub page_meta: Option<PageMeta>,
+    /// Put into forms and htmx headers, see [crate::csrf]
+    pub csrf_token: String,
 }
 
 impl BaseTemplate {
@@ -128,6 +133,10 @@ impl BaseTemplate {
         self.page_meta = m;
         self
     }
+    /// Hidden field with the csrf token, which every post form has to contain
+    pub fn csrf_field(&self) -> Markup {
+        csrf_field(&self.csrf_token)
+    }
     /// Render BaseTemplate into markup
     pub fn render(self) -> Markup {
         render_base_template(
@@ -136,6 +145,7 @@ impl BaseTemplate {
             self.content,
             &self.headers,
             self.page_meta,
+            &self.csrf_token,
         )
     }
 }
@@ -173,6 +183,7 @@ where
 
         Ok(BaseTemplate {
             user,
+            csrf_token: csrf_token(&cookies),
             cookies,
             headers,
             title: None,
@@ -15,9 +15,10 @@ pub async fn yes_no_pie_chart(statement_id: i64, pool: &SqlitePool) -> Result<Ma
         total_votes,
         yes_votes,
         no_votes,
+        itdepends_votes,
         ..
     } = statement_stats(statement_id, pool).await?;
-    if total_votes == 0 {
+    if total_votes + itdepends_votes == 0 {
         Ok(html! {})
     } else {
         Ok(apex_chart(
@@ -26,7 +27,8 @@ pub async fn yes_no_pie_chart(statement_id: i64, pool: &SqlitePool) -> Result<Ma
                 {{
                   "labels": [
                     "Yes",
-                    "No"
+                    "No",
+                    "It depends"
                   ],
                   "chart": {{
                     "type": "pie",
@@ -39,7 +41,7 @@ pub async fn yes_no_pie_chart(statement_id: i64, pool: &SqlitePool) -> Result<Ma
                        click: function(event, chartContext, config) {{
                            // workaround from https://github.com/apexcharts/apexcharts.js/issues/2251#issuecomment-904377385
                            const seriesIndex = event.target.parentElement.getAttribute("data:realIndex")
-                           const targetQuery = ['target_yes', 'target_all', 'target_no'];
+                           const targetQuery = ['target_yes', 'target_no', 'target_all'];
                            location.href = `/new?target=${{{statement_id}}}&${{targetQuery[seriesIndex]}}=true`;
                        }}
                      }},
@@ -48,6 +50,7 @@ pub async fn yes_no_pie_chart(statement_id: i64, pool: &SqlitePool) -> Result<Ma
                   "colors": [
                     "#16a34a",
                     "#dc2626",
+                    "#2563eb",
                   ],
                   "tooltip": {{ "enabled": false }},
                   "dataLabels": {{
@@ -60,6 +63,7 @@ pub async fn yes_no_pie_chart(statement_id: i64, pool: &SqlitePool) -> Result<Ma
                       "colors": [
                         "#16a34a",
                         "#dc2626",
+                        "#2563eb",
                       ],
                     }},
                     "background": {{
@@ -78,7 +82,7 @@ pub async fn yes_no_pie_chart(statement_id: i64, pool: &SqlitePool) -> Result<Ma
                   }},
                   "series": {}
                 }}"##,
-                json!([yes_votes, no_votes]),
+                json!([yes_votes, no_votes, itdepends_votes]),
             )
             .as_str(),
         ))
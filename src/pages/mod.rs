@@ -6,6 +6,7 @@ pub mod frontpage;
 pub mod new_statement;
 #[cfg(feature = "with_predictions")]
 pub mod prediction;
+pub mod relations;
 pub mod statement;
 pub mod statement_ui;
 pub mod subscribe;
@@ -7,6 +7,12 @@ use crate::pages::statement_ui::{
 use crate::structs::{TargetSegment, User};
 
 use crate::db::search_statement;
+#[cfg(feature = "with_predictions")]
+use crate::prediction::runner::{semantic_search, SharedPredictor};
+#[cfg(feature = "with_predictions")]
+use crate::structs::SearchResultStatement;
+#[cfg(feature = "with_predictions")]
+use tracing::warn;
 
 use axum::{extract::Query, response::Redirect, Extension, Form};
 use http::HeaderMap;
@@ -15,6 +21,16 @@ use serde::Deserialize;
 use sqlx::SqlitePool;
 use tower_cookies::Cookies;
 
+/// Typed text needs at least this many characters to be embedded for suggestions
+#[cfg(feature = "with_predictions")]
+const MIN_SEMANTIC_SEARCH_CHARS: usize = 10;
+/// Maximum number of suggestions found via embeddings
+#[cfg(feature = "with_predictions")]
+const SIMILAR_SUGGESTIONS: usize = 5;
+/// Embedding similarity above which an existing question is suggested
+#[cfg(feature = "with_predictions")]
+const MIN_SUGGESTION_SIMILARITY: f64 = 0.85;
+
 #[derive(Deserialize, Debug)]
 pub struct NewStatementUrlQuery {
     target: Option<i64>,
@@ -46,6 +62,7 @@ pub async fn new_statement(
     let content = html! {
         div x-data="{ typed_statement: '', alternative_statement: null }" {
             form method="post" action="/create" {
+                (base.csrf_field())
                 h2 class="text-xl mb-4" { "Ask Question" }
                 div { "A good question..." }
                 ul class="mb-2 list-disc list-inside" {
@@ -74,7 +91,9 @@ pub async fn new_statement(
                     hx-validate="true"
                     hx-target="#similar"
                     hx-post="/new/completions"
-                    hx-trigger="keyup changed delay:500ms, load"
+                    // every completion may embed the typed text, which costs tokens
+                    hx-trigger="keyup changed delay:1s, load"
+                    hx-sync="this:replace"
                     data-testid="create-statement-field"
                     {};
                 // template x-if="alternative_statement === null" {
@@ -120,9 +139,14 @@ pub async fn new_statement_completions(
     _header_map: HeaderMap,
     maybe_user: Option<User>,
     Extension(pool): Extension<SqlitePool>,
+    #[cfg(feature = "with_predictions")] Extension(predictor): Extension<SharedPredictor>,
     Form(form): Form<AddStatementForm>,
 ) -> Result<Markup, AppError> {
-    let statements = search_statement(form.typed_statement.as_str(), &pool).await?;
+    #[allow(unused_mut)]
+    let mut statements = search_statement(form.typed_statement.as_str(), &pool).await?;
+    #[cfg(feature = "with_predictions")]
+    statements
+        .extend(similar_to_typed(&predictor, form.typed_statement.as_str(), &statements).await);
     Ok(html! {
         @if !statements.is_empty() {
             h2 class="text-xl mb-4" { "Did you mean" }
@@ -141,12 +165,44 @@ pub async fn new_statement_completions(
     })
 }
 
+/// Statements with embeddings similar to the typed text, which the keyword search did not find
+///
+/// The text is embedded by the prediction workers' key selection, within their budgets and
+/// quotas. Errors and exceeded quotas are only logged, since keyword results are still useful.
+#[cfg(feature = "with_predictions")]
+async fn similar_to_typed(
+    predictor: &SharedPredictor,
+    typed_statement: &str,
+    keyword_results: &[SearchResultStatement],
+) -> Vec<SearchResultStatement> {
+    if typed_statement.trim().chars().count() < MIN_SEMANTIC_SEARCH_CHARS {
+        return vec![];
+    }
+    match semantic_search(predictor, typed_statement, SIMILAR_SUGGESTIONS).await {
+        Ok(similar) => similar
+            .into_iter()
+            .filter(|s| s.similarity >= MIN_SUGGESTION_SIMILARITY)
+            .filter(|s| !keyword_results.iter().any(|r| r.id == s.statement.id))
+            .map(|s| SearchResultStatement {
+                id: s.statement.id,
+                text_highlighted: s.statement.text.to_owned(),
+                text_original: s.statement.text,
+            })
+            .collect(),
+        Err(err) => {
+            warn!("semantic search failed: {err:?}");
+            vec![]
+        }
+    }
+}
+
 pub async fn create_statement(
     cookies: Cookies,
+    headers: HeaderMap,
     Extension(pool): Extension<SqlitePool>,
     Form(form_data): Form<AddStatementForm>,
 ) -> Result<Redirect, AppError> {
-    let user = User::get_or_create(&cookies, &pool).await?;
+    let user = User::get_or_create(&cookies, &headers, &pool).await?;
     let target_segment = match form_data.target_id {
         Some(target_id) => Some(TargetSegment {
             statement_id: target_id,
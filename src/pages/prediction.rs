@@ -3,13 +3,15 @@ use axum::{extract::Path, Extension};
 use maud::{html, Markup};
 use sqlx::SqlitePool;
 
-use crate::{db::get_statement, error::AppError};
+use crate::{db::find_statement, error::AppError};
 
 pub async fn prediction_page(
     Extension(pool): Extension<SqlitePool>,
     Path(statement_id): Path<i64>,
 ) -> Result<Markup, AppError> {
-    let statement = get_statement(statement_id, &pool).await?;
+    let statement = find_statement(statement_id, &pool)
+        .await?
+        .ok_or(AppError::not_found("Question"))?;
 
     let meta = statement.get_meta(&pool).await?;
     let pred_formatted = match meta {
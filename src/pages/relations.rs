@@ -0,0 +1,173 @@
+use crate::csrf::{csrf_field, csrf_token};
+use crate::db::{add_relation, find_statement, get_relations, get_statement, search_statement};
+use crate::error::AppError;
+use crate::pages::base_template::BaseTemplate;
+use crate::pages::statement_ui::{
+    inline_statement_content, inline_statement_piechart, inline_statement_vote_fetch,
+};
+use crate::structs::{RelationType, Statement, StatementRelation, User};
+
+use anyhow::Result;
+use axum::extract::Path;
+use axum::response::Redirect;
+use axum::{Extension, Form};
+use http::HeaderMap;
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use tower_cookies::Cookies;
+
+/// Relation types in the order they are offered and shown
+const RELATION_TYPES: [RelationType; 4] = [
+    RelationType::Generalization,
+    RelationType::Specialization,
+    RelationType::Alternative,
+    RelationType::Contradiction,
+];
+
+#[derive(Deserialize)]
+pub struct RelationSearchForm {
+    typed_query: String,
+}
+
+#[derive(Deserialize)]
+pub struct AddRelationForm {
+    related_statement_id: i64,
+    /// Type of the related statement, seen from the statement of the page
+    relation_type: i64,
+}
+
+pub async fn new_relation(
+    Path(statement_id): Path<i64>,
+    maybe_user: Option<User>,
+    Extension(pool): Extension<SqlitePool>,
+    base: BaseTemplate,
+) -> Result<Markup, AppError> {
+    let statement = find_statement(statement_id, &pool)
+        .await?
+        .ok_or(AppError::not_found("Question"))?;
+
+    let content = html! {
+        h2 class="text-xl mb-4" { "Link Question" }
+        div class="mb-5 rounded-lg shadow bg-white dark:bg-slate-700 flex" {
+            (inline_statement_content(&statement, None, false, &maybe_user, &pool).await?)
+            (inline_statement_piechart(statement.id, &pool).await?)
+        }
+        input
+            type="search"
+            name="typed_query"
+            placeholder="Find a related question"
+            class="mb-5 dark:text-black w-full rounded-full px-7 py-4 border border-1 border-gray-400"
+            minLength="1"
+            hx-validate="true"
+            hx-target="#results"
+            hx-post=(format!("/statement/{statement_id}/relations/search"))
+            hx-trigger="keyup changed delay:100ms, keydown[key=='Enter']"
+            {}
+        div id="results" {}
+    };
+    Ok(base.title("Link Question").content(content).render())
+}
+
+pub async fn relation_search_results(
+    Path(statement_id): Path<i64>,
+    maybe_user: Option<User>,
+    cookies: Cookies,
+    Extension(pool): Extension<SqlitePool>,
+    Form(form): Form<RelationSearchForm>,
+) -> Result<Markup, AppError> {
+    let statements = search_statement(form.typed_query.as_str(), &pool).await?;
+    let csrf_field = csrf_field(&csrf_token(&cookies));
+    Ok(html! {
+        @for search_result_statement in statements.iter().filter(|s| s.id != statement_id) {
+            div class="mb-5 rounded-lg shadow bg-white dark:bg-slate-700" {
+                div class="flex" {
+                    (inline_statement_content(&search_result_statement.statement_highlighted(), None, false, &maybe_user, &pool).await?)
+                    (inline_statement_piechart(search_result_statement.id, &pool).await?)
+                }
+                form class="flex gap-2 px-4 pb-4" method="post" action=(format!("/statement/{statement_id}/relations")) {
+                    (csrf_field)
+                    input type="hidden" name="related_statement_id" value=(search_result_statement.id);
+                    select name="relation_type" class="dark:bg-slate-700 px-2 rounded border border-1 border-gray-400" {
+                        @for relation_type in RELATION_TYPES {
+                            option value=((relation_type as i64)) { (relation_type.label()) }
+                        }
+                    }
+                    button class="text-white bg-slate-500 px-4 py-1 rounded" { "Link" }
+                }
+            }
+        }
+    })
+}
+
+pub async fn add_relation_post(
+    Path(statement_id): Path<i64>,
+    cookies: Cookies,
+    headers: HeaderMap,
+    Extension(pool): Extension<SqlitePool>,
+    Form(form): Form<AddRelationForm>,
+) -> Result<Redirect, AppError> {
+    let user = User::get_or_create(&cookies, &headers, &pool).await?;
+    let relation_type = RelationType::from(form.relation_type)
+        .map_err(|err| AppError::BadRequest(err.to_string()))?;
+    for id in [statement_id, form.related_statement_id] {
+        find_statement(id, &pool)
+            .await?
+            .ok_or(AppError::not_found("Question"))?;
+    }
+    if statement_id == form.related_statement_id {
+        return Err(AppError::BadRequest(
+            "Cannot link question to itself".to_string(),
+        ));
+    }
+
+    // the form describes the related statement, the relation is stored from the page's statement
+    add_relation(
+        StatementRelation {
+            statement_id,
+            related_statement_id: form.related_statement_id,
+            relation_type: relation_type.inverse(),
+        },
+        user.id,
+        &pool,
+    )
+    .await?;
+
+    Ok(Redirect::to(&format!("/statement/{statement_id}")))
+}
+
+/// Linked statements grouped by relation type, for the statement page
+pub async fn statement_relations(
+    statement_id: i64,
+    maybe_user: &Option<User>,
+    pool: &SqlitePool,
+) -> Result<Markup> {
+    let relations = get_relations(statement_id, pool).await?;
+    let mut groups: Vec<(RelationType, Vec<Statement>)> = vec![];
+    for relation_type in RELATION_TYPES {
+        let mut statements = vec![];
+        // seen from the related statement, the relation type is inverted
+        for relation in relations
+            .iter()
+            .filter(|r| r.relation_type.inverse() == relation_type)
+        {
+            statements.push(get_statement(relation.related_statement_id, pool).await?);
+        }
+        if !statements.is_empty() {
+            groups.push((relation_type, statements));
+        }
+    }
+
+    Ok(html! {
+        @for (relation_type, statements) in groups {
+            h2 class="text-xl mb-4" { (relation_type.label()) }
+            @for statement in statements {
+                div class="mb-5 rounded-lg shadow bg-white dark:bg-slate-700 flex" {
+                    (inline_statement_content(&statement, None, true, maybe_user, pool).await?)
+                    (inline_statement_piechart(statement.id, pool).await?)
+                    (inline_statement_vote_fetch(statement.id, maybe_user, pool).await?)
+                }
+            }
+        }
+    })
+}
@@ -1,6 +1,6 @@
 use crate::pages::base_template::BaseTemplate;
 use crate::{
-    db::{get_followups, get_statement},
+    db::{find_statement, get_followups, get_statement, similar_statements, SharedEmbeddingCache},
     error::AppError,
     pages::statement_ui::{
         inline_statement_content, inline_statement_piechart, inline_statement_vote,
@@ -16,17 +16,26 @@ use maud::{html, Markup};
 use sqlx::SqlitePool;
 
 use crate::db::random_statement_id;
+use crate::duplicates::statement_redirect;
+use crate::pages::relations::statement_relations;
+use crate::prediction::queue;
+use crate::selection::{SharedSelectionStrategy, StatementSelectionStrategy};
 use axum::response::Response;
 use axum::response::{IntoResponse, Redirect};
 
 use anyhow::Result;
+use tracing::warn;
+
+/// Number of related questions shown below a statement
+const RELATED_STATEMENTS: usize = 5;
 
 pub async fn next_statement_id(
     existing_user: Option<User>,
+    selection: &dyn StatementSelectionStrategy,
     pool: &SqlitePool,
 ) -> Result<Option<i64>> {
     Ok(match existing_user {
-        Some(user) => user.next_statement_for_user(pool).await?,
+        Some(user) => user.next_statement_for_user(selection, pool).await?,
         None => random_statement_id(pool).await?,
     })
 }
@@ -35,9 +44,10 @@ pub async fn statement_frontpage(
     existing_user: Option<User>,
     maybe_user: Option<User>,
     Extension(pool): Extension<SqlitePool>,
+    Extension(selection): Extension<SharedSelectionStrategy>,
     base: BaseTemplate,
 ) -> Result<Response, AppError> {
-    let statement_id = next_statement_id(existing_user, &pool).await?;
+    let statement_id = next_statement_id(existing_user, &*selection, &pool).await?;
 
     Ok(match statement_id {
         Some(id) => Redirect::to(format!("/statement/{id}").as_str()).into_response(),
@@ -52,63 +62,90 @@ pub async fn statement_page(
     Path(statement_id): Path<i64>,
     maybe_user: Option<User>,
     Extension(pool): Extension<SqlitePool>,
+    Extension(embeddings): Extension<SharedEmbeddingCache>,
     headers: HeaderMap,
     base: BaseTemplate,
-) -> Result<Markup, AppError> {
-    let statement: Option<Statement> = get_statement(statement_id, &pool).await.ok();
+) -> Result<Response, AppError> {
+    let Some(statement) = find_statement(statement_id, &pool).await? else {
+        // merged duplicates redirect to the statement they were merged into
+        return match statement_redirect(statement_id, &pool).await? {
+            Some(canonical_id) => {
+                Ok(Redirect::permanent(&format!("/statement/{canonical_id}")).into_response())
+            }
+            None => Err(AppError::not_found("Question")),
+        };
+    };
+    // views raise the priority of statements waiting for predictions
+    if let Err(err) = queue::raise_priority(statement_id, 1, &pool).await {
+        warn!("Unable to queue statement for predictions: {err:?}");
+    }
     let user_vote = match &maybe_user {
         Some(user) => user.get_vote(statement_id, &pool).await?,
         None => None,
     };
     let content = html! {
-        @if let Some(statement) = &statement {
-            div data-testid="current-statement" class="rounded-lg shadow bg-white dark:bg-slate-700 flex " {
-                div data-testid="statement-text" class="w-full text-xl p-6" {
-                    (statement.text)
-                }
-                @if user_vote.is_some() {
-                    (inline_statement_piechart(statement.id, &pool).await?)
-                    (inline_statement_vote(user_vote)?)
-                }
+        div data-testid="current-statement" class="rounded-lg shadow bg-white dark:bg-slate-700 flex " {
+            div data-testid="statement-text" class="w-full text-xl p-6" {
+                (statement.text)
             }
-            form hx-post="/vote" {
-                input type="hidden" value=(statement_id) name="statement_id";
-                div class="flex gap-2 mb-12 mt-3" {
-                    button class="text-white bg-green-600 px-4 py-1 rounded" name="vote" value="Yes" { "YES" }
-                    button class="text-white bg-red-600 px-4 py-1 rounded" name="vote" value="No" { "NO" }
-                    button class="px-4 py-1" name="vote" value="Skip" { "skip / I don't know" }
-                }
+            @if user_vote.is_some() {
+                (inline_statement_piechart(statement.id, &pool).await?)
+                (inline_statement_vote(user_vote)?)
             }
-            @match user_vote {
-                Some(_) => {
-                    h2 class="text-xl mb-4" { "Follow-ups" }
-                    @let followups = get_followups(statement_id, &pool).await?;
-                    @if followups.is_empty() {
-                        div { "No follow-ups yet." }
-                    }
-                    @for statement_id in followups {
-                        // TODO: different columns depending on vote-dependent follow up
-                        div class="mb-5 rounded-lg shadow bg-white dark:bg-slate-700 flex" {
-                            (inline_statement_content(&get_statement(statement_id, &pool).await?, None, true, &maybe_user, &pool).await?)
-                            (inline_statement_piechart(statement_id, &pool).await?)
-                            (inline_statement_vote_fetch(statement_id, &maybe_user, &pool).await?)
-                        }
+        }
+        form hx-post="/vote" {
+            input type="hidden" value=(statement_id) name="statement_id";
+            div class="flex gap-2 mb-12 mt-3" {
+                button class="text-white bg-green-600 px-4 py-1 rounded" name="vote" value="Yes" { "YES" }
+                button class="text-white bg-red-600 px-4 py-1 rounded" name="vote" value="No" { "NO" }
+                button class="text-white bg-blue-600 px-4 py-1 rounded" name="vote" value="ItDepends" { "IT DEPENDS" }
+                button class="px-4 py-1" name="vote" value="Skip" { "skip / I don't know" }
+                a class="ml-auto px-4 py-1" href=(format!("/statement/{statement_id}/relations/new")) { "link related question" }
+            }
+        }
+        (statement_relations(statement_id, &maybe_user, &pool).await?)
+        @match user_vote {
+            Some(_) => {
+                h2 class="text-xl mb-4" { "Follow-ups" }
+                @let followups = get_followups(statement_id, &pool).await?;
+                @if followups.is_empty() {
+                    div { "No follow-ups yet." }
+                }
+                @for statement_id in followups {
+                    // TODO: different columns depending on vote-dependent follow up
+                    div class="mb-5 rounded-lg shadow bg-white dark:bg-slate-700 flex" {
+                        (inline_statement_content(&get_statement(statement_id, &pool).await?, None, true, &maybe_user, &pool).await?)
+                        (inline_statement_piechart(statement_id, &pool).await?)
+                        (inline_statement_vote_fetch(statement_id, &maybe_user, &pool).await?)
                     }
                 }
-                None => (history(&maybe_user, &pool).await?)
             }
-        } @else {
-            div { "Question not found." }
+            None => (history(&maybe_user, &pool).await?)
+        }
+        @let related = similar_statements(statement_id, RELATED_STATEMENTS, &embeddings, &pool).await?;
+        @if !related.is_empty() {
+            h2 class="text-xl mb-4" { "Related questions" }
+        }
+        @for similar in related {
+            div class="mb-5 rounded-lg shadow bg-white dark:bg-slate-700 flex" {
+                (inline_statement_content(&similar.statement, None, true, &maybe_user, &pool).await?)
+                (inline_statement_piechart(similar.statement.id, &pool).await?)
+                (inline_statement_vote_fetch(similar.statement.id, &maybe_user, &pool).await?)
+            }
         }
     };
 
-    let page_meta = statement.as_ref().map(|statement| PageMeta {
+    let page_meta = PageMeta {
         title: Some("Yes or no?".to_string()),
         description: Some(statement.text.to_owned()),
         url: Some(format!("{}/statement/{}", base_url(&headers), statement_id)),
-    });
+    };
 
-    Ok(base.content(content).page_meta_opt(page_meta).into())
+    Ok(base
+        .content(content)
+        .page_meta(page_meta)
+        .render()
+        .into_response())
 }
 
 pub async fn history(maybe_user: &Option<User>, pool: &SqlitePool) -> Result<Markup, AppError> {
@@ -61,6 +61,7 @@ pub fn inline_statement_vote(vote: Option<Vote>) -> Result<Markup> {
     let vote_color = match vote {
         Some(Vote::Yes) => "bg-green-600",
         Some(Vote::No) => "bg-red-600",
+        Some(Vote::ItDepends) => "bg-blue-600",
         Some(Vote::Skip) => "",
         None => "",
     };
@@ -69,6 +70,7 @@ pub fn inline_statement_vote(vote: Option<Vote>) -> Result<Markup> {
             @match vote {
                 Some(Vote::Yes) => "YES",
                 Some(Vote::No) => "NO",
+                Some(Vote::ItDepends) => "DEPENDS",
                 Some(Vote::Skip) => "SKIP",
                 None => "",
             }
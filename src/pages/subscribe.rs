@@ -1,6 +1,7 @@
 use crate::{error::AppError, structs::User};
 
 use axum::{Extension, Form};
+use http::HeaderMap;
 
 use anyhow::Result;
 use maud::{html, Markup};
@@ -15,10 +16,11 @@ pub struct FollowForm {
 
 pub async fn subscribe(
     cookies: Cookies,
+    headers: HeaderMap,
     Extension(pool): Extension<SqlitePool>,
     Form(form_data): Form<FollowForm>,
 ) -> Result<Markup, AppError> {
-    let user = User::get_or_create(&cookies, &pool).await?;
+    let user = User::get_or_create(&cookies, &headers, &pool).await?;
     user.subscribe(form_data.statement_id, &pool).await?;
 
     Ok(html! { span class="opacity-50" { "subscribed" } })
@@ -0,0 +1,139 @@
+use crate::credentials::{create_challenge, MIN_PASSWORD_LEN};
+use crate::error::AppError;
+use crate::pages::base_template::BaseTemplate;
+use crate::structs::User;
+use crate::util::human_relative_time;
+use crate::webauthn::{verify_registration, CeremonyOptions, PasskeyRegistration, RelyingParty};
+
+use anyhow::Result;
+use axum::extract::Path;
+use axum::response::Redirect;
+use axum::{Extension, Form, Json};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use http::StatusCode;
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+#[derive(Deserialize)]
+pub struct PasswordForm {
+    username: String,
+    password: String,
+}
+
+pub async fn credentials(
+    user: User,
+    Extension(pool): Extension<SqlitePool>,
+    base: BaseTemplate,
+) -> Result<Markup, AppError> {
+    let username = user.username(&pool).await?;
+    let passkeys = user.passkeys(&pool).await?;
+
+    let content = html! {
+        h1 class="text-xl mb-4" { "Login" }
+        p class="mb-4" {
+            "Your account works without any of these. "
+            "Add a password or passkey to log in on another device, if you lose this one."
+        }
+        h2 class="text-lg mb-2" { "Password" }
+        form class="mb-4" method="post" action="/user/credentials/password" {
+            (base.csrf_field())
+            label class="block mb-2" {
+                span class="block" { "Username" }
+                input class="dark:text-black px-2 rounded border border-1 border-gray-400" type="text" name="username" autocomplete="username" value=[username.as_ref()] required;
+            }
+            label class="block mb-4" {
+                span class="block" { "Password" }
+                input class="dark:text-black px-2 rounded border border-1 border-gray-400" type="password" name="password" autocomplete="new-password" minlength=(MIN_PASSWORD_LEN) required;
+            }
+            button class="text-white bg-slate-500 px-4 py-1 rounded" {
+                @if username.is_some() { "Change password" } @else { "Set password" }
+            }
+        }
+        @if username.is_some() {
+            form class="mb-8" method="post" action="/user/credentials/password/remove" {
+                (base.csrf_field())
+                button class="text-red-600" { "Remove password" }
+            }
+        }
+        h2 class="text-lg mb-2 mt-8" { "Passkeys" }
+        ul class="mb-4" {
+            @for passkey in &passkeys {
+                li class="flex gap-4 mb-2" {
+                    span { (passkey.name) }
+                    span class="opacity-50" {
+                        "added " (human_relative_time(passkey.created))
+                        @if let Some(last_used) = passkey.last_used {
+                            ", last used " (human_relative_time(last_used))
+                        }
+                    }
+                    form method="post" action=(format!("/user/credentials/passkey/{}/delete", passkey.id)) {
+                        (base.csrf_field())
+                        button class="text-red-600" { "Remove" }
+                    }
+                }
+            }
+        }
+        input id="passkey-name" class="dark:text-black px-2 rounded border border-1 border-gray-400" type="text" placeholder="Name, e.g. My phone";
+        button type="button" class="ml-2 text-white bg-slate-500 px-4 py-1 rounded" onclick="propolisRegisterPasskey(document.getElementById('passkey-name').value)" { "Add passkey" }
+    };
+    Ok(base.title("Login").content(content).into())
+}
+
+pub async fn set_password(
+    user: User,
+    Extension(pool): Extension<SqlitePool>,
+    Form(form): Form<PasswordForm>,
+) -> Result<Redirect, AppError> {
+    user.set_password(form.username.trim(), &form.password, &pool)
+        .await?;
+    Ok(Redirect::to("/user/credentials"))
+}
+
+pub async fn remove_password(
+    user: User,
+    Extension(pool): Extension<SqlitePool>,
+) -> Result<Redirect, AppError> {
+    user.remove_password(&pool).await?;
+    Ok(Redirect::to("/user/credentials"))
+}
+
+pub async fn passkey_options(
+    user: User,
+    Extension(pool): Extension<SqlitePool>,
+) -> Result<Json<CeremonyOptions>, AppError> {
+    let user_name = match user.username(&pool).await? {
+        Some(username) => username,
+        None => format!("Propolis account {}", user.id),
+    };
+    Ok(Json(CeremonyOptions {
+        challenge: create_challenge(Some(&user), &pool).await?,
+        rp_id: RelyingParty::configured().id.to_owned(),
+        user_id: Some(URL_SAFE_NO_PAD.encode(user.id.to_string())),
+        user_name: Some(user_name),
+    }))
+}
+
+pub async fn add_passkey(
+    user: User,
+    Extension(pool): Extension<SqlitePool>,
+    Json(registration): Json<PasskeyRegistration>,
+) -> Result<StatusCode, AppError> {
+    let passkey = verify_registration(RelyingParty::configured(), &registration)
+        .map_err(|err| AppError::BadRequest(err.to_string()))?;
+    if !user.add_passkey(&registration, passkey, &pool).await? {
+        return Err(AppError::BadRequest(
+            "Unknown or expired challenge".to_string(),
+        ));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_passkey(
+    user: User,
+    Path(passkey_id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+) -> Result<Redirect, AppError> {
+    user.delete_passkey(passkey_id, &pool).await?;
+    Ok(Redirect::to("/user/credentials"))
+}
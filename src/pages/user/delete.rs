@@ -0,0 +1,78 @@
+use crate::auth::remove_auth_cookie;
+use crate::error::AppError;
+use crate::pages::base_template::BaseTemplate;
+use crate::structs::{AccountDeletion, User};
+
+use anyhow::Result;
+use axum::{Extension, Form};
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use tower_cookies::Cookies;
+
+#[derive(Deserialize)]
+pub struct DeleteAccountForm {
+    mode: AccountDeletion,
+    /// Checkbox, only sent when checked
+    confirmed: Option<bool>,
+}
+
+pub async fn delete_account(
+    user: User,
+    Extension(pool): Extension<SqlitePool>,
+    base: BaseTemplate,
+) -> Result<Markup, AppError> {
+    let num_votes = user.num_votes(&pool).await?;
+    let num_statements = user.num_statements(&pool).await?;
+
+    let content = html! {
+        h1 class="text-xl mb-4" { "Delete account" }
+        form method="post" action="/user/delete" {
+            (base.csrf_field())
+            fieldset class="mb-4" {
+                p class="mb-2" { "You cast " (num_votes) " votes and asked " (num_statements) " questions. What should happen to them?" }
+                label class="block mb-2" {
+                    input type="radio" name="mode" value="Anonymize" checked;
+                    span class="ml-1" { "Keep my votes anonymously, so that the results of questions stay the same" }
+                }
+                label class="block mb-2" {
+                    input type="radio" name="mode" value="Delete";
+                    span class="ml-1" { "Delete my votes" }
+                }
+                p class="opacity-50" { "Questions stay in both cases, but are no longer connected to you." }
+            }
+            label class="block mb-4" {
+                input type="checkbox" name="confirmed" value="true" required;
+                span class="ml-1" { "I understand that my account can't be restored" }
+            }
+            button class="text-white bg-red-600 px-4 py-1 rounded" { "Delete account" }
+        }
+    };
+    Ok(base.title("Delete account").content(content).into())
+}
+
+pub async fn delete_account_post(
+    user: User,
+    cookies: Cookies,
+    Extension(pool): Extension<SqlitePool>,
+    base: BaseTemplate,
+    Form(form): Form<DeleteAccountForm>,
+) -> Result<Markup, AppError> {
+    if form.confirmed != Some(true) {
+        return Err(AppError::BadRequest(
+            "Please confirm the deletion".to_string(),
+        ));
+    }
+    user.delete_account(form.mode, &pool).await?;
+    remove_auth_cookie(&cookies, &pool).await?;
+
+    let content = html! {
+        h1 class="text-xl mb-4" { "Account deleted" }
+        p { "Your account has been deleted." }
+    };
+    // render without the deleted user
+    Ok(BaseTemplate { user: None, ..base }
+        .title("Account deleted")
+        .content(content)
+        .into())
+}
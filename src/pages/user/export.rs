@@ -0,0 +1,24 @@
+use crate::error::AppError;
+use crate::export::UserExport;
+use crate::structs::User;
+
+use anyhow::Result;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use http::header::CONTENT_DISPOSITION;
+use sqlx::SqlitePool;
+
+/// Downloads everything stored about the user as a JSON file, see [crate::export]
+pub async fn export(
+    user: User,
+    Extension(pool): Extension<SqlitePool>,
+) -> Result<impl IntoResponse, AppError> {
+    let export = UserExport::create(&user, &pool).await?;
+    Ok((
+        [(
+            CONTENT_DISPOSITION,
+            "attachment; filename=\"propolis-export.json\"",
+        )],
+        Json(export),
+    ))
+}
@@ -0,0 +1,108 @@
+use crate::credentials::{create_challenge, user_id_from_passkey, user_id_from_password};
+use crate::error::AppError;
+use crate::pages::base_template::BaseTemplate;
+use crate::rate_limit::SharedRateLimits;
+use crate::sessions::log_in;
+use crate::structs::User;
+use crate::webauthn::{CeremonyOptions, PasskeyAssertion, RelyingParty};
+
+use anyhow::Result;
+use axum::response::Redirect;
+use axum::{Extension, Form, Json};
+use http::{HeaderMap, StatusCode};
+use maud::{html, Markup};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use tower_cookies::Cookies;
+
+#[derive(Deserialize)]
+pub struct PasswordLoginForm {
+    username: String,
+    password: String,
+}
+
+pub async fn login(base: BaseTemplate) -> Result<Markup, AppError> {
+    let content = html! {
+        h1 class="text-xl mb-4" { "Log in" }
+        p class="mb-4" {
+            "Log into an account which has a password or passkey. "
+            "Votes cast on this device are moved to that account."
+        }
+        form class="mb-8" method="post" action="/login" {
+            (base.csrf_field())
+            label class="block mb-2" {
+                span class="block" { "Username" }
+                input class="dark:text-black px-2 rounded border border-1 border-gray-400" type="text" name="username" autocomplete="username" required;
+            }
+            label class="block mb-4" {
+                span class="block" { "Password" }
+                input class="dark:text-black px-2 rounded border border-1 border-gray-400" type="password" name="password" autocomplete="current-password" required;
+            }
+            button class="text-white bg-slate-500 px-4 py-1 rounded" { "Log in" }
+        }
+        button type="button" class="text-white bg-slate-500 px-4 py-1 rounded" onclick="propolisLoginWithPasskey()" { "Log in with passkey" }
+    };
+    Ok(base.title("Log in").content(content).into())
+}
+
+pub async fn login_post(
+    headers: HeaderMap,
+    cookies: Cookies,
+    Extension(pool): Extension<SqlitePool>,
+    Extension(limits): Extension<SharedRateLimits>,
+    Form(form): Form<PasswordLoginForm>,
+) -> Result<Redirect, AppError> {
+    limits.limit_login(&form.username)?;
+    let account_id = user_id_from_password(&form.username, &form.password, &pool)
+        .await?
+        .ok_or(AppError::Unauthorized)?;
+    switch_account(account_id, "password", &headers, &cookies, &pool).await?;
+    Ok(Redirect::to("/"))
+}
+
+pub async fn passkey_login_options(
+    Extension(pool): Extension<SqlitePool>,
+) -> Result<Json<CeremonyOptions>, AppError> {
+    Ok(Json(CeremonyOptions {
+        challenge: create_challenge(None, &pool).await?,
+        rp_id: RelyingParty::configured().id.to_owned(),
+        user_id: None,
+        user_name: None,
+    }))
+}
+
+pub async fn passkey_login(
+    headers: HeaderMap,
+    cookies: Cookies,
+    Extension(pool): Extension<SqlitePool>,
+    Json(assertion): Json<PasskeyAssertion>,
+) -> Result<StatusCode, AppError> {
+    let account_id = user_id_from_passkey(RelyingParty::configured(), &assertion, &pool)
+        .await?
+        .ok_or(AppError::Unauthorized)?;
+    switch_account(account_id, "passkey", &headers, &cookies, &pool).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Logs the browser into the account
+///
+/// An anonymous account of the browser is merged into it, an account with credentials is kept,
+/// since it can be logged into again.
+async fn switch_account(
+    account_id: i64,
+    method: &str,
+    headers: &HeaderMap,
+    cookies: &Cookies,
+    pool: &SqlitePool,
+) -> Result<()> {
+    if let Some(device_user) = User::from_cookies(cookies, headers, pool).await? {
+        if device_user.id != account_id && !device_user.has_credentials(pool).await? {
+            let account = User {
+                id: account_id,
+                secret: None,
+            };
+            device_user.merge_into(&account, true, pool).await?;
+        }
+    }
+    log_in(account_id, method, headers, cookies, pool).await
+}
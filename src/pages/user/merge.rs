@@ -1,9 +1,12 @@
 use crate::pages::base_template::BaseTemplate;
 
+use crate::devices::{device_link_user_id, redeem_device_link};
+use crate::error::AppError;
+use crate::sessions::log_in;
 use crate::structs::User;
-use crate::{auth::change_auth_cookie, error::AppError};
 
 use axum::{extract::Path, Extension, Form};
+use http::HeaderMap;
 use maud::{html, Markup};
 use serde::Deserialize;
 use sqlx::SqlitePool;
@@ -21,33 +24,49 @@ pub struct MergeForm {
     value: MergeAnswer,
 }
 
+/// Account of a valid device link
+async fn valid_link_target(token: &str, pool: &SqlitePool) -> Result<i64, AppError> {
+    device_link_user_id(token, pool)
+        .await?
+        .ok_or(AppError::NotFound(
+            "This link is invalid or expired".to_string(),
+        ))
+}
+
+/// Fails if the link points to the account of this device
+fn other_account(target_id: i64, user: &User) -> Result<i64, AppError> {
+    if target_id == user.id {
+        return Err(AppError::Conflict(
+            "This device is already logged into this account".to_string(),
+        ));
+    }
+    Ok(target_id)
+}
+
 pub async fn merge(
-    Path(secret): Path<String>,
+    Path(token): Path<String>,
     cookies: Cookies,
+    headers: HeaderMap,
     Extension(pool): Extension<SqlitePool>,
     base: BaseTemplate,
 ) -> Result<Markup, AppError> {
-    let user = User::get_or_create(&cookies, &pool).await?;
+    // guessed links must not create accounts
+    let target_id = valid_link_target(&token, &pool).await?;
+    let user = User::get_or_create(&cookies, &headers, &pool).await?;
+    other_account(target_id, &user)?;
     let num_votes = user.num_votes(&pool).await?;
     let num_statements = user.num_statements(&pool).await?;
 
-    let current_secret = user.secret.to_owned();
-    let new_secret = secret;
-    if new_secret == current_secret {
-        // TODO: how to use anyhow::ensure! here?
-        return Err(AppError(anyhow::anyhow!("Cannot merge with same secret")));
-    }
-
     let content = html! {
         h1 { "Merge" }
-        form hx-post={"/merge/"(new_secret)} {
+        form hx-post={"/merge/"(token)} {
             fieldset {
-            big { "Switch from " code { (current_secret) } " to " code { (new_secret) } "?" }
+            big { "Switch this device to the account which created the link?" }
             p { "This will..." }
             ul {
-                li { "move " (num_votes) " votes and " (num_statements) " statements" }
-                li { "switch to new secret " code { (new_secret) } }
-                li { "delete old secret " code { (current_secret) } }
+                li { "move " (num_votes) " votes and " (num_statements) " statements of this device" }
+                li { "log this device into the other account, it can be revoked there in the options" }
+                li { "delete the account of this device" }
             }
             p { "Continue?" }
             button name="value" type="submit" value="Yes" { "yes" }
@@ -63,37 +82,31 @@ pub async fn merge(
 pub async fn merge_post(
     user: User,
     cookies: Cookies,
-    Path(secret): Path<String>,
+    headers: HeaderMap,
+    Path(token): Path<String>,
     Extension(pool): Extension<SqlitePool>,
     Form(merge): Form<MergeForm>,
 ) -> Result<Markup, AppError> {
-    Ok(match User::from_secret(secret.as_str(), &pool).await? {
-        Some(new_user) => {
-            if user.id == new_user.id {
-                return Ok(html! {"Merge aborted: same user"});
-            }
-
-            match merge.value {
-                MergeAnswer::Yes | MergeAnswer::YesWithoutMerge => {
-                    let tx = pool.begin().await.expect("Transaction begin failed");
-
-                    if merge.value == MergeAnswer::Yes {
-                        user.move_content_to(&new_user, &pool).await?;
-                    } else {
-                        user.delete_content(&pool).await?;
-                    }
-
-                    user.delete(&pool).await?;
-                    change_auth_cookie(new_user.secret.as_str(), &cookies);
-                    tx.commit().await.expect("Transaction commit failed");
+    if merge.value == MergeAnswer::No {
+        return Ok(html! {"Merge aborted."});
+    }
+    other_account(valid_link_target(&token, &pool).await?, &user)?;
 
-                    html! {"Merge successful"}
-                }
+    // the link is only used up if the merge goes through
+    let mut tx = pool.begin().await?;
+    Ok(match redeem_device_link(&token, &mut *tx).await? {
+        Some(target_id) => {
+            let move_content = merge.value == MergeAnswer::Yes;
+            let target = User {
+                id: target_id,
+                secret: None,
+            };
+            user.merge_into(&target, move_content, &mut *tx).await?;
+            tx.commit().await?;
+            log_in(target_id, "link", &headers, &cookies, &pool).await?;
 
-                MergeAnswer::No => html! {"Merge aborted."},
-            }
+            html! {"Merge successful"}
         }
-
-        None => html! {"Target user does not exist."},
+        None => html! {"This link is invalid or expired."},
     })
 }
@@ -1,3 +1,7 @@
+pub mod credentials;
+pub mod delete;
+pub mod export;
+pub mod login;
 pub mod merge;
 pub mod options;
 pub mod profile;
@@ -1,8 +1,18 @@
+use crate::auth::{remove_auth_cookie, rotate_secret};
+use crate::devices::{
+    AuditEntry, AuditEvent, LinkedDevice, DEVICE_LINK_SECONDS, RECENT_AUDIT_ENTRIES,
+};
 use crate::error::AppError;
 use crate::pages::base_template::BaseTemplate;
+use crate::sessions::{current_session, start_session, Session, SESSION_LIFETIME_SECONDS};
 use crate::structs::User;
-use crate::util::base_url;
+use crate::util::{base_url, human_relative_time};
+use axum::extract::Path;
+use axum::response::Redirect;
+use axum::Extension;
 use maud::{html, Markup};
+use sqlx::SqlitePool;
+use tower_cookies::Cookies;
 
 use anyhow::Result;
 
@@ -19,18 +29,109 @@ pub fn qr_code_base64(code: &String) -> String {
     general_purpose::STANDARD_NO_PAD.encode(code.render::<svg::Color>().build())
 }
 
-fn html(merge_url: &str, qr_code: &str) -> Markup {
+fn html(
+    sessions: &[Session],
+    current_session_id: Option<i64>,
+    devices: &[LinkedDevice],
+    audit_log: &[AuditEntry],
+    csrf_field: &Markup,
+) -> Markup {
     html! {
         fieldset {
-            p { "Use this QR Code on another device to switch it to this account:" }
-            img id="qr-code" src=(format!("data:image/svg+xml;base64,{qr_code}"));
-            br;
-            small {
-                "Or open ";
-                a href=( merge_url ) { ( merge_url ) }
-                " on your other device"
+            p { "Switch another device to this account with a link or QR code:" }
+            form method="post" action="/user/device_link" {
+                (csrf_field)
+                button { "Link a device" }
+            }
+        }
+        fieldset class="mt-8" {
+            p { "Logged in browsers:" }
+            ul {
+                @for session in sessions {
+                    li class="flex gap-4 mb-2" {
+                        span { (session.name) }
+                        span class="opacity-50" {
+                            @if current_session_id == Some(session.id) {
+                                "this browser, "
+                            }
+                            "logged in " (human_relative_time(session.created))
+                            ", last seen " (human_relative_time(session.last_seen))
+                        }
+                        form method="post" action=(format!("/user/sessions/{}/revoke", session.id)) {
+                            (csrf_field)
+                            button class="text-red-600" { "Log out" }
+                        }
+                    }
+                }
+            }
+            p class="opacity-50" {
+                "Browsers are logged out after " ((SESSION_LIFETIME_SECONDS / (24 * 60 * 60))) " days without a visit."
+            }
+        }
+        @if !devices.is_empty() {
+            fieldset class="mt-8" {
+                p { "Linked apps:" }
+                ul {
+                    @for device in devices {
+                        li class="flex gap-4 mb-2" {
+                            span { (device.name) }
+                            span class="opacity-50" {
+                                "linked " (human_relative_time(device.created))
+                                @if let Some(last_used) = device.last_used {
+                                    ", last used " (human_relative_time(last_used))
+                                }
+                            }
+                            form method="post" action=(format!("/user/devices/{}/revoke", device.id)) {
+                                (csrf_field)
+                                button class="text-red-600" { "Revoke" }
+                            }
+                        }
+                    }
+                }
             }
         }
+        @if !audit_log.is_empty() {
+            fieldset class="mt-8" {
+                p { "Recent activity:" }
+                ul {
+                    @for entry in audit_log {
+                        li {
+                            (AuditEvent::from_name(&entry.event).map(AuditEvent::label).unwrap_or(&entry.event))
+                            @if let Some(detail) = &entry.detail {
+                                ": " (detail)
+                            }
+                            span class="opacity-50" { " " (human_relative_time(entry.created)) }
+                        }
+                    }
+                }
+            }
+        }
+        fieldset class="mt-8" {
+            p { "Replace your secret, e.g. if someone else got access to this device. Other browsers, linked apps and links stop working:" }
+            form method="post" action="/user/secret/rotate" {
+                (csrf_field)
+                button class="text-red-600" { "Rotate secret" }
+            }
+        }
+        fieldset class="mt-8" {
+            p { "Add a password or passkey to log in from anywhere:" }
+            a href="/user/credentials" { "Login settings" }
+        }
+        fieldset class="mt-8" {
+            p { "Log this browser out. Without a password or passkey, you can't log back in:" }
+            form method="post" action="/logout" {
+                (csrf_field)
+                button class="text-red-600" { "Log out" }
+            }
+        }
+        fieldset class="mt-8" {
+            p { "Download everything we store about you as a JSON file:" }
+            a href="/user/export" download { "Export my data" }
+        }
+        fieldset class="mt-8" {
+            p { "Delete your account, with or without your votes:" }
+            a class="text-red-600" href="/user/delete" { "Delete account" }
+        }
         // TODO: save theme in localstorage
         // fieldset {
         //     label for="theme" { "theme" }
@@ -43,28 +144,104 @@ fn html(merge_url: &str, qr_code: &str) -> Markup {
 }
 
 pub async fn options(
-    headers: HeaderMap,
+    cookies: Cookies,
     maybe_user: Option<User>,
+    Extension(pool): Extension<SqlitePool>,
     base: BaseTemplate,
 ) -> Result<Markup, AppError> {
     let title = "Options";
 
     match maybe_user {
         Some(user) => {
-            let merge_url = format!("{}/merge/{}", base_url(&headers), &user.secret);
-            let content = html(&merge_url, qr_code_base64(&merge_url).as_str());
+            let content = html(
+                &user.sessions(&pool).await?,
+                current_session(&cookies, &pool)
+                    .await?
+                    .map(|session| session.id),
+                &user.linked_devices(&pool).await?,
+                &user.audit_log(RECENT_AUDIT_ENTRIES, &pool).await?,
+                &base.csrf_field(),
+            );
+            Ok(base.title(title).content(content).into())
+        }
+        None => {
+            let content = html! {
+                (warning_dialog("Options disabled until you cast your first vote.", None))
+                p class="mt-4" {
+                    "Already have an account with a password or passkey? "
+                    a href="/login" { "Log in" }
+                }
+            };
             Ok(base.title(title).content(content).into())
         }
-        None => Ok(base
-            .title(title)
-            .content(warning_dialog(
-                "Options disabled until you cast your first vote.",
-                None,
-            ))
-            .into()),
     }
 }
 
+/// Creates a one-time link to switch another device to this account
+pub async fn device_link_post(
+    user: User,
+    headers: HeaderMap,
+    Extension(pool): Extension<SqlitePool>,
+    base: BaseTemplate,
+) -> Result<Markup, AppError> {
+    let link = user.create_device_link(&pool).await?;
+    let merge_url = format!("{}/merge/{}", base_url(&headers), link.token);
+    let qr_code = qr_code_base64(&merge_url);
+    let content = html! {
+        fieldset {
+            p { "Use this QR Code on another device to switch it to this account:" }
+            img id="qr-code" src=(format!("data:image/svg+xml;base64,{qr_code}"));
+            br;
+            small {
+                "Or open ";
+                a href=( merge_url ) { ( merge_url ) }
+                " on your other device. "
+                "The link works once and expires in " ((DEVICE_LINK_SECONDS / 60)) " minutes."
+            }
+        }
+        a href="/options" { "Back to options" }
+    };
+    Ok(base.title("Link a device").content(content).into())
+}
+
+/// Rotates the secret, this browser stays logged in with a new session
+pub async fn rotate_secret_post(
+    user: User,
+    headers: HeaderMap,
+    cookies: Cookies,
+    Extension(pool): Extension<SqlitePool>,
+) -> Result<Redirect, AppError> {
+    rotate_secret(user.id, &pool).await?;
+    start_session(user.id, &headers, &cookies, &pool).await?;
+    Ok(Redirect::to("/options"))
+}
+
+pub async fn revoke_session_post(
+    user: User,
+    Path(session_id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+) -> Result<Redirect, AppError> {
+    user.revoke_session(session_id, &pool).await?;
+    Ok(Redirect::to("/options"))
+}
+
+pub async fn logout_post(
+    cookies: Cookies,
+    Extension(pool): Extension<SqlitePool>,
+) -> Result<Redirect, AppError> {
+    remove_auth_cookie(&cookies, &pool).await?;
+    Ok(Redirect::to("/"))
+}
+
+pub async fn revoke_device_post(
+    user: User,
+    Path(device_id): Path<i64>,
+    Extension(pool): Extension<SqlitePool>,
+) -> Result<Redirect, AppError> {
+    user.revoke_device(device_id, &pool).await?;
+    Ok(Redirect::to("/options"))
+}
+
 pub fn warning_dialog(msg: &str, caption: Option<&str>) -> Markup {
     html!(
         div.warn.card {
@@ -73,3 +250,57 @@ pub fn warning_dialog(msg: &str, caption: Option<&str>) -> Markup {
         }
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::LEGACY_SECRET_COOKIE;
+    use crate::csrf::{csrf_token_for_nonce, CSRF_COOKIE, CSRF_FIELD};
+    use crate::structs::User;
+    use crate::testing::{body_text, full_app};
+    use axum::body::Body;
+    use http::header::{CONTENT_TYPE, COOKIE};
+    use http::{Request, StatusCode};
+    use sqlx::SqlitePool;
+    use tower::ServiceExt;
+
+    async fn device_links(pool: &SqlitePool) -> anyhow::Result<i32> {
+        Ok(
+            sqlx::query_scalar!("SELECT COUNT(*) FROM device_link_tokens")
+                .fetch_one(pool)
+                .await?,
+        )
+    }
+
+    #[sqlx::test]
+    async fn only_posts_create_device_links(pool: SqlitePool) -> anyhow::Result<()> {
+        let app = full_app(&pool);
+        let user = User::create(&pool).await?;
+        let cookie = format!(
+            "{LEGACY_SECRET_COOKIE}={}; {CSRF_COOKIE}=nonce",
+            user.known_secret()?
+        );
+
+        let request = Request::get("/options")
+            .header(COOKIE, &cookie)
+            .body(Body::empty())?;
+        let response = app.clone().oneshot(request).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!body_text(response).await.contains("/merge/"));
+        assert_eq!(device_links(&pool).await?, 0);
+
+        let token = csrf_token_for_nonce("nonce");
+        let body = format!(
+            "{CSRF_FIELD}={}",
+            token.replace('+', "%2B").replace('/', "%2F")
+        );
+        let request = Request::post("/user/device_link")
+            .header(COOKIE, &cookie)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(body))?;
+        let response = app.oneshot(request).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(body_text(response).await.contains("/merge/"));
+        assert_eq!(device_links(&pool).await?, 1);
+        Ok(())
+    }
+}
@@ -5,12 +5,13 @@ use crate::structs::Vote;
 use anyhow::Result;
 
 use axum::{response::IntoResponse, Extension, Form};
-use http::StatusCode;
+use http::{HeaderMap, StatusCode};
 use serde::Deserialize;
 use sqlx::SqlitePool;
 use tower_cookies::Cookies;
 
 use crate::pages::statement::next_statement_id;
+use crate::selection::SharedSelectionStrategy;
 
 #[derive(Deserialize)]
 pub struct VoteForm {
@@ -20,17 +21,19 @@ pub struct VoteForm {
 
 pub async fn vote_post(
     cookies: Cookies,
+    headers: HeaderMap,
     Extension(pool): Extension<SqlitePool>,
+    Extension(selection): Extension<SharedSelectionStrategy>,
     Form(vote_form): Form<VoteForm>,
 ) -> Result<impl IntoResponse, AppError> {
-    let user = User::get_or_create(&cookies, &pool).await?;
+    let user = User::get_or_create(&cookies, &headers, &pool).await?;
 
     user.vote(vote_form.statement_id, vote_form.vote, &pool)
         .await?;
 
     match vote_form.vote {
-        Vote::Yes | Vote::No | Vote::Skip => {
-            let next_statement_id = next_statement_id(Some(user), &pool).await?;
+        Vote::Yes | Vote::No | Vote::Skip | Vote::ItDepends => {
+            let next_statement_id = next_statement_id(Some(user), &*selection, &pool).await?;
             let redirect_url = match next_statement_id {
                 Some(statement_id) => format!("/statement/{statement_id}"),
                 None => "/statement".to_string(),
@@ -0,0 +1,143 @@
+//! AI backends as selected via [PredictionArgs]
+
+use ai_prompt::{
+    backend::{AiBackend, EmbeddingBackend},
+    compatible::OpenAiCompatibleEnv,
+    hashing::HashingEmbeddingEnv,
+    openai::{OpenAiEnv, OpenAiModel},
+};
+use anyhow::{anyhow, Result};
+use propolis_datas::apikey::{ApiKey, TransientApiKey};
+use sqlx::SqlitePool;
+
+use crate::command_line_args::{AiBackendKind, EmbeddingBackendKind, PredictionArgs};
+
+/// Stored as hash of the api key which results of backends without openai keys are stored with
+pub const KEYLESS_API_KEY_HASH: &str = "keyless";
+
+fn compatible_env(args: &PredictionArgs) -> Result<OpenAiCompatibleEnv> {
+    Ok(OpenAiCompatibleEnv::new(
+        args.ai_base_url
+            .as_deref()
+            .ok_or(anyhow!("openai-compatible backends need --ai-base-url"))?,
+        args.ai_model.as_deref().unwrap_or_default(),
+        args.embedding_model.as_deref().unwrap_or_default(),
+        args.ai_base_url_api_key.to_owned(),
+    ))
+}
+
+/// Backend to run predictions with
+pub fn ai_backend(args: &PredictionArgs) -> Result<AiBackend> {
+    Ok(match args.ai_backend {
+        AiBackendKind::Openai => AiBackend::OpenAi(OpenAiEnv::from(match &args.ai_model {
+            Some(model) => model.parse()?,
+            None => OpenAiModel::Gpt35Turbo,
+        })),
+        AiBackendKind::OpenaiCompatible => {
+            if args.ai_model.is_none() {
+                return Err(anyhow!("openai-compatible predictions need --ai-model"));
+            }
+            AiBackend::OpenAiCompatible(compatible_env(args)?)
+        }
+    })
+}
+
+/// Backend to embed statements and search queries with
+pub fn embedding_backend(args: &PredictionArgs) -> Result<EmbeddingBackend> {
+    Ok(match args.embedding_backend {
+        EmbeddingBackendKind::Openai => {
+            EmbeddingBackend::OpenAi(OpenAiEnv::from(OpenAiModel::Gpt35Turbo))
+        }
+        EmbeddingBackendKind::OpenaiCompatible => {
+            if args.embedding_model.is_none() {
+                return Err(anyhow!(
+                    "openai-compatible embeddings need --embedding-model"
+                ));
+            }
+            EmbeddingBackend::OpenAiCompatible(compatible_env(args)?)
+        }
+        EmbeddingBackendKind::Hashing => EmbeddingBackend::Hashing(HashingEmbeddingEnv::default()),
+    })
+}
+
+/// Api key to store results of backends without openai keys with, since every prediction and
+/// embedding references one
+pub async fn keyless_api_key(pool: &mut SqlitePool) -> Result<ApiKey> {
+    ApiKey::get_or_create(
+        pool,
+        &TransientApiKey::Hashed(KEYLESS_API_KEY_HASH.into()),
+        Some("used by backends without openai keys"),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ai_prompt::api::{AsEmbeddingEnv, WithApiKey};
+    use axum::{routing::post, Json, Router};
+    use clap::Parser;
+    use serde_json::{json, Value};
+
+    fn args(args: &[&str]) -> PredictionArgs {
+        PredictionArgs::parse_from([&["propolis"], args].concat())
+    }
+
+    #[test]
+    fn compatible_backends_need_url_and_model() {
+        let compatible = ["--ai-backend", "openai-compatible"];
+        assert!(ai_backend(&args(&compatible)).is_err());
+        assert!(ai_backend(&args(&[&compatible[..], &["--ai-model", "llama"]].concat())).is_err());
+        let env = ai_backend(&args(
+            &[
+                &compatible[..],
+                &[
+                    "--ai-model",
+                    "llama",
+                    "--ai-base-url",
+                    "http://localhost:8080/v1",
+                ],
+            ]
+            .concat(),
+        ))
+        .unwrap();
+        assert!(!env.uses_openai_key());
+        assert!(ai_backend(&args(&["--ai-model", "llama"])).is_err());
+        assert!(ai_backend(&args(&[])).unwrap().uses_openai_key());
+    }
+
+    #[tokio::test]
+    async fn compatible_server_embeds_in_input_order() {
+        // answers in reverse order, like servers processing inputs in parallel may do
+        let app = Router::new().route(
+            "/v1/embeddings",
+            post(|Json(body): Json<Value>| async move {
+                assert_eq!(body["model"], "nomic-embed");
+                let inputs = body["input"].as_array().unwrap().len();
+                let data: Vec<Value> = (0..inputs)
+                    .rev()
+                    .map(|i| json!({ "index": i, "embedding": [i as f64, 1.0] }))
+                    .collect();
+                Json(json!({ "data": data, "usage": { "prompt_tokens": 7, "total_tokens": 7 } }))
+            }),
+        );
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let base_url = format!("http://{}/v1/", server.local_addr());
+        tokio::spawn(server);
+
+        let env = embedding_backend(&args(&[
+            "--embedding-backend",
+            "openai-compatible",
+            "--embedding-model",
+            "nomic-embed",
+            "--ai-base-url",
+            &base_url,
+        ]))
+        .unwrap();
+        let result = env.embed(&["a", "b", "c"]).await.unwrap();
+        let firsts: Vec<f64> = result.data.iter().map(|e| e.values[0]).collect();
+        assert_eq!(firsts, vec![0.0, 1.0, 2.0]);
+        assert_eq!(result.total_tokens, 7);
+    }
+}
@@ -0,0 +1,203 @@
+//! What API keys spent, priced by the list prices of the used models
+//!
+//! Spend is derived from the tokens stored with every prediction, embedding and embedded search
+//! query, so it is the same across restarts. Periods are calendar days and months in UTC, like
+//! provider invoices.
+
+use std::collections::HashMap;
+
+use ai_prompt::openai::{model_price, EMBEDDING_MODEL};
+use sqlx::SqlitePool;
+use tracing::warn;
+
+use crate::command_line_args::PredictionArgs;
+use crate::prediction::backend::KEYLESS_API_KEY_HASH;
+
+/// USD spent by an API key
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct KeySpend {
+    pub today: f64,
+    pub month: f64,
+}
+
+impl KeySpend {
+    fn add(&mut self, today: bool, cost: f64) {
+        if today {
+            self.today += cost;
+        }
+        self.month += cost;
+    }
+}
+
+/// USD each API key may spend per period
+#[derive(Clone, Copy, Debug)]
+pub struct Budget {
+    pub daily: Option<f64>,
+    pub monthly: Option<f64>,
+}
+
+impl Budget {
+    pub fn from_args(args: &PredictionArgs) -> Self {
+        Budget {
+            daily: args.api_key_daily_budget,
+            monthly: args.api_key_monthly_budget,
+        }
+    }
+
+    /// Whether a key which spent `spend` may still be used
+    pub fn allows(&self, spend: &KeySpend) -> bool {
+        self.daily.is_none_or(|daily| spend.today < daily)
+            && self.monthly.is_none_or(|monthly| spend.month < monthly)
+    }
+}
+
+/// Spend of every API key which was used this month, by api key id
+pub async fn key_spend(pool: &SqlitePool) -> anyhow::Result<HashMap<i64, KeySpend>> {
+    let mut spend: HashMap<i64, KeySpend> = HashMap::new();
+
+    let predictions = sqlx::query!(
+        r#"SELECT api_key_id, ai_env,
+                  created >= CAST(strftime('%s', 'now', 'start of day') AS INTEGER) as "today!: bool",
+                  SUM(prompt_tokens) as "prompt_tokens!: i64",
+                  SUM(completion_tokens) as "completion_tokens!: i64"
+           FROM statement_predictions
+           WHERE created >= CAST(strftime('%s', 'now', 'start of month') AS INTEGER)
+           GROUP BY 1, 2, 3"#
+    )
+    .fetch_all(pool)
+    .await?;
+    for row in predictions {
+        // ai_env is stored as "model,provider", prices are only known for openai
+        let Some((model, "openai")) = row.ai_env.split_once(',') else {
+            continue;
+        };
+        let Some(price) = model_price(model) else {
+            warn!("No price known for model {model}, its usage is not counted as spend");
+            continue;
+        };
+        spend.entry(row.api_key_id).or_default().add(
+            row.today,
+            price.cost(row.prompt_tokens, row.completion_tokens),
+        );
+    }
+
+    // statements embedded together store their share of the tokens of the call. Embeddings of
+    // backends without openai keys are stored with the keyless key and cost nothing.
+    let embeddings = sqlx::query!(
+        r#"SELECT api_key_id as "api_key_id!: i64",
+                  created >= CAST(strftime('%s', 'now', 'start of day') AS INTEGER) as "today!: bool",
+                  SUM(tokens) as "tokens!: i64"
+           FROM (SELECT api_key_id, created, prompt_tokens as tokens
+                 FROM statement_embeddings
+                 UNION ALL
+                 SELECT api_key_id, created, prompt_tokens as tokens
+                 FROM search_embeddings)
+           WHERE created >= CAST(strftime('%s', 'now', 'start of month') AS INTEGER)
+             AND api_key_id NOT IN (SELECT id FROM api_keys WHERE hash = ?)
+           GROUP BY 1, 2"#,
+        KEYLESS_API_KEY_HASH
+    )
+    .fetch_all(pool)
+    .await?;
+    let price = model_price(EMBEDDING_MODEL).expect("Embedding model has a price");
+    for row in embeddings {
+        spend
+            .entry(row.api_key_id)
+            .or_default()
+            .add(row.today, price.cost(row.tokens, 0));
+    }
+
+    Ok(spend)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn spend_is_priced_per_model_and_period(pool: SqlitePool) -> anyhow::Result<()> {
+        sqlx::query!("INSERT INTO api_keys (id, hash) VALUES (1, 'a'), (2, 'b')")
+            .execute(&pool)
+            .await?;
+        let today =
+            sqlx::query_scalar!(r#"SELECT CAST(strftime('%s', 'now') AS INTEGER) as "now!: i64""#)
+                .fetch_one(&pool)
+                .await?;
+        let this_month = sqlx::query_scalar!(
+            r#"SELECT CAST(strftime('%s', 'now', 'start of month') AS INTEGER) as "t!: i64""#
+        )
+        .fetch_one(&pool)
+        .await?;
+        let last_month = this_month - 24 * 3600;
+        for (statement_id, ai_env, created) in [
+            (1, "gpt-4,openai", today),
+            (2, "gpt-3.5-turbo,openai", this_month),
+            (3, "gpt-4,openai", last_month),
+            (4, "unknown,openai", today),
+        ] {
+            sqlx::query!(
+                "INSERT INTO statements (id, text) VALUES (?, 'text')",
+                statement_id
+            )
+            .execute(&pool)
+            .await?;
+            sqlx::query!(
+                r#"INSERT INTO statement_predictions
+                (statement_id, ai_env, prompt_name, prompt_version, prompt_result,
+                 completion_tokens, prompt_tokens, api_key_id, created)
+                VALUES (?, ?, 'prompt', 1, 'result', 1000000, 1000000, 1, ?)"#,
+                statement_id,
+                ai_env,
+                created,
+            )
+            .execute(&pool)
+            .await?;
+        }
+        // one embedding call for two statements, each with half of its tokens, and a search query
+        for statement_id in [1, 2] {
+            sqlx::query!(
+                r#"INSERT INTO statement_embeddings
+                (statement_id, data, prompt_tokens, api_key_id, created)
+                VALUES (?, NULL, 5000000, 2, ?)"#,
+                statement_id,
+                today,
+            )
+            .execute(&pool)
+            .await?;
+        }
+        sqlx::query!(
+            r#"INSERT INTO search_embeddings (api_key_id, model, prompt_tokens, created)
+            VALUES (2, 'text-embedding-ada-002,openai', 5000000, ?)"#,
+            today,
+        )
+        .execute(&pool)
+        .await?;
+
+        let spend = key_spend(&pool).await?;
+        let first = spend[&1];
+        let second = spend[&2];
+        // today may be the first of the month
+        let expected_today = if today - this_month < 24 * 3600 {
+            92.0
+        } else {
+            90.0
+        };
+        assert_eq!(first.month, 92.0);
+        assert_eq!(first.today, expected_today);
+        assert_eq!(
+            second,
+            KeySpend {
+                today: 1.5,
+                month: 1.5
+            }
+        );
+
+        let budget = Budget {
+            daily: None,
+            monthly: Some(50.0),
+        };
+        assert!(!budget.allows(&first));
+        assert!(budget.allows(&second));
+        Ok(())
+    }
+}
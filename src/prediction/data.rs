@@ -45,7 +45,7 @@ impl BigFivePersonaValue {
 }
 
 #[derive(Debug)]
-#[allow(unused_imports)]
+#[allow(unused_imports, dead_code)]
 pub struct BigFivePersonaTrait {
     pub axis: BigFivePersonaAxis,
     pub value: BigFivePersonaValue,
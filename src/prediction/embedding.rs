@@ -1,50 +1,107 @@
 use ai_prompt::api::{AsEmbeddable, AsEmbeddingEnv, Embedding};
-use rl_queue::{QuotaState, RateLimiter};
+use rl_queue::{QuotaState, RateLimiter, Usage};
 use sqlx::SqlitePool;
-use std::time::Instant;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use tracing::{info, warn};
 
+use crate::prediction::queue::notify;
 use crate::structs::Statement;
 
 /// Used to select statements from the db for various uses
 pub struct StatementSelector {}
 
 impl StatementSelector {
-    /// Returns the next statements to embed
-    pub async fn next_for_embedding(&self, pool: &SqlitePool) -> anyhow::Result<Vec<Statement>> {
+    /// Returns up to `limit` queued statements without an embedding of `model` to embed next,
+    /// the most viewed first
+    pub async fn next_for_embedding(
+        &self,
+        pool: &SqlitePool,
+        model: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Statement>> {
+        let limit = limit as i64;
         Ok(sqlx::query_as!(
             Statement,
             "
             SELECT id, text from statements
-            WHERE id NOT IN (SELECT id FROM statement_embeddings)
-            LIMIT 100"
+            JOIN prediction_queue ON prediction_queue.statement_id = statements.id
+            WHERE id NOT IN (SELECT statement_id FROM statement_embeddings WHERE model = ?)
+            ORDER BY prediction_queue.priority DESC, prediction_queue.created
+            LIMIT ?",
+            model,
+            limit
         )
         .fetch_all(pool)
         .await?)
     }
 }
 
+/// Queues the statements embedded with another model than `model`, so they are embedded again
+///
+/// Returns how many were queued.
+pub async fn enqueue_outdated_embeddings(model: &str, pool: &SqlitePool) -> anyhow::Result<u64> {
+    let queued = sqlx::query!(
+        "insert into prediction_queue (statement_id)
+        select statement_id from statement_embeddings where model != ?
+        on conflict (statement_id) do nothing",
+        model
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if queued > 0 {
+        notify();
+    }
+    Ok(queued)
+}
+
+/// Tokens used for embeddings since the unix time `since`, to restore token quotas
+///
+/// Statements embedded together store their share of the tokens of the call. Embedded search
+/// queries are counted as well.
+pub async fn embedding_token_usage(since: i64, pool: &SqlitePool) -> anyhow::Result<Vec<Usage>> {
+    let rows = sqlx::query!(
+        r#"SELECT created as "created!", SUM(total_tokens) as "tokens!: i64"
+           FROM statement_embeddings
+           WHERE created > ?
+           GROUP BY created
+           UNION ALL
+           SELECT created, total_tokens
+           FROM search_embeddings
+           WHERE created > ?"#,
+        since,
+        since
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| Usage {
+            at: UNIX_EPOCH + Duration::from_secs(row.created as u64),
+            amount: row.tokens as f64,
+        })
+        .collect())
+}
+
 /// Runner for calculating embeddings
-pub struct EmbeddingsRunner<'a, E: AsEmbeddingEnv + 'a> {
+pub struct EmbeddingsRunner {
     /// Used to set a rate based on the amount of tokens that we have used overall
     pub token_rate_limiter: RateLimiter,
     /// Used to set a rate based on how many API calls were done
     pub api_calls_rate_limiter: RateLimiter,
-    pub env: &'a E,
 }
 
-impl<'a, E: AsEmbeddingEnv> EmbeddingsRunner<'a, E> {
-    /// Run the given prompt and return the result
-    pub async fn run<I: AsEmbeddable>(
-        &mut self,
+impl EmbeddingsRunner {
+    /// Embed the given items with `env` and return the result
+    pub async fn run<E: AsEmbeddingEnv, I: AsEmbeddable>(
+        &self,
+        env: &E,
         items: &[I],
     ) -> anyhow::Result<(Vec<Embedding>, u32)> {
         self.token_rate_limiter.block_until_ok().await;
-        self.api_calls_rate_limiter.block_until_ok().await;
-        self.api_calls_rate_limiter.add(1_f64);
+        self.api_calls_rate_limiter.acquire(1).await;
 
-        let response = self
-            .env
+        let response = env
             .embed(
                 items
                     .iter()
@@ -1,3 +1,9 @@
+#[cfg(feature = "with_predictions")]
+pub mod backend;
+
+#[cfg(feature = "with_predictions")]
+pub mod budget;
+
 #[cfg(feature = "with_predictions")]
 pub mod data;
 
@@ -10,17 +16,28 @@ pub mod multi_statement_classifier;
 #[cfg(feature = "with_predictions")]
 pub mod prompts;
 
+pub mod queue;
+
 #[cfg(feature = "with_predictions")]
 pub mod runner;
 
+#[cfg(feature = "with_predictions")]
+pub mod workers;
+
 #[cfg(not(feature = "with_predictions"))]
 pub mod runner {
     use anyhow::Result;
     use sqlx::SqlitePool;
 
+    /// There is no predictor without predictions
+    pub type SharedPredictor = std::sync::Arc<()>;
+
     pub async fn run(
-        _args: &crate::command_line_args::PredictionArgs,
-        _pool: &SqlitePool,
+        _args: crate::command_line_args::PredictionArgs,
+        _embeddings: crate::db::SharedEmbeddingCache,
+        _predictor: SharedPredictor,
+        _pool: SqlitePool,
+        _shutdown: crate::shutdown::Shutdown,
     ) -> Result<()> {
         Ok(())
     }
@@ -7,6 +7,7 @@ use crate::structs::{Statement, StatementPrediction};
 use ai_prompt::api::{AiMessage, AiPrompt, PromptResponse};
 
 use propolis_datas::apikey::ApiKey;
+use propolis_datas::statement::StatementFlagState;
 
 /// Helper trait to specify which other traits a type must fulfil in order to be used as a result type
 /// of a prompt.
@@ -94,13 +95,13 @@ impl<R: MultiStatementResultTypes> MultiStatementPromptResult<R> {
 }
 
 /// Used to generate prompts and handle the result
-pub struct MultiStatementPromptGen<'a, R: MultiStatementResultTypes> {
+pub struct MultiStatementPromptGen<R: MultiStatementResultTypes> {
     /// Amount of statements to include in the prompt
     pub batch_size: u8,
     /// Fn taking a batch of statements and yielding a prompt to run
     pub prompt: fn(Vec<Statement>) -> MultiStatementPrompt<R>,
     /// Used for database access to e.g. find next statements to run the prompt on
-    pub pool: &'a SqlitePool,
+    pub pool: SqlitePool,
 }
 
 impl<R: MultiStatementResultTypes> AiPrompt for MultiStatementPrompt<R> {
@@ -128,19 +129,25 @@ impl<R: MultiStatementResultTypes> AiPrompt for MultiStatementPrompt<R> {
     }
 }
 
-impl<'a, R: MultiStatementResultTypes> MultiStatementPromptGen<'a, R> {
-    pub async fn unflagged_batch(&self) -> anyhow::Result<Vec<Statement>> {
+impl<R: MultiStatementResultTypes> MultiStatementPromptGen<R> {
+    /// Returns the next queued statements to predict in a batch, the most viewed first. `extra`
+    /// statements are returned on top of the batch size, to make up for those other workers are
+    /// predicting already.
+    pub async fn unflagged_batch(&self, extra: usize) -> anyhow::Result<Vec<Statement>> {
         // -- create a dummy prompt so we can figure out for which (name, version) pair to look for --
         let dummy_statement = Statement {
             id: 0,
             text: "".into(),
         };
         let dummy_prompt = (self.prompt)(vec![dummy_statement]);
+        let limit = self.batch_size as i64 + extra as i64;
 
         // -- find those statements for which a prediction is missing --
         let stmts = sqlx::query_as!(
             Statement,
-            "SELECT id,text FROM statements WHERE
+            "SELECT id,text FROM statements
+JOIN prediction_queue ON prediction_queue.statement_id = statements.id
+WHERE
 id NOT IN
   (SELECT statement_id
    FROM statement_predictions
@@ -153,29 +160,37 @@ id NOT IN
 (SELECT statement_id
    FROM statement_flags
 )
+ORDER BY prediction_queue.priority DESC, prediction_queue.created
 LIMIT ?",
             dummy_prompt.name,
             dummy_prompt.version,
-            self.batch_size,
+            limit,
         )
-        .fetch_all(self.pool)
+        .fetch_all(&self.pool)
         .await?;
         Ok(stmts)
     }
 
-    /// Return those statements that are flagged with a particular flag_state
-    pub async fn next_with_flag(&self, flag_state: i64) -> anyhow::Result<Vec<Statement>> {
+    /// Return up to `limit` queued statements that are flagged with a particular flag_state
+    pub async fn next_with_flag(
+        &self,
+        flag_state: i64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Statement>> {
         // -- create a dummy prompt so we can figure out for which (name, version) pair to look for --
         let dummy_statement = Statement {
             id: 0,
             text: "".into(),
         };
         let dummy_prompt = (self.prompt)(vec![dummy_statement]);
+        let limit = limit as i64;
 
         // -- find those statements for which a prediction is missing --
         let stmts = sqlx::query_as!(
             Statement,
-            "SELECT id,text FROM statements WHERE
+            "SELECT id,text FROM statements
+JOIN prediction_queue ON prediction_queue.statement_id = statements.id
+WHERE
 id NOT IN
   (SELECT statement_id
    FROM statement_predictions
@@ -190,53 +205,47 @@ id IN
    WHERE
      state = ?
 )
-LIMIT 1",
+ORDER BY prediction_queue.priority DESC, prediction_queue.created
+LIMIT ?",
             dummy_prompt.name,
             dummy_prompt.version,
             flag_state,
+            limit,
         )
-        .fetch_all(self.pool)
+        .fetch_all(&self.pool)
         .await?;
         Ok(stmts)
     }
 
-    /// Returns the next batch of statements to predict
-    pub async fn next_batch(&self) -> anyhow::Result<Vec<Statement>> {
-        // -- create a dummy prompt so we can figure out for which (name, version) pair to look for --
+    /// Removes the statements from the prediction queue, which are predicted or flagged and
+    /// embedded with `embedding_model`
+    pub async fn dequeue_done(&self, embedding_model: &str) -> anyhow::Result<()> {
         let dummy_statement = Statement {
             id: 0,
             text: "".into(),
         };
         let dummy_prompt = (self.prompt)(vec![dummy_statement]);
-
-        // -- find those statements for which a prediction is missing --
-        let unflagged = self.unflagged_batch().await?;
-        // -- also, go through those statements that have been flagged with MaybeFlagged individually --
-        let stmts = match unflagged.as_slice() {
-            [] => self.next_with_flag(1).await?,
-            _ => unflagged,
-        };
-
-        if !stmts.is_empty() {
-            debug!(
-                "Next batch len for {} V{}: {}",
-                dummy_prompt.name,
-                dummy_prompt.version,
-                stmts.len()
-            );
-        }
-        Ok(stmts)
-    }
-
-    /// Returns a prompt for the next batch of statements
-    pub async fn next_prompt(&self) -> anyhow::Result<Option<MultiStatementPrompt<R>>> {
-        let batch = self.next_batch().await?;
-        if !batch.is_empty() {
-            let prompt = (self.prompt)(batch);
-
-            Ok(Some(prompt))
-        } else {
-            Ok(None)
-        }
+        let flagged = StatementFlagState::Flagged as i64;
+
+        sqlx::query!(
+            "DELETE FROM prediction_queue WHERE
+statement_id IN (SELECT statement_id FROM statement_embeddings WHERE model = ?) AND (
+  statement_id IN
+    (SELECT statement_id
+     FROM statement_predictions
+     WHERE
+       prompt_name = ? AND
+       prompt_version = ?
+  ) OR
+  statement_id IN (SELECT statement_id FROM statement_flags WHERE state = ?)
+)",
+            embedding_model,
+            dummy_prompt.name,
+            dummy_prompt.version,
+            flagged,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
     }
 }
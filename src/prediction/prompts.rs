@@ -321,10 +321,9 @@ num|category|label1|label2|label3|tag1|tag2|tag3
 #[test]
 fn test_statement_meta_from_lines() {
     let v = StatementMeta::from_lines(
-        concat!(
-            "1|politics|conservatism:s|nationalism:s|law and order:s|immigration:s|border security:w|protectionism:w\n",
-        )
-        ).unwrap();
+        "1|politics|conservatism:s|nationalism:s|law and order:s|immigration:s|border security:w|protectionism:w\n",
+    )
+    .unwrap();
     assert_eq!(v.value.len(), 1);
     let meta = &v.value[0];
     match meta {
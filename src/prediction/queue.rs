@@ -0,0 +1,98 @@
+//! Statements waiting for predictions and embeddings
+//!
+//! Statements are queued when they are created. Views raise the priority of statements which are
+//! still queued, so the most viewed are worked on first. Waiting workers are woken whenever
+//! statements are queued.
+
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use sqlx::SqlitePool;
+use tokio::sync::watch;
+
+/// Changed whenever there is new work for the prediction workers
+static NEW_WORK: Lazy<watch::Sender<()>> = Lazy::new(|| watch::channel(()).0);
+
+/// Queues the statement for predictions and embeddings, or raises its priority by `priority` if
+/// it is queued already
+pub async fn enqueue(statement_id: i64, priority: i64, pool: &SqlitePool) -> Result<()> {
+    let queued = sqlx::query!(
+        "insert into prediction_queue (statement_id, priority) values (?, ?)
+        on conflict (statement_id) do nothing",
+        statement_id,
+        priority
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    match queued {
+        0 => raise_priority(statement_id, priority, pool).await,
+        _ => {
+            notify();
+            Ok(())
+        }
+    }
+}
+
+/// Raises the priority of the statement by `priority` if it is queued. Statements which are not
+/// queued have all their results already.
+pub async fn raise_priority(statement_id: i64, priority: i64, pool: &SqlitePool) -> Result<()> {
+    sqlx::query!(
+        "update prediction_queue set priority = priority + ? where statement_id = ?",
+        priority,
+        statement_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Wakes the waiting prediction workers
+pub fn notify() {
+    NEW_WORK.send_replace(());
+}
+
+/// Marked as changed whenever there is new work, i.e. on [notify]
+#[cfg_attr(not(feature = "with_predictions"), allow(dead_code))]
+pub fn subscribe() -> watch::Receiver<()> {
+    NEW_WORK.subscribe()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::User;
+    use crate::testing::init_auth;
+
+    #[sqlx::test]
+    async fn created_statements_are_queued_and_raised_by_views(
+        pool: SqlitePool,
+    ) -> anyhow::Result<()> {
+        init_auth();
+        let mut new_work = subscribe();
+        new_work.borrow_and_update();
+        let user = User::create(&pool).await?;
+        let statement_id = user.add_statement("Is the world flat?", &pool).await?;
+        assert!(new_work.has_changed()?);
+
+        enqueue(statement_id, 1, &pool).await?;
+        raise_priority(statement_id, 2, &pool).await?;
+        let priority = sqlx::query_scalar!(
+            "select priority from prediction_queue where statement_id = ?",
+            statement_id
+        )
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!(priority, 3);
+
+        // done statements are not queued again by views
+        sqlx::query!("delete from prediction_queue")
+            .execute(&pool)
+            .await?;
+        raise_priority(statement_id, 1, &pool).await?;
+        let queued = sqlx::query_scalar!("select count(*) from prediction_queue")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(queued, 0);
+        Ok(())
+    }
+}
@@ -1,25 +1,35 @@
 use std::collections::HashMap;
-use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use once_cell::sync::OnceCell;
 use propolis_datas::embedding::Embedding;
-use rand::seq::SliceRandom;
 use tracing::log::error;
 
 use crate::command_line_args::PredictionArgs;
-use crate::prediction::embedding::{EmbeddingsRunner, StatementSelector};
+use crate::db::SharedEmbeddingCache;
+use crate::duplicates::detect_duplicates;
+use crate::prediction::backend::{ai_backend, embedding_backend, keyless_api_key};
+use crate::prediction::budget::{key_spend, Budget};
+use crate::prediction::embedding::{
+    embedding_token_usage, enqueue_outdated_embeddings, EmbeddingsRunner, StatementSelector,
+};
+use crate::prediction::queue;
+use crate::prediction::workers::{supervise, Claim, Claims, Work};
+use crate::shutdown::Shutdown;
 
 use propolis_datas::apikey::{ApiKey, TransientApiKey};
-use propolis_datas::statement::StatementFlag;
+use propolis_datas::statement::{StatementFlag, StatementFlagState};
 use propolis_utils::StringExt;
-use rl_queue::{QuotaState, RateLimiter};
+use rl_queue::{QuotaState, RateLimiter, Usage};
 use sqlx::SqlitePool;
 use tracing::{
     debug,
     log::{info, warn},
 };
 
-use crate::structs::Statement;
+use crate::structs::{SimilarStatement, Statement};
 
 use super::{
     multi_statement_classifier::{
@@ -28,23 +38,21 @@ use super::{
     },
     prompts::{StatementMeta, StatementMetaContainer},
 };
-use ai_prompt::{
-    api::{AiEnv, CheckResult},
-    openai::{OpenAiEnv, OpenAiModel},
-};
+use ai_prompt::api::{AiEnv, AsEmbeddingEnv, CheckResult, WithApiKey};
+use ai_prompt::backend::{AiBackend, EmbeddingBackend};
 
 /// Runs given prompts and yields results
-pub struct PromptRunner<'a, E: AiEnv + 'a> {
+pub struct PromptRunner {
     /// Used to set a rate based on the amount of tokens that we have used overall
     token_rate_limiter: RateLimiter,
     /// Used to set a rate based on how many API calls were done
     api_calls_rate_limiter: RateLimiter,
-    env: &'a E,
 }
 
 #[derive(Debug)]
 pub enum PromptRunnerError {
     CheckFailed, // OpenAI Prompt moderation check
+    #[allow(dead_code)]
     Anyhow(anyhow::Error),
 }
 
@@ -59,26 +67,25 @@ where
     }
 }
 
-impl<'a, E: AiEnv> PromptRunner<'a, E> {
-    /// Run the given prompt and return the result
-    pub async fn run<R>(
-        &mut self,
+impl PromptRunner {
+    /// Run the given prompt with `env` and return the result
+    pub async fn run<E: AiEnv, R>(
+        &self,
+        env: &E,
         prompt: &MultiStatementPrompt<R>,
     ) -> anyhow::Result<MultiStatementPromptResult<R>, PromptRunnerError>
     where
         R: MultiStatementResultTypes,
     {
         self.token_rate_limiter.block_until_ok().await;
-        self.api_calls_rate_limiter.block_until_ok().await;
-
-        self.api_calls_rate_limiter.add(1);
+        self.api_calls_rate_limiter.acquire(1).await;
 
         info!("Running prompt: {}, V{}", prompt.name, prompt.version);
-        if let CheckResult::Flagged(err) = self.env.check_prompt(prompt).await? {
+        if let CheckResult::Flagged(err) = env.check_prompt(prompt).await? {
             debug!("Prompt failed check: {:?}", err);
             return Err(PromptRunnerError::CheckFailed);
         }
-        let response = self.env.send_prompt(prompt).await?;
+        let response = env.send_prompt(prompt).await?;
         match self
             .token_rate_limiter
             .add(response.response.total_tokens as f64)
@@ -108,7 +115,7 @@ pub async fn update_failing_statement_flags(
     stmts: &[Statement],
     pool: &mut SqlitePool,
 ) -> anyhow::Result<()> {
-    use propolis_datas::statement::{FlagCategoryContainer, StatementFlagState};
+    use propolis_datas::statement::FlagCategoryContainer;
 
     debug!("Updating statement_flags for failed statements");
     let num_stmts = stmts.len();
@@ -135,13 +142,94 @@ pub async fn update_failing_statement_flags(
             }
         }
     }
+    // statements which may be flagged are moderated one by one
+    queue::notify();
+    Ok(())
+}
+
+/// Names of the api call limiters in the rate_limiter_usage table
+const PROMPT_API_CALLS: &str = "prompt_api_calls";
+const EMBEDDING_API_CALLS: &str = "embedding_api_calls";
+
+/// Tokens used for predictions since the unix time `since`, to restore token quotas
+pub async fn prediction_token_usage(since: i64, pool: &SqlitePool) -> Result<Vec<Usage>> {
+    let rows = sqlx::query!(
+        r#"SELECT created, SUM(total_tokens) as "tokens!: i64"
+           FROM statement_predictions
+           WHERE created > ?
+           GROUP BY created"#,
+        since
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| Usage {
+            at: UNIX_EPOCH + Duration::from_secs(row.created as u64),
+            amount: row.tokens as f64,
+        })
+        .collect())
+}
+
+/// Restores the quotas used before the last restart. Tokens are derived from the stored
+/// predictions and embeddings, api calls from the rate_limiter_usage table.
+async fn restore_quotas(
+    args: &PredictionArgs,
+    runner: &PromptRunner,
+    erunner: &EmbeddingsRunner,
+    pool: &SqlitePool,
+) -> Result<()> {
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .saturating_sub(Duration::from_secs(args.tokens_seconds_per_duration))
+        .as_secs() as i64;
+    runner
+        .token_rate_limiter
+        .restore(prediction_token_usage(since, pool).await?);
+    erunner
+        .token_rate_limiter
+        .restore(embedding_token_usage(since, pool).await?);
+    runner
+        .api_calls_rate_limiter
+        .load(PROMPT_API_CALLS, pool)
+        .await?;
+    erunner
+        .api_calls_rate_limiter
+        .load(EMBEDDING_API_CALLS, pool)
+        .await?;
     Ok(())
 }
 
 /// Used to select next key to use for requests
+///
+/// Picks the key used by the fewest running requests, then the one which spent the least this
+/// month. Leaves out keys over their [Budget] and keys whose last request failed recently.
 pub struct ApiKeySelector {
     /// Mapping of raw key to ApiKey instance
     pub keys: HashMap<String, ApiKey>,
+    budget: Budget,
+    /// How long a key is left out after a failed request
+    error_pause: Duration,
+    /// Keys left out until the given time, by api key id
+    failing: HashMap<i64, Instant>,
+    /// Number of running requests, by api key id
+    in_use: Arc<Mutex<HashMap<i64, usize>>>,
+}
+
+/// Key selected for a request, counted as in use until dropped
+pub struct KeyLease {
+    pub raw_key: String,
+    pub api_key: ApiKey,
+    in_use: Arc<Mutex<HashMap<i64, usize>>>,
+}
+
+impl Drop for KeyLease {
+    fn drop(&mut self) {
+        let mut in_use = self.in_use.lock().unwrap();
+        if let Some(count) = in_use.get_mut(&self.api_key.id) {
+            *count -= 1;
+        }
+    }
 }
 
 impl ApiKeySelector {
@@ -157,173 +245,1129 @@ impl ApiKeySelector {
         result
     }
 
-    /// Create an instance, creating keys in the DB (for stats only) if necessary
-    pub async fn create(args: &PredictionArgs, pool: &mut SqlitePool) -> anyhow::Result<Self> {
-        let mut keys: HashMap<String, ApiKey> = HashMap::new();
+    /// Raw openai keys given via arguments and environment
+    pub fn raw_keys(args: &PredictionArgs) -> Vec<String> {
         let mut raw_keys: Vec<String> = args.openai_api_keys.to_owned();
         raw_keys.append(Self::collect_numbered_openai_env_keys().as_mut());
         if let Some(rk) = &args.openai_api_key {
             raw_keys.push(rk.into());
         }
-        for raw_key in raw_keys {
+        raw_keys
+    }
+
+    /// Create an instance, creating keys in the DB (for stats only) if necessary
+    pub async fn create(args: &PredictionArgs, pool: &mut SqlitePool) -> anyhow::Result<Self> {
+        let mut keys: HashMap<String, ApiKey> = HashMap::new();
+        for raw_key in Self::raw_keys(args) {
             let rkey = TransientApiKey::Raw(raw_key.to_owned());
             let key = ApiKey::get_or_create(pool, &rkey, None::<String>).await?;
             keys.insert(raw_key, key);
         }
-        Ok(Self { keys })
+        Ok(Self {
+            keys,
+            budget: Budget::from_args(args),
+            error_pause: Duration::from_secs(args.api_key_error_seconds),
+            failing: HashMap::new(),
+            in_use: Arc::new(Mutex::new(HashMap::new())),
+        })
     }
 
     /// Yield next key to use
-    pub fn next(&self) -> anyhow::Result<(String, ApiKey)> {
-        let raw_key = self
+    pub async fn next(&mut self, pool: &SqlitePool) -> anyhow::Result<KeyLease> {
+        let now = Instant::now();
+        self.failing.retain(|_, until| *until > now);
+        let spend = key_spend(pool).await?;
+        let mut in_use = self.in_use.lock().unwrap();
+        let (raw_key, api_key) = self
             .keys
-            .keys()
-            .collect::<Vec<&String>>()
-            .choose(&mut rand::thread_rng())
-            .ok_or(anyhow!("Unable to select any API key."))?
-            .to_string();
-        let api_key = self.keys.get(&raw_key).unwrap();
-        Ok((raw_key, api_key.to_owned()))
+            .iter()
+            .filter(|(_, key)| !self.failing.contains_key(&key.id))
+            .map(|(raw_key, key)| {
+                (
+                    raw_key,
+                    key,
+                    spend.get(&key.id).copied().unwrap_or_default(),
+                )
+            })
+            .filter(|(_, _, spend)| self.budget.allows(spend))
+            .min_by(|(_, a, a_spend), (_, b, b_spend)| {
+                let uses = |key: &ApiKey| in_use.get(&key.id).copied().unwrap_or_default();
+                uses(a)
+                    .cmp(&uses(b))
+                    .then(a_spend.month.total_cmp(&b_spend.month))
+            })
+            .map(|(raw_key, key, _)| (raw_key, key))
+            .ok_or(anyhow!(
+                "No API key within budget, {} failed recently.",
+                self.failing.len()
+            ))?;
+        *in_use.entry(api_key.id).or_default() += 1;
+        Ok(KeyLease {
+            raw_key: raw_key.to_owned(),
+            api_key: api_key.to_owned(),
+            in_use: self.in_use.clone(),
+        })
     }
-}
 
-/// Setup continuous prompt generation and runner in an async loop
-///
-/// Will store prompt results in the db
-pub async fn run(args: &PredictionArgs, pool: &mut SqlitePool) -> Result<()> {
-    let key_selector = ApiKeySelector::create(args, pool)
-        .await
-        .expect("Unable to setup key selection.");
-    let env = OpenAiEnv::from(OpenAiModel::Gpt35Turbo);
+    /// Leaves the key out for a while, after a request with it failed
+    pub fn report_error(&mut self, api_key: &ApiKey) {
+        warn!(
+            "Not using key ({}) for {}s after an error",
+            api_key.id,
+            self.error_pause.as_secs()
+        );
+        self.failing
+            .insert(api_key.id, Instant::now() + self.error_pause);
+    }
 
-    info!("OPENAI API keys loaded: {}", key_selector.keys.len());
-    if key_selector.keys.is_empty() {
-        panic!("No API keys loaded.");
+    /// Logs what every key spent
+    pub async fn log_spend(&self, pool: &SqlitePool) -> anyhow::Result<()> {
+        let spend = key_spend(pool).await?;
+        for key in self.keys.values() {
+            let spend = spend.get(&key.id).copied().unwrap_or_default();
+            info!(
+                "Key ({}) spent ${:.2} today and ${:.2} this month",
+                key.id, spend.today, spend.month
+            );
+        }
+        Ok(())
     }
-    info!("Prediction environment: {:?}", env);
+}
 
-    let mut pool2 = pool.to_owned();
-    let prompt_gen = MultiStatementPromptGen::<StatementMetaContainer> {
-        batch_size: 5,
-        prompt: |stmts| StatementMeta::prompt(&stmts),
-        pool,
-    };
+/// What a [Predictor::step] did
+#[derive(PartialEq, Eq, Debug)]
+pub enum Step {
+    /// Nothing to do
+    Idle,
+    /// There was work, but no API key could be used
+    NoKey,
+    /// Did the work on the next statements
+    Ran,
+}
 
-    let mut erunner = EmbeddingsRunner {
-        token_rate_limiter: RateLimiter::new(
-            args.tokens_per_duration as f64,
-            Duration::from_secs(args.tokens_seconds_per_duration),
-        ),
-        api_calls_rate_limiter: RateLimiter::new(
-            args.api_calls_per_duration as f64,
-            Duration::from_secs(args.api_calls_seconds_per_duration),
-        ),
-        env: &env,
-    };
-    let selector = StatementSelector {};
-
-    let mut runner = PromptRunner {
-        token_rate_limiter: RateLimiter::new(
-            args.tokens_per_duration as f64,
-            Duration::from_secs(args.tokens_seconds_per_duration),
-        ),
-        api_calls_rate_limiter: RateLimiter::new(
-            args.api_calls_per_duration as f64,
-            Duration::from_secs(args.api_calls_seconds_per_duration),
-        ),
-        env: &env,
-    };
-    loop {
-        async_std::task::sleep(Duration::from_secs(1)).await;
+/// Statements embedded with one request
+const EMBEDDING_BATCH_SIZE: usize = 100;
+/// How long a failed worker is paused before it is started again
+const WORKER_RESTART_DELAY: Duration = Duration::from_secs(10);
 
-        // select data
-        let prompt = prompt_gen.next_prompt().await.unwrap_or_else(|err| {
-            error!("next_prompt failed: {}", err);
-            None
-        });
+/// Predictor of the running workers, once they are set up. It also embeds search queries, so
+/// they are sent with the same keys, budgets and quotas.
+pub type SharedPredictor = Arc<OnceCell<Arc<Predictor<AiBackend, EmbeddingBackend>>>>;
 
-        let embed_stmts = match selector.next_for_embedding(pool).await {
-            Ok(stmts) if !stmts.is_empty() => Some(stmts),
-            Ok(_) => None,
-            Err(err) => {
-                error!("Unable to select next statements for embedding: {:?}", err);
-                None
-            }
+/// Prompt generation, prediction, moderation and embedding of the statements missing them
+///
+/// Shared by the prediction workers and generic over the backends, so it can also be driven with
+/// a mock env. Every request is sent by a copy of the env with its own api key, so workers can
+/// run concurrently with different keys.
+pub struct Predictor<P: AiEnv + WithApiKey, E: AsEmbeddingEnv + WithApiKey> {
+    key_selector: tokio::sync::Mutex<ApiKeySelector>,
+    /// Results of backends without openai keys are stored with this key
+    keyless_key: Option<ApiKey>,
+    prompt_gen: MultiStatementPromptGen<StatementMetaContainer>,
+    env: P,
+    embedding_env: E,
+    runner: PromptRunner,
+    erunner: EmbeddingsRunner,
+    selector: StatementSelector,
+    /// Statements being worked on by the workers of each kind
+    classifying: Claims,
+    moderating: Claims,
+    embedding: Claims,
+    /// Shared with the pages, which search the same embeddings
+    embeddings: SharedEmbeddingCache,
+    pool: SqlitePool,
+}
+
+impl<P: AiEnv + WithApiKey, E: AsEmbeddingEnv + WithApiKey> Predictor<P, E> {
+    pub async fn create(
+        args: &PredictionArgs,
+        env: P,
+        embedding_env: E,
+        embeddings: SharedEmbeddingCache,
+        pool: SqlitePool,
+    ) -> Result<Self> {
+        let mut store = pool.to_owned();
+        let key_selector = ApiKeySelector::create(args, &mut store)
+            .await
+            .context("Unable to setup key selection")?;
+
+        info!("OPENAI API keys loaded: {}", key_selector.keys.len());
+        if (env.uses_openai_key() || embedding_env.uses_openai_key())
+            && key_selector.keys.is_empty()
+        {
+            bail!("No API keys loaded, but the backends need them");
+        }
+        let keyless_key = match env.uses_openai_key() && embedding_env.uses_openai_key() {
+            true => None,
+            false => Some(keyless_api_key(&mut store).await?),
         };
+        let reembedding =
+            enqueue_outdated_embeddings(&embedding_env.embedding_model(), &pool).await?;
+        if reembedding > 0 {
+            info!(
+                "Embedding {} statements again with {}",
+                reembedding,
+                embedding_env.embedding_model()
+            );
+        }
+
+        Ok(Self {
+            key_selector: tokio::sync::Mutex::new(key_selector),
+            keyless_key,
+            prompt_gen: MultiStatementPromptGen {
+                batch_size: 5,
+                prompt: |stmts| StatementMeta::prompt(&stmts),
+                pool: pool.to_owned(),
+            },
+            env,
+            embedding_env,
+            runner: PromptRunner {
+                token_rate_limiter: RateLimiter::sliding_window(
+                    args.tokens_per_duration as f64,
+                    Duration::from_secs(args.tokens_seconds_per_duration),
+                ),
+                api_calls_rate_limiter: RateLimiter::token_bucket(
+                    args.api_calls_per_duration as f64,
+                    Duration::from_secs(args.api_calls_seconds_per_duration),
+                ),
+            },
+            erunner: EmbeddingsRunner {
+                token_rate_limiter: RateLimiter::sliding_window(
+                    args.tokens_per_duration as f64,
+                    Duration::from_secs(args.tokens_seconds_per_duration),
+                ),
+                api_calls_rate_limiter: RateLimiter::token_bucket(
+                    args.api_calls_per_duration as f64,
+                    Duration::from_secs(args.api_calls_seconds_per_duration),
+                ),
+            },
+            selector: StatementSelector {},
+            classifying: Claims::default(),
+            moderating: Claims::default(),
+            embedding: Claims::default(),
+            embeddings,
+            pool,
+        })
+    }
 
-        // short-circuit loop in case no data
-        if let (None, None) = (&prompt, &embed_stmts) {
-            continue;
+    /// Does the given work on the next queued statements, storing the results
+    ///
+    /// Statements which need nothing more leave the queue afterwards.
+    pub async fn step(&self, work: Work) -> Step {
+        let step = match work {
+            Work::Classification => self.classify().await,
+            Work::Embedding => self.embed().await,
+            Work::Moderation => self.moderate().await,
+        };
+        let embedding_model = self.embedding_env.embedding_model();
+        if let Err(err) = self.prompt_gen.dequeue_done(&embedding_model).await {
+            error!("Unable to remove done statements from the queue: {:?}", err);
         }
+        step
+    }
 
-        // select key
-        let (raw_key, api_key) = key_selector.next().expect("Unable to select key");
+    /// Key to send a request with, None if the backend uses none
+    async fn lease_key(&self, uses_openai_key: bool) -> Result<Option<KeyLease>> {
+        if !uses_openai_key {
+            return Ok(None);
+        }
+        let lease = self.key_selector.lock().await.next(&self.pool).await?;
         debug!(
             "Using key ({}): {}",
-            api_key.id,
-            raw_key.as_str().shortify(2, 4, "..")
+            lease.api_key.id,
+            lease.raw_key.as_str().shortify(2, 4, "..")
         );
-        ai_prompt::openai::set_key(raw_key);
-
-        if let Some(prompt) = prompt {
-            // run prompt
-            // println!("Running prompt: {prompt:?}");
-            match runner.run(&prompt).await {
-                Ok(result) => {
-                    if let Err(err) = result.store(&api_key, pool).await {
-                        error!("storing result failed: {err}");
-                    };
+        Ok(Some(lease))
+    }
+
+    /// Key to store the results of a request with, the keyless key for other backends
+    fn result_key(&self, lease: &Option<KeyLease>) -> ApiKey {
+        lease
+            .as_ref()
+            .map(|lease| lease.api_key.to_owned())
+            .or(self.keyless_key.to_owned())
+            .expect("A key is set up for every backend")
+    }
+
+    async fn classify(&self) -> Step {
+        let claim = self
+            .classifying
+            .claim_next(self.prompt_gen.batch_size.into(), |claimed| {
+                self.prompt_gen.unflagged_batch(claimed)
+            })
+            .await;
+        match claim {
+            Ok(Some(claim)) => self.predict(claim).await,
+            Ok(None) => Step::Idle,
+            Err(err) => {
+                error!("Unable to select next statements to predict: {}", err);
+                Step::Idle
+            }
+        }
+    }
+
+    async fn moderate(&self) -> Step {
+        let claim = self
+            .moderating
+            .claim_next(1, |claimed| {
+                self.prompt_gen
+                    .next_with_flag(StatementFlagState::MaybeFlagged as i64, 1 + claimed)
+            })
+            .await;
+        match claim {
+            Ok(Some(claim)) => self.predict(claim).await,
+            Ok(None) => Step::Idle,
+            Err(err) => {
+                error!("Unable to select next flagged statement: {}", err);
+                Step::Idle
+            }
+        }
+    }
+
+    /// Runs the prompt for the claimed statements, flagging them if it fails
+    async fn predict(&self, claim: Claim) -> Step {
+        let lease = match self.lease_key(self.env.uses_openai_key()).await {
+            Ok(lease) => lease,
+            Err(err) => {
+                warn!("Unable to select key: {}", err);
+                return Step::NoKey;
+            }
+        };
+        let keyed_env = lease
+            .as_ref()
+            .map(|lease| self.env.with_api_key(&lease.raw_key));
+        let env = keyed_env.as_ref().unwrap_or(&self.env);
+        let api_key = self.result_key(&lease);
+
+        let prompt = (self.prompt_gen.prompt)(claim.stmts.to_owned());
+        match self.runner.run(env, &prompt).await {
+            Ok(result) => {
+                if let Err(err) = result.store(&api_key, &self.pool).await {
+                    error!("storing result failed: {err}");
+                };
+            }
+            Err(err) => {
+                error!("running prompt failed: {:?}", err);
+                if lease.is_some() && matches!(err, PromptRunnerError::Anyhow(_)) {
+                    self.key_selector.lock().await.report_error(&api_key);
                 }
-                Err(err) => {
-                    error!("running prompt failed: {:?}", err);
-                    match update_failing_statement_flags(&prompt.stmts, &mut pool2).await {
-                        Ok(_) => {}
-                        Err(err) => {
-                            error!("Unable to update statement flags: {}", err)
-                        }
+                match update_failing_statement_flags(&prompt.stmts, &mut self.pool.to_owned()).await
+                {
+                    Ok(_) => {}
+                    Err(err) => {
+                        error!("Unable to update statement flags: {}", err)
                     }
                 }
-            };
+            }
+        };
+        if let Err(err) = self
+            .runner
+            .api_calls_rate_limiter
+            .store(PROMPT_API_CALLS, &self.pool)
+            .await
+        {
+            error!("Unable to store used api calls: {:?}", err);
+        }
+        Step::Ran
+    }
+
+    async fn embed(&self) -> Step {
+        let pool = &self.pool;
+        let model = self.embedding_env.embedding_model();
+        let claim = match self
+            .embedding
+            .claim_next(EMBEDDING_BATCH_SIZE, |claimed| {
+                self.selector
+                    .next_for_embedding(pool, &model, EMBEDDING_BATCH_SIZE + claimed)
+            })
+            .await
+        {
+            Ok(Some(claim)) => claim,
+            Ok(None) => return Step::Idle,
+            Err(err) => {
+                error!("Unable to select next statements for embedding: {:?}", err);
+                return Step::Idle;
+            }
+        };
+        let lease = match self.lease_key(self.embedding_env.uses_openai_key()).await {
+            Ok(lease) => lease,
+            Err(err) => {
+                warn!("Unable to select key: {}", err);
+                return Step::NoKey;
+            }
+        };
+        let keyed_env = lease
+            .as_ref()
+            .map(|lease| self.embedding_env.with_api_key(&lease.raw_key));
+        let env = keyed_env.as_ref().unwrap_or(&self.embedding_env);
+        let api_key = self.result_key(&lease);
+
+        let embed_stmts = &claim.stmts;
+        info!("Embedding {} statements", embed_stmts.len());
+        let embeddings = match self
+            .erunner
+            .run(
+                env,
+                &embed_stmts
+                    .iter()
+                    .map(|stmt| stmt.text.as_str())
+                    .collect::<Vec<&str>>(),
+            )
+            .await
+        {
+            Ok(e) => Some(e),
+            Err(err) => {
+                error!("running for embeddings failed: {:?}", err);
+                if lease.is_some() {
+                    self.key_selector.lock().await.report_error(&api_key);
+                }
+                None
+            }
+        };
+        if let Err(err) = self
+            .erunner
+            .api_calls_rate_limiter
+            .store(EMBEDDING_API_CALLS, pool)
+            .await
+        {
+            error!("Unable to store used api calls: {:?}", err);
         }
 
-        if let Some(embed_stmts) = embed_stmts {
-            // run embeddings
-            let len = embed_stmts.len();
-            info!("Embedding {len} statements");
-            let embeddings = match erunner
-                .run(
-                    &embed_stmts
-                        .iter()
-                        .map(|stmt| stmt.text.as_str())
-                        .collect::<Vec<&str>>(),
+        if let Some((embeddings, total_tokens)) = embeddings {
+            let mut store = pool.to_owned();
+            let total_tokens = i64::from(total_tokens);
+            let count = embeddings.len() as i64;
+            for (i, embedding) in embeddings.iter().enumerate() {
+                // every statement stores its share of the call, so the shares add up to it
+                let tokens = total_tokens / count + i64::from((i as i64) < total_tokens % count);
+                match Embedding::create(
+                    &mut store,
+                    embed_stmts[i].id,
+                    embedding.values.clone(),
+                    tokens,
+                    api_key.id,
+                    &model,
                 )
                 .await
-            {
-                Ok(e) => Some(e),
-                Err(err) => {
-                    error!("running for embeddings failed: {:?}", err);
-                    None
-                }
-            };
-
-            if let Some((embeddings, total_tokens)) = embeddings {
-                for (i, embedding) in embeddings.iter().enumerate() {
-                    match Embedding::create(
-                        &mut pool2,
-                        embed_stmts[i].id,
-                        embedding.values.clone(),
-                        total_tokens.into(),
-                        api_key.id,
-                    )
-                    .await
-                    {
-                        Ok(_) => {}
-                        Err(err) => {
-                            error!("storing of embedding failed: {:?}", err);
+                {
+                    Ok(_) => {
+                        if let Err(err) =
+                            detect_duplicates(embed_stmts[i].id, Some(&self.embeddings), pool).await
+                        {
+                            error!("duplicate detection failed: {:?}", err);
                         }
                     }
+                    Err(err) => {
+                        error!("storing of embedding failed: {:?}", err);
+                    }
                 }
             }
         }
+        Step::Ran
+    }
+
+    /// Embeds `text` like a statement and returns the `k` statements with the most similar
+    /// embeddings
+    ///
+    /// Searches run while users type, so exceeded quotas fail right away instead of waiting.
+    pub async fn semantic_search(&self, text: &str, k: usize) -> Result<Vec<SimilarStatement>> {
+        if !self.erunner.token_rate_limiter.check() {
+            bail!("Embedding token quota exceeded");
+        }
+        if self.erunner.api_calls_rate_limiter.try_acquire(1).is_err() {
+            bail!("Embedding api call quota exceeded");
+        }
+        let lease = self.lease_key(self.embedding_env.uses_openai_key()).await?;
+        let keyed_env = lease
+            .as_ref()
+            .map(|lease| self.embedding_env.with_api_key(&lease.raw_key));
+        let env = keyed_env.as_ref().unwrap_or(&self.embedding_env);
+        let api_key = self.result_key(&lease);
+        let model = self.embedding_env.embedding_model();
+
+        let response = env.embed(&[text]).await;
+        if let Err(err) = self
+            .erunner
+            .api_calls_rate_limiter
+            .store(EMBEDDING_API_CALLS, &self.pool)
+            .await
+        {
+            error!("Unable to store used api calls: {:?}", err);
+        }
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => {
+                if lease.is_some() {
+                    self.key_selector.lock().await.report_error(&api_key);
+                }
+                return Err(err);
+            }
+        };
+        self.erunner
+            .token_rate_limiter
+            .add(response.total_tokens as f64);
+        let tokens = response.total_tokens as i64;
+        sqlx::query!(
+            "INSERT INTO search_embeddings (api_key_id, model, prompt_tokens) VALUES (?, ?, ?)",
+            api_key.id,
+            model,
+            tokens
+        )
+        .execute(&self.pool)
+        .await?;
+
+        let query: Vec<f32> = response
+            .data
+            .first()
+            .ok_or(anyhow!("Embedding API yielded an empty result"))?
+            .values
+            .iter()
+            .map(|v| *v as f32)
+            .collect();
+        self.embeddings
+            .nearest(&query, &model, k, None, &self.pool)
+            .await
+    }
+}
+
+/// Embeds `text` with the running predictor and returns the `k` statements with the most similar
+/// embeddings. Fails until predictions are set up.
+pub async fn semantic_search(
+    predictor: &SharedPredictor,
+    text: &str,
+    k: usize,
+) -> Result<Vec<SimilarStatement>> {
+    predictor
+        .get()
+        .context("Predictions are not set up yet")?
+        .semantic_search(text, k)
+        .await
+}
+
+/// Lets a worker do its work until shutdown, waiting for new work whenever it is idle
+async fn work_until_shutdown<P, E>(predictor: Arc<Predictor<P, E>>, work: Work, shutdown: Shutdown)
+where
+    P: AiEnv + WithApiKey,
+    E: AsEmbeddingEnv + WithApiKey,
+{
+    let mut new_work = queue::subscribe();
+    while !shutdown.requested() {
+        match predictor.step(work).await {
+            Step::Ran => {}
+            Step::NoKey => shutdown.sleep(Duration::from_secs(60)).await,
+            Step::Idle => {
+                tokio::select! {
+                    _ = new_work.changed() => {}
+                    _ = shutdown.clone().wait() => {}
+                }
+            }
+        }
+    }
+}
+
+/// Predictor with the configured backends, which the workers share
+async fn setup(
+    args: &PredictionArgs,
+    embeddings: &SharedEmbeddingCache,
+    pool: &SqlitePool,
+) -> Result<Predictor<AiBackend, EmbeddingBackend>> {
+    let env = ai_backend(args)?;
+    let embedding_env = embedding_backend(args)?;
+    info!("Prediction environment: {:?}", env);
+    info!("Embedding environment: {:?}", embedding_env);
+
+    let predictor = Predictor::create(
+        args,
+        env,
+        embedding_env,
+        embeddings.to_owned(),
+        pool.to_owned(),
+    )
+    .await?;
+    if let Err(err) = predictor.key_selector.lock().await.log_spend(pool).await {
+        error!("Unable to calculate spend of API keys: {:?}", err);
+    }
+    if let Err(err) = restore_quotas(args, &predictor.runner, &predictor.erunner, pool).await {
+        error!("Unable to restore used quotas: {:?}", err);
+    }
+    Ok(predictor)
+}
+
+/// Runs the configured number of workers of every kind until shutdown
+///
+/// A failed setup is logged right away and tried again, like failed workers are restarted, so
+/// that nothing fails silently while the http server keeps running. Once set up, the predictor
+/// is put into `shared`, for the searches of the http server. Results are stored in the db.
+pub async fn run(
+    args: PredictionArgs,
+    embeddings: SharedEmbeddingCache,
+    shared: SharedPredictor,
+    pool: SqlitePool,
+    shutdown: Shutdown,
+) -> Result<()> {
+    let predictor = loop {
+        match setup(&args, &embeddings, &pool).await {
+            Ok(predictor) => break Arc::new(predictor),
+            Err(err) => error!(
+                "Unable to set up predictions, retrying in {}s: {:?}",
+                WORKER_RESTART_DELAY.as_secs(),
+                err
+            ),
+        }
+        shutdown.sleep(WORKER_RESTART_DELAY).await;
+        if shutdown.requested() {
+            return Ok(());
+        }
+    };
+    shared
+        .set(predictor.to_owned())
+        .map_err(|_| anyhow!("Predictions were already set up"))?;
+
+    let workers = [
+        (Work::Classification, args.classification_workers),
+        (Work::Embedding, args.embedding_workers),
+        (Work::Moderation, args.moderation_workers),
+    ];
+    let supervisors = workers
+        .into_iter()
+        .flat_map(|(work, count)| (0..count).map(move |n| (work, n)))
+        .map(|(work, n)| {
+            let predictor = predictor.to_owned();
+            let worker_shutdown = shutdown.to_owned();
+            supervise(
+                format!("{work:?} worker {n}"),
+                WORKER_RESTART_DELAY,
+                shutdown.to_owned(),
+                move || work_until_shutdown(predictor.to_owned(), work, worker_shutdown.to_owned()),
+            )
+        });
+    futures::future::join_all(supervisors).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ai_prompt::api::AiMessage;
+    use ai_prompt::mock::{MockAiEnv, MockReply};
+    use clap::Parser;
+
+    fn test_args() -> PredictionArgs {
+        PredictionArgs::parse_from([
+            "propolis",
+            "--tokens-per-duration",
+            "100000",
+            "--api-calls-per-duration",
+            "1000",
+        ])
+    }
+
+    /// Answers the statement_meta prompt like the model would, flagging "forbidden" statements
+    fn statement_meta_reply(messages: &[AiMessage]) -> MockReply {
+        let csv = &messages.last().unwrap().content;
+        let ids: Vec<&str> = csv
+            .lines()
+            .filter_map(|line| line.split_once('|'))
+            .map(|(id, _)| id)
+            .collect();
+        if csv.contains("forbidden") {
+            return MockReply::Flagged("violence".into());
+        }
+        let rows: String = ids
+            .iter()
+            .map(|id| format!("{id}|politics|liberalism:s|-|-|tag{id}:s|-|-\n"))
+            .collect();
+        MockReply::Content {
+            content: format!("```csv\nnum|category|label1|label2|label3|tag1|tag2|tag3\n{rows}```"),
+            prompt_tokens: 100 * ids.len() as i64,
+            completion_tokens: 10 * ids.len() as i64,
+        }
+    }
+
+    async fn insert_statements(texts: &[&str], pool: &SqlitePool) -> anyhow::Result<()> {
+        for text in texts {
+            let id = sqlx::query_scalar!(
+                "INSERT INTO statements (text) VALUES (?) RETURNING id",
+                text
+            )
+            .fetch_one(pool)
+            .await?;
+            queue::enqueue(id, 0, pool).await?;
+        }
+        Ok(())
+    }
+
+    /// Lets one worker of every kind work at the same time, until all of them are idle. Returns
+    /// the number of rounds with work.
+    async fn run_until_idle<P: AiEnv + WithApiKey, E: AsEmbeddingEnv + WithApiKey>(
+        predictor: &Predictor<P, E>,
+    ) -> usize {
+        let mut rounds = 0;
+        loop {
+            let steps = futures::join!(
+                predictor.step(Work::Classification),
+                predictor.step(Work::Embedding),
+                predictor.step(Work::Moderation),
+            );
+            if ![steps.0, steps.1, steps.2].contains(&Step::Ran) {
+                return rounds;
+            }
+            rounds += 1;
+            assert!(rounds < 20, "workers do not finish");
+        }
+    }
+
+    #[sqlx::test]
+    async fn predicts_and_embeds_in_batches(pool: SqlitePool) -> anyhow::Result<()> {
+        let texts: Vec<String> = (1..=7).map(|i| format!("statement number {i}")).collect();
+        insert_statements(&texts.iter().map(|t| t.as_str()).collect::<Vec<_>>(), &pool).await?;
+        let env = MockAiEnv::new(statement_meta_reply);
+        let predictor = Predictor::create(
+            &test_args(),
+            env.clone(),
+            env.clone(),
+            Default::default(),
+            pool.clone(),
+        )
+        .await?;
+
+        assert_eq!(run_until_idle(&predictor).await, 2);
+
+        // batches of five and two statements
+        let batch_sizes: Vec<usize> = env
+            .prompts()
+            .iter()
+            .map(|messages| messages.last().unwrap().content.matches('|').count())
+            .collect();
+        assert_eq!(batch_sizes, vec![5, 2]);
+        let predictions = sqlx::query!(
+            r#"SELECT statement_id, ai_env, prompt_name, prompt_result, prompt_tokens,
+                      completion_tokens, api_key_id
+               FROM statement_predictions ORDER BY statement_id"#
+        )
+        .fetch_all(&pool)
+        .await?;
+        assert_eq!(predictions.len(), 7);
+        let keyless = keyless_api_key(&mut pool.to_owned()).await?;
+        for (i, prediction) in predictions.iter().enumerate() {
+            assert_eq!(prediction.statement_id, i as i64 + 1);
+            assert_eq!(prediction.ai_env, "mock,mock");
+            assert_eq!(prediction.prompt_name, "statement_meta");
+            assert!(prediction.prompt_result.contains(&format!("tag{}", i + 1)));
+            // tokens of a call are split between its statements
+            assert_eq!(prediction.prompt_tokens, 100);
+            assert_eq!(prediction.completion_tokens, 10);
+            assert_eq!(prediction.api_key_id, keyless.id);
+        }
+
+        assert_eq!(env.embedded(), vec![texts]);
+
+        // statements added later are picked up as well
+        insert_statements(&["statement number 8"], &pool).await?;
+        assert_eq!(run_until_idle(&predictor).await, 1);
+        assert_eq!(env.embedded().last().unwrap(), &vec!["statement number 8"]);
+        let embeddings = sqlx::query_scalar!("SELECT COUNT(*) FROM statement_embeddings")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(embeddings, 8);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn failed_batches_are_retried_individually(pool: SqlitePool) -> anyhow::Result<()> {
+        insert_statements(&["fine", "forbidden", "also fine"], &pool).await?;
+        let env = MockAiEnv::new(statement_meta_reply);
+        env.reply(MockReply::Fail("timeout".into()))
+            .fail_embedding("timeout");
+        let predictor = Predictor::create(
+            &test_args(),
+            env.clone(),
+            env.clone(),
+            Default::default(),
+            pool.clone(),
+        )
+        .await?;
+
+        // the failed batch, then every statement on its own
+        assert_eq!(predictor.step(Work::Classification).await, Step::Ran);
+        assert_eq!(run_until_idle(&predictor).await, 3);
+        assert_eq!(env.prompts().len(), 4);
+
+        let flags = sqlx::query!("SELECT statement_id, state FROM statement_flags ORDER BY 1")
+            .fetch_all(&pool)
+            .await?
+            .into_iter()
+            .map(|flag| (flag.statement_id, flag.state))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            flags,
+            vec![
+                (1, StatementFlagState::MaybeFlagged as i64),
+                (2, StatementFlagState::Flagged as i64),
+                (3, StatementFlagState::MaybeFlagged as i64),
+            ]
+        );
+        let predicted = sqlx::query_scalar!(
+            "SELECT statement_id FROM statement_predictions ORDER BY statement_id"
+        )
+        .fetch_all(&pool)
+        .await?;
+        assert_eq!(predicted, vec![1, 3]);
+
+        // the failed embedding request was repeated in the next step
+        assert_eq!(env.embedded().len(), 2);
+        let embeddings = sqlx::query_scalar!("SELECT COUNT(*) FROM statement_embeddings")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(embeddings, 3);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn most_viewed_statements_are_predicted_first(pool: SqlitePool) -> anyhow::Result<()> {
+        let texts: Vec<String> = (1..=7).map(|i| format!("statement number {i}")).collect();
+        insert_statements(&texts.iter().map(|t| t.as_str()).collect::<Vec<_>>(), &pool).await?;
+        // not queued, so it is left alone
+        sqlx::query!("INSERT INTO statements (text) VALUES ('statement number 8')")
+            .execute(&pool)
+            .await?;
+        queue::enqueue(7, 3, &pool).await?;
+        queue::enqueue(6, 1, &pool).await?;
+        let env = MockAiEnv::new(statement_meta_reply);
+        let predictor = Predictor::create(
+            &test_args(),
+            env.clone(),
+            env.clone(),
+            Default::default(),
+            pool.clone(),
+        )
+        .await?;
+
+        assert_eq!(predictor.step(Work::Classification).await, Step::Ran);
+        let predicted = sqlx::query_scalar!(
+            "SELECT statement_id FROM statement_predictions ORDER BY statement_id"
+        )
+        .fetch_all(&pool)
+        .await?;
+        assert_eq!(predicted, vec![1, 2, 3, 6, 7]);
+
+        // statements leave the queue once they are predicted and embedded
+        assert_eq!(run_until_idle(&predictor).await, 1);
+        let queued = sqlx::query_scalar!("SELECT statement_id FROM prediction_queue")
+            .fetch_all(&pool)
+            .await?;
+        assert!(queued.is_empty());
+        let embedded = sqlx::query_scalar!("SELECT COUNT(*) FROM statement_embeddings")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(embedded, 7);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn statements_are_embedded_again_after_the_model_changed(
+        pool: SqlitePool,
+    ) -> anyhow::Result<()> {
+        insert_statements(&["statement number 1", "statement number 2"], &pool).await?;
+        let env = MockAiEnv::new(statement_meta_reply);
+        let predictor = Predictor::create(
+            &test_args(),
+            env.clone(),
+            env.clone(),
+            Default::default(),
+            pool.clone(),
+        )
+        .await?;
+        run_until_idle(&predictor).await;
+
+        let other_model = env.with_embedding_model("other,mock");
+        let predictor = Predictor::create(
+            &test_args(),
+            env.clone(),
+            other_model,
+            Default::default(),
+            pool.clone(),
+        )
+        .await?;
+        let queued = sqlx::query_scalar!("SELECT statement_id FROM prediction_queue")
+            .fetch_all(&pool)
+            .await?;
+        assert_eq!(queued.len(), 2);
+
+        // only embedded again, the predictions are kept
+        assert_eq!(run_until_idle(&predictor).await, 1);
+        assert_eq!(env.embedded().len(), 2);
+        assert_eq!(env.prompts().len(), 1);
+        let models = sqlx::query_scalar!("SELECT model FROM statement_embeddings")
+            .fetch_all(&pool)
+            .await?;
+        assert_eq!(models, vec!["other,mock", "other,mock"]);
+        let queued = sqlx::query_scalar!("SELECT statement_id FROM prediction_queue")
+            .fetch_all(&pool)
+            .await?;
+        assert!(queued.is_empty());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn searches_are_recorded_and_limited(pool: SqlitePool) -> anyhow::Result<()> {
+        insert_statements(&["statement number 1", "another question"], &pool).await?;
+        let env = MockAiEnv::new(statement_meta_reply);
+        let args = PredictionArgs::parse_from([
+            "propolis",
+            "--tokens-per-duration",
+            "100000",
+            "--api-calls-per-duration",
+            "2",
+        ]);
+        let predictor = Predictor::create(
+            &args,
+            env.clone(),
+            env.clone(),
+            Default::default(),
+            pool.clone(),
+        )
+        .await?;
+        assert_eq!(predictor.step(Work::Embedding).await, Step::Ran);
+
+        let similar = predictor.semantic_search("statement number 1", 1).await?;
+        assert_eq!(similar[0].statement.id, 1);
+        let tokens = sqlx::query_scalar!("SELECT prompt_tokens FROM search_embeddings")
+            .fetch_all(&pool)
+            .await?;
+        assert_eq!(tokens, vec![3]);
+
+        // fails right away, instead of waiting for the quota
+        assert!(predictor.semantic_search("statement", 1).await.is_err());
+        assert_eq!(env.embedded().len(), 2);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn idle_workers_are_woken_by_queued_statements(pool: SqlitePool) -> anyhow::Result<()> {
+        let env = MockAiEnv::new(statement_meta_reply);
+        let predictor = Arc::new(
+            Predictor::create(
+                &test_args(),
+                env.clone(),
+                env,
+                Default::default(),
+                pool.clone(),
+            )
+            .await?,
+        );
+        let (sender, shutdown) = Shutdown::manual();
+        let worker = tokio::spawn(work_until_shutdown(
+            predictor,
+            Work::Classification,
+            shutdown,
+        ));
+
+        insert_statements(&["one", "two"], &pool).await?;
+        let mut predicted = 0;
+        for _ in 0..500 {
+            predicted = sqlx::query_scalar!("SELECT COUNT(*) FROM statement_predictions")
+                .fetch_one(&pool)
+                .await?;
+            if predicted == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(predicted, 2);
+
+        sender.send(true)?;
+        worker.await?;
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn concurrent_workers_predict_different_statements(
+        pool: SqlitePool,
+    ) -> anyhow::Result<()> {
+        let texts: Vec<String> = (1..=10).map(|i| format!("statement number {i}")).collect();
+        insert_statements(&texts.iter().map(|t| t.as_str()).collect::<Vec<_>>(), &pool).await?;
+        let env = MockAiEnv::new(statement_meta_reply);
+        let predictor = Predictor::create(
+            &test_args(),
+            env.clone(),
+            env.clone(),
+            Default::default(),
+            pool.clone(),
+        )
+        .await?;
+
+        let steps = futures::join!(
+            predictor.step(Work::Classification),
+            predictor.step(Work::Classification),
+        );
+        assert_eq!(steps, (Step::Ran, Step::Ran));
+
+        let predicted = sqlx::query_scalar!(
+            "SELECT statement_id FROM statement_predictions ORDER BY statement_id"
+        )
+        .fetch_all(&pool)
+        .await?;
+        assert_eq!(predicted, (1..=10).collect::<Vec<i64>>());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn failed_setups_are_retried_until_shutdown(pool: SqlitePool) -> anyhow::Result<()> {
+        let env = MockAiEnv::new(statement_meta_reply).with_openai_keys();
+        assert!(Predictor::create(
+            &test_args(),
+            env.clone(),
+            env.clone(),
+            Default::default(),
+            pool.clone()
+        )
+        .await
+        .is_err());
+
+        // openai backends without keys can't be set up
+        let (sender, shutdown) = Shutdown::manual();
+        let predictions = tokio::spawn(run(
+            test_args(),
+            Default::default(),
+            Default::default(),
+            pool,
+            shutdown,
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!predictions.is_finished());
+        sender.send(true)?;
+        predictions.await??;
+        Ok(())
+    }
+
+    fn args_with_keys(raw_keys: &[&str]) -> PredictionArgs {
+        PredictionArgs::parse_from([
+            "propolis",
+            "--tokens-per-duration",
+            "100000",
+            "--api-calls-per-duration",
+            "1000",
+            "--openai-api-keys",
+            &raw_keys.join(":"),
+        ])
+    }
+
+    #[sqlx::test]
+    async fn keys_in_use_are_picked_last(pool: SqlitePool) -> anyhow::Result<()> {
+        let args = args_with_keys(&["sk-first-test-key", "sk-second-test-key"]);
+        let mut selector = ApiKeySelector::create(&args, &mut pool.to_owned()).await?;
+
+        let first = selector.next(&pool).await?;
+        let second = selector.next(&pool).await?;
+        assert_ne!(first.api_key.id, second.api_key.id);
+
+        let first_id = first.api_key.id;
+        drop(first);
+        assert_eq!(selector.next(&pool).await?.api_key.id, first_id);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn results_are_stored_with_the_key_they_were_requested_with(
+        pool: SqlitePool,
+    ) -> anyhow::Result<()> {
+        insert_statements(&["one", "two"], &pool).await?;
+        let raw_keys = ["sk-first-test-key", "sk-second-test-key"];
+        let env = MockAiEnv::new(statement_meta_reply).with_openai_keys();
+        let predictor = Predictor::create(
+            &args_with_keys(&raw_keys),
+            env.clone(),
+            env.clone(),
+            Default::default(),
+            pool.clone(),
+        )
+        .await?;
+
+        assert_eq!(run_until_idle(&predictor).await, 1);
+
+        let (prompt_keys, embedding_keys) = env.api_keys();
+        let prompt_key = prompt_keys[0].to_owned().unwrap();
+        let embedding_key = embedding_keys[0].to_owned().unwrap();
+        assert!(raw_keys.contains(&prompt_key.as_str()));
+        assert!(raw_keys.contains(&embedding_key.as_str()));
+
+        let key_id = |raw_key: String| async {
+            ApiKey::get_or_create(
+                &mut pool.to_owned(),
+                &TransientApiKey::Raw(raw_key),
+                None::<String>,
+            )
+            .await
+            .map(|key| key.id)
+        };
+        let prediction_keys =
+            sqlx::query_scalar!("SELECT DISTINCT api_key_id FROM statement_predictions")
+                .fetch_all(&pool)
+                .await?;
+        assert_eq!(prediction_keys, vec![key_id(prompt_key).await?]);
+        let embedding_key_ids =
+            sqlx::query_scalar!("SELECT DISTINCT api_key_id FROM statement_embeddings")
+                .fetch_all(&pool)
+                .await?;
+        assert_eq!(embedding_key_ids, vec![key_id(embedding_key).await?]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn quotas_survive_restarts(pool: SqlitePool) -> anyhow::Result<()> {
+        sqlx::query!("INSERT INTO api_keys (id, hash) VALUES (1, 'hash')")
+            .execute(&pool)
+            .await?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        // two statements of one call within the last minute and one older call
+        for (statement_id, created) in [(1, now - 10), (2, now - 10), (3, now - 120)] {
+            sqlx::query!(
+                "INSERT INTO statements (id, text) VALUES (?, 'text')",
+                statement_id
+            )
+            .execute(&pool)
+            .await?;
+            sqlx::query!(
+                r#"INSERT INTO statement_predictions
+                (statement_id, ai_env, prompt_name, prompt_version, prompt_result,
+                 completion_tokens, prompt_tokens, api_key_id, created)
+                VALUES (?, 'env', 'prompt', 1, 'result', 200, 300, 1, ?)"#,
+                statement_id,
+                created,
+            )
+            .execute(&pool)
+            .await?;
+        }
+
+        let tokens = RateLimiter::sliding_window(1500.0, Duration::from_secs(60));
+        tokens.restore(prediction_token_usage(now - 60, &pool).await?);
+        assert!(tokens.try_acquire(600).is_err());
+        assert_eq!(tokens.try_acquire(500), Ok(0.0));
+
+        // two calls with the same key in the same second, of two statements and one
+        for (statement_id, tokens) in [(1, 51), (2, 50), (3, 40)] {
+            sqlx::query!(
+                "INSERT INTO statement_embeddings (statement_id, prompt_tokens, api_key_id, created)
+                VALUES (?, ?, 1, ?)",
+                statement_id,
+                tokens,
+                now,
+            )
+            .execute(&pool)
+            .await?;
+        }
+        let tokens = RateLimiter::sliding_window(200.0, Duration::from_secs(60));
+        tokens.restore(embedding_token_usage(now - 60, &pool).await?);
+        assert!(tokens.try_acquire(60).is_err());
+        assert_eq!(tokens.try_acquire(59), Ok(0.0));
+
+        let api_calls = RateLimiter::token_bucket(2.0, Duration::from_secs(3600));
+        api_calls.acquire(2).await;
+        api_calls.store(PROMPT_API_CALLS, &pool).await?;
+        let restarted = RateLimiter::token_bucket(2.0, Duration::from_secs(3600));
+        restarted.load(PROMPT_API_CALLS, &pool).await?;
+        assert!(restarted.try_acquire(1).is_err());
+        Ok(())
     }
 }
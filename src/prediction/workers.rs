@@ -0,0 +1,168 @@
+//! Supervised tasks which do the prediction work concurrently
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tracing::{error, info};
+
+use crate::shutdown::Shutdown;
+use crate::structs::Statement;
+
+/// What a prediction worker does
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Work {
+    /// Predicts batches of statements
+    Classification,
+    /// Embeds statements
+    Embedding,
+    /// Goes through statements of failed batches one by one, flagging those failing the
+    /// moderation check and predicting the others
+    Moderation,
+}
+
+/// Statements being worked on, so concurrent workers of a kind pick different ones
+#[derive(Default)]
+pub struct Claims {
+    claimed: Arc<Mutex<HashSet<i64>>>,
+    /// Held while selecting, so workers see the statements claimed by each other
+    selecting: tokio::sync::Mutex<()>,
+}
+
+/// Statements claimed by a worker, released when dropped
+pub struct Claim {
+    pub stmts: Vec<Statement>,
+    claims: Arc<Mutex<HashSet<i64>>>,
+}
+
+impl Claims {
+    /// Claims up to `n` of the statements yielded by `select`, leaving out those claimed
+    /// already. `select` is passed the number of claimed statements, to yield that many more.
+    /// None if there are none left.
+    pub async fn claim_next<F, Fut>(&self, n: usize, select: F) -> anyhow::Result<Option<Claim>>
+    where
+        F: FnOnce(usize) -> Fut,
+        Fut: Future<Output = anyhow::Result<Vec<Statement>>>,
+    {
+        let _selecting = self.selecting.lock().await;
+        let claimed = self.claimed.lock().unwrap().len();
+        let stmts = select(claimed).await?;
+        Ok(self.claim(stmts, n))
+    }
+
+    fn claim(&self, stmts: Vec<Statement>, n: usize) -> Option<Claim> {
+        let mut claimed = self.claimed.lock().unwrap();
+        let stmts: Vec<Statement> = stmts
+            .into_iter()
+            .filter(|stmt| !claimed.contains(&stmt.id))
+            .take(n)
+            .collect();
+        if stmts.is_empty() {
+            return None;
+        }
+        claimed.extend(stmts.iter().map(|stmt| stmt.id));
+        Some(Claim {
+            stmts,
+            claims: self.claimed.clone(),
+        })
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let mut claimed = self.claims.lock().unwrap();
+        for stmt in &self.stmts {
+            claimed.remove(&stmt.id);
+        }
+    }
+}
+
+/// Runs the task created by `start` until shutdown, starting a new one after `restart_delay`
+/// whenever it panics or returns early
+pub async fn supervise<F, Fut>(name: String, restart_delay: Duration, shutdown: Shutdown, start: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    info!("Starting {name}");
+    loop {
+        let result = tokio::spawn(start()).await;
+        if shutdown.requested() {
+            break;
+        }
+        match result {
+            Ok(()) => error!("{name} stopped, restarting in {}s", restart_delay.as_secs()),
+            Err(err) => error!(
+                "{name} failed: {err}, restarting in {}s",
+                restart_delay.as_secs()
+            ),
+        }
+        shutdown.sleep(restart_delay).await;
+        if shutdown.requested() {
+            break;
+        }
+    }
+    info!("Stopped {name}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn stmt(id: i64) -> Statement {
+        Statement {
+            id,
+            text: format!("statement {id}"),
+        }
+    }
+
+    #[test]
+    fn claimed_statements_are_left_out_until_released() {
+        let claims = Claims::default();
+        let first = claims.claim((1..=3).map(stmt).collect(), 2).unwrap();
+        assert_eq!(first.stmts.iter().map(|s| s.id).collect::<Vec<_>>(), [1, 2]);
+
+        let second = claims.claim((1..=3).map(stmt).collect(), 2).unwrap();
+        assert_eq!(second.stmts.iter().map(|s| s.id).collect::<Vec<_>>(), [3]);
+        assert!(claims.claim((1..=3).map(stmt).collect(), 2).is_none());
+
+        drop(first);
+        let third = claims.claim((1..=3).map(stmt).collect(), 5).unwrap();
+        assert_eq!(third.stmts.iter().map(|s| s.id).collect::<Vec<_>>(), [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn failed_tasks_are_restarted_until_shutdown() {
+        let (sender, shutdown) = Shutdown::manual();
+        let starts = Arc::new(AtomicUsize::new(0));
+        let supervisor = {
+            let starts = starts.clone();
+            let task_shutdown = shutdown.clone();
+            tokio::spawn(supervise(
+                "test worker".into(),
+                Duration::from_millis(1),
+                shutdown,
+                move || {
+                    let starts = starts.clone();
+                    let shutdown = task_shutdown.clone();
+                    async move {
+                        match starts.fetch_add(1, Ordering::SeqCst) {
+                            0 => panic!("first run fails"),
+                            1 => {}
+                            _ => shutdown.wait().await,
+                        }
+                    }
+                },
+            ))
+        };
+
+        while starts.load(Ordering::SeqCst) < 3 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        sender.send(true).unwrap();
+        supervisor.await.unwrap();
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,404 @@
+//! Limits how often a client may use routes which are cheap to call but costly to serve or easy
+//! to spam, see [RouteClass]
+//!
+//! Requests are counted per client ip and, if they carry one, per credential, so that neither
+//! switching accounts nor switching networks gets around a limit. Login attempts are counted per
+//! username as well, see [RateLimits::limit_login]. Limited requests are answered with
+//! [AppError::TooManyRequests].
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Extension;
+use http::header::AUTHORIZATION;
+use http::{HeaderMap, HeaderName, Method, Request};
+use rl_queue::KeyedRateLimiter;
+use tower_cookies::Cookies;
+
+use crate::auth::LEGACY_SECRET_COOKIE;
+use crate::command_line_args::RateLimitArgs;
+use crate::error::AppError;
+use crate::sessions::SESSION_COOKIE;
+
+/// Clients with their full quota are dropped once this many are tracked for a route class
+const PRUNE_THRESHOLD: usize = 10_000;
+
+pub type SharedRateLimits = Arc<RateLimits>;
+
+/// Routes sharing a limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RouteClass {
+    AccountCreation,
+    Login,
+    StatementCreation,
+    Voting,
+    Search,
+}
+
+impl RouteClass {
+    /// Class of a request, None if it is not limited
+    pub fn of(method: &Method, path: &str) -> Option<Self> {
+        let segments: Vec<&str> = path.trim_end_matches('/').split('/').skip(1).collect();
+        if method == Method::GET {
+            return match segments[..] {
+                // creates an account for devices without one
+                ["merge", _] => Some(RouteClass::AccountCreation),
+                ["api", "v1", "search"] => Some(RouteClass::Search),
+                _ => None,
+            };
+        }
+        if method != Method::POST {
+            return None;
+        }
+        match segments[..] {
+            ["api", "v0" | "v1", "user", "create"] => Some(RouteClass::AccountCreation),
+            ["login"]
+            | ["login", "passkey"]
+            | ["login", "passkey", "options"]
+            | ["user", "credentials", "passkey", "options"]
+            | ["api", "v1", "login", "password"] => Some(RouteClass::Login),
+            ["create"] | ["api", "v1", "statement"] => Some(RouteClass::StatementCreation),
+            ["statement", "vote"] | ["api", "v0" | "v1", "statement", _, "vote"] => {
+                Some(RouteClass::Voting)
+            }
+            ["search"] | ["new", "completions"] | ["statement", _, "relations", "search"] => {
+                Some(RouteClass::Search)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum ClientKey {
+    Ip(IpAddr),
+    /// Hash of a bearer token or session cookie, to not keep credentials in memory
+    Credential(u64),
+    /// Hash of a username logged into, whatever client tries it
+    Username(u64),
+}
+
+fn hashed(value: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Limiter of one route class for every client. A token bucket, so that a client can do a few
+/// requests in a row, but not keep that pace up.
+struct ClassLimiter(KeyedRateLimiter<ClientKey>);
+
+impl ClassLimiter {
+    fn new(allowed: u64, seconds: u64) -> Self {
+        ClassLimiter(KeyedRateLimiter::token_bucket(
+            allowed as f64,
+            Duration::from_secs(seconds),
+        ))
+    }
+
+    /// Counts a request of every key, returns how long to wait if any key is over its limit
+    fn add(&self, keys: &[ClientKey]) -> Option<Duration> {
+        if self.0.len() >= PRUNE_THRESHOLD {
+            self.0.prune();
+        }
+        keys.iter()
+            .filter_map(|key| self.0.try_acquire(key, 1).err())
+            .max()
+            .map(|ready| ready.saturating_duration_since(Instant::now()))
+    }
+}
+
+/// Limits of all route classes, see [from_args]
+pub struct RateLimits {
+    classes: HashMap<RouteClass, ClassLimiter>,
+    client_ip_header: Option<HeaderName>,
+}
+
+impl RateLimits {
+    /// Ip of the client from the header set by a reverse proxy, or the address of the peer if
+    /// the header is not configured or missing, e.g. on requests which bypassed the proxy
+    fn client_ip(&self, headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<IpAddr> {
+        self.client_ip_header
+            .as_ref()
+            .and_then(|header| headers.get(header))
+            .and_then(|value| value.to_str().ok())
+            // X-Forwarded-For style lists start with the client
+            .and_then(|value| value.split(',').next())
+            .and_then(|ip| ip.trim().parse().ok())
+            .or(peer.map(|peer| peer.ip()))
+    }
+
+    /// Counts a login attempt for the username, so that guessing its password is limited even
+    /// when the attempts come from many clients. The username is only known to the handler.
+    pub fn limit_login(&self, username: &str) -> Result<(), AppError> {
+        let Some(limiter) = self.classes.get(&RouteClass::Login) else {
+            return Ok(());
+        };
+        // usernames are unique regardless of case
+        let key = ClientKey::Username(hashed(&username.to_lowercase()));
+        match limiter.add(&[key]) {
+            Some(retry_after) => Err(AppError::TooManyRequests { retry_after }),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn from_args(args: &RateLimitArgs) -> SharedRateLimits {
+    let limits = [
+        (
+            RouteClass::AccountCreation,
+            args.account_creations_per_duration,
+            args.account_creations_seconds_per_duration,
+        ),
+        (
+            RouteClass::Login,
+            args.logins_per_duration,
+            args.logins_seconds_per_duration,
+        ),
+        (
+            RouteClass::StatementCreation,
+            args.statements_per_duration,
+            args.statements_seconds_per_duration,
+        ),
+        (
+            RouteClass::Voting,
+            args.votes_per_duration,
+            args.votes_seconds_per_duration,
+        ),
+        (
+            RouteClass::Search,
+            args.searches_per_duration,
+            args.searches_seconds_per_duration,
+        ),
+    ];
+    Arc::new(RateLimits {
+        classes: limits
+            .into_iter()
+            // a limit of 0 disables limiting
+            .filter(|(_, allowed, _)| *allowed > 0)
+            .map(|(class, allowed, seconds)| (class, ClassLimiter::new(allowed, seconds)))
+            .collect(),
+        client_ip_header: args
+            .client_ip_header
+            .as_ref()
+            .and_then(|header| header.parse().ok()),
+    })
+}
+
+/// Bearer token or session cookie the request authenticates with
+fn credential(headers: &HeaderMap, cookies: &Cookies) -> Option<String> {
+    let bearer = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match bearer {
+        Some(token) => Some(token.to_string()),
+        None => cookies
+            .get(SESSION_COOKIE)
+            .or_else(|| cookies.get(LEGACY_SECRET_COOKIE))
+            .map(|cookie| cookie.value().to_string()),
+    }
+}
+
+/// Middleware answering requests over the limit of their [RouteClass] with 429
+pub async fn rate_limit(
+    Extension(limits): Extension<SharedRateLimits>,
+    cookies: Cookies,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, AppError> {
+    let Some(limiter) = RouteClass::of(request.method(), request.uri().path())
+        .and_then(|class| limits.classes.get(&class))
+    else {
+        return Ok(next.run(request).await);
+    };
+
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| *peer);
+    let mut keys = vec![];
+    if let Some(ip) = limits.client_ip(request.headers(), peer) {
+        keys.push(ClientKey::Ip(ip));
+    }
+    if let Some(credential) = credential(request.headers(), &cookies) {
+        keys.push(ClientKey::Credential(hashed(&credential)));
+    }
+
+    match limiter.add(&keys) {
+        Some(retry_after) => Err(AppError::TooManyRequests { retry_after }),
+        None => Ok(next.run(request).await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::post;
+    use axum::{middleware, Router};
+    use clap::Parser;
+    use http::header::RETRY_AFTER;
+    use http::StatusCode;
+    use tower::ServiceExt;
+    use tower_cookies::CookieManagerLayer;
+
+    fn app() -> Router {
+        let args = RateLimitArgs::parse_from([
+            "test",
+            "--votes-per-duration",
+            "2",
+            "--client-ip-header",
+            "fly-client-ip",
+        ]);
+        Router::new()
+            .route("/statement/vote", post(|| async { "voted" }))
+            .route("/statement/1", post(|| async { "not limited" }))
+            .layer(middleware::from_fn(rate_limit))
+            .layer(Extension(from_args(&args)))
+            .layer(CookieManagerLayer::new())
+    }
+
+    fn post_from(uri: &str, ip: &str, cookie: Option<&str>) -> Request<Body> {
+        let mut builder = Request::post(uri).header("fly-client-ip", ip);
+        if let Some(cookie) = cookie {
+            builder = builder.header(http::header::COOKIE, cookie);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn clients_over_the_limit_get_429() {
+        let app = app();
+        for _ in 0..2 {
+            let response = app
+                .clone()
+                .oneshot(post_from("/statement/vote", "1.2.3.4", None))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = app
+            .clone()
+            .oneshot(post_from("/statement/vote", "1.2.3.4", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(RETRY_AFTER));
+
+        // other routes and clients are not affected
+        let response = app
+            .clone()
+            .oneshot(post_from("/statement/1", "1.2.3.4", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .oneshot(post_from("/statement/vote", "5.6.7.8", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn switching_networks_keeps_the_limit_of_a_session() {
+        let app = app();
+        for ip in ["1.1.1.1", "2.2.2.2"] {
+            let response = app
+                .clone()
+                .oneshot(post_from("/statement/vote", ip, Some("session=abc")))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = app
+            .oneshot(post_from("/statement/vote", "3.3.3.3", Some("session=abc")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn client_ip_falls_back_to_the_peer() {
+        let limits = from_args(&RateLimitArgs::parse_from([
+            "test",
+            "--client-ip-header",
+            "fly-client-ip",
+        ]));
+        let peer: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            limits.client_ip(&headers, Some(peer)),
+            Some(peer.ip()),
+            "missing header"
+        );
+        headers.insert("fly-client-ip", "1.2.3.4, 10.0.0.1".parse().unwrap());
+        assert_eq!(
+            limits.client_ip(&headers, Some(peer)),
+            Some("1.2.3.4".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn logins_are_limited_per_username() {
+        let limits = from_args(&RateLimitArgs::parse_from([
+            "test",
+            "--logins-per-duration",
+            "2",
+        ]));
+        assert!(limits.limit_login("alice").is_ok());
+        assert!(limits.limit_login("Alice").is_ok());
+        assert!(matches!(
+            limits.limit_login("alice"),
+            Err(AppError::TooManyRequests { .. })
+        ));
+        assert!(limits.limit_login("bob").is_ok());
+    }
+
+    #[test]
+    fn routes_are_classified() {
+        let class = |method: Method, path| RouteClass::of(&method, path);
+        assert_eq!(
+            class(Method::POST, "/api/v0/user/create"),
+            Some(RouteClass::AccountCreation)
+        );
+        assert_eq!(
+            class(Method::GET, "/merge/token"),
+            Some(RouteClass::AccountCreation)
+        );
+        assert_eq!(class(Method::POST, "/login"), Some(RouteClass::Login));
+        assert_eq!(
+            class(Method::POST, "/login/passkey/options"),
+            Some(RouteClass::Login)
+        );
+        assert_eq!(
+            class(Method::POST, "/api/v1/login/password"),
+            Some(RouteClass::Login)
+        );
+        assert_eq!(
+            class(Method::POST, "/create"),
+            Some(RouteClass::StatementCreation)
+        );
+        assert_eq!(
+            class(Method::POST, "/api/v1/statement/3/vote"),
+            Some(RouteClass::Voting)
+        );
+        assert_eq!(class(Method::GET, "/api/v1/statement/3/vote"), None);
+        assert_eq!(
+            class(Method::GET, "/api/v1/search"),
+            Some(RouteClass::Search)
+        );
+        assert_eq!(
+            class(Method::POST, "/statement/3/relations/search"),
+            Some(RouteClass::Search)
+        );
+        assert_eq!(class(Method::POST, "/statement/3/subscribe"), None);
+    }
+}
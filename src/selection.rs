@@ -0,0 +1,336 @@
+//! Strategies to select the next statement a user should vote on
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::Utc;
+use propolis_datas::embedding::{cosine_similarity, vector_from_blob};
+use sqlx::SqlitePool;
+
+use crate::command_line_args::{SelectionArgs, StatementSelection};
+use crate::structs::User;
+
+/// Amount of recently voted statements to compare candidates against
+const DIVERSITY_RECENT_VOTES: i64 = 20;
+/// Amount of random candidates to pick the most diverse statement from
+const DIVERSITY_CANDIDATES: i64 = 100;
+
+/// Selects the next statement for a [User]
+#[async_trait]
+pub trait StatementSelectionStrategy: Send + Sync {
+    /// Returns the id of the next statement or None if there is nothing left to vote on
+    async fn next_statement_id(&self, user: &User, pool: &SqlitePool) -> Result<Option<i64>>;
+}
+
+/// Strategy shared between all handlers
+pub type SharedSelectionStrategy = Arc<dyn StatementSelectionStrategy>;
+
+/// Builds the strategy configured via command line: follow-ups in the queue always come first
+pub fn from_args(args: &SelectionArgs) -> SharedSelectionStrategy {
+    match args.statement_selection {
+        StatementSelection::Random => Arc::new(QueueFirst(RandomUnvoted)),
+        StatementSelection::LeastVoted => Arc::new(QueueFirst(LeastVoted)),
+        StatementSelection::MostPolarizing => Arc::new(QueueFirst(MostPolarizing)),
+        StatementSelection::EmbeddingDiversity => Arc::new(QueueFirst(EmbeddingDiversity)),
+        StatementSelection::NotRecentlySkipped => Arc::new(QueueFirst(NotRecentlySkipped {
+            cooldown: Duration::from_secs(args.skip_cooldown_days * 24 * 60 * 60),
+        })),
+    }
+}
+
+/// Picks the oldest entry of the user's queue, otherwise asks the fallback strategy
+pub struct QueueFirst<S: StatementSelectionStrategy>(pub S);
+
+#[async_trait]
+impl<S: StatementSelectionStrategy> StatementSelectionStrategy for QueueFirst<S> {
+    async fn next_statement_id(&self, user: &User, pool: &SqlitePool) -> Result<Option<i64>> {
+        // TODO: sqlx bug: adding `order by timestamp` infers wrong type in macro
+        let queued = sqlx::query_scalar::<_, i64>(
+            "select statement_id from queue where user_id = ? order by created asc limit 1",
+        )
+        .bind(user.id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(match queued {
+            Some(statement_id) => Some(statement_id),
+            None => self.0.next_statement_id(user, pool).await?,
+        })
+    }
+}
+
+/// Random statement the user has not voted on yet
+pub struct RandomUnvoted;
+
+#[async_trait]
+impl StatementSelectionStrategy for RandomUnvoted {
+    async fn next_statement_id(&self, user: &User, pool: &SqlitePool) -> Result<Option<i64>> {
+        Ok(sqlx::query_scalar::<_, i64>(
+            "select id from statements where id not in (select statement_id from votes v where v.user_id = ?) order by random() limit 1")
+            .bind(user.id)
+            .fetch_optional(pool)
+            .await?)
+    }
+}
+
+/// Unvoted statement with the fewest votes, to collect opinions on new statements quickly
+pub struct LeastVoted;
+
+#[async_trait]
+impl StatementSelectionStrategy for LeastVoted {
+    async fn next_statement_id(&self, user: &User, pool: &SqlitePool) -> Result<Option<i64>> {
+        Ok(sqlx::query_scalar::<_, i64>(
+            "select s.id from statements s
+            left outer join votes v on v.statement_id = s.id
+            where s.id not in (select statement_id from votes where user_id = ?)
+            group by s.id
+            order by count(v.statement_id) asc, random()
+            limit 1",
+        )
+        .bind(user.id)
+        .fetch_optional(pool)
+        .await?)
+    }
+}
+
+/// Unvoted statement with the highest polarization, ties are broken by number of votes
+pub struct MostPolarizing;
+
+#[async_trait]
+impl StatementSelectionStrategy for MostPolarizing {
+    async fn next_statement_id(&self, user: &User, pool: &SqlitePool) -> Result<Option<i64>> {
+        Ok(sqlx::query_scalar::<_, i64>(
+            "select statement_id from statement_stats
+            where statement_id not in (select statement_id from votes where user_id = ?)
+            order by polarization desc, total_votes desc, random()
+            limit 1",
+        )
+        .bind(user.id)
+        .fetch_optional(pool)
+        .await?)
+    }
+}
+
+/// Random unvoted statement. Statements skipped longer than `cooldown` ago are shown again.
+pub struct NotRecentlySkipped {
+    pub cooldown: Duration,
+}
+
+#[async_trait]
+impl StatementSelectionStrategy for NotRecentlySkipped {
+    async fn next_statement_id(&self, user: &User, pool: &SqlitePool) -> Result<Option<i64>> {
+        let skipped_before = Utc::now().timestamp() - self.cooldown.as_secs() as i64;
+        Ok(sqlx::query_scalar::<_, i64>(
+            "select id from statements
+            where id not in (select statement_id from votes where user_id = ? and vote != 0)
+            and id not in (select statement_id from vote_history where user_id = ? and vote = 0 and created > ?)
+            order by random()
+            limit 1",
+        )
+        .bind(user.id)
+        .bind(user.id)
+        .bind(skipped_before)
+        .fetch_optional(pool)
+        .await?)
+    }
+}
+
+/// Unvoted statement whose embedding is least similar to the recently voted statements
+///
+/// Only embeddings of the model which made the newest embedding are compared, since other models
+/// are being replaced. Falls back to [RandomUnvoted] if there are no embeddings for unvoted
+/// statements.
+pub struct EmbeddingDiversity;
+
+#[async_trait]
+impl StatementSelectionStrategy for EmbeddingDiversity {
+    async fn next_statement_id(&self, user: &User, pool: &SqlitePool) -> Result<Option<i64>> {
+        let recent: Vec<Vec<f32>> = sqlx::query_scalar::<_, Vec<u8>>(
+            "select e.data from vote_history v
+            join statement_embeddings e on e.statement_id = v.statement_id
+            where v.user_id = ? and e.data is not null
+            and e.model = (select model from statement_embeddings order by created desc limit 1)
+            order by v.created desc
+            limit ?",
+        )
+        .bind(user.id)
+        .bind(DIVERSITY_RECENT_VOTES)
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .filter_map(|blob| vector_from_blob(blob))
+        .collect();
+
+        let candidates = sqlx::query_as::<_, (i64, Vec<u8>)>(
+            "select statement_id, data from statement_embeddings
+            where data is not null
+            and model = (select model from statement_embeddings order by created desc limit 1)
+            and statement_id not in (select statement_id from votes where user_id = ?)
+            order by random()
+            limit ?",
+        )
+        .bind(user.id)
+        .bind(DIVERSITY_CANDIDATES)
+        .fetch_all(pool)
+        .await?;
+
+        // the most diverse candidate is the one whose closest recent statement is farthest away
+        let most_diverse = candidates
+            .iter()
+            .filter_map(|(id, blob)| Some((*id, vector_from_blob(blob)?)))
+            .map(|(id, candidate)| {
+                let closest = recent
+                    .iter()
+                    .map(|r| cosine_similarity(&candidate, r))
+                    .fold(f64::MIN, f64::max);
+                (id, closest)
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(id, _)| id);
+
+        match most_diverse {
+            Some(id) => Ok(Some(id)),
+            None => RandomUnvoted.next_statement_id(user, pool).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn setup(pool: &SqlitePool) -> sqlx::Result<User> {
+        sqlx::query!("insert into users(id, secret) values (1, 'abc')")
+            .execute(pool)
+            .await?;
+        sqlx::query!("insert into users(id, secret) values (2, 'def')")
+            .execute(pool)
+            .await?;
+        for (id, text) in [(1, "The world is flat."), (2, "The universe is flat.")] {
+            sqlx::query!("insert into statements(id, text) values (?, ?)", id, text)
+                .execute(pool)
+                .await?;
+        }
+        Ok(User {
+            id: 1,
+            secret: Some("abc".to_string()),
+        })
+    }
+
+    #[sqlx::test]
+    async fn queue_first_prefers_queue(pool: SqlitePool) -> anyhow::Result<()> {
+        let user = setup(&pool).await?;
+        sqlx::query!("insert into queue(user_id, statement_id) values (1, 2)")
+            .execute(&pool)
+            .await?;
+
+        let strategy = QueueFirst(LeastVoted);
+        assert_eq!(strategy.next_statement_id(&user, &pool).await?, Some(2));
+
+        sqlx::query!("insert into vote_history(user_id, statement_id, vote) values (1, 2, 1)")
+            .execute(&pool)
+            .await?;
+        assert_eq!(strategy.next_statement_id(&user, &pool).await?, Some(1));
+
+        sqlx::query!("insert into vote_history(user_id, statement_id, vote) values (1, 1, 1)")
+            .execute(&pool)
+            .await?;
+        assert_eq!(strategy.next_statement_id(&user, &pool).await?, None);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn least_voted_first(pool: SqlitePool) -> anyhow::Result<()> {
+        let user = setup(&pool).await?;
+        sqlx::query!("insert into vote_history(user_id, statement_id, vote) values (2, 1, 1)")
+            .execute(&pool)
+            .await?;
+
+        assert_eq!(LeastVoted.next_statement_id(&user, &pool).await?, Some(2));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn most_polarizing_first(pool: SqlitePool) -> anyhow::Result<()> {
+        let user = setup(&pool).await?;
+        sqlx::query!("insert into users(id, secret) values (3, 'ghi')")
+            .execute(&pool)
+            .await?;
+        // statement 1: unanimous, statement 2: split
+        for (user_id, statement_id, vote) in [(2, 1, 1), (3, 1, 1), (2, 2, 1), (3, 2, -1)] {
+            sqlx::query!(
+                "insert into vote_history(user_id, statement_id, vote) values (?, ?, ?)",
+                user_id,
+                statement_id,
+                vote
+            )
+            .execute(&pool)
+            .await?;
+        }
+
+        assert_eq!(
+            MostPolarizing.next_statement_id(&user, &pool).await?,
+            Some(2)
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn not_recently_skipped(pool: SqlitePool) -> anyhow::Result<()> {
+        let user = setup(&pool).await?;
+        sqlx::query!("insert into vote_history(user_id, statement_id, vote) values (1, 1, 1)")
+            .execute(&pool)
+            .await?;
+        sqlx::query!("insert into vote_history(user_id, statement_id, vote) values (1, 2, 0)")
+            .execute(&pool)
+            .await?;
+
+        let strategy = NotRecentlySkipped {
+            cooldown: Duration::from_secs(60 * 60),
+        };
+        assert_eq!(strategy.next_statement_id(&user, &pool).await?, None);
+
+        sqlx::query!("update vote_history set created = created - 2 * 60 * 60 where vote = 0")
+            .execute(&pool)
+            .await?;
+        assert_eq!(strategy.next_statement_id(&user, &pool).await?, Some(2));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn embedding_diversity_picks_least_similar(pool: SqlitePool) -> anyhow::Result<()> {
+        let user = setup(&pool).await?;
+        sqlx::query!("insert into statements(id, text) values (3, 'Cats are better than dogs.')")
+            .execute(&pool)
+            .await?;
+        sqlx::query!("insert into api_keys(id, hash) values (1, 'hash')")
+            .execute(&pool)
+            .await?;
+        // vectors as sqlite-vector blobs: [1, 0], [1, 0.1] and [0, 1]
+        for (statement_id, data) in [
+            (1, "76010000803f00000000"),
+            (2, "76010000803fcdcccc3d"),
+            (3, "7601000000000000803f"),
+        ] {
+            sqlx::query(
+                "insert into statement_embeddings(statement_id, data, prompt_tokens, api_key_id)
+                values (?, unhex(?), 0, 1)",
+            )
+            .bind(statement_id)
+            .bind(data)
+            .execute(&pool)
+            .await?;
+        }
+        sqlx::query!("insert into vote_history(user_id, statement_id, vote) values (1, 1, 1)")
+            .execute(&pool)
+            .await?;
+
+        assert_eq!(
+            EmbeddingDiversity.next_statement_id(&user, &pool).await?,
+            Some(3)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+//! Server-side sessions of browsers
+//!
+//! The session cookie only holds a random token, whose keyed hash maps to the user in the
+//! sessions table. A session expires after [SESSION_LIFETIME_SECONDS] without being used and can
+//! be revoked on its own on the options page. Browsers never get to see the secret of an account.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+use chrono::Utc;
+use http::HeaderMap;
+use sqlx::SqlitePool;
+use tower_cookies::cookie::time::Duration;
+use tower_cookies::cookie::SameSite;
+use tower_cookies::{Cookie, Cookies};
+
+use crate::auth::{generate_secret, hash_secret};
+use crate::command_line_args::AuthArgs;
+use crate::devices::{add_audit_entry, device_name, AuditEvent};
+use crate::structs::User;
+
+pub const SESSION_COOKIE: &str = "session";
+/// Sessions which are not used for this long expire
+pub const SESSION_LIFETIME_SECONDS: i64 = 90 * 24 * 60 * 60;
+/// last_seen and expiry of a session are moved forward at most this often, to avoid a write on
+/// every request
+const REFRESH_SECONDS: i64 = 60 * 60;
+
+static SECURE_COOKIES: AtomicBool = AtomicBool::new(true);
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct Session {
+    pub id: i64,
+    pub name: String,
+    pub created: i64,
+    pub last_seen: i64,
+    pub expires: i64,
+}
+
+/// Session of the current request
+pub struct CurrentSession {
+    pub id: i64,
+    pub user_id: i64,
+}
+
+impl User {
+    /// Sessions which have not expired, most recently used first
+    pub async fn sessions(&self, pool: &SqlitePool) -> Result<Vec<Session>> {
+        let now = Utc::now().timestamp();
+        Ok(sqlx::query_as!(
+            Session,
+            "SELECT id, name, created, last_seen, expires FROM sessions
+            WHERE user_id = ? AND expires > ? ORDER BY last_seen DESC",
+            self.id,
+            now
+        )
+        .fetch_all(pool)
+        .await?)
+    }
+
+    /// Logs a browser out of the account
+    pub async fn revoke_session(&self, session_id: i64, pool: &SqlitePool) -> Result<()> {
+        let mut tx = pool.begin().await?;
+        let name = sqlx::query_scalar!(
+            "DELETE FROM sessions WHERE id = ? AND user_id = ? RETURNING name",
+            session_id,
+            self.id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(name) = name {
+            add_audit_entry(self.id, AuditEvent::SessionRevoked, Some(&name), &mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+/// Whether the session and csrf cookies are only sent over https, see `--insecure-cookies`
+pub fn init_session_cookies(args: &AuthArgs) {
+    SECURE_COOKIES.store(!args.insecure_cookies, Ordering::Relaxed);
+}
+
+/// HttpOnly cookie for the whole site, which lives as long as a session
+pub fn secure_cookie(name: &'static str, value: String) -> Cookie<'static> {
+    Cookie::build(name, value)
+        .path("/")
+        .http_only(true)
+        .secure(SECURE_COOKIES.load(Ordering::Relaxed))
+        .same_site(SameSite::Lax)
+        .max_age(Duration::seconds(SESSION_LIFETIME_SECONDS))
+        .finish()
+}
+
+/// Starts a session for the user and sets its cookie. A previous session of the browser ends.
+pub async fn start_session(
+    user_id: i64,
+    headers: &HeaderMap,
+    cookies: &Cookies,
+    pool: &SqlitePool,
+) -> Result<()> {
+    end_session(cookies, pool).await?;
+    let now = Utc::now().timestamp();
+    sqlx::query!("DELETE FROM sessions WHERE expires <= ?", now)
+        .execute(pool)
+        .await?;
+
+    let token = generate_secret();
+    let (_, token_hash) = hash_secret(&token);
+    let name = device_name(headers);
+    let expires = now + SESSION_LIFETIME_SECONDS;
+    sqlx::query!(
+        "INSERT INTO sessions (user_id, token_hash, name, expires) VALUES (?, ?, ?, ?)",
+        user_id,
+        token_hash,
+        name,
+        expires
+    )
+    .execute(pool)
+    .await?;
+    cookies.add(secure_cookie(SESSION_COOKIE, token));
+    Ok(())
+}
+
+/// Logs the browser into an existing account, e.g. with a password
+///
+/// `method` describes how the browser logged in and ends up in the audit log.
+pub async fn log_in(
+    user_id: i64,
+    method: &str,
+    headers: &HeaderMap,
+    cookies: &Cookies,
+    pool: &SqlitePool,
+) -> Result<()> {
+    start_session(user_id, headers, cookies, pool).await?;
+    let detail = format!("{} via {method}", device_name(headers));
+    add_audit_entry(user_id, AuditEvent::LoggedIn, Some(&detail), pool).await?;
+    Ok(())
+}
+
+/// Finds the session of the cookie and moves its expiry forward
+pub async fn current_session(
+    cookies: &Cookies,
+    pool: &SqlitePool,
+) -> Result<Option<CurrentSession>> {
+    let Some(cookie) = cookies.get(SESSION_COOKIE) else {
+        return Ok(None);
+    };
+    let token = cookie.value().to_string();
+    let (_, token_hash) = hash_secret(&token);
+    let now = Utc::now().timestamp();
+    let Some(session) = sqlx::query!(
+        "SELECT id, user_id, last_seen FROM sessions WHERE token_hash = ? AND expires > ?",
+        token_hash,
+        now
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    if session.last_seen < now - REFRESH_SECONDS {
+        let expires = now + SESSION_LIFETIME_SECONDS;
+        sqlx::query!(
+            "UPDATE sessions SET last_seen = ?, expires = ? WHERE id = ?",
+            now,
+            expires,
+            session.id
+        )
+        .execute(pool)
+        .await?;
+        cookies.add(secure_cookie(SESSION_COOKIE, token));
+    }
+    Ok(Some(CurrentSession {
+        id: session.id,
+        user_id: session.user_id,
+    }))
+}
+
+/// Logs the browser out, the session stops working
+pub async fn end_session(cookies: &Cookies, pool: &SqlitePool) -> Result<()> {
+    if let Some(cookie) = cookies.get(SESSION_COOKIE) {
+        let (_, token_hash) = hash_secret(cookie.value());
+        sqlx::query!("DELETE FROM sessions WHERE token_hash = ?", token_hash)
+            .execute(pool)
+            .await?;
+        let mut cookie = Cookie::named(SESSION_COOKIE);
+        cookie.set_path("/");
+        cookies.remove(cookie);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::init_auth;
+
+    /// Cookies as the browser would send them on the next request
+    fn next_request(cookies: &Cookies) -> Cookies {
+        let next = Cookies::default();
+        for cookie in cookies.list() {
+            next.add(Cookie::new(
+                cookie.name().to_string(),
+                cookie.value().to_string(),
+            ));
+        }
+        next
+    }
+
+    #[sqlx::test]
+    async fn sessions_resolve_to_their_user(pool: SqlitePool) -> anyhow::Result<()> {
+        init_auth();
+        let user = User::create(&pool).await?;
+        let cookies = Cookies::default();
+        start_session(user.id, &HeaderMap::new(), &cookies, &pool).await?;
+        let cookie = cookies.get(SESSION_COOKIE).expect("session cookie");
+        assert!(cookie.http_only().unwrap_or(false));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        assert_ne!(cookie.value(), user.secret.as_deref().unwrap_or(""));
+
+        let cookies = next_request(&cookies);
+        let session = current_session(&cookies, &pool)
+            .await?
+            .expect("valid session");
+        assert_eq!(session.user_id, user.id);
+        assert_eq!(user.sessions(&pool).await?.len(), 1);
+
+        user.revoke_session(session.id, &pool).await?;
+        assert!(current_session(&cookies, &pool).await?.is_none());
+        assert!(user.sessions(&pool).await?.is_empty());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn sessions_expire_unless_used(pool: SqlitePool) -> anyhow::Result<()> {
+        init_auth();
+        let user = User::create(&pool).await?;
+        let cookies = Cookies::default();
+        start_session(user.id, &HeaderMap::new(), &cookies, &pool).await?;
+        let cookies = next_request(&cookies);
+
+        // used a day ago, so the expiry is moved forward
+        sqlx::query!(
+            "UPDATE sessions SET last_seen = last_seen - 86400, expires = expires - 86400"
+        )
+        .execute(&pool)
+        .await?;
+        assert!(current_session(&cookies, &pool).await?.is_some());
+        let expires = sqlx::query_scalar!("SELECT expires FROM sessions")
+            .fetch_one(&pool)
+            .await?;
+        assert!(expires > Utc::now().timestamp() + SESSION_LIFETIME_SECONDS - 60);
+
+        sqlx::query!("UPDATE sessions SET expires = strftime('%s', 'now') - 1")
+            .execute(&pool)
+            .await?;
+        assert!(current_session(&cookies, &pool).await?.is_none());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn logging_out_ends_the_session(pool: SqlitePool) -> anyhow::Result<()> {
+        init_auth();
+        let user = User::create(&pool).await?;
+        let cookies = Cookies::default();
+        start_session(user.id, &HeaderMap::new(), &cookies, &pool).await?;
+        let cookies = next_request(&cookies);
+
+        end_session(&cookies, &pool).await?;
+        assert!(user.sessions(&pool).await?.is_empty());
+        assert!(current_session(&next_request(&cookies), &pool)
+            .await?
+            .is_none());
+        Ok(())
+    }
+}
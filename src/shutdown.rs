@@ -0,0 +1,69 @@
+//! Graceful shutdown of the http server and background tasks
+
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tracing::info;
+
+/// Tells long running tasks to stop, after SIGTERM or ctrl-c
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Listens for SIGTERM and ctrl-c in the background
+    pub fn on_signal() -> Self {
+        let (sender, shutdown) = Self::manual();
+        tokio::spawn(async move {
+            signal().await;
+            info!("Shutting down");
+            let _ = sender.send(true);
+        });
+        shutdown
+    }
+
+    /// Shutdown which is requested by sending `true`
+    pub fn manual() -> (watch::Sender<bool>, Self) {
+        let (sender, receiver) = watch::channel(false);
+        (sender, Self(receiver))
+    }
+
+    // only the prediction workers check for shutdown themselves
+    #[cfg_attr(not(feature = "with_predictions"), allow(dead_code))]
+    pub fn requested(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Waits until shutdown was requested
+    pub async fn wait(mut self) {
+        // an error means the sender is gone, so it can't be requested anymore
+        if self.0.wait_for(|requested| *requested).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Sleeps for `duration`, returns early if shutdown was requested
+    #[cfg_attr(not(feature = "with_predictions"), allow(dead_code))]
+    pub async fn sleep(&self, duration: Duration) {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => {}
+            _ = self.clone().wait() => {}
+        }
+    }
+}
+
+async fn signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Unable to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
@@ -11,7 +11,18 @@ use serde::Serialize;
 #[derive(Serialize, sqlx::FromRow, Debug, Clone)]
 pub struct User {
     pub id: i64,
-    pub secret: String,
+    /// Plain secret, see [User::known_secret]
+    pub secret: Option<String>,
+}
+
+/// What happens to the content of a deleted account
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountDeletion {
+    /// Keep votes attached to the user, which becomes an anonymous tombstone, so that statistics
+    /// stay the same. Authorship, subscriptions, queue, credentials and devices are deleted.
+    Anonymize,
+    /// Delete the user with all votes. Statements stay, but lose their author.
+    Delete,
 }
 
 /// Represents a voting history entry
@@ -24,7 +35,7 @@ pub struct VoteHistoryItem {
 }
 
 /// Represents a statement
-#[derive(Serialize, sqlx::FromRow, Clone, Debug)]
+#[derive(Serialize, Deserialize, sqlx::FromRow, Clone, Debug)]
 pub struct Statement {
     pub id: i64,
     pub text: String,
@@ -46,11 +57,20 @@ impl SearchResultStatement {
     }
 }
 
-#[derive(Debug, PartialEq, Deserialize, Copy, Clone, FromPrimitive)]
+/// Statement found via embedding similarity
+#[derive(Serialize, Clone, Debug)]
+pub struct SimilarStatement {
+    pub statement: Statement,
+    /// Cosine similarity of the embeddings in [-1, 1]
+    pub similarity: f64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Copy, Clone, FromPrimitive)]
 pub enum Vote {
     No = -1,
     Skip = 0,
     Yes = 1,
+    ItDepends = 2,
 }
 
 impl Vote {
@@ -64,15 +84,61 @@ impl Vote {
             Vote::No => -1,
             Vote::Skip => 0,
             Vote::Yes => 1,
+            Vote::ItDepends => 0,
+        }
+    }
+}
+
+/// Type of a [StatementRelation], see table `relation_types`
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Copy, Clone, FromPrimitive)]
+pub enum RelationType {
+    Generalization = 1,
+    Specialization = 2,
+    Alternative = 3,
+    Contradiction = 4,
+}
+
+impl RelationType {
+    pub fn from(relation_type: i64) -> Result<RelationType> {
+        FromPrimitive::from_i64(relation_type)
+            .ok_or(anyhow!("Unknown relation type: {}", relation_type))
+    }
+
+    /// Type of the same relation seen from the related statement
+    pub fn inverse(self) -> RelationType {
+        match self {
+            RelationType::Generalization => RelationType::Specialization,
+            RelationType::Specialization => RelationType::Generalization,
+            RelationType::Alternative => RelationType::Alternative,
+            RelationType::Contradiction => RelationType::Contradiction,
+        }
+    }
+
+    /// Describes the related statement, e.g. "More general question"
+    pub fn label(self) -> &'static str {
+        match self {
+            RelationType::Generalization => "More general question",
+            RelationType::Specialization => "More specific question",
+            RelationType::Alternative => "Alternative",
+            RelationType::Contradiction => "Contradiction",
         }
     }
 }
 
+/// `statement` is a `relation_type` of `related_statement`
+#[derive(Debug, PartialEq, Eq, Serialize, Clone, Copy)]
+pub struct StatementRelation {
+    pub statement_id: i64,
+    pub related_statement_id: i64,
+    pub relation_type: RelationType,
+}
+
 #[derive(Serialize, sqlx::FromRow)]
 pub struct StatementStats {
     pub yes_votes: i64,
     pub no_votes: i64,
     pub skip_votes: i64,
+    pub itdepends_votes: i64,
     pub subscriptions: i64,
     pub total_votes: i64,
     pub participation: f64,
@@ -86,6 +152,7 @@ impl StatementStats {
             yes_votes: 0,
             no_votes: 0,
             skip_votes: 0,
+            itdepends_votes: 0,
             subscriptions: 0,
             total_votes: 0,
             participation: 0.0,
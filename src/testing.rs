@@ -0,0 +1,46 @@
+//! Setup shared by the tests
+
+use std::sync::Once;
+
+use axum::body::Bytes;
+use axum::extract::FromRequest;
+use axum::response::Response;
+use axum::Router;
+use clap::Parser;
+use http::Request;
+use sqlx::SqlitePool;
+
+use crate::command_line_args::{AuthArgs, RateLimitArgs, SelectionArgs};
+use crate::http_server::router;
+use crate::{auth, rate_limit, selection, webauthn};
+
+/// Sets up authentication like `main` does, with a fixed key and the default passkey origin.
+/// Needed by every test which hashes secrets, e.g. by creating users, or uses passkeys. Tests
+/// share the process, so this only runs once.
+pub fn init_auth() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let args = AuthArgs::parse_from(["test", "--secret-hash-key", "test key"]);
+        auth::init_secret_hash_key(&args).expect("Secret hash key is not set yet");
+        webauthn::init_relying_party(&args).expect("Relying party is not set up yet");
+    });
+}
+
+/// All routes with their middlewares and default settings, like the http server has them
+pub fn full_app(pool: &SqlitePool) -> Router {
+    init_auth();
+    router(
+        pool.clone(),
+        selection::from_args(&SelectionArgs::parse_from(["test"])),
+        rate_limit::from_args(&RateLimitArgs::parse_from(["test"])),
+        Default::default(),
+        Default::default(),
+    )
+}
+
+pub async fn body_text(response: Response) -> String {
+    let body = Bytes::from_request(Request::new(response.into_body()), &())
+        .await
+        .unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
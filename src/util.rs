@@ -15,15 +15,22 @@ pub fn human_relative_time(timestamp: i64) -> String {
 }
 
 /// Returns http(s)://domain, depending on what is used inside the headers
+///
+/// Only for links shown to the client, since the client chooses the headers.
 pub fn base_url(headers: &HeaderMap) -> String {
     let referer = headers
         .get(REFERER)
-        .map_or("https://", |header_value| header_value.to_str().unwrap());
+        .and_then(|header_value| header_value.to_str().ok())
+        .unwrap_or("https://");
     let splits: Vec<&str> = referer.split(':').collect();
     let proto = match splits[..] {
         [proto, ..] => proto,
         _ => "http",
     };
-    let host = headers[HOST].to_str().expect("Unable te get HOST header");
+    // http/2 requests may only carry the :authority pseudo header
+    let host = headers
+        .get(HOST)
+        .and_then(|header_value| header_value.to_str().ok())
+        .unwrap_or("localhost");
     format!("{proto}://{host}")
 }
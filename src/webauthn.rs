@@ -0,0 +1,288 @@
+//! Minimal WebAuthn relying party for passkey login
+//!
+//! Only ES256 (P-256) credentials are accepted and attestation is not checked, since we don't
+//! care which authenticator holds the key. The browser hands us the public key as DER via
+//! `AuthenticatorAttestationResponse.getPublicKey()`, so no CBOR parsing is needed.
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use once_cell::sync::OnceCell;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use p256::pkcs8::DecodePublicKey;
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::command_line_args::AuthArgs;
+
+/// COSE algorithm identifier of ECDSA with P-256 and SHA-256
+pub const ES256: i64 = -7;
+/// Seconds a challenge can be answered
+pub const CHALLENGE_TIMEOUT_SECONDS: i64 = 5 * 60;
+
+/// user present flag of the authenticator data
+const FLAG_USER_PRESENT: u8 = 0x01;
+/// rp id hash (32), flags (1), sign count (4)
+const AUTHENTICATOR_DATA_MIN_LEN: usize = 37;
+
+static RELYING_PARTY: OnceCell<RelyingParty> = OnceCell::new();
+
+/// The site passkeys are bound to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelyingParty {
+    /// Domain without port
+    pub id: String,
+    /// Scheme, domain and port, as reported by the browser
+    pub origin: String,
+}
+
+impl RelyingParty {
+    /// Relying party of an origin like https://example.com:8000
+    pub fn from_origin(origin: &str) -> Result<Self> {
+        let origin = origin.trim_end_matches('/');
+        let host = origin
+            .strip_prefix("https://")
+            .or_else(|| origin.strip_prefix("http://"))
+            .context("Passkey origin must start with http:// or https://")?;
+        let id = host.split(':').next().unwrap_or_default();
+        if id.is_empty() || host.contains('/') {
+            bail!("Passkey origin must only have scheme, domain and port: {origin}");
+        }
+        Ok(Self {
+            id: id.to_string(),
+            origin: origin.to_string(),
+        })
+    }
+
+    /// The relying party set up with [init_relying_party]
+    pub fn configured() -> &'static Self {
+        RELYING_PARTY
+            .get()
+            .expect("Passkey relying party is not initialized")
+    }
+}
+
+/// Sets up the relying party from `--passkey-origin`, never from request headers, since passkeys
+/// must not be bound to a site the client chose
+pub fn init_relying_party(args: &AuthArgs) -> Result<()> {
+    let rp = RelyingParty::from_origin(&args.passkey_origin)?;
+    RELYING_PARTY
+        .set(rp)
+        .map_err(|_| anyhow!("Passkey relying party is already initialized"))
+}
+
+/// Parameters for `navigator.credentials.create()` or `navigator.credentials.get()`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CeremonyOptions {
+    /// base64url
+    pub challenge: String,
+    pub rp_id: String,
+    /// base64url user handle, only set when registering
+    pub user_id: Option<String>,
+    /// Shown by the authenticator, only set when registering
+    pub user_name: Option<String>,
+}
+
+/// Response of `navigator.credentials.create()`, all binary fields are base64url
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PasskeyRegistration {
+    pub name: String,
+    pub credential_id: String,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub public_key: String,
+    pub public_key_algorithm: i64,
+}
+
+/// Response of `navigator.credentials.get()`, all binary fields are base64url
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PasskeyAssertion {
+    pub credential_id: String,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+/// A passkey that passed [verify_registration]
+pub struct VerifiedPasskey {
+    pub challenge: String,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony: String,
+    challenge: String,
+    origin: String,
+}
+
+/// Random base64url challenge
+pub fn new_challenge() -> String {
+    let mut bytes = [0u8; 32];
+    thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn decode(field: &str) -> Result<Vec<u8>> {
+    Ok(URL_SAFE_NO_PAD.decode(field)?)
+}
+
+/// Checks type and origin of the client data and returns the challenge it answers
+fn verify_client_data(
+    rp: &RelyingParty,
+    client_data_json: &[u8],
+    ceremony: &str,
+) -> Result<String> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)?;
+    if client_data.ceremony != ceremony {
+        bail!("Unexpected ceremony {}", client_data.ceremony);
+    }
+    if client_data.origin != rp.origin {
+        bail!("Unexpected origin {}", client_data.origin);
+    }
+    Ok(client_data.challenge)
+}
+
+/// Checks the rp id hash and user presence, returns the signature counter
+fn verify_authenticator_data(rp: &RelyingParty, authenticator_data: &[u8]) -> Result<u32> {
+    if authenticator_data.len() < AUTHENTICATOR_DATA_MIN_LEN {
+        bail!("Authenticator data too short");
+    }
+    if authenticator_data[..32] != Sha256::digest(rp.id.as_bytes())[..] {
+        bail!("Passkey belongs to a different site");
+    }
+    if authenticator_data[32] & FLAG_USER_PRESENT == 0 {
+        bail!("User not present");
+    }
+    let sign_count: [u8; 4] = authenticator_data[33..37].try_into()?;
+    Ok(u32::from_be_bytes(sign_count))
+}
+
+/// Verifies a new passkey, the returned challenge still has to be checked by the caller
+pub fn verify_registration(
+    rp: &RelyingParty,
+    registration: &PasskeyRegistration,
+) -> Result<VerifiedPasskey> {
+    if registration.public_key_algorithm != ES256 {
+        bail!("Only ES256 passkeys are supported");
+    }
+    let challenge = verify_client_data(
+        rp,
+        &decode(&registration.client_data_json)?,
+        "webauthn.create",
+    )?;
+    let sign_count = verify_authenticator_data(rp, &decode(&registration.authenticator_data)?)?;
+    let public_key = decode(&registration.public_key)?;
+    VerifyingKey::from_public_key_der(&public_key).map_err(|err| anyhow!(err))?;
+    Ok(VerifiedPasskey {
+        challenge,
+        public_key,
+        sign_count,
+    })
+}
+
+/// Verifies a login signed with the stored public key, returns the answered challenge and the
+/// new signature counter
+pub fn verify_assertion(
+    rp: &RelyingParty,
+    public_key: &[u8],
+    assertion: &PasskeyAssertion,
+) -> Result<(String, u32)> {
+    let client_data_json = decode(&assertion.client_data_json)?;
+    let authenticator_data = decode(&assertion.authenticator_data)?;
+    let challenge = verify_client_data(rp, &client_data_json, "webauthn.get")?;
+    let sign_count = verify_authenticator_data(rp, &authenticator_data)?;
+
+    let key = VerifyingKey::from_public_key_der(public_key).map_err(|err| anyhow!(err))?;
+    let signature = Signature::from_der(&decode(&assertion.signature)?)?;
+    let signed = [
+        authenticator_data.as_slice(),
+        Sha256::digest(&client_data_json).as_slice(),
+    ]
+    .concat();
+    key.verify(&signed, &signature)?;
+    Ok((challenge, sign_count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::signature::Signer;
+    use p256::ecdsa::SigningKey;
+    use p256::pkcs8::EncodePublicKey;
+
+    fn rp() -> RelyingParty {
+        RelyingParty {
+            id: "localhost".to_string(),
+            origin: "http://localhost:8000".to_string(),
+        }
+    }
+
+    fn sign(key: &SigningKey, rp_id: &str, client_data: &str, sign_count: u32) -> PasskeyAssertion {
+        let authenticator_data = [
+            Sha256::digest(rp_id.as_bytes()).as_slice(),
+            &[FLAG_USER_PRESENT],
+            &sign_count.to_be_bytes(),
+        ]
+        .concat();
+        let signed = [
+            authenticator_data.as_slice(),
+            Sha256::digest(client_data.as_bytes()).as_slice(),
+        ]
+        .concat();
+        let signature: Signature = key.sign(&signed);
+        PasskeyAssertion {
+            credential_id: "id".to_string(),
+            client_data_json: URL_SAFE_NO_PAD.encode(client_data),
+            authenticator_data: URL_SAFE_NO_PAD.encode(authenticator_data),
+            signature: URL_SAFE_NO_PAD.encode(signature.to_der()),
+        }
+    }
+
+    #[test]
+    fn relying_party_from_origin() -> Result<()> {
+        assert_eq!(
+            RelyingParty::from_origin("https://propolis.example:8443/")?,
+            RelyingParty {
+                id: "propolis.example".to_string(),
+                origin: "https://propolis.example:8443".to_string(),
+            }
+        );
+        assert!(RelyingParty::from_origin("propolis.example").is_err());
+        assert!(RelyingParty::from_origin("https://propolis.example/login").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn verify_signed_assertion() -> Result<()> {
+        let key = SigningKey::from_bytes(&[7u8; 32].into())?;
+        let public_key = key.verifying_key().to_public_key_der()?.into_vec();
+        let client_data =
+            r#"{"type":"webauthn.get","challenge":"abc","origin":"http://localhost:8000"}"#;
+
+        let assertion = sign(&key, "localhost", client_data, 3);
+        assert_eq!(
+            verify_assertion(&rp(), &public_key, &assertion)?,
+            ("abc".to_string(), 3)
+        );
+
+        // signed for a different site
+        let assertion = sign(&key, "example.com", client_data, 3);
+        assert!(verify_assertion(&rp(), &public_key, &assertion).is_err());
+
+        // signed by a different key
+        let other_key = SigningKey::from_bytes(&[8u8; 32].into())?;
+        let assertion = sign(&other_key, "localhost", client_data, 3);
+        assert!(verify_assertion(&rp(), &public_key, &assertion).is_err());
+
+        // registration response replayed as login
+        let client_data =
+            r#"{"type":"webauthn.create","challenge":"abc","origin":"http://localhost:8000"}"#;
+        let assertion = sign(&key, "localhost", client_data, 3);
+        assert!(verify_assertion(&rp(), &public_key, &assertion).is_err());
+        Ok(())
+    }
+}
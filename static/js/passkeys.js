@@ -0,0 +1,83 @@
+// WebAuthn passkeys, see src/webauthn.rs for the server side
+
+function propolisToBase64Url(buffer) {
+    const bytes = new Uint8Array(buffer);
+    let binary = "";
+    bytes.forEach((byte) => binary += String.fromCharCode(byte));
+    return btoa(binary).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+function propolisFromBase64Url(string) {
+    const base64 = string.replace(/-/g, "+").replace(/_/g, "/");
+    return Uint8Array.from(atob(base64), (c) => c.charCodeAt(0));
+}
+
+async function propolisPostJson(url, body) {
+    const response = await fetch(url, {
+        method: "POST",
+        headers: {
+            "Content-Type": "application/json",
+            // see src/csrf.rs
+            "X-CSRF-Token": document.querySelector('meta[name="csrf-token"]').content,
+        },
+        body: JSON.stringify(body),
+    });
+    if (!response.ok) {
+        // error bodies are html pages, the status is enough for an alert
+        throw new Error(response.status + " " + response.statusText);
+    }
+    return response.status === 204 ? null : response.json();
+}
+
+async function propolisRegisterPasskey(name) {
+    try {
+        const options = await propolisPostJson("/user/credentials/passkey/options", {});
+        const credential = await navigator.credentials.create({
+            publicKey: {
+                challenge: propolisFromBase64Url(options.challenge),
+                rp: { id: options.rp_id, name: "Propolis" },
+                user: {
+                    id: propolisFromBase64Url(options.user_id),
+                    name: options.user_name,
+                    displayName: options.user_name,
+                },
+                pubKeyCredParams: [{ type: "public-key", alg: -7 }],
+                authenticatorSelection: { residentKey: "required", userVerification: "preferred" },
+                attestation: "none",
+            },
+        });
+        await propolisPostJson("/user/credentials/passkey", {
+            name: name || "Passkey",
+            credential_id: propolisToBase64Url(credential.rawId),
+            client_data_json: propolisToBase64Url(credential.response.clientDataJSON),
+            authenticator_data: propolisToBase64Url(credential.response.getAuthenticatorData()),
+            public_key: propolisToBase64Url(credential.response.getPublicKey()),
+            public_key_algorithm: credential.response.getPublicKeyAlgorithm(),
+        });
+        window.location.reload();
+    } catch (error) {
+        alert("Could not add passkey: " + error.message);
+    }
+}
+
+async function propolisLoginWithPasskey() {
+    try {
+        const options = await propolisPostJson("/login/passkey/options", {});
+        const credential = await navigator.credentials.get({
+            publicKey: {
+                challenge: propolisFromBase64Url(options.challenge),
+                rpId: options.rp_id,
+                userVerification: "preferred",
+            },
+        });
+        await propolisPostJson("/login/passkey", {
+            credential_id: propolisToBase64Url(credential.rawId),
+            client_data_json: propolisToBase64Url(credential.response.clientDataJSON),
+            authenticator_data: propolisToBase64Url(credential.response.authenticatorData),
+            signature: propolisToBase64Url(credential.response.signature),
+        });
+        window.location = "/";
+    } catch (error) {
+        alert("Could not log in: " + error.message);
+    }
+}
@@ -78,12 +78,14 @@ async fn update_current_vote_and_stats(pool: SqlitePool) -> sqlx::Result<()> {
         .await?;
 
     // expect updated stats
-    let stats =
-        sqlx::query!("select yes_votes, no_votes from statement_stats where statement_id = 2")
-            .fetch_one(&pool)
-            .await?;
+    let stats = sqlx::query!(
+        "select yes_votes, no_votes, itdepends_votes from statement_stats where statement_id = 2"
+    )
+    .fetch_one(&pool)
+    .await?;
     assert_eq!(stats.yes_votes, 0);
     assert_eq!(stats.no_votes, 2);
+    assert_eq!(stats.itdepends_votes, 1);
 
     Ok(())
 }
@@ -255,6 +257,48 @@ async fn vote_adds_no_followup_to_queue(pool: SqlitePool) -> sqlx::Result<()> {
     Ok(())
 }
 
+#[sqlx::test]
+async fn vote_itdepends_adds_all_followups_to_queue(pool: SqlitePool) -> sqlx::Result<()> {
+    sqlx::query!("insert into users(id, secret) values (17, 'abc')")
+        .execute(&pool)
+        .await?;
+    sqlx::query!("insert into statements(id, text) values (2, 'The world is flat.')")
+        .execute(&pool)
+        .await?;
+    sqlx::query!("insert into statements(id, text) values (3, 'The universe is flat.')")
+        .execute(&pool)
+        .await?;
+    sqlx::query!("insert into followups(statement_id, followup_id, target_yes, target_no) values (2, 3, 1, 0)")
+        .execute(&pool)
+        .await?;
+    sqlx::query!("insert into statements(id, text) values (4, 'The world is round.')")
+        .execute(&pool)
+        .await?;
+    sqlx::query!("insert into followups(statement_id, followup_id, target_yes, target_no) values (2, 4, 0, 1)")
+        .execute(&pool)
+        .await?;
+
+    //////////////////////////////
+    // add it-depends vote
+    sqlx::query!("insert into vote_history(user_id, statement_id, vote) values (17, 2, 2)")
+        .execute(&pool)
+        .await?;
+
+    // expect both followup statements in queue
+    let count =
+        sqlx::query_scalar!("select count(*) from queue where user_id = 17 and statement_id = 3")
+            .fetch_one(&pool)
+            .await?;
+    assert_eq!(count, 1);
+    let count =
+        sqlx::query_scalar!("select count(*) from queue where user_id = 17 and statement_id = 4")
+            .fetch_one(&pool)
+            .await?;
+    assert_eq!(count, 1);
+
+    Ok(())
+}
+
 //adding a followups, puts the followup in the queue for yes voters
 #[sqlx::test]
 async fn followup_adds_yes_followup_to_queue(pool: SqlitePool) -> sqlx::Result<()> {
@@ -345,6 +389,40 @@ async fn followup_adds_no_followup_to_queue(pool: SqlitePool) -> sqlx::Result<()
     Ok(())
 }
 
+// adding a followup, puts the followup in the queue for it-depends voters
+#[sqlx::test]
+async fn followup_adds_itdepends_followup_to_queue(pool: SqlitePool) -> sqlx::Result<()> {
+    sqlx::query!("insert into users(id, secret) values (17, 'abc')")
+        .execute(&pool)
+        .await?;
+    sqlx::query!("insert into statements(id, text) values (2, 'The world is flat.')")
+        .execute(&pool)
+        .await?;
+    sqlx::query!("insert into statements(id, text) values (3, 'The universe is flat.')")
+        .execute(&pool)
+        .await?;
+
+    //////////////////////////////
+    // add it-depends vote
+    sqlx::query!("insert into vote_history(user_id, statement_id, vote) values (17, 2, 2)")
+        .execute(&pool)
+        .await?;
+
+    // add followup, which is targeted at neither yes nor no voters
+    sqlx::query!("insert into followups(statement_id, followup_id, target_yes, target_no) values (2, 3, 0, 0)")
+        .execute(&pool)
+        .await?;
+
+    // expect followup statement in queue
+    let count =
+        sqlx::query_scalar!("select count(*) from queue where user_id = 17 and statement_id = 3")
+            .fetch_one(&pool)
+            .await?;
+    assert_eq!(count, 1);
+
+    Ok(())
+}
+
 // subscribing a statement with a followup, puts that followup in the queue for the subscriber
 #[sqlx::test]
 async fn subscribe_adds_followup_to_queue(pool: SqlitePool) -> sqlx::Result<()> {
@@ -532,3 +610,78 @@ async fn maintain_statement_full_text_search_index(pool: SqlitePool) -> sqlx::Re
 
     Ok(())
 }
+
+#[sqlx::test]
+async fn vote_on_specialization_adds_related_to_queue(pool: SqlitePool) -> sqlx::Result<()> {
+    sqlx::query!("insert into users(id, secret) values (3, 'abc')")
+        .execute(&pool)
+        .await?;
+    for (id, text) in [
+        (1, "Should cars be banned from cities?"),
+        (2, "Should cars be banned from Berlin?"),
+        (3, "Should cars be banned from Paris?"),
+        (4, "Should cars be banned from London?"),
+    ] {
+        sqlx::query!("insert into statements(id, text) values (?, ?)", id, text)
+            .execute(&pool)
+            .await?;
+    }
+    // 2 is a specialization of 1, 1 is a generalization of 3 and 4
+    sqlx::query!(
+        "insert into statement_relations(statement_id, related_statement_id, relation_type) values (2, 1, 2), (1, 3, 1), (1, 4, 1)"
+    )
+    .execute(&pool)
+    .await?;
+    // already voted on 4
+    sqlx::query!("insert into vote_history(user_id, statement_id, vote) values (3, 4, 1)")
+        .execute(&pool)
+        .await?;
+
+    sqlx::query!("insert into vote_history(user_id, statement_id, vote) values (3, 2, 1)")
+        .execute(&pool)
+        .await?;
+
+    let queue = sqlx::query_scalar!(
+        "select statement_id from queue where user_id = 3 order by statement_id"
+    )
+    .fetch_all(&pool)
+    .await?;
+    assert_eq!(queue, vec![1, 3]);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn skip_on_specialization_does_not_add_related_to_queue(
+    pool: SqlitePool,
+) -> sqlx::Result<()> {
+    sqlx::query!("insert into users(id, secret) values (3, 'abc')")
+        .execute(&pool)
+        .await?;
+    sqlx::query!(
+        "insert into statements(id, text) values (1, 'Should cars be banned from cities?')"
+    )
+    .execute(&pool)
+    .await?;
+    sqlx::query!(
+        "insert into statements(id, text) values (2, 'Should cars be banned from Berlin?')"
+    )
+    .execute(&pool)
+    .await?;
+    sqlx::query!(
+        "insert into statement_relations(statement_id, related_statement_id, relation_type) values (2, 1, 2)"
+    )
+    .execute(&pool)
+    .await?;
+
+    sqlx::query!("insert into vote_history(user_id, statement_id, vote) values (3, 2, 0)")
+        .execute(&pool)
+        .await?;
+
+    let count = sqlx::query_scalar!("select count(*) from queue where user_id = 3")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(count, 0);
+
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use sqlx::SqlitePool;
+
+#[sqlx::test]
+async fn merge_moves_content_and_keeps_newest_vote(pool: SqlitePool) -> sqlx::Result<()> {
+    sqlx::query!("insert into users(id, secret) values (1, 'source'), (2, 'target')")
+        .execute(&pool)
+        .await?;
+    for id in [1, 2, 3, 4] {
+        sqlx::query!("insert into statements(id, text) values (?, 'text')", id)
+            .execute(&pool)
+            .await?;
+    }
+    // statement 1: source voted last, statement 2: target voted last, statement 3: only source
+    sqlx::query!(
+        "insert into vote_history(user_id, statement_id, vote, created) values
+        (2, 1, -1, 100), (1, 1, 1, 200),
+        (1, 2, -1, 100), (2, 2, 1, 200),
+        (1, 3, 2, 100)"
+    )
+    .execute(&pool)
+    .await?;
+    sqlx::query!("insert into authors(user_id, statement_id) values (1, 3)")
+        .execute(&pool)
+        .await?;
+    sqlx::query!("insert into subscriptions(user_id, statement_id) values (1, 4), (2, 4), (1, 3)")
+        .execute(&pool)
+        .await?;
+    sqlx::query!("insert into queue(user_id, statement_id) values (1, 4), (2, 3)")
+        .execute(&pool)
+        .await?;
+    sqlx::query!(
+        "insert into statement_relations(statement_id, related_statement_id, relation_type, created_by)
+        values (3, 4, 3, 1)"
+    )
+    .execute(&pool)
+    .await?;
+
+    //////////////////////////////
+    // merge with content
+    sqlx::query!("insert into user_merges(source_id, target_id, move_content) values (1, 2, 1)")
+        .execute(&pool)
+        .await?;
+
+    // expect the source to be gone
+    let users = sqlx::query_scalar!("select id from users")
+        .fetch_all(&pool)
+        .await?;
+    assert_eq!(users, vec![2]);
+
+    // expect the newer vote to win
+    let votes = sqlx::query!(
+        "select statement_id, vote from votes where user_id = 2 order by statement_id"
+    )
+    .fetch_all(&pool)
+    .await?;
+    assert_eq!(
+        votes
+            .iter()
+            .map(|v| (v.statement_id, v.vote))
+            .collect::<Vec<_>>(),
+        vec![(1, 1), (2, 1), (3, 2)]
+    );
+    let history = sqlx::query_scalar!("select count(*) from vote_history where user_id = 2")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(history, 5);
+
+    // expect moved authorship and subscriptions
+    let authored = sqlx::query_scalar!("select statement_id from authors where user_id = 2")
+        .fetch_all(&pool)
+        .await?;
+    assert_eq!(authored, vec![3]);
+    let subscriptions = sqlx::query_scalar!(
+        "select statement_id from subscriptions where user_id = 2 order by statement_id"
+    )
+    .fetch_all(&pool)
+    .await?;
+    assert_eq!(subscriptions, vec![3, 4]);
+    let relation_creators = sqlx::query_scalar!("select created_by from statement_relations")
+        .fetch_all(&pool)
+        .await?;
+    assert_eq!(relation_creators, vec![Some(2)]);
+
+    // statement 3 was voted on by the source, so it leaves the queue
+    let queue = sqlx::query_scalar!("select statement_id from queue where user_id = 2")
+        .fetch_all(&pool)
+        .await?;
+    assert_eq!(queue, vec![4]);
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn merge_without_content_deletes_source(pool: SqlitePool) -> sqlx::Result<()> {
+    sqlx::query!("insert into users(id, secret) values (1, 'source'), (2, 'target')")
+        .execute(&pool)
+        .await?;
+    sqlx::query!("insert into statements(id, text) values (1, 'text')")
+        .execute(&pool)
+        .await?;
+    sqlx::query!("insert into vote_history(user_id, statement_id, vote) values (1, 1, 1)")
+        .execute(&pool)
+        .await?;
+
+    //////////////////////////////
+    // merging a user with itself fails
+    let merged = sqlx::query!(
+        "insert into user_merges(source_id, target_id, move_content) values (1, 1, 0)"
+    )
+    .execute(&pool)
+    .await;
+    assert!(merged.is_err());
+
+    //////////////////////////////
+    // merge without content
+    sqlx::query!("insert into user_merges(source_id, target_id, move_content) values (1, 2, 0)")
+        .execute(&pool)
+        .await?;
+
+    // expect the source and everything of it to be gone
+    let users = sqlx::query_scalar!("select id from users")
+        .fetch_all(&pool)
+        .await?;
+    assert_eq!(users, vec![2]);
+    let votes = sqlx::query_scalar!("select count(*) from votes")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(votes, 0);
+    let history = sqlx::query_scalar!("select count(*) from vote_history")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(history, 0);
+
+    Ok(())
+}